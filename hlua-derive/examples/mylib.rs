@@ -0,0 +1,34 @@
+// Lua module written in Rust. Build it with `cargo build --example mylib`, then copy
+// `libmylib.so` (or `mylib.dll` on Windows) to `mylib.so` somewhere in the `package.cpath` of
+// your Lua interpreter. The module must be linked to the same shared Lua library as the
+// interpreter, which `lua52-sys` only does if `pkg-config` finds `lua5.2` when building it:
+//
+//     > mylib = require("mylib")
+//     mylib is now loaded!
+//     > return mylib.function1(2, 4)
+//     6
+//     > return mylib.VERSION
+//     1.0
+
+extern crate hlua;
+extern crate hlua_derive;
+
+use hlua_derive::lua_module;
+
+#[lua_module]
+pub mod mylib {
+    pub static VERSION: &str = "1.0";
+
+    pub fn function1(a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    pub fn function2(a: i32) -> i32 {
+        a + 5
+    }
+
+    #[lua_module_init]
+    fn init() {
+        println!("mylib is now loaded!")
+    }
+}
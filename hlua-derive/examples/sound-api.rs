@@ -0,0 +1,75 @@
+// Same as the `sound-api` example of hlua, but with the attributes of hlua-derive instead of
+// filling the metatable manually.
+
+extern crate hlua;
+extern crate hlua_derive;
+
+use hlua_derive::{lua_class, lua_methods};
+
+fn main() {
+    let mut lua = hlua::Lua::new();
+    lua.openlibs();
+
+    // the `Sound` table contains the functions of the impl block that don't take `self`
+    lua.set("Sound", hlua::LuaClassTable::<Sound>::new());
+
+    lua.execute::<()>(r#"
+        s = Sound.new();
+        s:play();
+
+        print("hello world from within lua!");
+        print("is the sound playing:", s:is_playing());
+
+        s.volume = 20;
+        print("current sound:", tostring(s));
+
+        s:stop();
+        print("is the sound playing:", s:is_playing());
+
+    "#)
+        .unwrap();
+}
+
+// this `Sound` struct is the object that we will use to demonstrate hlua
+#[lua_class]
+struct Sound {
+    playing: bool,
+
+    // fields can be exposed as properties
+    #[lua(get, set)]
+    volume: u8,
+}
+
+// the methods of this impl block can be called from Lua
+#[lua_methods]
+impl Sound {
+    pub fn new() -> Sound {
+        Sound { playing: false, volume: 100 }
+    }
+
+    pub fn play(&mut self) {
+        println!("playing");
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        println!("stopping");
+        self.playing = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    #[lua(tostring)]
+    pub fn describe(&self) -> String {
+        format!("sound at volume {}", self.volume)
+    }
+}
+
+// this destructor is here to show you that objects are properly getting destroyed
+impl Drop for Sound {
+    fn drop(&mut self) {
+        println!("`Sound` object destroyed");
+    }
+}
@@ -0,0 +1,101 @@
+use syn;
+use syn::spanned::Spanned;
+
+/// Options that can be set on an item with `#[lua(...)]`.
+#[derive(Default)]
+pub struct Options {
+    /// Name of the item in Lua, if different from the Rust name.
+    pub rename: Option<String>,
+    /// If true, the item is ignored.
+    pub skip: bool,
+    /// If true, the field can be read from Lua.
+    pub get: bool,
+    /// If true, the field can be written from Lua.
+    pub set: bool,
+    /// If true, the method is used to convert the object to a string.
+    pub tostring: bool,
+    /// Name of the metamethod that the method implements.
+    pub meta: Option<String>,
+}
+
+/// Parses the `#[lua(...)]` attributes of an item. Returns an error if an option that isn't in
+/// `allowed` is used.
+pub fn parse(attrs: &[syn::Attribute], allowed: &[&str]) -> syn::Result<Options> {
+    let mut options = Options::default();
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("lua")) {
+        attr.parse_nested_meta(|meta| {
+            let name = meta.path.get_ident().map(|i| i.to_string()).unwrap_or_default();
+            if !allowed.contains(&&name[..]) {
+                let expected: Vec<_> = allowed.iter().map(|a| format!("`{}`", a)).collect();
+                return Err(meta.error(format!("unknown lua attribute, expected {}",
+                                              expected.join(" or "))));
+            }
+
+            match &name[..] {
+                "rename" => {
+                    let name: syn::LitStr = meta.value()?.parse()?;
+                    options.rename = Some(name.value());
+                }
+                "meta" => {
+                    let name: syn::LitStr = meta.value()?.parse()?;
+                    options.meta = Some(name.value());
+                }
+                "skip" => options.skip = true,
+                "get" => options.get = true,
+                "set" => options.set = true,
+                "tostring" => options.tostring = true,
+                _ => unreachable!(),
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(options)
+}
+
+/// Removes the `#[lua(...)]` attributes, for the attribute macros that re-emit their input.
+pub fn strip(attrs: &mut Vec<syn::Attribute>) {
+    attrs.retain(|a| !a.path().is_ident("lua"));
+}
+
+/// A field of a struct or of a variant that is not skipped.
+pub struct Field<'a> {
+    /// Position of the field in the Rust definition.
+    pub position: usize,
+    /// Key of the field in the Lua table, for named fields.
+    pub key: String,
+    pub ty: &'a syn::Type,
+}
+
+/// Returns the fields of a struct or of a variant that are not skipped.
+pub fn fields(fields: &syn::Fields) -> syn::Result<Vec<Field<'_>>> {
+    let mut kept = Vec::new();
+
+    for (position, field) in fields.iter().enumerate() {
+        let options = parse(&field.attrs, &["rename", "skip"])?;
+        if options.skip {
+            continue;
+        }
+
+        if options.rename.is_some() && field.ident.is_none() {
+            return Err(syn::Error::new(field.span(), "tuple fields can't be renamed"));
+        }
+
+        let key = match (options.rename, &field.ident) {
+            (Some(name), _) => name,
+            (None, Some(ident)) => ident.to_string(),
+            (None, None) => String::new(),
+        };
+
+        kept.push(Field { position, key, ty: &field.ty });
+    }
+
+    Ok(kept)
+}
+
+/// Returns the name of a variant in Lua.
+pub fn variant_name(variant: &syn::Variant) -> syn::Result<String> {
+    let options = parse(&variant.attrs, &["rename"])?;
+    Ok(options.rename.unwrap_or_else(|| variant.ident.to_string()))
+}
@@ -0,0 +1,242 @@
+use proc_macro2::{Span, TokenStream};
+use syn;
+use syn::spanned::Spanned;
+
+use attr;
+
+/// Implementation of `#[lua_class]`.
+pub fn lua_class(args: TokenStream, input: TokenStream) -> syn::Result<TokenStream> {
+    let mut item: syn::ItemStruct = syn::parse2(input)?;
+    let name = item.ident.clone();
+
+    let mut class_name = name.to_string();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            let value: syn::LitStr = meta.value()?.parse()?;
+            class_name = value.value();
+            Ok(())
+        } else {
+            Err(meta.error("unknown lua_class attribute, expected `name`"))
+        }
+    });
+    syn::parse::Parser::parse2(parser, args)?;
+
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new(item.generics.span(), "classes can't be generic"));
+    }
+
+    let mut getters = Vec::new();
+    let mut setters = Vec::new();
+    for field in item.fields.iter_mut() {
+        let options = attr::parse(&field.attrs, &["get", "set", "rename"])?;
+        attr::strip(&mut field.attrs);
+
+        let ident = match field.ident {
+            Some(ref ident) => ident,
+            None if options.get || options.set => {
+                return Err(syn::Error::new(field.span(), "only named fields can be properties"));
+            }
+            None => continue,
+        };
+        let key = options.rename.unwrap_or_else(|| ident.to_string());
+        let ty = &field.ty;
+
+        if options.get {
+            getters.push(quote! {
+                getters.set(#key, ::hlua::function1(|this: &#name| {
+                    ::std::clone::Clone::clone(&this.#ident)
+                }));
+            });
+        }
+        if options.set {
+            setters.push(quote! {
+                setters.set(#key, ::hlua::function2(|this: &mut #name, value: #ty| {
+                    this.#ident = value;
+                }));
+            });
+        }
+    }
+
+    let getters = if getters.is_empty() {
+        quote!()
+    } else {
+        quote! {
+            #[inline]
+            fn lua_getters<'__lua, __L>(getters: &mut ::hlua::LuaTable<__L>)
+                where __L: ::hlua::AsMutLua<'__lua>
+            {
+                #(#getters)*
+            }
+        }
+    };
+
+    let setters = if setters.is_empty() {
+        quote!()
+    } else {
+        quote! {
+            #[inline]
+            fn lua_setters<'__lua, __L>(setters: &mut ::hlua::LuaTable<__L>)
+                where __L: ::hlua::AsMutLua<'__lua>
+            {
+                #(#setters)*
+            }
+        }
+    };
+
+    Ok(quote! {
+        #item
+
+        impl ::hlua::LuaClass for #name {
+            #[inline]
+            fn class_name() -> &'static str {
+                #class_name
+            }
+
+            #getters
+            #setters
+        }
+
+        impl<'__lua, __L> ::hlua::Push<__L> for #name
+            where __L: ::hlua::AsMutLua<'__lua>
+        {
+            type Err = ::hlua::Void;
+
+            #[inline]
+            fn push_to_lua(self, lua: __L)
+                           -> Result<::hlua::PushGuard<__L>, (::hlua::Void, __L)>
+            {
+                Ok(::hlua::push_class(self, lua))
+            }
+        }
+
+        impl<'__lua, __L> ::hlua::PushOne<__L> for #name
+            where __L: ::hlua::AsMutLua<'__lua>
+        {
+        }
+
+        ::hlua::implement_lua_read!(#name);
+    })
+}
+
+/// Implementation of `#[lua_methods]`.
+pub fn lua_methods(args: TokenStream, input: TokenStream) -> syn::Result<TokenStream> {
+    if !args.is_empty() {
+        return Err(syn::Error::new(args.span(), "lua_methods doesn't take any argument"));
+    }
+
+    let mut item: syn::ItemImpl = syn::parse2(input)?;
+    if let Some((_, ref path, _)) = item.trait_ {
+        return Err(syn::Error::new(path.span(), "lua_methods can't be used on a trait impl"));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new(item.generics.span(), "classes can't be generic"));
+    }
+    let self_ty = item.self_ty.clone();
+
+    let mut methods = Vec::new();
+    let mut metamethods = Vec::new();
+    let mut static_methods = Vec::new();
+
+    for impl_item in item.items.iter_mut() {
+        let method = match *impl_item {
+            syn::ImplItem::Fn(ref mut method) => method,
+            _ => continue,
+        };
+
+        let options = attr::parse(&method.attrs, &["rename", "skip", "tostring", "meta"])?;
+        attr::strip(&mut method.attrs);
+        if options.skip {
+            continue;
+        }
+
+        let function = wrap_method(&self_ty, &method.sig)?;
+        let key = options.rename.unwrap_or_else(|| method.sig.ident.to_string());
+        let has_receiver = method.sig.receiver().is_some();
+
+        if options.tostring || options.meta.is_some() {
+            if !has_receiver {
+                return Err(syn::Error::new(method.sig.span(),
+                                           "metamethods must take `&self` or `&mut self`"));
+            }
+            let key = if options.tostring {
+                "__tostring".to_owned()
+            } else {
+                options.meta.unwrap()
+            };
+            metamethods.push(quote!(metatable.set(#key, #function);));
+        } else if has_receiver {
+            methods.push(quote!(methods.set(#key, #function);));
+        } else {
+            static_methods.push(quote!(class.set(#key, #function);));
+        }
+    }
+
+    let mut functions = Vec::new();
+    for (name, table, content) in [("lua_methods", "methods", methods),
+                                   ("lua_metamethods", "metatable", metamethods),
+                                   ("lua_static_methods", "class", static_methods)] {
+        if content.is_empty() {
+            continue;
+        }
+        let name = syn::Ident::new(name, Span::call_site());
+        let table = syn::Ident::new(table, Span::call_site());
+        functions.push(quote! {
+            #[inline]
+            fn #name<'__lua, __L>(#table: &mut ::hlua::LuaTable<__L>)
+                where __L: ::hlua::AsMutLua<'__lua>
+            {
+                #(#content)*
+            }
+        });
+    }
+
+    Ok(quote! {
+        #item
+
+        impl ::hlua::LuaMethods for #self_ty {
+            #(#functions)*
+        }
+    })
+}
+
+/// Builds a `hlua::functionN` that calls the method with the object as first parameter, if any.
+fn wrap_method(self_ty: &syn::Type, sig: &syn::Signature) -> syn::Result<TokenStream> {
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(sig.generics.span(), "methods can't be generic"));
+    }
+    if sig.asyncness.is_some() {
+        return Err(syn::Error::new(sig.span(), "methods can't be async"));
+    }
+
+    let mut params = Vec::new();
+    let mut args = Vec::new();
+    for (n, input) in sig.inputs.iter().enumerate() {
+        match *input {
+            syn::FnArg::Receiver(ref receiver) => {
+                if receiver.reference.is_none() {
+                    return Err(syn::Error::new(receiver.span(),
+                                               "methods can't take `self` by value"));
+                }
+                let mutability = &receiver.mutability;
+                params.push(quote!(this: &#mutability #self_ty));
+                args.push(quote!(this));
+            }
+            syn::FnArg::Typed(ref typed) => {
+                let arg = syn::Ident::new(&format!("__arg{}", n), Span::call_site());
+                let ty = &typed.ty;
+                params.push(quote!(#arg: #ty));
+                args.push(quote!(#arg));
+            }
+        }
+    }
+
+    if params.len() > 10 {
+        return Err(syn::Error::new(sig.inputs.span(), "methods can't have more than 10 parameters"));
+    }
+
+    let function = syn::Ident::new(&format!("function{}", params.len()), Span::call_site());
+    let ident = &sig.ident;
+    Ok(quote! {
+        ::hlua::#function(|#(#params),*| <#self_ty>::#ident(#(#args),*))
+    })
+}
@@ -0,0 +1,308 @@
+//! Procedural macros for hlua.
+//!
+//! `#[derive(LuaPush)]` and `#[derive(LuaRead)]` can be applied to structs and enums whose fields
+//! can themselves be pushed or read. The value is converted to and from a Lua table:
+//!
+//! - A struct with named fields becomes a table whose keys are the names of the fields.
+//! - A tuple struct becomes an array whose elements are the fields, starting at index 1.
+//! - A tuple struct with exactly one field is converted like this field.
+//! - A unit struct becomes an empty table.
+//! - A unit variant of an enum becomes a string containing the name of the variant.
+//! - Any other variant becomes a table with a single key, the name of the variant, whose value
+//!   is the content of the variant converted like a struct.
+//!
+//! Fields and variants accept the following attributes:
+//!
+//! - `#[lua(rename = "name")]` uses `name` as the key in Lua instead of the Rust name.
+//! - `#[lua(skip)]` ignores a field. It is filled with `Default::default()` when reading.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate hlua;
+//! #[macro_use] extern crate hlua_derive;
+//!
+//! #[derive(Debug, PartialEq, LuaPush, LuaRead)]
+//! struct Config {
+//!     name: String,
+//!     #[lua(rename = "max-players")]
+//!     max_players: u32,
+//!     #[lua(skip)]
+//!     cache: Vec<u8>,
+//! }
+//!
+//! # fn main() {
+//! let mut lua = hlua::Lua::new();
+//! lua.set("config", Config { name: "test".to_owned(), max_players: 4, cache: vec![1, 2] });
+//!
+//! let max: u32 = lua.execute("return config['max-players']").unwrap();
+//! assert_eq!(max, 4);
+//!
+//! let config: Config = lua.get("config").unwrap();
+//! assert_eq!(config, Config { name: "test".to_owned(), max_players: 4, cache: vec![] });
+//! # }
+//! ```
+//!
+//! # Classes
+//!
+//! The `#[lua_class]` and `#[lua_methods]` attributes expose a struct to Lua as a user data, with
+//! properties and methods. Both attributes are needed, on the struct and on one of its impl
+//! blocks.
+//!
+//! On the struct, `#[lua(get)]` and `#[lua(set)]` make a field readable with `obj.field` and
+//! writable with `obj.field = value`. Reading a field clones it. The name of the class can be
+//! changed with `#[lua_class(name = "...")]`.
+//!
+//! In the impl block, the methods that take `&self` or `&mut self` can be called with
+//! `obj:method(...)`. The other functions go in the table that is created by pushing a
+//! `hlua::LuaClassTable`, which is where constructors belong. Methods accept `#[lua(rename =
+//! "...")]`, `#[lua(skip)]`, `#[lua(tostring)]` to implement `tostring(obj)`, and `#[lua(meta =
+//! "__name")]` to implement any other metamethod.
+//!
+//! ```
+//! extern crate hlua;
+//! extern crate hlua_derive;
+//!
+//! use hlua_derive::{lua_class, lua_methods};
+//!
+//! #[lua_class]
+//! struct Sound {
+//!     #[lua(get, set)]
+//!     volume: u8,
+//!     playing: bool,
+//! }
+//!
+//! #[lua_methods]
+//! impl Sound {
+//!     fn new() -> Sound {
+//!         Sound { volume: 100, playing: false }
+//!     }
+//!
+//!     fn play(&mut self) {
+//!         self.playing = true;
+//!     }
+//!
+//!     fn is_playing(&self) -> bool {
+//!         self.playing
+//!     }
+//!
+//!     #[lua(tostring)]
+//!     fn describe(&self) -> String {
+//!         format!("sound at volume {}", self.volume)
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let mut lua = hlua::Lua::new();
+//! lua.openlibs();
+//! lua.set("Sound", hlua::LuaClassTable::<Sound>::new());
+//!
+//! lua.execute::<()>("s = Sound.new(); s.volume = 50; s:play()").unwrap();
+//! assert!(lua.execute::<bool>("return s:is_playing()").unwrap());
+//! assert_eq!(lua.execute::<String>("return tostring(s)").unwrap(), "sound at volume 50");
+//! # }
+//! ```
+//!
+//! # Modules
+//!
+//! The `#[lua_module]` attribute turns a Rust module into a Lua module. It adds to the module a
+//! `luaopen_<name>` function, where `<name>` is the name of the Rust module, that returns a table
+//! containing the functions, the statics and the constants of the module. Compile the crate as a
+//! `cdylib` and the library can be loaded from Lua with `require("<name>")`.
+//!
+//! The library must use the same shared Lua library as the interpreter that loads it, which is
+//! the case if `pkg-config` found `lua5.2` when `lua52-sys` was built. Otherwise it contains its
+//! own copy of Lua, and `luaopen_<name>` raises a "multiple Lua VMs detected" error instead of
+//! corrupting the state of the interpreter.
+//!
+//! Items accept `#[lua(rename = "...")]` and `#[lua(skip)]`, and the name of the module can be
+//! changed with `#[lua_module(name = "...")]`. A function marked with `#[lua_module_init]` is
+//! called when the module is loaded instead of being exported. It can take a `&mut hlua::Lua`
+//! parameter.
+//!
+//! ```
+//! extern crate hlua;
+//! extern crate hlua_derive;
+//!
+//! use hlua_derive::lua_module;
+//!
+//! #[lua_module]
+//! pub mod mylib {
+//!     pub static PI: f32 = 3.141592;
+//!
+//!     pub fn add(a: i32, b: i32) -> i32 {
+//!         a + b
+//!     }
+//!
+//!     #[lua_module_init]
+//!     fn init() {
+//!         println!("mylib is now loaded!")
+//!     }
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! ```lua
+//! > mylib = require("mylib")
+//! mylib is now loaded!
+//! > return mylib.add(2, 4)
+//! 6
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+
+mod attr;
+mod class;
+mod module;
+mod push;
+mod read;
+
+/// Derives `Push` and `PushOne` for a struct or an enum.
+#[proc_macro_derive(LuaPush, attributes(lua))]
+pub fn derive_lua_push(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    push::derive(&input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+/// Derives `LuaRead` for a struct or an enum.
+#[proc_macro_derive(LuaRead, attributes(lua))]
+pub fn derive_lua_read(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    read::derive(&input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+/// Turns a struct into a class that can be pushed to Lua as a user data.
+///
+/// Implements `LuaClass`, `Push` and `LuaRead` for the struct. The methods of the class are
+/// defined with `#[lua_methods]`, which is mandatory.
+#[proc_macro_attribute]
+pub fn lua_class(args: TokenStream, input: TokenStream) -> TokenStream {
+    class::lua_class(args.into(), input.into()).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+/// Exposes the methods of an impl block to Lua.
+///
+/// Implements `LuaMethods` for the type of the impl block.
+#[proc_macro_attribute]
+pub fn lua_methods(args: TokenStream, input: TokenStream) -> TokenStream {
+    class::lua_methods(args.into(), input.into())
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Turns a module into a Lua module that can be loaded with `require`.
+///
+/// Generates a `luaopen_<name>` function in the module, which returns a table containing the
+/// functions, statics and constants of the module.
+#[proc_macro_attribute]
+pub fn lua_module(args: TokenStream, input: TokenStream) -> TokenStream {
+    module::lua_module(args.into(), input.into())
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Returns the generics of the implementation: the generics of the type, plus a `'__lua`
+/// lifetime and a `__L` type parameter for the Lua context.
+fn impl_generics(generics: &syn::Generics) -> syn::Generics {
+    let mut generics = generics.clone();
+    generics.params.insert(0, syn::parse_quote!('__lua));
+    generics.params.push(syn::parse_quote!(__L));
+    generics
+}
+
+/// Returns the types of all the fields of the struct or enum that aren't skipped, without
+/// duplicates.
+fn field_types(data: &syn::Data) -> syn::Result<Vec<&syn::Type>> {
+    let fields: Vec<&syn::Fields> = match *data {
+        syn::Data::Struct(ref data) => vec![&data.fields],
+        syn::Data::Enum(ref data) => data.variants.iter().map(|v| &v.fields).collect(),
+        syn::Data::Union(ref data) => {
+            return Err(syn::Error::new(data.union_token.span, "unions are not supported"));
+        }
+    };
+
+    let mut types: Vec<&syn::Type> = Vec::new();
+    let mut seen = Vec::new();
+    for fields in fields {
+        for field in attr::fields(fields)? {
+            let ty = field.ty;
+            let key = quote!(#ty).to_string();
+            if !seen.contains(&key) {
+                seen.push(key);
+                types.push(field.ty);
+            }
+        }
+    }
+    Ok(types)
+}
+
+/// Builds the nul-terminated byte string of a key, to pass to `lua_getfield` or `lua_setfield`.
+fn c_key(key: &str, span: proc_macro2::Span) -> syn::Result<syn::LitByteStr> {
+    if key.contains('\0') {
+        return Err(syn::Error::new(span, "keys can't contain nul characters"));
+    }
+    Ok(syn::LitByteStr::new(format!("{}\0", key).as_bytes(), span))
+}
+
+/// How the content of a struct or of a variant is represented in Lua.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Shape {
+    /// A table whose keys are the names of the fields.
+    Named,
+    /// An array of the fields.
+    Tuple,
+    /// The single field of a tuple struct or variant, converted directly.
+    Newtype,
+    /// A unit struct or variant.
+    Unit,
+}
+
+impl Shape {
+    fn of(fields: &syn::Fields, kept: &[attr::Field]) -> Shape {
+        match *fields {
+            syn::Fields::Named(_) => Shape::Named,
+            syn::Fields::Unnamed(ref f) if f.unnamed.len() == 1 && kept.len() == 1 => {
+                Shape::Newtype
+            }
+            syn::Fields::Unnamed(_) => Shape::Tuple,
+            syn::Fields::Unit => Shape::Unit,
+        }
+    }
+}
+
+/// Name of the variable that holds the field at the given position.
+fn binding(position: usize) -> syn::Ident {
+    syn::Ident::new(&format!("__field{}", position), proc_macro2::Span::call_site())
+}
+
+/// Builds a pattern or an expression made of the path of a struct or a variant and of the
+/// `binding` of each field. Skipped fields are filled with `skipped`.
+fn fields_pattern(path: &proc_macro2::TokenStream, fields: &syn::Fields, kept: &[attr::Field],
+                  skipped: &proc_macro2::TokenStream) -> proc_macro2::TokenStream
+{
+    let values = fields.iter().enumerate().map(|(position, field)| {
+        let value = if kept.iter().any(|f| f.position == position) {
+            let binding = binding(position);
+            quote!(#binding)
+        } else {
+            skipped.clone()
+        };
+
+        match field.ident {
+            Some(ref ident) => quote!(#ident: #value),
+            None => value,
+        }
+    });
+
+    match *fields {
+        syn::Fields::Named(_) => quote!(#path { #(#values),* }),
+        syn::Fields::Unnamed(_) => quote!(#path ( #(#values),* )),
+        syn::Fields::Unit => quote!(#path),
+    }
+}
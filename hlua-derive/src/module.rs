@@ -0,0 +1,137 @@
+use proc_macro2::{Span, TokenStream};
+use syn;
+use syn::spanned::Spanned;
+
+use attr;
+
+/// Implementation of `#[lua_module]`.
+pub fn lua_module(args: TokenStream, input: TokenStream) -> syn::Result<TokenStream> {
+    let mut item: syn::ItemMod = syn::parse2(input)?;
+
+    let mut name = item.ident.to_string();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            let value: syn::LitStr = meta.value()?.parse()?;
+            name = value.value();
+            Ok(())
+        } else {
+            Err(meta.error("unknown lua_module attribute, expected `name`"))
+        }
+    });
+    syn::parse::Parser::parse2(parser, args)?;
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(syn::Error::new(item.ident.span(),
+                                   "the name of the module can only contain letters, digits \
+                                    and underscores"));
+    }
+
+    let span = item.span();
+    let items = match item.content {
+        Some((_, ref mut items)) => items,
+        None => {
+            return Err(syn::Error::new(span, "lua_module must be used on a module with a body"));
+        }
+    };
+
+    let mut inits = Vec::new();
+    let mut entries = Vec::new();
+
+    for item in items.iter_mut() {
+        let (ident, attrs, value) = match *item {
+            syn::Item::Fn(ref mut function) => {
+                let ident = function.sig.ident.clone();
+
+                let is_init = function.attrs.iter().any(|a| a.path().is_ident("lua_module_init"));
+                if is_init {
+                    function.attrs.retain(|a| !a.path().is_ident("lua_module_init"));
+                    inits.push(match function.sig.inputs.len() {
+                        0 => quote!(#ident();),
+                        1 => quote!(#ident(&mut lua);),
+                        _ => {
+                            return Err(syn::Error::new(function.sig.inputs.span(),
+                                                       "the init function must take either no \
+                                                        parameter or a `&mut hlua::Lua`"));
+                        }
+                    });
+                    continue;
+                }
+
+                if !function.sig.generics.params.is_empty() {
+                    return Err(syn::Error::new(function.sig.generics.span(),
+                                               "functions of a lua_module can't be generic"));
+                }
+                let count = function.sig.inputs.len();
+                if count > 10 {
+                    return Err(syn::Error::new(function.sig.inputs.span(),
+                                               "functions can't have more than 10 parameters"));
+                }
+
+                let wrapper = syn::Ident::new(&format!("function{}", count), Span::call_site());
+                let value = quote!(::hlua::#wrapper(#ident));
+                (ident, &mut function.attrs, value)
+            }
+            syn::Item::Static(ref mut item) => {
+                if let syn::StaticMutability::Mut(_) = item.mutability {
+                    return Err(syn::Error::new(item.span(),
+                                               "mutable statics can't be exported to Lua"));
+                }
+                let ident = item.ident.clone();
+                let value = quote!(#ident);
+                (ident, &mut item.attrs, value)
+            }
+            syn::Item::Const(ref mut item) => {
+                let ident = item.ident.clone();
+                let value = quote!(#ident);
+                (ident, &mut item.attrs, value)
+            }
+            _ => continue,
+        };
+
+        let options = attr::parse(attrs, &["rename", "skip"])?;
+        attr::strip(attrs);
+        if options.skip {
+            continue;
+        }
+
+        let key = options.rename.unwrap_or_else(|| ident.to_string());
+        let key = ::c_key(&key, ident.span())?;
+        entries.push(quote! {
+            ::hlua::PushGuard::forget(::hlua::Push::push_no_err(#value, &mut lua));
+            ::hlua::ffi::lua_setfield(state, -2, #key.as_ptr() as *const _);
+        });
+    }
+
+    let count = entries.len() as i32;
+    let luaopen = syn::Ident::new(&format!("luaopen_{}", name), Span::call_site());
+    items.push(syn::parse_quote! {
+        /// Entry point called by `require` when the module is loaded. Returns the table of the
+        /// module.
+        #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern "C" fn #luaopen(state: *mut ::hlua::ffi::lua_State) -> ::std::os::raw::c_int {
+            unsafe {
+                // Like `luaL_checkversion`. If the module contains its own copy of Lua instead of
+                // using the one of the interpreter, using the state would corrupt it.
+                if ::hlua::ffi::lua_version(state) !=
+                   ::hlua::ffi::lua_version(::std::ptr::null_mut())
+                {
+                    let message = b"multiple Lua VMs detected: the module must be linked to \
+                                    the Lua library of the interpreter\0";
+                    ::hlua::ffi::lua_pushstring(state, message.as_ptr() as *const _);
+                    ::hlua::ffi::lua_error(state);
+                }
+
+                #[allow(unused_mut, unused_variables)]
+                let mut lua = ::hlua::Lua::from_existing_state(state, false);
+                #(#inits)*
+
+                ::hlua::ffi::lua_createtable(state, 0, #count);
+                #(#entries)*
+            }
+            1
+        }
+    });
+
+    Ok(quote!(#item))
+}
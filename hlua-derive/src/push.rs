@@ -0,0 +1,146 @@
+use proc_macro2::{Span, TokenStream};
+use syn;
+use syn::spanned::Spanned;
+
+use attr;
+use Shape;
+
+pub fn derive(input: &syn::DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let types = ::field_types(&input.data)?;
+
+    let errors: Vec<syn::Ident> = (0..types.len())
+        .map(|n| syn::Ident::new(&format!("__E{}", n), Span::call_site()))
+        .collect();
+
+    let mut generics = ::impl_generics(&input.generics);
+    for error in &errors {
+        generics.params.push(syn::parse_quote!(#error));
+    }
+    let (impl_generics, _, _) = generics.split_for_impl();
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+    let predicates = where_clause.map(|w| w.predicates.iter().collect()).unwrap_or_else(Vec::new);
+
+    let body = match input.data {
+        syn::Data::Struct(ref data) => {
+            let kept = attr::fields(&data.fields)?;
+            let pattern = ::fields_pattern(&quote!(#name), &data.fields, &kept, &quote!(_));
+            let content = push_content(Shape::of(&data.fields, &kept), &kept)?;
+            quote! {
+                let #pattern = self;
+                #content
+            }
+        }
+        syn::Data::Enum(ref data) => {
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let key = attr::variant_name(variant)?;
+                let kept = attr::fields(&variant.fields)?;
+                let pattern = ::fields_pattern(&quote!(#name::#ident), &variant.fields, &kept,
+                                               &quote!(_));
+
+                let shape = Shape::of(&variant.fields, &kept);
+                let arm = if shape == Shape::Unit {
+                    let len = key.len();
+                    let key = syn::LitByteStr::new(key.as_bytes(), variant.span());
+                    quote! {
+                        #pattern => {
+                            ::hlua::ffi::lua_pushlstring(__raw, #key.as_ptr() as *const _, #len as _);
+                        }
+                    }
+                } else {
+                    let content = push_content(shape, &kept)?;
+                    let key = ::c_key(&key, variant.span())?;
+                    quote! {
+                        #pattern => {
+                            ::hlua::ffi::lua_createtable(__raw, 0, 1);
+                            #content
+                            ::hlua::ffi::lua_setfield(__raw, -2, #key.as_ptr() as *const _);
+                        }
+                    }
+                };
+                arms.push(arm);
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        syn::Data::Union(_) => unreachable!(),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::hlua::Push<__L> for #name #ty_generics
+            where #(#predicates,)*
+                  __L: ::hlua::AsMutLua<'__lua>,
+                  #(#types: for<'__a> ::hlua::PushOne<&'__a mut __L, Err = #errors>,)*
+                  #(#errors: Into<::hlua::Void>,)*
+        {
+            type Err = ::hlua::Void;
+
+            #[inline]
+            fn push_to_lua(self, mut lua: __L)
+                           -> Result<::hlua::PushGuard<__L>, (::hlua::Void, __L)>
+            {
+                unsafe {
+                    #[allow(unused_variables)]
+                    let __raw = ::hlua::AsMutLua::as_mut_lua(&mut lua).state_ptr();
+                    #body
+                    Ok(::hlua::PushGuard::new(lua, 1))
+                }
+            }
+        }
+
+        impl #impl_generics ::hlua::PushOne<__L> for #name #ty_generics
+            where #(#predicates,)*
+                  __L: ::hlua::AsMutLua<'__lua>,
+                  #(#types: for<'__a> ::hlua::PushOne<&'__a mut __L, Err = #errors>,)*
+                  #(#errors: Into<::hlua::Void>,)*
+        {
+        }
+    })
+}
+
+/// Generates the code that pushes exactly one value containing the fields of a struct or of a
+/// variant, assuming that each field is in its `binding`.
+fn push_content(shape: Shape, kept: &[attr::Field]) -> syn::Result<TokenStream> {
+    let mut content = Vec::new();
+
+    for (index, field) in kept.iter().enumerate() {
+        let binding = ::binding(field.position);
+        let push = quote! {
+            ::hlua::PushGuard::forget(::hlua::Push::push_no_err(#binding, &mut lua));
+        };
+
+        content.push(match shape {
+            Shape::Named => {
+                let key = ::c_key(&field.key, field.ty.span())?;
+                quote! {
+                    #push
+                    ::hlua::ffi::lua_setfield(__raw, -2, #key.as_ptr() as *const _);
+                }
+            }
+            Shape::Tuple => {
+                let index = index as i32 + 1;
+                quote! {
+                    #push
+                    ::hlua::ffi::lua_rawseti(__raw, -2, #index);
+                }
+            }
+            Shape::Newtype => return Ok(push),
+            Shape::Unit => unreachable!(),
+        });
+    }
+
+    let (narr, nrec) = match shape {
+        Shape::Tuple => (kept.len() as i32, 0),
+        _ => (0, kept.len() as i32),
+    };
+
+    Ok(quote! {
+        ::hlua::ffi::lua_createtable(__raw, #narr, #nrec);
+        #(#content)*
+    })
+}
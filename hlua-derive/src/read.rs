@@ -0,0 +1,210 @@
+use proc_macro2::TokenStream;
+use syn;
+use syn::spanned::Spanned;
+
+use attr;
+use Shape;
+
+pub fn derive(input: &syn::DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let types = ::field_types(&input.data)?;
+
+    let generics = ::impl_generics(&input.generics);
+    let (impl_generics, _, _) = generics.split_for_impl();
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+    let predicates = where_clause.map(|w| w.predicates.iter().collect()).unwrap_or_else(Vec::new);
+
+    let fail = quote!(return Err(lua););
+
+    let body = match input.data {
+        syn::Data::Struct(ref data) => {
+            let kept = attr::fields(&data.fields)?;
+            let construct = construct(&quote!(#name), &data.fields, &kept);
+
+            match Shape::of(&data.fields, &kept) {
+                Shape::Newtype => {
+                    let read = read_value(&kept[0], &quote!(index), &fail);
+                    quote! {
+                        #read
+                        Ok(#construct)
+                    }
+                }
+                shape => {
+                    let fields = read_fields(shape, &kept, &fail)?;
+                    quote! {
+                        if !::hlua::ffi::lua_istable(__raw, index) {
+                            #fail
+                        }
+                        let __index = ::hlua::ffi::lua_absindex(__raw, index);
+                        #(#fields)*
+                        Ok(#construct)
+                    }
+                }
+            }
+        }
+        syn::Data::Enum(ref data) => {
+            let mut unit_variants = Vec::new();
+            let mut other_variants = Vec::new();
+
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let key = attr::variant_name(variant)?;
+                let kept = attr::fields(&variant.fields)?;
+                let construct = construct(&quote!(#name::#ident), &variant.fields, &kept);
+
+                // Failing to read the content of the variant must also pop the value of the key.
+                let fail = quote! {
+                    ::hlua::ffi::lua_pop(__raw, 1);
+                    return Err(lua);
+                };
+
+                let read = match Shape::of(&variant.fields, &kept) {
+                    Shape::Unit => {
+                        let key = syn::LitByteStr::new(key.as_bytes(), variant.span());
+                        unit_variants.push(quote! {
+                            if __name == #key {
+                                return Ok(#construct);
+                            }
+                        });
+                        continue;
+                    }
+                    Shape::Newtype => read_value(&kept[0], &quote!(-1), &fail),
+                    shape => {
+                        let fields = read_fields(shape, &kept, &fail)?;
+                        quote! {
+                            if !::hlua::ffi::lua_istable(__raw, -1) {
+                                #fail
+                            }
+                            let __index = ::hlua::ffi::lua_absindex(__raw, -1);
+                            #(#fields)*
+                        }
+                    }
+                };
+
+                let key = ::c_key(&key, variant.span())?;
+                other_variants.push(quote! {
+                    ::hlua::ffi::lua_getfield(__raw, __index, #key.as_ptr() as *const _);
+                    if !::hlua::ffi::lua_isnil(__raw, -1) {
+                        #read
+                        ::hlua::ffi::lua_pop(__raw, 1);
+                        return Ok(#construct);
+                    }
+                    ::hlua::ffi::lua_pop(__raw, 1);
+                });
+            }
+
+            let unit_variants = if unit_variants.is_empty() {
+                quote!()
+            } else {
+                quote! {
+                    if ::hlua::ffi::lua_type(__raw, index) == ::hlua::ffi::LUA_TSTRING {
+                        let mut __len = 0;
+                        let __ptr = ::hlua::ffi::lua_tolstring(__raw, index, &mut __len);
+                        let __name = ::std::slice::from_raw_parts(__ptr as *const u8,
+                                                                   __len as usize);
+                        #(#unit_variants)*
+                        return Err(lua);
+                    }
+                }
+            };
+
+            let other_variants = if other_variants.is_empty() {
+                quote!()
+            } else {
+                quote! {
+                    if ::hlua::ffi::lua_istable(__raw, index) {
+                        let __index = ::hlua::ffi::lua_absindex(__raw, index);
+                        #(#other_variants)*
+                    }
+                }
+            };
+
+            quote! {
+                #unit_variants
+                #other_variants
+                Err(lua)
+            }
+        }
+        syn::Data::Union(_) => unreachable!(),
+    };
+
+    let type_name = name.to_string();
+    Ok(quote! {
+        impl #impl_generics ::hlua::LuaRead<__L> for #name #ty_generics
+            where #(#predicates,)*
+                  __L: ::hlua::AsMutLua<'__lua>,
+                  #(#types: for<'__a> ::hlua::LuaRead<&'__a mut __L>,)*
+        {
+            #[inline]
+            #[allow(unused_mut)]
+            fn lua_read_at_position(mut lua: __L, index: i32) -> Result<Self, __L> {
+                unsafe {
+                    #[allow(unused_variables)]
+                    let __raw = ::hlua::AsLua::as_lua(&lua).state_ptr();
+                    #body
+                }
+            }
+
+            #[inline]
+            fn lua_type_name() -> ::std::borrow::Cow<'static, str> {
+                ::std::borrow::Cow::Borrowed(#type_name)
+            }
+        }
+    })
+}
+
+/// Builds the value of the struct or of the variant from the bindings of the fields.
+fn construct(path: &TokenStream, fields: &syn::Fields, kept: &[attr::Field]) -> TokenStream {
+    ::fields_pattern(path, fields, kept, &quote!(::std::default::Default::default()))
+}
+
+/// Generates the code that reads the field from the value at `index` into its binding, or runs
+/// `fail`.
+fn read_value(field: &attr::Field, index: &TokenStream, fail: &TokenStream) -> TokenStream {
+    let binding = ::binding(field.position);
+    let ty = field.ty;
+    quote! {
+        let #binding = match <#ty as ::hlua::LuaRead<_>>::lua_read_at_position(&mut lua, #index)
+                                 .ok()
+        {
+            Some(v) => v,
+            None => { #fail }
+        };
+    }
+}
+
+/// Generates the code that reads each field from the table at `__index` into its binding. If a
+/// field can't be read, `fail` is run after the value of the field has been popped.
+fn read_fields(shape: Shape, kept: &[attr::Field], fail: &TokenStream)
+               -> syn::Result<Vec<TokenStream>>
+{
+    let mut fields = Vec::new();
+
+    for (index, field) in kept.iter().enumerate() {
+        let get = match shape {
+            Shape::Named => {
+                let key = ::c_key(&field.key, field.ty.span())?;
+                quote!(::hlua::ffi::lua_getfield(__raw, __index, #key.as_ptr() as *const _);)
+            }
+            Shape::Tuple => {
+                let index = index as i32 + 1;
+                quote!(::hlua::ffi::lua_rawgeti(__raw, __index, #index);)
+            }
+            Shape::Newtype | Shape::Unit => unreachable!(),
+        };
+
+        let binding = ::binding(field.position);
+        let ty = field.ty;
+        fields.push(quote! {
+            #get
+            let __value = <#ty as ::hlua::LuaRead<_>>::lua_read_at_position(&mut lua, -1).ok();
+            ::hlua::ffi::lua_pop(__raw, 1);
+            let #binding = match __value {
+                Some(v) => v,
+                None => { #fail }
+            };
+        });
+    }
+
+    Ok(fields)
+}
@@ -0,0 +1,153 @@
+extern crate hlua;
+extern crate hlua_derive;
+
+use hlua::{Lua, LuaClassTable};
+use hlua_derive::{lua_class, lua_methods};
+
+#[lua_class]
+struct Vector {
+    #[lua(get, set)]
+    x: f64,
+    #[lua(get, set)]
+    y: f64,
+    #[lua(get, rename = "label")]
+    name: String,
+    hidden: u32,
+}
+
+#[lua_methods]
+impl Vector {
+    fn new(x: f64, y: f64) -> Vector {
+        Vector { x, y, name: "vector".to_owned(), hidden: 0 }
+    }
+
+    #[lua(rename = "zero")]
+    fn origin() -> Vector {
+        Vector::new(0.0, 0.0)
+    }
+
+    fn length(&self) -> f64 {
+        (self.x * self.x + self.y * self.y).sqrt()
+    }
+
+    fn scale(&mut self, factor: f64) {
+        self.x *= factor;
+        self.y *= factor;
+    }
+
+    fn dot(&self, x: f64, y: f64) -> f64 {
+        self.x * x + self.y * y
+    }
+
+    #[lua(skip)]
+    fn take_hidden(&mut self) -> u32 {
+        let hidden = self.hidden;
+        self.hidden = 0;
+        hidden
+    }
+
+    #[lua(tostring)]
+    fn describe(&self) -> String {
+        format!("({}, {})", self.x, self.y)
+    }
+
+    #[lua(meta = "__len")]
+    fn len(&self) -> u32 {
+        2
+    }
+}
+
+#[lua_class(name = "Flag")]
+struct Empty;
+
+impl hlua::LuaMethods for Empty {}
+
+fn new_lua() -> Lua<'static> {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    lua.set("Vector", LuaClassTable::<Vector>::new());
+    lua
+}
+
+#[test]
+fn constructors() {
+    let mut lua = new_lua();
+
+    let x: f64 = lua.execute("return Vector.new(3, 4).x").unwrap();
+    assert_eq!(x, 3.0);
+
+    let y: f64 = lua.execute("return Vector.zero().y").unwrap();
+    assert_eq!(y, 0.0);
+    assert!(lua.execute::<bool>("return Vector.origin == nil").unwrap());
+}
+
+#[test]
+fn properties() {
+    let mut lua = new_lua();
+    lua.execute::<()>("v = Vector.new(1, 2)").unwrap();
+
+    let sum: f64 = lua.execute("v.x = 10; return v.x + v.y").unwrap();
+    assert_eq!(sum, 12.0);
+
+    let label: String = lua.execute("return v.label").unwrap();
+    assert_eq!(label, "vector");
+
+    assert!(lua.execute::<bool>("return v.hidden == nil and v.name == nil").unwrap());
+    assert!(lua.execute::<()>("v.label = 'other'").is_err());
+    assert!(lua.execute::<()>("v.hidden = 3").is_err());
+    assert!(lua.execute::<()>("v.x = 'not a number'").is_err());
+}
+
+#[test]
+fn methods() {
+    let mut lua = new_lua();
+    lua.execute::<()>("v = Vector.new(3, 4)").unwrap();
+
+    let len: f64 = lua.execute("return v:length()").unwrap();
+    assert_eq!(len, 5.0);
+
+    let len: f64 = lua.execute("v:scale(2); return v:length()").unwrap();
+    assert_eq!(len, 10.0);
+
+    let dot: f64 = lua.execute("return v:dot(1, 0)").unwrap();
+    assert_eq!(dot, 6.0);
+
+    assert!(lua.execute::<bool>("return v.take_hidden == nil").unwrap());
+}
+
+#[test]
+fn metamethods() {
+    let mut lua = new_lua();
+    lua.execute::<()>("v = Vector.new(1, 2)").unwrap();
+
+    let s: String = lua.execute("return tostring(v)").unwrap();
+    assert_eq!(s, "(1, 2)");
+
+    let len: u32 = lua.execute("return #v").unwrap();
+    assert_eq!(len, 2);
+}
+
+#[test]
+fn default_tostring() {
+    let mut lua = new_lua();
+    lua.set("flag", Empty);
+
+    let s: String = lua.execute("return tostring(flag)").unwrap();
+    assert!(s.starts_with("Flag: "), "{}", s);
+}
+
+#[test]
+fn push_and_read_from_rust() {
+    let mut v = Vector::new(6.0, 8.0);
+    v.hidden = 7;
+    assert_eq!(v.take_hidden(), 7);
+
+    let mut lua = new_lua();
+    lua.set("v", v);
+    lua.set("norm", hlua::function1(|v: &Vector| v.length()));
+
+    let norm: f64 = lua.execute("return norm(v)").unwrap();
+    assert_eq!(norm, 10.0);
+
+    assert!(lua.execute::<f64>("return norm({})").is_err());
+}
@@ -0,0 +1,221 @@
+extern crate hlua;
+#[macro_use]
+extern crate hlua_derive;
+
+use hlua::Lua;
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+struct Player {
+    name: String,
+    #[lua(rename = "pos")]
+    position: Point,
+    health: Option<u32>,
+    #[lua(skip)]
+    session: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+struct Color(u8, u8, u8);
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+struct Meters(f64);
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+struct Marker;
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+enum Shape {
+    Empty,
+    #[lua(rename = "circle")]
+    Circle(f64),
+    Rect(f64, f64),
+    Polygon { sides: u32, center: Point },
+}
+
+#[derive(Debug, Clone, PartialEq, LuaPush, LuaRead)]
+struct Wrapper<T> {
+    value: T,
+}
+
+#[test]
+fn struct_round_trip() {
+    let mut lua = Lua::new();
+    lua.set("p", Point { x: 3, y: -4 });
+
+    let x: i32 = lua.execute("return p.x").unwrap();
+    assert_eq!(x, 3);
+
+    let p: Point = lua.get("p").unwrap();
+    assert_eq!(p, Point { x: 3, y: -4 });
+}
+
+#[test]
+fn read_from_lua() {
+    let mut lua = Lua::new();
+    lua.execute::<()>("p = { x = 1, y = 2, z = 3 }").unwrap();
+
+    let p: Point = lua.get("p").unwrap();
+    assert_eq!(p, Point { x: 1, y: 2 });
+}
+
+#[test]
+fn rename_and_skip() {
+    let mut lua = Lua::new();
+    lua.set("player", Player {
+        name: "bob".to_owned(),
+        position: Point { x: 1, y: 2 },
+        health: None,
+        session: 42,
+    });
+
+    let y: i32 = lua.execute("return player.pos.y").unwrap();
+    assert_eq!(y, 2);
+    assert!(lua.execute::<bool>("return player.position == nil and player.session == nil")
+               .unwrap());
+
+    lua.execute::<()>("player.health = 10").unwrap();
+    let player: Player = lua.get("player").unwrap();
+    assert_eq!(player, Player {
+        name: "bob".to_owned(),
+        position: Point { x: 1, y: 2 },
+        health: Some(10),
+        session: 0,
+    });
+}
+
+#[test]
+fn tuple_struct() {
+    let mut lua = Lua::new();
+    lua.set("c", Color(255, 128, 0));
+
+    let g: u8 = lua.execute("return c[2]").unwrap();
+    assert_eq!(g, 128);
+
+    let c: Color = lua.execute("return { 1, 2, 3 }").unwrap();
+    assert_eq!(c, Color(1, 2, 3));
+}
+
+#[test]
+fn newtype_struct() {
+    let mut lua = Lua::new();
+    lua.set("m", Meters(2.5));
+
+    let m: f64 = lua.execute("return m").unwrap();
+    assert_eq!(m, 2.5);
+
+    let m: Meters = lua.execute("return 4").unwrap();
+    assert_eq!(m, Meters(4.0));
+}
+
+#[test]
+fn unit_struct() {
+    let mut lua = Lua::new();
+    lua.open_base();
+    lua.set("m", Marker);
+    assert!(lua.execute::<bool>("return type(m) == 'table' and next(m) == nil").unwrap());
+
+    let m: Marker = lua.get("m").unwrap();
+    assert_eq!(m, Marker);
+}
+
+#[test]
+fn enum_round_trip() {
+    let shapes = vec![
+        Shape::Empty,
+        Shape::Circle(1.5),
+        Shape::Rect(2.0, 3.0),
+        Shape::Polygon { sides: 5, center: Point { x: 1, y: 1 } },
+    ];
+
+    let mut lua = Lua::new();
+    for shape in shapes {
+        lua.set("s", shape.clone());
+        let read: Shape = lua.get("s").unwrap();
+        assert_eq!(read, shape);
+    }
+}
+
+#[test]
+fn enum_layout() {
+    let mut lua = Lua::new();
+
+    lua.set("s", Shape::Empty);
+    let s: String = lua.execute("return s").unwrap();
+    assert_eq!(s, "Empty");
+
+    lua.set("s", Shape::Circle(1.5));
+    let r: f64 = lua.execute("return s.circle").unwrap();
+    assert_eq!(r, 1.5);
+
+    lua.set("s", Shape::Rect(2.0, 3.0));
+    let h: f64 = lua.execute("return s.Rect[2]").unwrap();
+    assert_eq!(h, 3.0);
+
+    let s: Shape = lua.execute("return { Polygon = { sides = 3, center = { x = 5, y = 6 } } }")
+                      .unwrap();
+    assert_eq!(s, Shape::Polygon { sides: 3, center: Point { x: 5, y: 6 } });
+}
+
+#[test]
+fn generic_struct() {
+    let mut lua = Lua::new();
+    lua.set("w", Wrapper { value: "hello" });
+
+    let w: Wrapper<String> = lua.get("w").unwrap();
+    assert_eq!(w.value, "hello");
+}
+
+#[test]
+fn wrong_types() {
+    let mut lua = Lua::new();
+
+    assert!(lua.execute::<Point>("return 5").is_err());
+    assert!(lua.execute::<Point>("return { x = 1 }").is_err());
+    assert!(lua.execute::<Point>("return { x = 1, y = {} }").is_err());
+    assert!(lua.execute::<Color>("return { 1, 2 }").is_err());
+    assert!(lua.execute::<Shape>("return 'Unknown'").is_err());
+    assert!(lua.execute::<Shape>("return { Rect = 5 }").is_err());
+    assert!(lua.execute::<Shape>("return {}").is_err());
+}
+
+#[test]
+fn stack_is_balanced() {
+    let mut lua = Lua::new();
+    lua.execute::<()>("bad = { Rect = { 1, {} } }").unwrap();
+
+    for _ in 0..100 {
+        assert!(lua.get::<Shape, _>("bad").is_none());
+        lua.set("p", Point { x: 1, y: 2 });
+    }
+
+    let p: Point = lua.get("p").unwrap();
+    assert_eq!(p, Point { x: 1, y: 2 });
+}
+
+#[test]
+fn function_arguments() {
+    let mut lua = Lua::new();
+    lua.set("len2", hlua::function1(|p: Point| Point { x: p.x * 2, y: p.y * 2 }));
+
+    let x: i32 = lua.execute("return len2({ x = 3, y = 4 }).x").unwrap();
+    assert_eq!(x, 6);
+}
+
+#[test]
+fn function_argument_error() {
+    let mut lua = Lua::new();
+    lua.set("len2", hlua::function1(|p: Point| p.x * 2));
+
+    match lua.execute::<()>("len2(5)") {
+        Err(hlua::LuaError::ExecutionError(err)) => {
+            assert!(err.message().ends_with("bad argument #1 to 'len2' (Point expected, got number)"));
+        }
+        _ => panic!(),
+    }
+}
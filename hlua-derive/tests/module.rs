@@ -0,0 +1,146 @@
+extern crate hlua;
+extern crate hlua_derive;
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hlua::{AsMutLua, Lua};
+use hlua_derive::lua_module;
+
+static INIT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[lua_module]
+mod mylib {
+    pub static PI: f32 = 3.5;
+    pub const NAME: &str = "mylib";
+
+    pub fn add(a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    #[lua(rename = "add_five")]
+    fn plus_five(a: i32) -> i32 {
+        a + 5
+    }
+
+    #[lua(skip)]
+    pub fn internal() -> i32 {
+        1
+    }
+
+    #[lua_module_init]
+    fn init() {
+        super::INIT_CALLS.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[lua_module(name = "other")]
+mod renamed {
+    use hlua::Lua;
+
+    pub fn hello() -> String {
+        "hello".to_owned()
+    }
+
+    #[lua_module_init]
+    fn init(lua: &mut Lua) {
+        lua.set("other_loaded", true);
+    }
+}
+
+// Registers the entry point in `package.preload`, which is where `require` looks first.
+fn preload(lua: &mut Lua, name: &[u8], open: hlua::ffi::lua_CFunction) {
+    unsafe {
+        let state = lua.as_mut_lua().state_ptr();
+        hlua::ffi::lua_getglobal(state, b"package\0".as_ptr() as *const _);
+        hlua::ffi::lua_getfield(state, -1, b"preload\0".as_ptr() as *const _);
+        hlua::ffi::lua_pushcfunction(state, open);
+        hlua::ffi::lua_setfield(state, -2, name.as_ptr() as *const _);
+        hlua::ffi::lua_pop(state, 2);
+    }
+}
+
+#[test]
+fn require_module() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    preload(&mut lua, b"mylib\0", mylib::luaopen_mylib);
+
+    let before = INIT_CALLS.load(Ordering::SeqCst);
+    lua.execute::<()>("m = require('mylib')").unwrap();
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), before + 1);
+
+    assert_eq!(lua.execute::<i32>("return m.add(2, 4)").unwrap(), 6);
+    assert_eq!(lua.execute::<i32>("return m.add_five(1)").unwrap(), 6);
+    assert_eq!(lua.execute::<f32>("return m.PI").unwrap(), 3.5);
+    assert_eq!(lua.execute::<String>("return m.NAME").unwrap(), "mylib");
+    assert!(lua.execute::<bool>("return m.internal == nil and m.init == nil").unwrap());
+    assert_eq!(mylib::internal(), 1);
+
+    // `require` caches the module.
+    lua.execute::<()>("require('mylib')").unwrap();
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), before + 1);
+}
+
+#[test]
+fn custom_name_and_init_with_lua() {
+    let mut lua = Lua::new();
+    lua.openlibs();
+    preload(&mut lua, b"other\0", renamed::luaopen_other);
+
+    let hello: String = lua.execute("return require('other').hello()").unwrap();
+    assert_eq!(hello, "hello");
+    assert_eq!(lua.get::<bool, _>("other_loaded"), Some(true));
+}
+
+#[test]
+fn stack_is_balanced() {
+    let mut lua = Lua::new();
+    let state = lua.as_mut_lua().state_ptr();
+
+    let returned = mylib::luaopen_mylib(state);
+    assert_eq!(returned, 1);
+    assert_eq!(unsafe { hlua::ffi::lua_gettop(state) }, 1);
+    unsafe { hlua::ffi::lua_pop(state, 1) };
+}
+
+#[test]
+fn require_shared_library() {
+    // `cargo test` builds the `mylib` example next to the directory of the test executable.
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir.push("examples");
+    let file = format!("{}mylib{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX);
+    if !dir.join(&file).exists() {
+        eprintln!("skipping: the mylib example hasn't been built");
+        return;
+    }
+
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let cpath = dir.join(format!("{}?{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX));
+    lua.set("cpath", cpath.to_str().unwrap());
+
+    let (loaded, result): (bool, hlua::AnyLuaValue) = lua.execute(r#"
+        package.cpath = cpath
+        local ok, mylib = pcall(require, "mylib")
+        if not ok then return false, mylib end
+        return true, mylib.function1(2, 4)
+    "#).unwrap();
+
+    if loaded {
+        assert_eq!(result, hlua::AnyLuaValue::LuaNumber(6.0));
+    } else {
+        // This test executable and the module contain their own copy of Lua, because no shared
+        // Lua library was found when building them. The module must refuse to be loaded.
+        match result {
+            hlua::AnyLuaValue::LuaString(ref message) => {
+                assert!(message.contains("multiple Lua VMs detected"), "{}", message)
+            }
+            ref other => panic!("{:?}", other),
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use std::collections::HashMap;
+use std::ptr;
+
 use ffi;
+use libc;
 
+use raw;
 use AsLua;
 use AsMutLua;
 
@@ -7,6 +12,7 @@ use Push;
 use PushGuard;
 use PushOne;
 use LuaRead;
+use LuaRef;
 use LuaTable;
 use Void;
 
@@ -18,7 +24,9 @@ pub struct AnyLuaString(pub Vec<u8>);
 pub enum AnyHashableLuaValue {
     LuaString(String),
     LuaAnyString(AnyLuaString),
-    LuaNumber(i32),
+    /// A number. As floats aren't hashable, non-integral numbers are truncated towards zero when
+    /// they are read.
+    LuaNumber(i64),
     LuaBoolean(bool),
     LuaArray(Vec<(AnyHashableLuaValue, AnyHashableLuaValue)>),
     LuaNil,
@@ -38,9 +46,234 @@ pub enum AnyLuaValue {
     LuaArray(Vec<(AnyLuaValue, AnyLuaValue)>),
     LuaNil,
 
-    /// The "Other" element is (hopefully) temporary and will be replaced by "Function" and "Userdata".
-    /// A panic! will trigger if you try to push a Other.
-    LuaOther,
+    /// A function, kept alive by a reference in the registry.
+    LuaFunction(LuaRef),
+    /// A full userdata, kept alive by a reference in the registry.
+    LuaUserdata(LuaRef),
+    /// A coroutine, kept alive by a reference in the registry.
+    LuaThread(LuaRef),
+    /// A light userdata, stored in the registry.
+    LuaLightUserdata(LuaRef),
+
+    /// A table that has already been met while reading the value. This is how a table that
+    /// contains itself is represented, and also any table that appears several times if the
+    /// `preserve_shared` option of the `TableConversion` is set.
+    ///
+    /// The tables of a value are numbered from 0 in the order their `LuaArray` appear when
+    /// walking through the value depth-first, keys before values. When the value is pushed back,
+    /// the reference is replaced with the table that has this number, so that the structure is
+    /// recreated. A reference to a table that doesn't exist is pushed as `nil`.
+    LuaArrayRef(usize),
+}
+
+/// Copy of an `AnyLuaValue` that doesn't depend on the Lua context, so that it can be sent to
+/// other threads.
+///
+/// Functions, userdata and coroutines can't be copied out of Lua. Only their type is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorValue {
+    LuaString(String),
+    LuaAnyString(AnyLuaString),
+    LuaNumber(f64),
+    LuaBoolean(bool),
+    LuaArray(Vec<(ErrorValue, ErrorValue)>),
+    LuaNil,
+    LuaFunction,
+    LuaUserdata,
+    LuaThread,
+    LuaLightUserdata,
+    LuaArrayRef(usize),
+}
+
+impl From<AnyLuaValue> for ErrorValue {
+    fn from(value: AnyLuaValue) -> ErrorValue {
+        match value {
+            AnyLuaValue::LuaString(s) => ErrorValue::LuaString(s),
+            AnyLuaValue::LuaAnyString(s) => ErrorValue::LuaAnyString(s),
+            AnyLuaValue::LuaNumber(n) => ErrorValue::LuaNumber(n),
+            AnyLuaValue::LuaBoolean(b) => ErrorValue::LuaBoolean(b),
+            AnyLuaValue::LuaArray(v) => {
+                ErrorValue::LuaArray(v.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+            }
+            AnyLuaValue::LuaNil => ErrorValue::LuaNil,
+            AnyLuaValue::LuaFunction(_) => ErrorValue::LuaFunction,
+            AnyLuaValue::LuaUserdata(_) => ErrorValue::LuaUserdata,
+            AnyLuaValue::LuaThread(_) => ErrorValue::LuaThread,
+            AnyLuaValue::LuaLightUserdata(_) => ErrorValue::LuaLightUserdata,
+            AnyLuaValue::LuaArrayRef(n) => ErrorValue::LuaArrayRef(n),
+        }
+    }
+}
+
+/// Options for converting Lua tables to `AnyLuaValue`s.
+///
+/// Use `Lua::set_table_conversion` to change them.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{AnyLuaValue, Lua, TableConversion};
+///
+/// let mut lua = Lua::new();
+/// lua.set_table_conversion(TableConversion { max_depth: 10, preserve_shared: true });
+/// lua.execute::<()>("local shared = {}; t = { a = shared, b = shared }").unwrap();
+///
+/// let t: AnyLuaValue = lua.get("t").unwrap();
+/// lua.set("u", t);
+/// assert!(lua.execute::<bool>("return u.a == u.b").unwrap());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableConversion {
+    /// Maximum number of nested tables. Reading a value that contains more nested tables fails.
+    ///
+    /// The default is 100.
+    pub max_depth: usize,
+
+    /// If true, a table that appears several times is only converted the first time, and then
+    /// replaced with an `AnyLuaValue::LuaArrayRef`. If false, it is converted every time, unless
+    /// it contains itself.
+    ///
+    /// The default is false.
+    pub preserve_shared: bool,
+}
+
+impl Default for TableConversion {
+    #[inline]
+    fn default() -> TableConversion {
+        TableConversion {
+            max_depth: 100,
+            preserve_shared: false,
+        }
+    }
+}
+
+// The `TableConversion` of a state is stored as a userdata in the registry, at the address of this
+// static.
+static TABLE_CONVERSION_KEY: u8 = 0;
+
+/// Sets the options for converting tables to `AnyLuaValue`s.
+pub fn set_table_conversion(lua: *mut ffi::lua_State, conversion: TableConversion) {
+    unsafe {
+        raw::insert(lua, &TABLE_CONVERSION_KEY, conversion);
+    }
+}
+
+unsafe fn table_conversion(lua: *mut ffi::lua_State) -> TableConversion {
+    let data = raw::get::<TableConversion>(lua, &TABLE_CONVERSION_KEY);
+    if data.is_null() { TableConversion::default() } else { *data }
+}
+
+struct ReadState {
+    options: TableConversion,
+    // Number of each table being read, or already read if `preserve_shared` is set.
+    seen: HashMap<*const libc::c_void, usize>,
+    next_id: usize,
+}
+
+// Reads the table at `index`. Returns `None` if the tables are nested too deeply.
+//
+// The tables are read on a `&mut AsMutLua` for the same reason they are pushed on one, see
+// `push_table`.
+fn read_table<'lua>(lua: &mut dyn AsMutLua<'lua>, index: i32, state: &mut ReadState,
+                    depth: usize) -> Option<AnyLuaValue>
+{
+    let raw_lua = lua.as_mut_lua().0;
+    let pointer = unsafe { ffi::lua_topointer(raw_lua, index) };
+    if let Some(&id) = state.seen.get(&pointer) {
+        return Some(AnyLuaValue::LuaArrayRef(id));
+    }
+    if depth >= state.options.max_depth || unsafe { ffi::lua_checkstack(raw_lua, 3) } == 0 {
+        return None;
+    }
+
+    state.seen.insert(pointer, state.next_id);
+    state.next_id += 1;
+
+    let index = unsafe { ffi::lua_absindex(raw_lua, index) };
+    let mut entries = Vec::new();
+    unsafe { ffi::lua_pushnil(raw_lua) };
+    while unsafe { ffi::lua_next(raw_lua, index) } != 0 {
+        let key = read_value(lua, -2, state, depth + 1);
+        let value = key.as_ref().and_then(|_| read_value(lua, -1, state, depth + 1));
+        unsafe { ffi::lua_pop(raw_lua, 1) };
+
+        match (key, value) {
+            (Some(key), Some(value)) => entries.push((key, value)),
+            _ => {
+                unsafe { ffi::lua_pop(raw_lua, 1) };
+                return None;
+            }
+        }
+    }
+
+    if !state.options.preserve_shared {
+        state.seen.remove(&pointer);
+    }
+
+    Some(AnyLuaValue::LuaArray(entries))
+}
+
+fn read_value<'lua>(lua: &mut dyn AsMutLua<'lua>, index: i32, state: &mut ReadState,
+                    depth: usize) -> Option<AnyLuaValue>
+{
+    if unsafe { ffi::lua_type(lua.as_lua().0, index) } == ffi::LUA_TTABLE {
+        read_table(lua, index, state, depth)
+    } else {
+        AnyLuaValue::lua_read_at_position(lua, index).ok()
+    }
+}
+
+// Pushes a table. `pushed` is the index of a table that contains all the tables pushed so far,
+// indexed by their number plus one.
+//
+// Pushing a `Vec<(AnyLuaValue, AnyLuaValue)>` on a `L` requires calling the function that pushes
+// a `AnyLuaValue` on a `&mut L`, which in turns requires calling the function that pushes a
+// `AnyLuaValue` on a `&mut &mut L`, and so on. In order to avoid this infinite recursion, we push
+// the tables on a `&mut AsMutLua` instead.
+fn push_table<'lua>(lua: &mut dyn AsMutLua<'lua>, entries: Vec<(AnyLuaValue, AnyLuaValue)>,
+                    pushed: i32, next_id: &mut usize)
+{
+    let raw_lua = lua.as_mut_lua().0;
+    unsafe {
+        if ffi::lua_checkstack(raw_lua, 3) == 0 {
+            panic!("not enough space on the Lua stack to push a table");
+        }
+
+        ffi::lua_createtable(raw_lua, 0, entries.len() as libc::c_int);
+        ffi::lua_pushvalue(raw_lua, -1);
+        ffi::lua_rawseti(raw_lua, pushed, *next_id as libc::c_int + 1);
+    }
+    *next_id += 1;
+
+    for (key, value) in entries {
+        push_value(lua, key, pushed, next_id);
+        push_value(lua, value, pushed, next_id);
+
+        unsafe {
+            // `nil` and NaN can't be used as keys
+            let mut is_number = 0;
+            let number = ffi::lua_tonumberx(raw_lua, -2, &mut is_number);
+            if ffi::lua_isnil(raw_lua, -2) || (is_number != 0 && number.is_nan()) {
+                ffi::lua_pop(raw_lua, 2);
+            } else {
+                ffi::lua_rawset(raw_lua, -3);
+            }
+        }
+    }
+}
+
+fn push_value<'lua>(lua: &mut dyn AsMutLua<'lua>, value: AnyLuaValue, pushed: i32,
+                    next_id: &mut usize)
+{
+    match value {
+        AnyLuaValue::LuaArray(entries) => push_table(lua, entries, pushed, next_id),
+        AnyLuaValue::LuaArrayRef(id) => unsafe {
+            ffi::lua_rawgeti(lua.as_mut_lua().0, pushed, id as libc::c_int + 1);
+        },
+        value => {
+            value.push_no_err(lua).forget_internal();
+        }
+    }
 }
 
 impl<'lua, L> Push<L> for AnyLuaValue
@@ -57,23 +290,22 @@ impl<'lua, L> Push<L> for AnyLuaValue
             AnyLuaValue::LuaNumber(val) => val.push_to_lua(lua),
             AnyLuaValue::LuaBoolean(val) => val.push_to_lua(lua),
             AnyLuaValue::LuaArray(val) => {
-                // Pushing a `Vec<(AnyLuaValue, AnyLuaValue)>` on a `L` requires calling the
-                // function that pushes a `AnyLuaValue` on a `&mut L`, which in turns requires
-                // calling the function that pushes a `AnyLuaValue` on a `&mut &mut L`, and so on.
-                // In order to avoid this infinite recursion, we push the array on a
-                // `&mut AsMutLua` instead.
-
-                // We also need to destroy and recreate the push guard, otherwise the type parameter
-                // doesn't match.
-                let size = val.push_no_err(&mut lua as &mut AsMutLua<'lua>).forget_internal();
+                // The tables are kept in a temporary table while pushing, so that
+                // `LuaArrayRef`s can find them.
+                let pushed = unsafe {
+                    ffi::lua_newtable(lua.as_mut_lua().0);
+                    ffi::lua_gettop(lua.as_mut_lua().0)
+                };
+                push_table(&mut lua, val, pushed, &mut 0);
+                unsafe { ffi::lua_remove(lua.as_mut_lua().0, pushed) };
 
                 Ok(PushGuard {
-                    lua: lua,
-                    size: size,
-                    raw_lua: raw_lua,
+                    lua,
+                    size: 1,
+                    raw_lua,
                 })
             }
-            AnyLuaValue::LuaNil => {
+            AnyLuaValue::LuaNil | AnyLuaValue::LuaArrayRef(_) => {
                 unsafe {
                     ffi::lua_pushnil(lua.as_mut_lua().0);
                 }
@@ -83,7 +315,10 @@ impl<'lua, L> Push<L> for AnyLuaValue
                     raw_lua: raw_lua,
                 })
             } // Use ffi::lua_pushnil.
-            AnyLuaValue::LuaOther => panic!("can't push a AnyLuaValue of type Other"),
+            AnyLuaValue::LuaFunction(val) |
+            AnyLuaValue::LuaUserdata(val) |
+            AnyLuaValue::LuaThread(val) |
+            AnyLuaValue::LuaLightUserdata(val) => val.push_to_lua(lua),
         }
     }
 }
@@ -95,59 +330,35 @@ impl<'lua, L> LuaRead<L> for AnyLuaValue
 {
     #[inline]
     fn lua_read_at_position(mut lua: L, index: i32) -> Result<AnyLuaValue, L> {
-
-        // If we know that the value on the stack is a string, we should try
-        // to parse it as a string instead of a number or boolean, so that
-        // values such as '1.10' don't become `AnyLuaValue::LuaNumber(1.1)`.
         let data_type = unsafe { ffi::lua_type(lua.as_lua().0, index) };
-        if data_type == ffi::LUA_TSTRING {
-
-            let mut lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyLuaValue::LuaString(v)),
-                Err(lua) => lua,
-            };
-
-            let _lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyLuaValue::LuaAnyString(v)),
-                Err(lua) => lua,
-            };
-
-            Ok(AnyLuaValue::LuaOther)
-
-        } else {
-
-            let mut lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyLuaValue::LuaNumber(v)),
-                Err(lua) => lua,
-            };
-
-            let mut lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyLuaValue::LuaBoolean(v)),
-                Err(lua) => lua,
-            };
-
-            let mut lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyLuaValue::LuaString(v)),
-                Err(lua) => lua,
-            };
-
-            let lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyLuaValue::LuaAnyString(v)),
-                Err(lua) => lua,
-            };
-
-            if unsafe { ffi::lua_isnil(lua.as_lua().0, index) } {
-                return Ok(AnyLuaValue::LuaNil);
+        match data_type {
+            // We try to parse a string as a string instead of a number or boolean, so that values
+            // such as '1.10' don't become `AnyLuaValue::LuaNumber(1.1)`.
+            ffi::LUA_TSTRING => {
+                let string = LuaRead::lua_read_at_position(&mut lua as &mut dyn AsMutLua<'lua>, index);
+                if let Ok(v) = string {
+                    return Ok(AnyLuaValue::LuaString(v));
+                }
+                LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaAnyString)
             }
-
-            let table: Result<LuaTable<_>, _> = LuaRead::lua_read_at_position(lua, index);
-            let _lua = match table {
-                Ok(mut v) => return Ok(AnyLuaValue::LuaArray(v.iter::<AnyLuaValue, AnyLuaValue>()
-                                                              .filter_map(|e| e).collect())),
-                Err(lua) => lua,
-            };
-
-            Ok(AnyLuaValue::LuaOther)
+            ffi::LUA_TNUMBER => LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaNumber),
+            ffi::LUA_TBOOLEAN => LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaBoolean),
+            ffi::LUA_TNONE | ffi::LUA_TNIL => Ok(AnyLuaValue::LuaNil),
+            ffi::LUA_TTABLE => {
+                let mut state = ReadState {
+                    options: unsafe { table_conversion(lua.as_mut_lua().0) },
+                    seen: HashMap::new(),
+                    next_id: 0,
+                };
+                read_table(&mut lua, index, &mut state, 0).ok_or(lua)
+            }
+            ffi::LUA_TFUNCTION => LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaFunction),
+            ffi::LUA_TUSERDATA => LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaUserdata),
+            ffi::LUA_TTHREAD => LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaThread),
+            ffi::LUA_TLIGHTUSERDATA => {
+                LuaRead::lua_read_at_position(lua, index).map(AnyLuaValue::LuaLightUserdata)
+            }
+            _ => Err(lua),
         }
     }
 }
@@ -163,7 +374,12 @@ impl<'lua, L> Push<L> for AnyHashableLuaValue
         match self {
             AnyHashableLuaValue::LuaString(val) => val.push_to_lua(lua),
             AnyHashableLuaValue::LuaAnyString(val) => val.push_to_lua(lua),
-            AnyHashableLuaValue::LuaNumber(val) => val.push_to_lua(lua),
+            AnyHashableLuaValue::LuaNumber(val) => {
+                // Numbers read from Lua always fit. Larger ones are rounded, as there's no way
+                // to report an error here.
+                unsafe { ffi::lua_pushnumber(lua.as_mut_lua().0, val as ffi::lua_Number) };
+                Ok(PushGuard { lua, size: 1, raw_lua })
+            }
             AnyHashableLuaValue::LuaBoolean(val) => val.push_to_lua(lua),
             AnyHashableLuaValue::LuaArray(val) => {
                 // Pushing a `Vec<(AnyHashableLuaValue, AnyHashableLuaValue)>` on a `L` requires calling the
@@ -219,12 +435,17 @@ impl<'lua, L> LuaRead<L> for AnyHashableLuaValue
             
             Ok(AnyHashableLuaValue::LuaOther)
 
-        } else {
+        } else if data_type == ffi::LUA_TNUMBER {
 
-            let mut lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
-                Ok(v) => return Ok(AnyHashableLuaValue::LuaNumber(v)),
-                Err(lua) => lua,
-            };
+            // floats aren't hashable, so non-integral numbers are truncated towards zero
+            let number = unsafe { ffi::lua_tonumberx(lua.as_lua().0, index, ptr::null_mut()) };
+            if number.is_finite() {
+                Ok(AnyHashableLuaValue::LuaNumber(number as i64))
+            } else {
+                Err(lua)
+            }
+
+        } else {
 
             let mut lua = match LuaRead::lua_read_at_position(&mut lua as &mut AsMutLua<'lua>, index) {
                 Ok(v) => return Ok(AnyHashableLuaValue::LuaBoolean(v)),
@@ -241,7 +462,7 @@ impl<'lua, L> LuaRead<L> for AnyHashableLuaValue
                 Err(lua) => lua,
             };
 
-            if unsafe { ffi::lua_isnil(lua.as_lua().0, index) } {
+            if unsafe { ffi::lua_isnoneornil(lua.as_lua().0, index) } {
                 return Ok(AnyHashableLuaValue::LuaNil);
             }
 
@@ -260,8 +481,13 @@ impl<'lua, L> LuaRead<L> for AnyHashableLuaValue
 
 #[cfg(test)]
 mod tests {
+    use ffi;
+    use libc;
+
+    use AsMutLua;
     use Lua;
     use AnyLuaValue;
+    use TableConversion;
     use AnyHashableLuaValue;
     use AnyLuaString;
 
@@ -301,6 +527,20 @@ mod tests {
         assert_eq!(z, AnyHashableLuaValue::LuaString("4".to_owned()));
     }
 
+    #[test]
+    fn read_hashable_large_and_non_integral_numbers() {
+        let mut lua = Lua::new();
+
+        lua.checked_set("a", 1_500_000_000_123i64).unwrap();
+        lua.set("b", 2.5f64);
+
+        let x: AnyHashableLuaValue = lua.get("a").unwrap();
+        assert_eq!(x, AnyHashableLuaValue::LuaNumber(1_500_000_000_123));
+
+        let y: AnyHashableLuaValue = lua.get("b").unwrap();
+        assert_eq!(y, AnyHashableLuaValue::LuaNumber(2));
+    }
+
     #[test]
     fn read_strings() {
         let mut lua = Lua::new();
@@ -430,7 +670,7 @@ mod tests {
         }
 
         fn get_numeric<'a>(table: &'a AnyHashableLuaValue, key: usize) -> &'a AnyHashableLuaValue {
-            let test_key = AnyHashableLuaValue::LuaNumber(key as i32);
+            let test_key = AnyHashableLuaValue::LuaNumber(key as i64);
             match table {
                 &AnyHashableLuaValue::LuaArray(ref vec) => {
                     let &(_, ref value) = vec.iter().find(|&&(ref key, _)| key == &test_key).expect("key not found");
@@ -548,4 +788,173 @@ mod tests {
             _ => panic!("Decoded to wrong variant"),
         }
     }
+
+    #[test]
+    fn read_and_push_function() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("function f(a) return a * 2 end").unwrap();
+
+        let f: AnyLuaValue = lua.get("f").unwrap();
+        match f {
+            AnyLuaValue::LuaFunction(_) => (),
+            _ => panic!("Decoded to wrong variant"),
+        }
+
+        lua.set("g", f);
+        assert!(lua.execute::<bool>("return f == g").unwrap());
+        let val: i32 = lua.execute("return g(21)").unwrap();
+        assert_eq!(val, 42);
+    }
+
+    #[test]
+    fn read_and_push_userdata_and_thread() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>("co = coroutine.create(function() end)").unwrap();
+
+        let stdout: AnyLuaValue = lua.execute("return io.stdout").unwrap();
+        let co: AnyLuaValue = lua.get("co").unwrap();
+        match (&stdout, &co) {
+            (&AnyLuaValue::LuaUserdata(_), &AnyLuaValue::LuaThread(_)) => (),
+            _ => panic!("Decoded to wrong variant"),
+        }
+
+        lua.set("a", stdout);
+        lua.set("b", co);
+        assert!(lua.execute::<bool>("return a == io.stdout and b == co").unwrap());
+    }
+
+    #[test]
+    fn read_and_push_light_userdata() {
+        let mut lua = Lua::new();
+        let ptr = &lua as *const _ as *mut libc::c_void;
+        unsafe {
+            ffi::lua_pushlightuserdata(lua.as_mut_lua().0, ptr);
+            ffi::lua_setglobal(lua.as_mut_lua().0, b"p\0".as_ptr() as *const _);
+        }
+
+        let p: AnyLuaValue = lua.get("p").unwrap();
+        match p {
+            AnyLuaValue::LuaLightUserdata(_) => (),
+            _ => panic!("Decoded to wrong variant"),
+        }
+
+        lua.set("q", p);
+        assert!(lua.execute::<bool>("return p == q").unwrap());
+    }
+
+    #[test]
+    fn compare_references() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("function f() end; function g() end").unwrap();
+
+        let f1: AnyLuaValue = lua.get("f").unwrap();
+        let f2: AnyLuaValue = lua.get("f").unwrap();
+        let g: AnyLuaValue = lua.get("g").unwrap();
+        assert_eq!(f1, f2);
+        assert!(f1 != g);
+    }
+
+    #[test]
+    fn read_table_with_function() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = { 1, function() return 5 end }").unwrap();
+
+        let t: AnyLuaValue = lua.get("t").unwrap();
+        lua.set("u", t);
+        let val: i32 = lua.execute("return u[2]()").unwrap();
+        assert_eq!(val, 5);
+    }
+
+    #[test]
+    fn read_cyclic_table() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = { a = 1 }; t.self = t").unwrap();
+
+        let t: AnyLuaValue = lua.get("t").unwrap();
+        match t {
+            AnyLuaValue::LuaArray(ref entries) => {
+                assert_eq!(entries.len(), 2);
+                assert!(entries.contains(&(AnyLuaValue::LuaString("self".to_owned()),
+                                           AnyLuaValue::LuaArrayRef(0))));
+            }
+            _ => panic!("Decoded to wrong variant"),
+        }
+
+        lua.set("u", t);
+        assert!(lua.execute::<bool>("return u.self == u and u ~= t and u.a == 1").unwrap());
+    }
+
+    #[test]
+    fn shared_tables_are_duplicated_by_default() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("local shared = { 5 }; t = { shared, shared }").unwrap();
+
+        let t: AnyLuaValue = lua.get("t").unwrap();
+        let shared = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0),
+                                                 AnyLuaValue::LuaNumber(5.0))]);
+        assert_eq!(t, AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0), shared.clone()),
+                                                 (AnyLuaValue::LuaNumber(2.0), shared)]));
+    }
+
+    #[test]
+    fn preserve_shared_tables() {
+        let mut lua = Lua::new();
+        lua.set_table_conversion(TableConversion { preserve_shared: true, ..Default::default() });
+        lua.execute::<()>("local shared = { 5 }; t = { shared, { shared } }").unwrap();
+
+        let t: AnyLuaValue = lua.get("t").unwrap();
+        let shared = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0),
+                                                 AnyLuaValue::LuaNumber(5.0))]);
+        let inner = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0),
+                                                AnyLuaValue::LuaArrayRef(1))]);
+        assert_eq!(t, AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0), shared),
+                                                 (AnyLuaValue::LuaNumber(2.0), inner)]));
+
+        lua.set("u", t);
+        assert!(lua.execute::<bool>("return u[1] == u[2][1] and u[1][1] == 5").unwrap());
+    }
+
+    #[test]
+    fn shared_table_as_key() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set_table_conversion(TableConversion { preserve_shared: true, ..Default::default() });
+        lua.execute::<()>("local k = {}; t = { [k] = k }").unwrap();
+
+        let t: AnyLuaValue = lua.get("t").unwrap();
+        lua.set("u", t);
+        assert!(lua.execute::<bool>("local k, v = next(u); return k == v").unwrap());
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = { { { 1 } } }").unwrap();
+
+        lua.set_table_conversion(TableConversion { max_depth: 3, ..Default::default() });
+        assert!(lua.get::<AnyLuaValue, _>("t").is_some());
+
+        lua.set_table_conversion(TableConversion { max_depth: 2, ..Default::default() });
+        assert!(lua.get::<AnyLuaValue, _>("t").is_none());
+        assert_eq!(unsafe { ffi::lua_gettop(lua.as_mut_lua().0) }, 0);
+    }
+
+    #[test]
+    fn deep_table_fails_by_default() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = {}; local c = t; for i = 1, 200 do c[1] = {}; c = c[1] end").unwrap();
+
+        assert!(lua.get::<AnyLuaValue, _>("t").is_none());
+    }
+
+    #[test]
+    fn push_dangling_array_ref() {
+        let mut lua = Lua::new();
+        lua.set("a", AnyLuaValue::LuaArrayRef(3));
+        lua.set("b", AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0),
+                                                 AnyLuaValue::LuaArrayRef(3))]));
+
+        assert!(lua.execute::<bool>("return a == nil and b[1] == nil").unwrap());
+    }
 }
@@ -9,8 +9,12 @@ use LuaRead;
 use Push;
 use PushGuard;
 use PushOne;
+use raw;
+use rust_errors;
 use Void;
 
+use std::error::Error;
+use std::ffi::CStr;
 use std::marker::PhantomData;
 use std::fmt::Display;
 use std::mem;
@@ -156,6 +160,11 @@ impl_function!(function10, A, B, C, D, E, F, G, H, I, J);
 /// let ret = lua.execute::<()>("res = assert(err())");
 /// assert!(ret.is_err());
 /// ```
+///
+/// # Yielding
+///
+/// A function that is called from within a coroutine can suspend it by returning a
+/// [`Yield`](struct.Yield.html). See the documentation of `Yield` for more information.
 #[derive(Debug)]
 pub struct Function<F, P, R> {
     function: F,
@@ -171,16 +180,6 @@ pub trait FunctionExt<P> {
     fn call_mut(&mut self, params: P) -> Self::Output;
 }
 
-// Called when an object inside Lua is being dropped.
-#[inline]
-extern "C" fn closure_destructor_wrapper<T>(lua: *mut ffi::lua_State) -> libc::c_int {
-    unsafe {
-        let obj = ffi::lua_touserdata(lua, -1);
-        ptr::drop_in_place((obj as *mut u8) as *mut T);
-        0
-    }
-}
-
 macro_rules! impl_function_ext {
     () => (
         impl<Z, R> FunctionExt<()> for Function<Z, (), R> where Z: FnMut() -> R {
@@ -225,7 +224,7 @@ macro_rules! impl_function_ext {
                             Err(_) => unreachable!(),
                         };
 
-                        ffi::lua_pushcfunction(lua.as_mut_lua().0, closure_destructor_wrapper::<Z>);
+                        ffi::lua_pushcfunction(lua.as_mut_lua().0, raw::destructor::<Z>);
                         ffi::lua_settable(lua.as_mut_lua().0, -3);
                     }
                     ffi::lua_setmetatable(lua_raw.0, -2);
@@ -292,7 +291,7 @@ macro_rules! impl_function_ext {
                             Err(_) => unreachable!(),
                         };
 
-                        ffi::lua_pushcfunction(lua.as_mut_lua().0, closure_destructor_wrapper::<Z>);
+                        ffi::lua_pushcfunction(lua.as_mut_lua().0, raw::destructor::<Z>);
                         ffi::lua_settable(lua.as_mut_lua().0, -3);
                     }
                     ffi::lua_setmetatable(lua_raw.0, -2);
@@ -335,6 +334,10 @@ impl_function_ext!(A, B, C, D, E, F, G, H, I, J);
 #[derive(Debug)]
 pub struct InsideCallback {
     lua: LuaContext,
+    // True if the values that have been pushed must be yielded instead of returned.
+    yielding: bool,
+    // True if the value that has been pushed must be raised as an error instead of returned.
+    throwing: bool,
 }
 
 unsafe impl<'a, 'lua> AsLua<'lua> for &'a InsideCallback {
@@ -381,6 +384,131 @@ impl<'a, T, E, P> PushOne<&'a mut InsideCallback> for Result<T, E>
 {
 }
 
+/// Return value of a Rust function or closure that suspends the coroutine that called it.
+///
+/// The content of the `Yield` is passed to the code that resumes the coroutine, just like the
+/// parameters of `coroutine.yield`. Once the coroutine is resumed, the values passed to `resume`
+/// are returned to the Lua code that called the Rust function.
+///
+/// Just like `Result`, `Yield` can only be pushed as the return type of a Rust function or
+/// closure. Calling a function that yields from outside of a coroutine is an error.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LuaCoroutine, LuaFunction, Yield};
+///
+/// let mut lua = Lua::new();
+/// lua.set("wait", hlua::function1(|seconds: f64| Yield(seconds)));
+/// lua.execute::<()>(r#"
+///     function script()
+///         local slept = wait(2.5)
+///         return slept * 2
+///     end
+/// "#).unwrap();
+///
+/// let script: LuaFunction<_> = lua.get("script").unwrap();
+/// let mut co = LuaCoroutine::new(script);
+///
+/// // The coroutine is suspended by `wait` until the host resumes it.
+/// let delay: f64 = co.resume(()).unwrap();
+/// assert_eq!(delay, 2.5);
+///
+/// // The value passed to `resume` is returned by `wait`.
+/// let result: f64 = co.resume(delay).unwrap();
+/// assert_eq!(result, 5.0);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Yield<T>(pub T);
+
+impl<'a, T, P> Push<&'a mut InsideCallback> for Yield<T>
+    where T: Push<&'a mut InsideCallback, Err = P>
+{
+    type Err = P;
+
+    #[inline]
+    fn push_to_lua(self, lua: &'a mut InsideCallback) -> Result<PushGuard<&'a mut InsideCallback>, (P, &'a mut InsideCallback)> {
+        let guard = self.0.push_to_lua(lua)?;
+        guard.lua.yielding = true;
+        Ok(guard)
+    }
+}
+
+impl<'a, T, P> PushOne<&'a mut InsideCallback> for Yield<T>
+    where T: PushOne<&'a mut InsideCallback, Err = P>
+{
+}
+
+/// Return value of a Rust function or closure that raises a Lua error if it contains an `Err`.
+///
+/// When a Rust function returns a `Result`, an `Err` is returned to Lua as `nil` followed by the
+/// error message, which the script has to check. If the function returns a `Throw` instead, an
+/// `Err` raises a real Lua error, which can be caught with `pcall` and otherwise aborts the
+/// script. The error object converts to the error message with `tostring`.
+///
+/// If the error reaches the Rust code that ran the script, the original Rust error can be
+/// recovered from the `ExecutionError` with `rust_error` or `into_rust_error`.
+///
+/// # Example
+///
+/// ```
+/// use std::num::ParseIntError;
+/// use hlua::{Lua, LuaError, Throw};
+///
+/// let mut lua = Lua::new();
+/// lua.set("parse", hlua::function1(|s: String| Throw(s.parse::<i32>())));
+///
+/// let val: i32 = lua.execute("return parse('12')").unwrap();
+/// assert_eq!(val, 12);
+///
+/// match lua.execute::<()>("parse('twelve')") {
+///     Err(LuaError::ExecutionError(err)) => {
+///         let err = err.into_rust_error().unwrap();
+///         assert!(err.downcast::<ParseIntError>().is_ok());
+///     }
+///     _ => unreachable!(),
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throw<T, E>(pub Result<T, E>);
+
+impl<'a, T, E, P> Push<&'a mut InsideCallback> for Throw<T, E>
+    where T: Push<&'a mut InsideCallback, Err = P>,
+          E: Error + Send + Sync + 'static
+{
+    type Err = P;
+
+    #[inline]
+    fn push_to_lua(self, lua: &'a mut InsideCallback) -> Result<PushGuard<&'a mut InsideCallback>, (P, &'a mut InsideCallback)> {
+        match self.0 {
+            Ok(val) => val.push_to_lua(lua),
+            Err(err) => {
+                unsafe { rust_errors::push_error(lua.lua.0, Box::new(err)) };
+                lua.throwing = true;
+                let raw_lua = lua.lua;
+                Ok(PushGuard { lua, size: 1, raw_lua })
+            }
+        }
+    }
+}
+
+impl<'a, T, E, P> PushOne<&'a mut InsideCallback> for Throw<T, E>
+    where T: PushOne<&'a mut InsideCallback, Err = P>,
+          E: Error + Send + Sync + 'static
+{
+}
+
+// Called when a coroutine that has been suspended by a `Yield` is resumed. The context is the
+// number of parameters of the Rust function, and the values above them are the values passed to
+// `resume`, which we return.
+extern "C" fn yield_continuation(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let mut arguments_count = 0;
+        ffi::lua_getctx(lua, &mut arguments_count);
+        ffi::lua_gettop(lua) - arguments_count
+    }
+}
+
 // this function is called when Lua wants to call one of our functions
 #[inline]
 extern "C" fn wrapper<T, P, R>(lua: *mut ffi::lua_State) -> libc::c_int
@@ -388,48 +516,132 @@ extern "C" fn wrapper<T, P, R>(lua: *mut ffi::lua_State) -> libc::c_int
           P: for<'p> LuaRead<&'p mut InsideCallback> + 'static,
           R: for<'p> Push<&'p mut InsideCallback>
 {
-    // loading the object that we want to call from the Lua context
-    let data_raw = unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) };
-    let data: &mut T = unsafe { mem::transmute(data_raw) };
-
-    // creating a temporary Lua context in order to pass it to push & read functions
-    let mut tmp_lua = InsideCallback { lua: LuaContext(lua) };
-
-    // trying to read the arguments
-    let arguments_count = unsafe { ffi::lua_gettop(lua) } as i32;
-    let args = match LuaRead::lua_read_at_position(&mut tmp_lua, -arguments_count as libc::c_int) {      // TODO: what if the user has the wrong params?
-        Err(_) => {
-            let err_msg = format!("wrong parameter types for callback function");
-            match err_msg.push_to_lua(&mut tmp_lua) {
-                Ok(p) => p.forget_internal(),
-                Err(_) => unreachable!(),
-            };
+    // All the Rust code runs inside `catch`, so that nothing is left to destroy when we call
+    // `lua_error` or `lua_yieldk` below.
+    let outcome = rust_errors::catch(lua, || {
+        // loading the object that we want to call from the Lua context
+        let data_raw = unsafe { ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) };
+        let data: &mut T = unsafe { mem::transmute(data_raw) };
+
+        // creating a temporary Lua context in order to pass it to push & read functions
+        let mut tmp_lua = InsideCallback { lua: LuaContext(lua), yielding: false, throwing: false };
+
+        // trying to read the arguments
+        let arguments_count = unsafe { ffi::lua_gettop(lua) } as i32;
+        // the arguments are read from the bottom of the stack, so that missing arguments are
+        // read above the top
+        let args = match LuaRead::lua_read_at_position(&mut tmp_lua, 1) {
+            Err(_) => {
+                let (offset, expected) =
+                    <P as LuaRead<&mut InsideCallback>>::lua_read_error(&mut tmp_lua, 1);
+                let err_msg = unsafe { argument_error(lua, offset + 1, &expected) };
+                match err_msg.push_to_lua(&mut tmp_lua) {
+                    Ok(p) => p.forget_internal(),
+                    Err(_) => unreachable!(),
+                };
+                return None;
+            }
+            Ok(a) => a,
+        };
+
+        let ret_value = data.call_mut(args);
+
+        // pushing back the result of the function on the stack
+        let nb = match ret_value.push_to_lua(&mut tmp_lua) {
+            Ok(p) => p.forget_internal(),
+            Err((_, lua)) => {
+                // The error isn't required to implement `Display`, as this would prevent the
+                // compiler from inferring the type of integer literals returned by closures.
+                let err_msg = "the value returned by the function can't be pushed";
+                match err_msg.push_to_lua(lua) {
+                    Ok(p) => p.forget_internal(),
+                    Err(_) => unreachable!(),
+                };
+                return None;
+            }
+        };
+        if tmp_lua.throwing {
+            return None;
+        }
+
+        Some((nb, arguments_count, tmp_lua.yielding))
+    });
+
+    match outcome {
+        Some(Some((nb, _, false))) => nb as libc::c_int,
+        Some(Some((nb, arguments_count, true))) => {
+            // Note that this function doesn't return, so nothing must be left to destroy.
+            unsafe {
+                ffi::lua_yieldk(lua, nb, arguments_count, Some(yield_continuation));
+            }
+            unreachable!()
+        }
+        // the arguments are wrong, the function returned an error or it panicked, and the error
+        // object is on the stack
+        Some(None) | None => {
             unsafe {
                 ffi::lua_error(lua);
             }
             unreachable!()
         }
-        Ok(a) => a,
+    }
+}
+
+// Builds the same message as `luaL_argerror` for the argument `arg` of the running function.
+pub unsafe fn argument_error(lua: *mut ffi::lua_State, mut arg: i32, expected: &str) -> String {
+    let got = CStr::from_ptr(ffi::lua_typename(lua, ffi::lua_type(lua, arg))).to_string_lossy();
+
+    // location of the caller, like `luaL_where`
+    let mut ar: ffi::lua_Debug = Default::default();
+    let location = if ffi::lua_getstack(lua, 1, &mut ar) != 0 &&
+                      ffi::lua_getinfo(lua, b"Sl\0".as_ptr() as *const _, &mut ar) != 0 &&
+                      ar.currentline > 0 {
+        let source = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy();
+        format!("{}:{}: ", source, ar.currentline)
+    } else {
+        String::new()
     };
 
-    let ret_value = data.call_mut(args);
+    let mut ar: ffi::lua_Debug = Default::default();
+    if ffi::lua_getstack(lua, 0, &mut ar) == 0 {
+        return format!("{}bad argument #{} ({} expected, got {})", location, arg, expected, got);
+    }
 
-    // pushing back the result of the function on the stack
-    let nb = match ret_value.push_to_lua(&mut tmp_lua) {
-        Ok(p) => p.forget_internal(),
-        Err(_) => panic!(),      // TODO: wrong
+    // the name of the function depends on how it has been called, just like in Lua
+    ffi::lua_getinfo(lua, b"n\0".as_ptr() as *const _, &mut ar);
+    let name = if ar.name.is_null() {
+        "?".into()
+    } else {
+        CStr::from_ptr(ar.name).to_string_lossy()
     };
-    nb as libc::c_int
+
+    if !ar.namewhat.is_null() && CStr::from_ptr(ar.namewhat).to_bytes() == b"method" {
+        arg -= 1;
+        if arg == 0 {
+            return format!("{}calling '{}' on bad self ({} expected, got {})",
+                           location, name, expected, got);
+        }
+    }
+
+    format!("{}bad argument #{} to '{}' ({} expected, got {})", location, arg, name, expected, got)
 }
 
 #[cfg(test)]
 mod tests {
     use Lua;
+    use LuaCoroutine;
+    use LuaCoroutineStatus;
     use LuaError;
+    use LuaFunction;
+    use Throw;
+    use Yield;
     use function0;
     use function1;
     use function2;
 
+    use std::error::Error;
+    use std::fmt;
+    use std::panic::{self, AssertUnwindSafe};
     use std::sync::Arc;
 
     #[test]
@@ -576,4 +788,235 @@ mod tests {
         }
         assert_eq!(unsafe { DID_DESTRUCTOR_RUN }, true);
     }
+
+    #[test]
+    fn yield_from_callback() {
+        let mut lua = Lua::new();
+        lua.set("wait", function1(|ticks: i32| Yield(ticks)));
+        lua.execute::<()>(r#"
+            function script()
+                local a = wait(3)
+                local b = wait(a + 1)
+                return a + b
+            end
+        "#).unwrap();
+
+        let script: LuaFunction<_> = lua.get("script").unwrap();
+        let mut co = LuaCoroutine::new(script);
+
+        let ticks: i32 = co.resume(()).unwrap();
+        assert_eq!(ticks, 3);
+        let ticks: i32 = co.resume(10).unwrap();
+        assert_eq!(ticks, 11);
+        assert_eq!(co.status(), LuaCoroutineStatus::Suspended);
+        let result: i32 = co.resume(20).unwrap();
+        assert_eq!(result, 30);
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn yield_multiple_values() {
+        let mut lua = Lua::new();
+        lua.set("pause", function2(|a: i32, b: i32| Yield((a * 2, b * 2))));
+        let f = LuaFunction::load(&mut lua, "local x, y = pause(1, 2); return x .. y").unwrap();
+        let mut co = LuaCoroutine::new(f);
+
+        let (a, b): (i32, i32) = co.resume(()).unwrap();
+        assert_eq!((a, b), (2, 4));
+        let s: String = co.resume(("foo", "bar")).unwrap();
+        assert_eq!(s, "foobar");
+    }
+
+    #[test]
+    fn yield_to_lua_resume() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set("wait", function0(|| Yield("waiting")));
+
+        let val: String = lua.execute(r#"
+            local co = coroutine.wrap(function() return wait() .. "!" end)
+            local first = co()
+            return first .. " " .. co("done")
+        "#).unwrap();
+        assert_eq!(val, "waiting done!");
+    }
+
+    #[test]
+    fn yield_outside_coroutine() {
+        let mut lua = Lua::new();
+        lua.set("wait", function0(|| Yield(())));
+
+        match lua.execute::<()>("wait()") {
+            Err(LuaError::ExecutionError(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn panic_becomes_lua_error() {
+        let mut lua = Lua::new();
+        lua.set("explode", function0(|| -> i32 { panic!("boom") }));
+
+        match lua.execute::<()>("explode()") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message(), "panic in Rust callback: boom");
+            }
+            _ => panic!(),
+        }
+
+        // the context is still usable
+        let val: i32 = lua.execute("return 5").unwrap();
+        assert_eq!(val, 5);
+    }
+
+    #[test]
+    fn panic_can_be_caught_by_lua() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set("explode", function1(|msg: String| -> i32 { panic!("{}", msg) }));
+
+        let val: String = lua.execute(r#"
+            local ok, err = pcall(explode, "caught")
+            return tostring(ok) .. " " .. tostring(err)
+        "#).unwrap();
+        assert_eq!(val, "false panic in Rust callback: caught");
+    }
+
+    #[test]
+    fn panic_is_resumed() {
+        let mut lua = Lua::new();
+        lua.set_resume_panics(true);
+        lua.set("explode", function0(|| -> i32 { panic!("boom") }));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = lua.execute::<()>("explode()");
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+
+        let val: i32 = lua.execute("return 5").unwrap();
+        assert_eq!(val, 5);
+    }
+
+    #[test]
+    fn panic_in_coroutine_is_resumed() {
+        let mut lua = Lua::new();
+        lua.set_resume_panics(true);
+        lua.set("explode", function0(|| -> i32 { panic!("boom") }));
+        let f = LuaFunction::load(&mut lua, "explode()").unwrap();
+        let mut co = LuaCoroutine::new(f);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = co.resume::<(), _, _>(());
+        }));
+        assert!(result.is_err());
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn panic_in_closure_destructor_is_ignored() {
+        struct Explode;
+        impl Drop for Explode {
+            fn drop(&mut self) {
+                panic!("boom");
+            }
+        }
+
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set_resume_panics(true);
+        let explode = Explode;
+        lua.set("f", function0(move || { let _ = &explode; }));
+
+        lua.execute::<()>("f = nil; collectgarbage()").unwrap();
+        let val: i32 = lua.execute("return 5").unwrap();
+        assert_eq!(val, 5);
+    }
+
+    #[test]
+    fn throw_ok() {
+        let mut lua = Lua::new();
+        lua.set("parse", function1(|s: String| Throw(s.parse::<i32>())));
+
+        let val: i32 = lua.execute("return parse('42')").unwrap();
+        assert_eq!(val, 42);
+    }
+
+    #[test]
+    fn throw_err_can_be_caught_by_lua() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set("parse", function1(|s: String| Throw(s.parse::<i32>())));
+
+        let val: String = lua.execute(r#"
+            local ok, err = pcall(parse, "foo")
+            return tostring(ok) .. " " .. tostring(err)
+        "#).unwrap();
+        assert_eq!(val, "false invalid digit found in string");
+    }
+
+    #[test]
+    fn throw_err_is_recovered() {
+        #[derive(Debug)]
+        struct MyError(i32);
+        impl fmt::Display for MyError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "my error {}", self.0)
+            }
+        }
+        impl Error for MyError {}
+
+        let mut lua = Lua::new();
+        lua.set("fail", function1(|code: i32| -> Throw<(), MyError> { Throw(Err(MyError(code))) }));
+
+        match lua.execute::<()>("fail(7)") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message(), "my error 7");
+                assert!(err.source().unwrap().is::<MyError>());
+                let err = err.into_rust_error().unwrap().downcast::<MyError>().unwrap();
+                assert_eq!(err.0, 7);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn bad_argument_message() {
+        let mut lua = Lua::new();
+        lua.set("add", function2(|a: i32, b: i32| a + b));
+
+        match lua.execute::<()>("add(1, 'two')") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message(),
+                           "[string \"chunk\"]:1: bad argument #2 to 'add' (number expected, got string)");
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn missing_argument_message() {
+        let mut lua = Lua::new();
+        lua.set("concat", function2(|a: String, b: String| a + &b));
+
+        match lua.execute::<()>("local f = concat; f('a')") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().ends_with("bad argument #2 to 'f' (string expected, got no value)"));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn bad_self_message() {
+        let mut lua = Lua::new();
+        lua.set("check", function1(|a: bool| a));
+
+        match lua.execute::<()>("local t = { check = check }; t:check()") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().ends_with("calling 'check' on bad self (boolean expected, got table)"));
+            }
+            _ => panic!(),
+        }
+    }
 }
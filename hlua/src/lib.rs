@@ -62,7 +62,9 @@
 //! You can push values that implement [the `Push` trait](trait.Push.html) or
 //! [the `PushOne` trait](trait.PushOne.html) depending on the situation:
 //!
-//! - Integers, floating point numbers and booleans.
+//! - Integers, floating point numbers and booleans. Since a Lua number can't represent all the
+//!   64-bit and 128-bit integers, pushing them can result in an error, and you need to use
+//!   [`checked_set`](struct.Lua.html#method.checked_set) instead of `set`.
 //! - `String` and `&str`.
 //! - Any Rust function or closure whose parameters and loadable and whose return type is pushable.
 //!   See the documentation of [the `Function` struct](struct.Function.html) for more information.
@@ -90,13 +92,18 @@
 //!
 //! You can load values that implement [the `LuaRead` trait](trait.LuaRead.html):
 //!
-//! - Integers, floating point numbers and booleans.
+//! - Integers, floating point numbers and booleans. Loading an integer fails, for example `get`
+//!   returns `None`, if the number isn't integral or is out of the range of the integer type.
 //! - `String` and [`StringInLua`](struct.StringInLua.html) (ie. the equivalent of `&str`). Loading
 //!   the latter has no cost while loading a `String` performs an allocation.
 //! - Any function (Lua or Rust), with [the `LuaFunction` struct](struct.LuaFunction.html). This
 //!   can then be used to execute the function.
+//! - Coroutines, with [the `LuaCoroutine` struct](struct.LuaCoroutine.html). This can then be
+//!   used to resume the coroutine.
 //! - [The `AnyLuaValue` struct](struct.AnyLuaValue.html). This enumeration represents any possible
 //!   value in Lua.
+//! - Any value, with [the `LuaRef` struct](struct.LuaRef.html). Contrary to the other handles, a
+//!   `LuaRef` doesn't borrow the Lua context and can be stored for later use.
 //! - [The `LuaTable` struct](struct.LuaTable.html). This struct represents a table in Lua, where
 //!   keys and values can be of different types. The table can then be iterated and individual
 //!   elements can be loaded or modified.
@@ -110,39 +117,74 @@
 #[doc(hidden)]
 pub extern crate lua52_sys as ffi;
 extern crate libc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
 
 use std::ffi::{CStr, CString};
 use std::io::Read;
 use std::io::Error as IoError;
-use std::borrow::Borrow;
+use std::any::type_name;
+use std::borrow::{Borrow, Cow};
 use std::marker::PhantomData;
 use std::error::Error;
 use std::fmt;
 use std::convert::From;
 use std::io;
+use std::slice;
+use std::time::Duration;
 
-pub use any::{AnyHashableLuaValue, AnyLuaString, AnyLuaValue};
-pub use functions_write::{Function, InsideCallback};
+pub use any::{AnyHashableLuaValue, AnyLuaString, AnyLuaValue, ErrorValue, TableConversion};
+pub use functions_write::{Function, InsideCallback, Throw, Yield};
 pub use functions_write::{function0, function1, function2, function3, function4, function5};
 pub use functions_write::{function6, function7, function8, function9, function10};
+pub use lua_classes::{push_class, LuaClass, LuaClassTable, LuaMethods};
+pub use lua_coroutines::{LuaCoroutine, LuaCoroutineIterator, LuaCoroutineStatus};
 pub use lua_functions::LuaFunction;
 pub use lua_functions::LuaFunctionCallError;
 pub use lua_functions::{LuaCode, LuaCodeFromReader};
+pub use lua_refs::LuaRef;
+#[cfg(feature = "serde")]
+pub use lua_serde::{from_lua, to_lua, LuaSerdeError};
 pub use lua_tables::LuaTable;
 pub use lua_tables::LuaTableIterator;
+pub use output::OutputCapture;
 pub use tuples::TuplePushError;
 pub use userdata::UserdataOnStack;
 pub use userdata::{push_userdata, read_userdata};
-pub use values::StringInLua;
+pub use safe_libs::SafetyProfile;
+pub use strict::Strict;
+pub use values::{IntegerPushError, StringInLua};
+pub use variadic::{MultiValue, Variadic};
+pub use vfs::{DirVfs, MemoryVfs, ReadOnlyVfs, Vfs};
+
+#[macro_use]
+mod macros;
 
 mod any;
 mod functions_write;
+mod limits;
+mod lua_classes;
+mod lua_coroutines;
 mod lua_functions;
+mod lua_refs;
+#[cfg(feature = "serde")]
+mod lua_serde;
 mod lua_tables;
-mod macros;
+mod memory;
+mod output;
+mod raw;
+mod rust_errors;
 mod rust_tables;
+mod safe_libs;
+mod strict;
 mod userdata;
 mod values;
+mod variadic;
+mod vfs;
 mod tuples;
 
 /// Main object of the library.
@@ -151,9 +193,11 @@ mod tuples;
 ///
 /// # About panic safety
 ///
-/// This type isn't panic safe. This means that if a panic happens while you were using the `Lua`,
-/// then it will probably stay in a corrupt state. Trying to use the `Lua` again will most likely
-/// result in another panic but shouldn't result in unsafety.
+/// Panics that happen inside Rust callbacks are caught before they reach Lua and are turned into
+/// Lua errors (see `set_resume_panics`). Panics in the `Drop` implementation of a value owned by
+/// Lua, such as a user data or a closure, are ignored. Other panics (for example in a `Drop`
+/// implementation called by Rust code) can leave the `Lua` in a corrupt state. Trying to use the `Lua` again
+/// will most likely result in another panic but shouldn't result in unsafety.
 #[derive(Debug)]
 pub struct Lua<'lua> {
     lua: LuaContext,
@@ -225,10 +269,9 @@ impl<'lua, L> PushGuard<L>
     pub fn into_inner(mut self) -> L {
         use std::{mem, ptr};
 
-        let mut res;
+        let res;
         unsafe {
-            res = mem::uninitialized();
-            ptr::copy_nonoverlapping(&self.lua, &mut res, 1);
+            res = ptr::read(&self.lua);
             if self.size != 0 {
                 ffi::lua_pop(self.lua.as_mut_lua().0, self.size);
             }
@@ -400,6 +443,27 @@ pub trait LuaRead<L>: Sized {
 
     /// Reads the data from Lua at a given position.
     fn lua_read_at_position(lua: L, index: i32) -> Result<Self, L>;
+
+    /// Returns a description of the value expected by `lua_read_at_position`, used in error
+    /// messages.
+    ///
+    /// The default implementation returns the name of the Rust type.
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed(type_name::<Self>())
+    }
+
+    /// Called after `lua_read_at_position` has failed, in order to build an error message.
+    /// Returns the offset from `index` of the value that couldn't be read, and a description of
+    /// what was expected there.
+    ///
+    /// The default implementation returns `0` and `lua_type_name()`. Types that read multiple
+    /// values, such as tuples, override it to point at the culprit.
+    #[inline]
+    fn lua_read_error(lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        let _ = (lua, index);
+        (0, Self::lua_type_name())
+    }
 }
 
 /// Error that can happen when executing Lua code.
@@ -410,13 +474,28 @@ pub enum LuaError {
 
     /// There was an error during execution of the Lua code
     /// (for example not enough parameters for a function call).
-    ExecutionError(String),
+    ExecutionError(ExecutionError),
 
     /// There was an IoError while reading the source code to execute.
     ReadError(IoError),
 
     /// The call to `execute` has requested the wrong type of data.
     WrongType,
+
+    /// The Lua code has been aborted because it exceeded the limit set with
+    /// `Lua::set_instruction_limit`.
+    InstructionLimit,
+
+    /// The Lua code has been aborted because it exceeded the limit set with `Lua::set_timeout`.
+    Timeout,
+
+    /// The Lua code has called `os.exit` with this exit code. The code has been aborted, but the
+    /// process keeps running.
+    Exit(i32),
+
+    /// The Lua code failed to allocate memory, most likely because of the limit set with
+    /// `Lua::with_memory_limit`.
+    OutOfMemory,
 }
 
 impl fmt::Display for LuaError {
@@ -425,9 +504,13 @@ impl fmt::Display for LuaError {
 
         match *self {
             SyntaxError(ref s) => write!(f, "Syntax error: {}", s),
-            ExecutionError(ref s) => write!(f, "Execution error: {}", s),
+            ExecutionError(ref e) => write!(f, "Execution error: {}", e),
             ReadError(ref e) => write!(f, "Read error: {}", e),
             WrongType => write!(f, "Wrong type returned by Lua"),
+            InstructionLimit => write!(f, "Instruction limit exceeded"),
+            Timeout => write!(f, "Execution timed out"),
+            Exit(code) => write!(f, "Script exited with code {}", code),
+            OutOfMemory => write!(f, "Out of memory"),
         }
     }
 }
@@ -438,21 +521,56 @@ impl Error for LuaError {
 
         match *self {
             SyntaxError(ref s) => &s,
-            ExecutionError(ref s) => &s,
+            ExecutionError(ref e) => e.message(),
             ReadError(_) => "read error",
             WrongType => "wrong type returned by Lua",
+            InstructionLimit => "instruction limit exceeded",
+            Timeout => "execution timed out",
+            Exit(_) => "script exited",
+            OutOfMemory => "out of memory",
         }
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         use LuaError::*;
 
         match *self {
             SyntaxError(_) => None,
-            ExecutionError(_) => None,
+            ExecutionError(ref e) => e.source(),
             ReadError(ref e) => Some(e),
             WrongType => None,
+            InstructionLimit => None,
+            Timeout => None,
+            Exit(_) => None,
+            OutOfMemory => None,
+        }
+    }
+}
+
+impl LuaError {
+    /// If this error was raised by a Rust function that returned a `Throw`, destroys it and
+    /// returns the Rust error. Otherwise returns the error unchanged.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::num::ParseIntError;
+    /// use hlua::{Lua, Throw};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.set("parse", hlua::function1(|s: String| Throw(s.parse::<i32>())));
+    ///
+    /// let err = lua.execute::<()>("parse('twelve')").unwrap_err();
+    /// let err = err.into_rust_error().ok().unwrap();
+    /// assert!(err.is::<ParseIntError>());
+    /// ```
+    pub fn into_rust_error(mut self) -> Result<Box<dyn Error + Send + Sync>, LuaError> {
+        if let LuaError::ExecutionError(ref mut err) = self {
+            if let Some(rust_error) = err.inner.rust_error.take() {
+                return Ok(rust_error);
+            }
         }
+        Err(self)
     }
 }
 
@@ -462,6 +580,221 @@ impl From<io::Error> for LuaError {
     }
 }
 
+/// Details about an error raised while executing Lua code.
+///
+/// Besides the error message, this contains the location where the error was raised, the stack
+/// traceback at this moment, and the value that was passed to `error`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{ErrorValue, Lua, LuaError};
+///
+/// let mut lua = Lua::new();
+/// lua.open_base();
+///
+/// match lua.execute::<()>("local a = 5\nerror('oops')") {
+///     Err(LuaError::ExecutionError(err)) => {
+///         assert_eq!(err.message(), "[string \"chunk\"]:2: oops");
+///         assert_eq!(err.chunk_name(), Some("chunk"));
+///         assert_eq!(err.line(), Some(2));
+///         assert!(err.traceback().unwrap().starts_with("stack traceback:"));
+///         assert_eq!(err.value(), &ErrorValue::LuaString(err.message().to_owned()));
+///     }
+///     _ => unreachable!()
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ExecutionError {
+    // Boxed so that `LuaError` stays small.
+    inner: Box<ExecutionErrorInner>,
+}
+
+#[derive(Debug)]
+struct ExecutionErrorInner {
+    message: String,
+    location: Option<(String, u32)>,
+    traceback: Option<String>,
+    value: ErrorValue,
+    rust_error: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl ExecutionError {
+    #[inline]
+    fn new(inner: ExecutionErrorInner) -> ExecutionError {
+        ExecutionError { inner: Box::new(inner) }
+    }
+
+    /// Builds an error from a message, as if it was thrown with `error(message, 0)`.
+    pub(crate) fn from_message(message: String) -> ExecutionError {
+        ExecutionError::new(ExecutionErrorInner {
+            location: None,
+            traceback: None,
+            value: ErrorValue::LuaString(message.clone()),
+            message,
+            rust_error: None,
+        })
+    }
+
+    /// Builds an error from the error object at the top of the stack. Doesn't pop it.
+    pub(crate) fn from_lua<'lua, L>(lua: L, traceback: Option<String>) -> ExecutionError
+        where L: AsMutLua<'lua>
+    {
+        let raw_lua = lua.as_lua().0;
+        let type_name = unsafe {
+            let ty = ffi::lua_type(raw_lua, -1);
+            CStr::from_ptr(ffi::lua_typename(raw_lua, ty)).to_string_lossy().into_owned()
+        };
+
+        let tostring = unsafe { call_tostring(raw_lua) };
+        let rust_error = unsafe { rust_errors::take_error(raw_lua, -1) };
+
+        let value = AnyLuaValue::lua_read(lua).unwrap_or(AnyLuaValue::LuaNil);
+        let message = match value {
+            AnyLuaValue::LuaString(ref s) => s.clone(),
+            AnyLuaValue::LuaAnyString(AnyLuaString(ref s)) => String::from_utf8_lossy(s).into_owned(),
+            AnyLuaValue::LuaNumber(n) => n.to_string(),
+            _ => tostring.unwrap_or_else(|| format!("(error object is a {} value)", type_name)),
+        };
+
+        let location = parse_location(&message);
+        ExecutionError::new(ExecutionErrorInner {
+            message,
+            location,
+            traceback,
+            value: value.into(),
+            rust_error,
+        })
+    }
+
+    /// Returns the error message.
+    ///
+    /// If the error object is a string or a number, this is the error object itself, which
+    /// includes the location of the error if Lua added it. Otherwise it describes the type of
+    /// the error object.
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.inner.message
+    }
+
+    /// Returns the name of the chunk where the error was raised, if the message contains a
+    /// location.
+    ///
+    /// For code loaded by hlua, this is `"chunk"`.
+    #[inline]
+    pub fn chunk_name(&self) -> Option<&str> {
+        self.inner.location.as_ref().map(|(chunk, _)| &chunk[..])
+    }
+
+    /// Returns the line where the error was raised, if the message contains a location.
+    #[inline]
+    pub fn line(&self) -> Option<u32> {
+        self.inner.location.as_ref().map(|&(_, line)| line)
+    }
+
+    /// Returns the stack traceback at the moment when the error was raised, if it could be
+    /// captured.
+    #[inline]
+    pub fn traceback(&self) -> Option<&str> {
+        self.inner.traceback.as_ref().map(|t| &t[..])
+    }
+
+    /// Returns the value that was passed to `error`.
+    ///
+    /// This makes it possible to read back error objects that aren't strings, for example the
+    /// table thrown by `error({code = 42})`. Functions, userdata and coroutines are replaced
+    /// with their type, so that the error doesn't keep the Lua context alive.
+    #[inline]
+    pub fn value(&self) -> &ErrorValue {
+        &self.inner.value
+    }
+
+    /// Destroys the error and returns the value that was passed to `error`.
+    #[inline]
+    pub fn into_value(self) -> ErrorValue {
+        self.inner.value
+    }
+
+    /// Returns the Rust error that caused this error, if it was raised by a Rust function that
+    /// returned a `Throw`.
+    #[inline]
+    pub fn rust_error(&self) -> Option<&(dyn Error + Send + Sync + 'static)> {
+        self.inner.rust_error.as_deref()
+    }
+
+    /// Destroys the error and returns the Rust error that caused it, if it was raised by a Rust
+    /// function that returned a `Throw`.
+    #[inline]
+    pub fn into_rust_error(self) -> Option<Box<dyn Error + Send + Sync>> {
+        self.inner.rust_error
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.inner.message)
+    }
+}
+
+impl Error for ExecutionError {
+    fn description(&self) -> &str {
+        &self.inner.message
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner.rust_error.as_ref().map(|e| &**e as &(dyn Error + 'static))
+    }
+}
+
+// Calls the `__tostring` metamethod of the value at the top of the stack, if it has one.
+unsafe fn call_tostring(lua: *mut ffi::lua_State) -> Option<String> {
+    if ffi::luaL_getmetafield(lua, -1, b"__tostring\0".as_ptr() as *const _) == 0 {
+        return None;
+    }
+
+    ffi::lua_pushvalue(lua, -2);
+    if ffi::lua_pcall(lua, 1, 1, 0) != 0 || ffi::lua_type(lua, -1) != ffi::LUA_TSTRING {
+        ffi::lua_pop(lua, 1);
+        return None;
+    }
+
+    let mut len = 0;
+    let data = ffi::lua_tolstring(lua, -1, &mut len);
+    let bytes = slice::from_raw_parts(data as *const u8, len);
+    let string = String::from_utf8_lossy(bytes).into_owned();
+    ffi::lua_pop(lua, 1);
+    Some(string)
+}
+
+// Extracts the chunk name and the line from a message of the form `chunk:line: message`, as
+// produced by `error` and by the runtime errors of Lua.
+fn parse_location(message: &str) -> Option<(String, u32)> {
+    for (pos, _) in message.match_indices(':') {
+        let after = &message[pos + 1..];
+        let digits = after.bytes().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 || !after[digits..].starts_with(':') {
+            continue;
+        }
+
+        let line = match after[..digits].parse() {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+
+        // `[string "chunk"]` is how Lua displays chunks that weren't loaded from a file.
+        let chunk = &message[..pos];
+        let chunk = if chunk.starts_with("[string \"") && chunk.ends_with("\"]") {
+            &chunk[9..chunk.len() - 2]
+        } else {
+            chunk
+        };
+
+        return Some((chunk.to_owned(), line));
+    }
+
+    None
+}
+
 impl<'lua> Lua<'lua> {
     /// Builds a new empty Lua context.
     ///
@@ -484,27 +817,47 @@ impl<'lua> Lua<'lua> {
     /// (which indicates lack of memory).
     #[inline]
     pub fn new() -> Lua<'lua> {
-        let lua = unsafe { ffi::lua_newstate(alloc, std::ptr::null_mut()) };
+        Lua::from_new_state(memory::new_state(None))
+    }
+
+    /// Builds a new empty Lua context whose memory usage can't exceed `limit` bytes.
+    ///
+    /// If the Lua code executed in this context tries to allocate more memory, the allocation
+    /// fails and the call to `execute` or `LuaFunction::call_with_args` returns
+    /// `LuaError::OutOfMemory`. The context can still be used afterwards.
+    ///
+    /// The memory used by the context includes the memory used by the standard library if you
+    /// open it, and by the values that you push from Rust. Memory allocation failures outside of
+    /// the execution of Lua code (for example while calling `set`) are not recoverable and result
+    /// in a panic.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaError};
+    /// let mut lua = Lua::with_memory_limit(1024 * 1024);
+    ///
+    /// match lua.execute::<()>("local s = 'a'; while true do s = s .. s end") {
+    ///     Err(LuaError::OutOfMemory) => (),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    ///
+    /// # Panic
+    ///
+    /// The function panics if the underlying call to `lua_newstate` fails, which can happen if
+    /// `limit` is too low.
+    #[inline]
+    pub fn with_memory_limit(limit: usize) -> Lua<'lua> {
+        Lua::from_new_state(memory::new_state(Some(limit)))
+    }
+
+    // Common code of the constructors that create a new state.
+    fn from_new_state(lua: *mut ffi::lua_State) -> Lua<'lua> {
         if lua.is_null() {
             panic!("lua_newstate failed");
         }
 
-        // this alloc function is required to create a lua state.
-        extern "C" fn alloc(_ud: *mut libc::c_void,
-                            ptr: *mut libc::c_void,
-                            _osize: libc::size_t,
-                            nsize: libc::size_t)
-                            -> *mut libc::c_void {
-            unsafe {
-                if nsize == 0 {
-                    libc::free(ptr as *mut libc::c_void);
-                    std::ptr::null_mut()
-                } else {
-                    libc::realloc(ptr, nsize)
-                }
-            }
-        }
-
         // called whenever lua encounters an unexpected error.
         extern "C" fn panic(lua: *mut ffi::lua_State) -> libc::c_int {
             let err = unsafe { ffi::lua_tostring(lua, -1) };
@@ -535,12 +888,54 @@ impl<'lua> Lua<'lua> {
         }
     }
 
+    /// Changes the maximum number of bytes that the context can allocate. Pass `None` to remove
+    /// the limit.
+    ///
+    /// See [`with_memory_limit`](#method.with_memory_limit) for more information. If the context
+    /// is already using more memory than the new limit, only allocations that would make it grow
+    /// further will fail.
+    ///
+    /// Returns `false` and does nothing if the context wasn't created by hlua (see
+    /// `from_existing_state`).
+    #[inline]
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) -> bool {
+        memory::set_limit(self.lua.0, limit)
+    }
+
+    /// Returns the number of bytes currently allocated by the context.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::Lua;
+    /// let mut lua = Lua::new();
+    /// let before = lua.memory_used();
+    /// lua.execute::<()>("a = {1, 2, 3}").unwrap();
+    /// assert!(lua.memory_used() > before);
+    /// ```
+    #[inline]
+    pub fn memory_used(&self) -> usize {
+        memory::used(self.lua.0)
+    }
+
+    /// Returns the highest number of bytes that have been allocated at once by the context since
+    /// it was created.
+    ///
+    /// If the context wasn't created by hlua (see `from_existing_state`), the peak isn't tracked
+    /// and this returns the same value as `memory_used`.
+    #[inline]
+    pub fn peak_memory_used(&self) -> usize {
+        memory::peak(self.lua.0)
+    }
+
     /// Opens all standard Lua libraries.
     ///
     /// See the reference for the standard library here:
     /// https://www.lua.org/manual/5.2/manual.html#6
     ///
-    /// This is done by calling `luaL_openlibs`.
+    /// This is done by calling `luaL_openlibs`. `os.exit` is then replaced so that it aborts the
+    /// running code with `LuaError::Exit` instead of terminating the process, and
+    /// `coroutine.resume` and `coroutine.wrap` are replaced as described in `open_coroutine`.
     ///
     /// # Example
     ///
@@ -552,6 +947,10 @@ impl<'lua> Lua<'lua> {
     #[inline]
     pub fn openlibs(&mut self) {
         unsafe { ffi::luaL_openlibs(self.lua.0) }
+        limits::replace_exit(self.lua.0);
+        limits::replace_resume(self);
+        output::install(self);
+        vfs::install(self)
     }
 
     /// Opens base library.
@@ -559,7 +958,9 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_base
     #[inline]
     pub fn open_base(&mut self) {
-        unsafe { ffi::luaopen_base(self.lua.0) }
+        self.open_library(b"_G\0", ffi::luaopen_base);
+        output::install(self);
+        vfs::install(self)
     }
 
     /// Opens bit32 library.
@@ -567,15 +968,20 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_bit32
     #[inline]
     pub fn open_bit32(&mut self) {
-        unsafe { ffi::luaopen_bit32(self.lua.0) }
+        self.open_library(b"bit32\0", ffi::luaopen_bit32)
     }
 
     /// Opens coroutine library.
     ///
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_coroutine
+    ///
+    /// `coroutine.resume` and `coroutine.wrap` are replaced so that the limits set with
+    /// `set_instruction_limit` and `set_timeout` apply to all the coroutines, including those
+    /// created before the limits were set.
     #[inline]
     pub fn open_coroutine(&mut self) {
-        unsafe { ffi::luaopen_coroutine(self.lua.0) }
+        self.open_library(b"coroutine\0", ffi::luaopen_coroutine);
+        limits::replace_resume(self)
     }
 
     /// Opens debug library.
@@ -583,7 +989,7 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_debug
     #[inline]
     pub fn open_debug(&mut self) {
-        unsafe { ffi::luaopen_debug(self.lua.0) }
+        self.open_library(b"debug\0", ffi::luaopen_debug)
     }
 
     /// Opens io library.
@@ -591,7 +997,9 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_io
     #[inline]
     pub fn open_io(&mut self) {
-        unsafe { ffi::luaopen_io(self.lua.0) }
+        self.open_library(b"io\0", ffi::luaopen_io);
+        output::install(self);
+        vfs::install(self)
     }
 
     /// Opens math library.
@@ -599,15 +1007,34 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_math
     #[inline]
     pub fn open_math(&mut self) {
-        unsafe { ffi::luaopen_math(self.lua.0) }
+        self.open_library(b"math\0", ffi::luaopen_math)
     }
 
     /// Opens os library.
     ///
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_os
+    ///
+    /// Instead of terminating the process, `os.exit` aborts the running code and makes
+    /// `execute` or `call_with_args` return `LuaError::Exit` with the exit code. Scripts can't
+    /// catch this error with `pcall`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaError};
+    /// let mut lua = Lua::new();
+    /// lua.open_os();
+    ///
+    /// match lua.execute::<()>("os.exit(3)") {
+    ///     Err(LuaError::Exit(3)) => (),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
     #[inline]
     pub fn open_os(&mut self) {
-        unsafe { ffi::luaopen_os(self.lua.0) }
+        self.open_library(b"os\0", ffi::luaopen_os);
+        limits::replace_exit(self.lua.0);
+        vfs::install(self)
     }
 
     /// Opens package library.
@@ -615,7 +1042,8 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_package
     #[inline]
     pub fn open_package(&mut self) {
-        unsafe { ffi::luaopen_package(self.lua.0) }
+        self.open_library(b"package\0", ffi::luaopen_package);
+        vfs::install(self)
     }
 
     /// Opens string library.
@@ -623,7 +1051,7 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_string
     #[inline]
     pub fn open_string(&mut self) {
-        unsafe { ffi::luaopen_string(self.lua.0) }
+        self.open_library(b"string\0", ffi::luaopen_string)
     }
 
     /// Opens table library.
@@ -631,7 +1059,252 @@ impl<'lua> Lua<'lua> {
     /// https://www.lua.org/manual/5.2/manual.html#pdf-luaopen_table
     #[inline]
     pub fn open_table(&mut self) {
-        unsafe { ffi::luaopen_table(self.lua.0) }
+        self.open_library(b"table\0", ffi::luaopen_table)
+    }
+
+    /// Opens the standard libraries, minus the functions that shouldn't be available to untrusted
+    /// code.
+    ///
+    /// `openlibs` gives access to functions such as `os.execute`, `io.popen` or `debug.sethook`,
+    /// which let a script do anything it wants. This method only opens the parts of the standard
+    /// library allowed by `profile`. See [`SafetyProfile`](enum.SafetyProfile.html) for the
+    /// details. The libraries that the profile doesn't allow are removed even if they have been
+    /// opened before.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, SafetyProfile};
+    /// let mut lua = Lua::new();
+    /// lua.open_safe_libs(SafetyProfile::Computation);
+    ///
+    /// let val: String = lua.execute("return string.rep('a', 3)").unwrap();
+    /// assert_eq!(val, "aaa");
+    /// assert!(lua.execute::<()>("os.execute('ls')").is_err());
+    /// ```
+    #[inline]
+    pub fn open_safe_libs(&mut self, profile: SafetyProfile) {
+        safe_libs::open(self, profile)
+    }
+
+    // Opens a library and stores it in the global variable `name`, like `luaL_requiref` does.
+    // `name` must be nul-terminated.
+    fn open_library(&mut self,
+                    name: &[u8],
+                    open: unsafe extern "C" fn(*mut ffi::lua_State) -> libc::c_int) {
+        unsafe {
+            let name = name.as_ptr() as *const libc::c_char;
+            open(self.lua.0);
+
+            // Registers the library so that `require` finds it.
+            ffi::lua_getfield(self.lua.0, ffi::LUA_REGISTRYINDEX, b"_LOADED\0".as_ptr() as *const _);
+            if ffi::lua_istable(self.lua.0, -1) {
+                ffi::lua_pushvalue(self.lua.0, -2);
+                ffi::lua_setfield(self.lua.0, -2, name);
+            }
+            ffi::lua_pop(self.lua.0, 1);
+
+            ffi::lua_setglobal(self.lua.0, name);
+        }
+    }
+
+    /// Limits the number of Lua instructions that can be run by a single call to `execute`,
+    /// `execute_from_reader` or `LuaFunction::call_with_args`. Pass `None` to remove the limit.
+    ///
+    /// If the code exceeds the limit, it is aborted and the call returns
+    /// `LuaError::InstructionLimit`. The context can still be used afterwards, and each new call
+    /// starts with a fresh budget. Calls that happen from within a Rust callback share the budget
+    /// of the call that invoked the callback.
+    ///
+    /// The count is checked every thousand instructions, so the code may slightly overshoot a
+    /// limit that isn't a multiple of a thousand. Time spent inside Rust callbacks isn't counted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaError};
+    /// let mut lua = Lua::new();
+    /// lua.set_instruction_limit(Some(100000));
+    ///
+    /// match lua.execute::<()>("while true do end") {
+    ///     Err(LuaError::InstructionLimit) => (),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    #[inline]
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        limits::set_instruction_limit(self.lua.0, limit)
+    }
+
+    /// Limits the duration of a single call to `execute`, `execute_from_reader` or
+    /// `LuaFunction::call_with_args`. Pass `None` to remove the limit.
+    ///
+    /// If the code is still running when the timeout expires, it is aborted and the call returns
+    /// `LuaError::Timeout`. The context can still be used afterwards. Just like with
+    /// `set_instruction_limit`, the clock is checked every thousand instructions and code that
+    /// is blocked inside a Rust callback can't be interrupted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use hlua::{Lua, LuaError};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.set_timeout(Some(Duration::from_millis(20)));
+    ///
+    /// match lua.execute::<()>("while true do end") {
+    ///     Err(LuaError::Timeout) => (),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    #[inline]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        limits::set_timeout(self.lua.0, timeout)
+    }
+
+    /// Sets whether a panic in a Rust callback should be resumed once it reaches the Rust code
+    /// that ran the Lua code.
+    ///
+    /// A panic inside a function or closure called by Lua never unwinds through Lua. Instead it
+    /// is turned into a Lua error whose message contains the panic message, and that Lua code can
+    /// catch with `pcall`. By default, if this error reaches `execute` or `call_with_args`, it is
+    /// returned as a `LuaError::ExecutionError`. If `resume` is true, the panic continues instead
+    /// from there with `std::panic::resume_unwind`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaError};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.set("explode", hlua::function0(|| -> i32 { panic!("boom") }));
+    ///
+    /// match lua.execute::<()>("explode()") {
+    ///     Err(LuaError::ExecutionError(err)) => {
+    ///         assert_eq!(err.message(), "panic in Rust callback: boom");
+    ///     }
+    ///     _ => unreachable!(),
+    /// }
+    ///
+    /// lua.set_resume_panics(true);
+    /// let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    ///     let _ = lua.execute::<()>("explode()");
+    /// }));
+    /// assert!(result.is_err());
+    /// ```
+    #[inline]
+    pub fn set_resume_panics(&mut self, resume: bool) {
+        rust_errors::set_resume(self.lua.0, resume)
+    }
+
+    /// Sets how Lua tables are converted when reading an `AnyLuaValue`.
+    ///
+    /// By default, a table that appears several times in a value is converted every time, except
+    /// if it contains itself, and a value can contain up to 100 nested tables.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{AnyLuaValue, Lua, TableConversion};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.execute::<()>("t = {{{}}}").unwrap();
+    /// assert!(lua.get::<AnyLuaValue, _>("t").is_some());
+    ///
+    /// lua.set_table_conversion(TableConversion { max_depth: 2, ..Default::default() });
+    /// assert!(lua.get::<AnyLuaValue, _>("t").is_none());
+    /// ```
+    #[inline]
+    pub fn set_table_conversion(&mut self, conversion: TableConversion) {
+        any::set_table_conversion(self.lua.0, conversion)
+    }
+
+    /// Redirects the standard output of the Lua code to `output`.
+    ///
+    /// `print`, `io.write` and `io.stdout` are replaced with versions that write to `output`
+    /// instead of the standard output of the process. The libraries that are opened afterwards
+    /// are redirected as well. Calling this method again replaces the previous writer.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::fs::File;
+    /// use hlua::Lua;
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    /// lua.set_output(Box::new(File::create("script.log").unwrap()));
+    /// lua.execute::<()>("print('hello') io.write('world')").unwrap();
+    /// ```
+    #[inline]
+    pub fn set_output(&mut self, output: Box<dyn io::Write>) {
+        output::set(self, output::Stream::Stdout, output)
+    }
+
+    /// Redirects the standard error of the Lua code to `output`.
+    ///
+    /// This does the same thing as [the `set_output` method](#method.set_output) for
+    /// `io.stderr`.
+    #[inline]
+    pub fn set_error_output(&mut self, output: Box<dyn io::Write>) {
+        output::set(self, output::Stream::Stderr, output)
+    }
+
+    /// Redirects the standard output of the Lua code to a new `OutputCapture`, and returns it.
+    ///
+    /// This is a shortcut for calling `set_output` with a clone of a new `OutputCapture`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::Lua;
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    ///
+    /// let output = lua.capture_output();
+    /// lua.execute::<()>("print('hello') io.write('world')").unwrap();
+    /// assert_eq!(output.contents(), "hello\nworld");
+    /// ```
+    #[inline]
+    pub fn capture_output(&mut self) -> OutputCapture {
+        let capture = OutputCapture::new();
+        self.set_output(Box::new(capture.clone()));
+        capture
+    }
+
+    /// Makes the Lua code access `vfs` instead of the real filesystem.
+    ///
+    /// `io.open`, `io.input`, `io.output`, `io.lines`, `loadfile`, `dofile`, `os.remove`,
+    /// `os.rename` and the searcher of `require` for modules written in Lua are replaced with
+    /// versions that use `vfs`. `io.popen`, `io.tmpfile`, `os.tmpname` and `package.loadlib` are
+    /// removed, and `require` doesn't look for native modules anymore. `loadfile` and `dofile`
+    /// refuse precompiled chunks. The libraries that are opened afterwards use `vfs` as well.
+    /// Calling this method again replaces the previous filesystem.
+    ///
+    /// To prevent the Lua code from modifying the files, wrap the filesystem in a `ReadOnlyVfs`.
+    /// This is done automatically after `open_safe_libs` has been called with
+    /// `SafetyProfile::ReadOnlyFilesystem`, whether it is called before or after this method.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, MemoryVfs};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.openlibs();
+    ///
+    /// let vfs = MemoryVfs::new();
+    /// vfs.insert("data.txt", "hello");
+    /// lua.set_vfs(Box::new(vfs));
+    ///
+    /// let val: String = lua.execute("return io.open('data.txt'):read('*a')").unwrap();
+    /// assert_eq!(val, "hello");
+    /// ```
+    #[inline]
+    pub fn set_vfs(&mut self, vfs: Box<dyn Vfs>) {
+        vfs::set(self, vfs)
     }
 
     /// Executes some Lua code in the context.
@@ -707,6 +1380,40 @@ impl<'lua> Lua<'lua> {
         f.call()
     }
 
+    /// Executes some Lua code in the context, with `env` as its environment.
+    ///
+    /// This does the same thing as [the `execute` method](#method.execute), except that the global
+    /// variables read or written by the code are looked up in `env` instead of in the table of
+    /// global variables. Use [the `new_env` method](#method.new_env) to create an environment.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `env` has been created from another Lua context.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::Lua;
+    /// let mut lua = Lua::new();
+    ///
+    /// let first = lua.new_env();
+    /// let second = lua.new_env();
+    /// lua.execute_in_env::<()>("function name() return 'first' end", &first).unwrap();
+    /// lua.execute_in_env::<()>("function name() return 'second' end", &second).unwrap();
+    ///
+    /// let name: String = lua.execute_in_env("return name()", &first).unwrap();
+    /// assert_eq!(name, "first");
+    /// let name: String = lua.execute_in_env("return name()", &second).unwrap();
+    /// assert_eq!(name, "second");
+    /// ```
+    #[inline]
+    pub fn execute_in_env<'a, T>(&'a mut self, code: &str, env: &LuaRef) -> Result<T, LuaError>
+        where T: for<'g> LuaRead<PushGuard<&'g mut PushGuard<&'a mut Lua<'lua>>>>
+    {
+        let mut f = lua_functions::LuaFunction::load_with_env(self, code, env)?;
+        f.call()
+    }
+
     /// Reads the value of a global variable.
     ///
     /// Returns `None` if the variable doesn't exist or has the wrong type.
@@ -940,13 +1647,95 @@ impl<'lua> Lua<'lua> {
         };
         LuaRead::lua_read(guard).ok().unwrap()
     }
+
+    /// Creates a new environment to pass to `execute_in_env` or `LuaFunction::load_with_env`.
+    ///
+    /// The environment is an empty table whose metatable redirects reads to the table of global
+    /// variables. Code that runs in this environment can read the global variables, but the
+    /// global variables that it creates or modifies are stored in the environment.
+    ///
+    /// > **Note**: The code can still modify the content of the tables stored in the global
+    /// > variables, for example `string.format = nil`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::Lua;
+    /// let mut lua = Lua::new();
+    /// lua.set("a", 5);
+    ///
+    /// let env = lua.new_env();
+    /// let b: i32 = lua.execute_in_env("a = a * 2; return a", &env).unwrap();
+    /// assert_eq!(b, 10);
+    /// assert_eq!(lua.get::<i32, _>("a"), Some(5));
+    /// ```
+    #[inline]
+    pub fn new_env(&mut self) -> LuaRef {
+        let globals: LuaRef = unsafe {
+            ffi::lua_pushglobaltable(self.lua.0);
+            let raw_lua = self.as_lua();
+            let guard = PushGuard {
+                lua: &mut *self,
+                size: 1,
+                raw_lua,
+            };
+            LuaRead::lua_read(guard).ok().unwrap()
+        };
+
+        self.new_env_with_base(Some(&globals))
+    }
+
+    /// Creates a new environment to pass to `execute_in_env` or `LuaFunction::load_with_env`.
+    ///
+    /// If `base` is `Some`, reading a variable that doesn't exist in the environment reads it
+    /// from `base` instead, similar to what `new_env` does with the global variables. If `base`
+    /// is `None`, the environment is an empty table and the code doesn't have access to anything.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `base` has been created from another Lua context.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaRef};
+    /// let mut lua = Lua::new();
+    ///
+    /// let base: LuaRef = lua.execute("return { answer = 42 }").unwrap();
+    /// let env = lua.new_env_with_base(Some(&base));
+    /// let answer: i32 = lua.execute_in_env("return answer", &env).unwrap();
+    /// assert_eq!(answer, 42);
+    ///
+    /// let empty = lua.new_env_with_base(None);
+    /// assert!(lua.execute_in_env::<()>("print('hello')", &empty).is_err());
+    /// ```
+    pub fn new_env_with_base(&mut self, base: Option<&LuaRef>) -> LuaRef {
+        unsafe { ffi::lua_newtable(self.lua.0) };
+
+        if let Some(base) = base {
+            unsafe { ffi::lua_createtable(self.lua.0, 0, 1) };
+            base.push_no_err(&mut *self).forget_internal();
+            unsafe {
+                ffi::lua_setfield(self.lua.0, -2, b"__index\0".as_ptr() as *const _);
+                ffi::lua_setmetatable(self.lua.0, -2);
+            }
+        }
+
+        let raw_lua = self.as_lua();
+        let guard = PushGuard {
+            lua: self,
+            size: 1,
+            raw_lua,
+        };
+        LuaRead::lua_read(guard).ok().unwrap()
+    }
 }
 
 impl<'lua> Drop for Lua<'lua> {
     #[inline]
     fn drop(&mut self) {
         if self.must_be_closed {
-            unsafe { ffi::lua_close(self.lua.0) }
+            unsafe { memory::close_state(self.lua.0) }
         }
     }
 }
@@ -994,4 +1783,16 @@ mod tests {
         lua.open_string();
         lua.open_table();
     }
+
+    #[test]
+    fn opened_libraries_are_globals() {
+        let mut lua = Lua::new();
+        lua.open_coroutine();
+        lua.open_string();
+
+        let len: i32 = lua.execute("return string.len('hello')").unwrap();
+        assert_eq!(len, 5);
+        let status: String = lua.execute("return coroutine.status(coroutine.create(string.len))").unwrap();
+        assert_eq!(status, "suspended");
+    }
 }
@@ -0,0 +1,518 @@
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use ffi;
+use libc;
+
+use raw;
+use AsLua;
+use Lua;
+use LuaError;
+use LuaFunction;
+use LuaRead;
+use LuaRef;
+use PushGuard;
+
+/// Number of Lua instructions between two checks of the limits.
+const CHECK_INTERVAL: u64 = 1000;
+
+// The `Limits` of a state are stored as a userdata in the registry, at the address of this static.
+static REGISTRY_KEY: u8 = 0;
+
+/// Why the running code has been aborted.
+#[derive(Debug, Copy, Clone)]
+enum Exceeded {
+    Instructions,
+    Time,
+    // The code has called `os.exit` with this code.
+    Exit(i32),
+}
+
+/// Execution budget of a Lua context, shared by all the calls made on it.
+struct Limits {
+    instruction_limit: Option<u64>,
+    timeout: Option<Duration>,
+
+    // The fields below concern the call that is currently running. Nested calls (for example a
+    // Rust callback calling back into Lua) share the budget of the outermost call.
+    depth: u32,
+    // Thread that started the outermost call.
+    thread: *mut ffi::lua_State,
+    instructions: u64,
+    deadline: Option<Instant>,
+    exceeded: Option<Exceeded>,
+}
+
+// Returns the `Limits` of the state, or null if no limit has ever been set.
+unsafe fn get(lua: *mut ffi::lua_State) -> *mut Limits {
+    raw::get(lua, &REGISTRY_KEY)
+}
+
+unsafe fn get_or_create(lua: *mut ffi::lua_State) -> *mut Limits {
+    raw::get_or_insert_with(lua, &REGISTRY_KEY, || Limits {
+        instruction_limit: None,
+        timeout: None,
+        depth: 0,
+        thread: ptr::null_mut(),
+        instructions: 0,
+        deadline: None,
+        exceeded: None,
+    })
+}
+
+// Installs or removes the hook of the thread depending on whether a limit is active.
+//
+// Every thread has its own hook, which is copied from the thread that creates it. The hook of a
+// thread created before the limits changed, or touched by `abort`, is outdated, so this is called
+// again every time a thread starts running.
+unsafe fn update_hook(lua: *mut ffi::lua_State, limits: &Limits) {
+    if limits.exceeded.is_some() {
+        ffi::lua_sethook(lua, hook, ffi::LUA_MASKCOUNT, 1);
+        return;
+    }
+
+    if limits.instruction_limit.is_none() && limits.timeout.is_none() {
+        ffi::lua_sethook(lua, hook, 0, 0);
+        return;
+    }
+
+    let count = limits.instruction_limit.unwrap_or(CHECK_INTERVAL).clamp(1, CHECK_INTERVAL);
+    ffi::lua_sethook(lua, hook, ffi::LUA_MASKCOUNT, count as libc::c_int);
+}
+
+/// Sets the maximum number of instructions of a call. `None` removes the limit.
+pub fn set_instruction_limit(lua: *mut ffi::lua_State, limit: Option<u64>) {
+    unsafe {
+        let limits = &mut *get_or_create(lua);
+        limits.instruction_limit = limit;
+        update_hook(lua, limits);
+    }
+}
+
+/// Sets the maximum duration of a call. `None` removes the limit.
+pub fn set_timeout(lua: *mut ffi::lua_State, timeout: Option<Duration>) {
+    unsafe {
+        let limits = &mut *get_or_create(lua);
+        limits.timeout = timeout;
+        update_hook(lua, limits);
+    }
+}
+
+/// Must be called before running Lua code. Starts a new budget if no other call is running.
+pub fn enter(lua: *mut ffi::lua_State) {
+    unsafe {
+        let limits = get(lua);
+        if limits.is_null() {
+            return;
+        }
+
+        let limits = &mut *limits;
+        if limits.depth == 0 {
+            limits.thread = lua;
+            limits.instructions = 0;
+            limits.deadline = limits.timeout.map(|t| Instant::now() + t);
+            limits.exceeded = None;
+        }
+        limits.depth += 1;
+        update_hook(lua, limits);
+    }
+}
+
+/// Must be called after running Lua code. If the code has been aborted because it exceeded its
+/// budget, returns the corresponding error.
+pub fn leave(lua: *mut ffi::lua_State) -> Option<LuaError> {
+    unsafe {
+        let limits = get(lua);
+        if limits.is_null() {
+            return None;
+        }
+
+        let limits = &mut *limits;
+        limits.depth -= 1;
+        match limits.exceeded {
+            Some(Exceeded::Instructions) => Some(LuaError::InstructionLimit),
+            Some(Exceeded::Time) => Some(LuaError::Timeout),
+            Some(Exceeded::Exit(code)) => Some(LuaError::Exit(code)),
+            None => None,
+        }
+    }
+}
+
+// Called by Lua every `CHECK_INTERVAL` instructions, or less if the instruction limit is lower.
+extern "C" fn hook(lua: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    unsafe {
+        let limits = get(lua);
+        if limits.is_null() {
+            return;
+        }
+
+        let limits = &mut *limits;
+        if limits.depth == 0 {
+            return;
+        }
+
+        let exceeded = if let Some(exceeded) = limits.exceeded {
+            exceeded
+        } else {
+            // Coroutines have their own hook count, so we ask Lua instead of recomputing it.
+            limits.instructions += ffi::lua_gethookcount(lua) as u64;
+
+            if limits.instruction_limit.is_some_and(|l| limits.instructions >= l) {
+                Exceeded::Instructions
+            } else if limits.deadline.is_some_and(|d| Instant::now() >= d) {
+                Exceeded::Time
+            } else {
+                return;
+            }
+        };
+
+        abort(lua, limits, exceeded);
+    }
+}
+
+// Raises an error that aborts the running call.
+unsafe fn abort(lua: *mut ffi::lua_State, limits: &mut Limits, exceeded: Exceeded) -> ! {
+    // Scripts can catch the error with `pcall`. Since the error would most likely be raised
+    // again inside of the `pcall`, from now on we raise it at every single instruction so
+    // that it reaches the first instruction that isn't protected. The normal count is restored
+    // by `update_hook` when the threads run again.
+    limits.exceeded = Some(exceeded);
+    update_hook(lua, limits);
+    if limits.thread != lua {
+        update_hook(limits.thread, limits);
+    }
+
+    let msg: &[u8] = match exceeded {
+        Exceeded::Instructions => b"instruction limit exceeded",
+        Exceeded::Time => b"execution timed out",
+        Exceeded::Exit(_) => b"script exited",
+    };
+    ffi::lua_pushlstring(lua, msg.as_ptr() as *const _, msg.len() as libc::size_t);
+    ffi::lua_error(lua);
+    unreachable!()
+}
+
+/// Replaces `os.exit`, if it exists, with a function that aborts the running call instead of
+/// terminating the process. The call then returns `LuaError::Exit`.
+pub fn replace_exit(lua: *mut ffi::lua_State) {
+    unsafe {
+        get_or_create(lua);
+
+        ffi::lua_getglobal(lua, b"os\0".as_ptr() as *const _);
+        if ffi::lua_istable(lua, -1) {
+            ffi::lua_pushcfunction(lua, exit);
+            ffi::lua_setfield(lua, -2, b"exit\0".as_ptr() as *const _);
+        }
+        ffi::lua_pop(lua, 1);
+    }
+}
+
+// Replaces `coroutine.resume` and `coroutine.wrap` with versions that update the hook of the
+// coroutine before resuming it. Called with the function that does it.
+const REPLACE_RESUME: &str = r#"
+local prepare_resume = ...
+local create, resume, error = coroutine.create, coroutine.resume, error
+
+function coroutine.resume(co, ...)
+    prepare_resume(co)
+    return resume(co, ...)
+end
+
+-- Like the original, adds the position of the caller to the error messages.
+local function wrap_results(ok, ...)
+    if ok then return ... end
+    error((...), 2)
+end
+
+function coroutine.wrap(f)
+    local co = create(f)
+    return function(...)
+        prepare_resume(co)
+        return wrap_results(resume(co, ...))
+    end
+end
+"#;
+
+/// Replaces `coroutine.resume` and `coroutine.wrap`, if they exist, so that the limits apply to
+/// the coroutines created before the limits were set.
+pub fn replace_resume(lua: &mut Lua) {
+    let raw_lua = lua.as_lua();
+
+    unsafe {
+        ffi::lua_getglobal(raw_lua.0, b"coroutine\0".as_ptr() as *const _);
+        let exists = ffi::lua_istable(raw_lua.0, -1);
+        ffi::lua_pop(raw_lua.0, 1);
+        if !exists {
+            return;
+        }
+
+        ffi::lua_pushcfunction(raw_lua.0, prepare_resume);
+    }
+
+    let guard = PushGuard {
+        lua: &mut *lua,
+        size: 1,
+        raw_lua,
+    };
+    let prepare_resume: LuaRef = LuaRead::lua_read(guard).ok().unwrap();
+
+    let mut replace = LuaFunction::load(lua, REPLACE_RESUME).unwrap();
+    replace.call_with_args::<(), _, _>(&prepare_resume)
+           .expect("failed to replace the coroutine library");
+}
+
+// Called with a coroutine that is about to be resumed by Lua code.
+extern "C" fn prepare_resume(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let thread = ffi::lua_tothread(lua, 1);
+        let limits = get(lua);
+        if !thread.is_null() && !limits.is_null() {
+            update_hook(thread, &*limits);
+        }
+        0
+    }
+}
+
+// Replacement for `os.exit`. The second parameter, which asks to close the state, is ignored.
+extern "C" fn exit(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let code = match ffi::lua_type(lua, 1) {
+            ffi::LUA_TNONE | ffi::LUA_TNIL => 0,
+            ffi::LUA_TBOOLEAN => if ffi::lua_toboolean(lua, 1) != 0 { 0 } else { 1 },
+            _ => {
+                let mut isnum = 0;
+                let code = ffi::lua_tointegerx(lua, 1, &mut isnum);
+                if isnum == 0 {
+                    let msg = b"bad argument #1 to 'exit' (number expected)";
+                    ffi::lua_pushlstring(lua, msg.as_ptr() as *const _, msg.len() as libc::size_t);
+                    ffi::lua_error(lua);
+                }
+                code as i32
+            }
+        };
+
+        let limits = get(lua);
+        if limits.is_null() || (*limits).depth == 0 {
+            // Not called from `execute` or `call_with_args`, so there's nobody to return the
+            // error to.
+            let msg = b"os.exit called outside of a call";
+            ffi::lua_pushlstring(lua, msg.as_ptr() as *const _, msg.len() as libc::size_t);
+            ffi::lua_error(lua);
+        }
+
+        abort(lua, &mut *limits, Exceeded::Exit(code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Lua;
+    use LuaCoroutine;
+    use LuaError;
+    use LuaFunction;
+    use LuaFunctionCallError;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn instruction_limit_aborts_infinite_loop() {
+        let mut lua = Lua::new();
+        lua.set_instruction_limit(Some(10000));
+
+        match lua.execute::<()>("while true do end") {
+            Err(LuaError::InstructionLimit) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn timeout_aborts_infinite_loop() {
+        let mut lua = Lua::new();
+        lua.set_timeout(Some(Duration::from_millis(50)));
+
+        let before = Instant::now();
+        match lua.execute::<()>("while true do end") {
+            Err(LuaError::Timeout) => (),
+            other => panic!("{:?}", other),
+        }
+        assert!(before.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn pcall_cant_catch_limit() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_instruction_limit(Some(10000));
+
+        let r = lua.execute::<()>(r#"
+            while true do
+                pcall(function() while true do end end)
+            end
+        "#);
+        match r {
+            Err(LuaError::InstructionLimit) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn state_usable_after_limit() {
+        let mut lua = Lua::new();
+        lua.set_instruction_limit(Some(10000));
+
+        lua.execute::<()>("a = 5").unwrap();
+        assert!(lua.execute::<()>("while true do end").is_err());
+
+        // The budget is restored for every call.
+        let a: i32 = lua.execute("local x = 0; for i = 1, 100 do x = x + a end; return x").unwrap();
+        assert_eq!(a, 500);
+    }
+
+    #[test]
+    fn limit_applies_to_function_calls() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("function spin() while true do end end").unwrap();
+        lua.set_instruction_limit(Some(10000));
+
+        let mut f: LuaFunction<_> = lua.get("spin").unwrap();
+        match f.call::<()>() {
+            Err(LuaError::InstructionLimit) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn limits_apply_to_existing_coroutines() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>(r#"
+            local function spin() while true do end end
+            co = coroutine.create(spin)
+            wrapped = coroutine.wrap(spin)
+        "#).unwrap();
+        lua.set_instruction_limit(Some(10000));
+
+        match lua.execute::<()>("coroutine.resume(co)") {
+            Err(LuaError::InstructionLimit) => (),
+            other => panic!("{:?}", other),
+        }
+        match lua.execute::<()>("wrapped()") {
+            Err(LuaError::InstructionLimit) => (),
+            other => panic!("{:?}", other),
+        }
+
+        lua.set_instruction_limit(None);
+        lua.execute::<()>("co = coroutine.create(function() while true do end end)").unwrap();
+        lua.set_timeout(Some(Duration::from_millis(50)));
+        match lua.execute::<()>("coroutine.resume(co)") {
+            Err(LuaError::Timeout) => (),
+            other => panic!("{:?}", other),
+        }
+
+        lua.set_timeout(None);
+        lua.execute::<()>("co = coroutine.create(function() while true do end end)").unwrap();
+        lua.set_instruction_limit(Some(10000));
+        let mut co: LuaCoroutine<_> = lua.get("co").unwrap();
+        match co.resume::<(), _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::InstructionLimit)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn coroutines_usable_after_limit() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>(r#"
+            co = coroutine.wrap(function()
+                while true do
+                    local x = 0
+                    for i = 1, 1000 do x = x + i end
+                    coroutine.yield(x)
+                end
+            end)
+        "#).unwrap();
+        lua.set_instruction_limit(Some(10000));
+
+        // The coroutine is aborted while the hook runs at every instruction, then resumed by a
+        // call with a fresh budget.
+        assert!(lua.execute::<()>("co() while true do end").is_err());
+        let val: i32 = lua.execute("return co()").unwrap();
+        assert_eq!(val, 500500);
+
+        // Like with the original `coroutine.wrap`, the position of the caller is added to the
+        // error message.
+        let err = lua.execute::<()>("coroutine.wrap(function() error('oops') end)()");
+        match err {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message().matches("]:1: ").count(), 2, "{}", err.message());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn removing_limit() {
+        let mut lua = Lua::new();
+        lua.set_instruction_limit(Some(100));
+        assert!(lua.execute::<()>("for i = 1, 1000 do end").is_err());
+
+        lua.set_instruction_limit(None);
+        lua.execute::<()>("for i = 1, 1000 do end").unwrap();
+    }
+
+    #[test]
+    fn exit_returns_error() {
+        let mut lua = Lua::new();
+        lua.open_os();
+
+        match lua.execute::<()>("os.exit(3)") {
+            Err(LuaError::Exit(3)) => (),
+            other => panic!("{:?}", other),
+        }
+        match lua.execute::<()>("os.exit()") {
+            Err(LuaError::Exit(0)) => (),
+            other => panic!("{:?}", other),
+        }
+        match lua.execute::<()>("os.exit(false)") {
+            Err(LuaError::Exit(1)) => (),
+            other => panic!("{:?}", other),
+        }
+
+        // The context is still usable.
+        let val: i32 = lua.execute("return 5").unwrap();
+        assert_eq!(val, 5);
+    }
+
+    #[test]
+    fn pcall_cant_catch_exit() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+
+        let r = lua.execute::<()>(r#"
+            local co = coroutine.create(function() os.exit(2) end)
+            pcall(coroutine.resume, co)
+            pcall(os.exit, 4)
+            reached = true
+        "#);
+        match r {
+            Err(LuaError::Exit(2)) => (),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(lua.get::<bool, _>("reached"), None);
+    }
+
+    #[test]
+    fn exit_from_function_call() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_instruction_limit(Some(100000));
+        lua.execute::<()>("function quit(code) os.exit(code) end").unwrap();
+
+        let mut f: LuaFunction<_> = lua.get("quit").unwrap();
+        match f.call_with_args::<(), _, _>(7) {
+            Err(LuaFunctionCallError::LuaError(LuaError::Exit(7))) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
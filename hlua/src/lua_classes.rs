@@ -0,0 +1,358 @@
+use std::any::Any;
+use std::marker::PhantomData;
+
+use ffi;
+use libc;
+
+use AsLua;
+use AsMutLua;
+use LuaRead;
+use LuaTable;
+use Push;
+use PushGuard;
+use PushOne;
+use Void;
+
+use userdata::push_userdata;
+
+/// Rust type that can be manipulated from Lua as an object with properties and methods.
+///
+/// This trait and the `LuaMethods` trait are usually implemented with the `#[lua_class]` and
+/// `#[lua_methods]` attributes of the `hlua-derive` crate. You can then implement `Push` with
+/// `push_class` and `LuaRead` with the `implement_lua_read!` macro, which `#[lua_class]` also
+/// does for you.
+///
+/// The properties of the object are accessed with `obj.name` and `obj.name = value`, and the
+/// methods are called with `obj:name(...)`.
+pub trait LuaClass: Any + Send + Sized {
+    /// Name of the class, used when converting an object to a string or in error messages.
+    fn class_name() -> &'static str;
+
+    /// Fills the table of the properties that can be read. The keys are the names of the
+    /// properties, and the values are functions that receive the object and return the value of
+    /// the property.
+    #[inline]
+    fn lua_getters<'lua, L>(_getters: &mut LuaTable<L>) where L: AsMutLua<'lua> {}
+
+    /// Fills the table of the properties that can be written. The keys are the names of the
+    /// properties, and the values are functions that receive the object and the new value.
+    #[inline]
+    fn lua_setters<'lua, L>(_setters: &mut LuaTable<L>) where L: AsMutLua<'lua> {}
+}
+
+/// Methods of a `LuaClass`.
+///
+/// If a class doesn't have any method, you can write `impl LuaMethods for MyClass {}`.
+pub trait LuaMethods: LuaClass {
+    /// Fills the table of the methods of the objects. The values are functions that receive the
+    /// object as first parameter.
+    #[inline]
+    fn lua_methods<'lua, L>(_methods: &mut LuaTable<L>) where L: AsMutLua<'lua> {}
+
+    /// Sets entries of the metatable of the objects, like `__tostring`. The `__index`,
+    /// `__newindex` and `__gc` entries are reserved.
+    ///
+    /// If `__tostring` isn't set, converting an object to a string gives the name of the class
+    /// and the address of the object.
+    #[inline]
+    fn lua_metamethods<'lua, L>(_metatable: &mut LuaTable<L>) where L: AsMutLua<'lua> {}
+
+    /// Fills the table returned by `LuaClassTable`, which usually contains the constructors of
+    /// the class.
+    #[inline]
+    fn lua_static_methods<'lua, L>(_class: &mut LuaTable<L>) where L: AsMutLua<'lua> {}
+}
+
+/// Pushes an object of a `LuaClass` as a user data, with a metatable that exposes its
+/// properties and its methods.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use] extern crate hlua;
+/// use hlua::{AsMutLua, LuaClass, LuaMethods, LuaTable};
+///
+/// struct Counter {
+///     value: i32,
+/// }
+///
+/// impl LuaClass for Counter {
+///     fn class_name() -> &'static str { "Counter" }
+///
+///     fn lua_getters<'lua, L>(getters: &mut LuaTable<L>) where L: AsMutLua<'lua> {
+///         getters.set("value", hlua::function1(|c: &Counter| c.value));
+///     }
+/// }
+///
+/// impl LuaMethods for Counter {
+///     fn lua_methods<'lua, L>(methods: &mut LuaTable<L>) where L: AsMutLua<'lua> {
+///         methods.set("increment", hlua::function1(|c: &mut Counter| c.value += 1));
+///     }
+/// }
+///
+/// impl<'lua, L> hlua::Push<L> for Counter where L: AsMutLua<'lua> {
+///     type Err = hlua::Void;
+///     fn push_to_lua(self, lua: L) -> Result<hlua::PushGuard<L>, (hlua::Void, L)> {
+///         Ok(hlua::push_class(self, lua))
+///     }
+/// }
+///
+/// impl<'lua, L> hlua::PushOne<L> for Counter where L: AsMutLua<'lua> {}
+///
+/// implement_lua_read!(Counter);
+///
+/// # fn main() {
+/// let mut lua = hlua::Lua::new();
+/// lua.set("c", Counter { value: 5 });
+///
+/// let value: i32 = lua.execute("c:increment(); return c.value").unwrap();
+/// assert_eq!(value, 6);
+/// # }
+/// ```
+#[inline]
+pub fn push_class<'lua, L, T>(object: T, lua: L) -> PushGuard<L>
+    where L: AsMutLua<'lua>,
+          T: LuaMethods
+{
+    push_userdata(object, lua, |mut metatable| {
+        T::lua_metamethods(&mut metatable);
+
+        unsafe {
+            let raw_lua = metatable.as_mut_lua().0;
+
+            push_table(&mut metatable, |methods| T::lua_methods(methods));
+            push_table(&mut metatable, |getters| T::lua_getters(getters));
+            ffi::lua_pushcclosure(raw_lua, index_dispatch, 2);
+            ffi::lua_setfield(raw_lua, -2, b"__index\0".as_ptr() as *const _);
+
+            push_table(&mut metatable, |setters| T::lua_setters(setters));
+            ffi::lua_pushcclosure(raw_lua, newindex_dispatch::<T>, 1);
+            ffi::lua_setfield(raw_lua, -2, b"__newindex\0".as_ptr() as *const _);
+
+            ffi::lua_getfield(raw_lua, -1, b"__tostring\0".as_ptr() as *const _);
+            let has_tostring = !ffi::lua_isnil(raw_lua, -1);
+            ffi::lua_pop(raw_lua, 1);
+            if !has_tostring {
+                ffi::lua_pushcfunction(raw_lua, default_tostring::<T>);
+                ffi::lua_setfield(raw_lua, -2, b"__tostring\0".as_ptr() as *const _);
+            }
+        }
+    })
+}
+
+/// Table that contains the static methods of a `LuaClass`, as defined by
+/// `LuaMethods::lua_static_methods`.
+///
+/// Pushing a `LuaClassTable` creates a new table. This is usually done to expose the
+/// constructors of the class to Lua.
+///
+/// ```ignore
+/// lua.set("Sound", hlua::LuaClassTable::<Sound>::new());
+/// lua.execute::<()>("local s = Sound.new(); s:play()").unwrap();
+/// ```
+#[derive(Debug)]
+pub struct LuaClassTable<T> {
+    marker: PhantomData<T>,
+}
+
+impl<T> LuaClassTable<T>
+    where T: LuaMethods
+{
+    /// Builds a new `LuaClassTable`.
+    #[inline]
+    pub fn new() -> LuaClassTable<T> {
+        LuaClassTable { marker: PhantomData }
+    }
+}
+
+impl<T> Default for LuaClassTable<T>
+    where T: LuaMethods
+{
+    #[inline]
+    fn default() -> LuaClassTable<T> {
+        LuaClassTable::new()
+    }
+}
+
+impl<'lua, L, T> Push<L> for LuaClassTable<T>
+    where L: AsMutLua<'lua>,
+          T: LuaMethods
+{
+    type Err = Void;      // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            push_table(&mut lua, |class| T::lua_static_methods(class));
+            Ok(PushGuard::new(lua, 1))
+        }
+    }
+}
+
+impl<'lua, L, T> PushOne<L> for LuaClassTable<T>
+    where L: AsMutLua<'lua>,
+          T: LuaMethods
+{
+}
+
+// Pushes a new table on the stack and lets `fill` modify it. The table stays on the stack.
+unsafe fn push_table<'lua, L, F>(lua: &mut L, fill: F)
+    where L: AsMutLua<'lua>,
+          F: FnOnce(&mut LuaTable<&mut PushGuard<&mut L>>)
+{
+    ffi::lua_newtable(lua.as_mut_lua().0);
+
+    let raw_lua = lua.as_lua();
+    let mut guard = PushGuard {
+        lua,
+        size: 1,
+        raw_lua,
+    };
+    {
+        let mut table = LuaRead::lua_read(&mut guard).ok().unwrap();
+        fill(&mut table);
+    }
+    guard.forget_internal();
+}
+
+// Value of `__index`. The upvalues are the table of the methods and the table of the getters.
+extern "C" fn index_dispatch(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        ffi::lua_pushvalue(lua, 2);
+        ffi::lua_rawget(lua, ffi::lua_upvalueindex(1));
+        if !ffi::lua_isnil(lua, -1) {
+            return 1;
+        }
+        ffi::lua_pop(lua, 1);
+
+        ffi::lua_pushvalue(lua, 2);
+        ffi::lua_rawget(lua, ffi::lua_upvalueindex(2));
+        if ffi::lua_isnil(lua, -1) {
+            return 1;
+        }
+        ffi::lua_pushvalue(lua, 1);
+        ffi::lua_call(lua, 1, 1);
+        1
+    }
+}
+
+// Value of `__newindex`. The upvalue is the table of the setters.
+extern "C" fn newindex_dispatch<T>(lua: *mut ffi::lua_State) -> libc::c_int
+    where T: LuaClass
+{
+    unsafe {
+        ffi::lua_pushvalue(lua, 2);
+        ffi::lua_rawget(lua, ffi::lua_upvalueindex(1));
+        if ffi::lua_isnil(lua, -1) {
+            // The message is built on the Lua stack, as `lua_error` doesn't return.
+            push_str(lua, "cannot set property '");
+            match ffi::lua_type(lua, 2) {
+                ffi::LUA_TSTRING | ffi::LUA_TNUMBER => ffi::lua_pushvalue(lua, 2),
+                _ => push_str(lua, "?"),
+            }
+            push_str(lua, "' of ");
+            push_str(lua, T::class_name());
+            ffi::lua_concat(lua, 4);
+            ffi::lua_error(lua);
+            unreachable!()
+        }
+
+        ffi::lua_pushvalue(lua, 1);
+        ffi::lua_pushvalue(lua, 3);
+        ffi::lua_call(lua, 2, 0);
+        0
+    }
+}
+
+// Default value of `__tostring`.
+extern "C" fn default_tostring<T>(lua: *mut ffi::lua_State) -> libc::c_int
+    where T: LuaClass
+{
+    unsafe {
+        let address = ffi::lua_touserdata(lua, 1);
+        push_str(lua, T::class_name());
+        ffi::lua_pushfstring(lua, b": %p\0".as_ptr() as *const _, address);
+        ffi::lua_concat(lua, 2);
+        1
+    }
+}
+
+#[inline]
+unsafe fn push_str(lua: *mut ffi::lua_State, s: &str) {
+    ffi::lua_pushlstring(lua, s.as_ptr() as *const _, s.len() as libc::size_t);
+}
+
+#[cfg(test)]
+mod tests {
+    use AsMutLua;
+    use Lua;
+    use LuaClass;
+    use LuaClassTable;
+    use LuaMethods;
+    use LuaTable;
+    use Push;
+    use PushGuard;
+    use PushOne;
+    use Void;
+    use function0;
+    use function1;
+    use function2;
+    use push_class;
+
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    impl LuaClass for Point {
+        fn class_name() -> &'static str {
+            "Point"
+        }
+
+        fn lua_getters<'lua, L>(getters: &mut LuaTable<L>) where L: AsMutLua<'lua> {
+            getters.set("x", function1(|p: &Point| p.x));
+            getters.set("y", function1(|p: &Point| p.y));
+        }
+
+        fn lua_setters<'lua, L>(setters: &mut LuaTable<L>) where L: AsMutLua<'lua> {
+            setters.set("x", function2(|p: &mut Point, x: i32| p.x = x));
+        }
+    }
+
+    impl LuaMethods for Point {
+        fn lua_methods<'lua, L>(methods: &mut LuaTable<L>) where L: AsMutLua<'lua> {
+            methods.set("sum", function1(|p: &Point| p.x + p.y));
+        }
+
+        fn lua_static_methods<'lua, L>(class: &mut LuaTable<L>) where L: AsMutLua<'lua> {
+            class.set("origin", function0(|| Point { x: 0, y: 0 }));
+        }
+    }
+
+    impl<'lua, L> Push<L> for Point where L: AsMutLua<'lua> {
+        type Err = Void;
+        fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+            Ok(push_class(self, lua))
+        }
+    }
+
+    impl<'lua, L> PushOne<L> for Point where L: AsMutLua<'lua> {}
+
+    implement_lua_read!(Point);
+
+    #[test]
+    fn properties_and_methods() {
+        let mut lua = Lua::new();
+        lua.set("Point", LuaClassTable::<Point>::new());
+        lua.execute::<()>("p = Point.origin()").unwrap();
+
+        let sum: i32 = lua.execute("p.x = 3; return p:sum()").unwrap();
+        assert_eq!(sum, 3);
+
+        let y: i32 = lua.execute("return p.y").unwrap();
+        assert_eq!(y, 0);
+
+        assert!(lua.execute::<()>("p.y = 5").is_err());
+        assert!(lua.execute::<bool>("return p.z == nil").unwrap());
+    }
+}
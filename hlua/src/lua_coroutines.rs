@@ -0,0 +1,518 @@
+use ffi;
+
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::panic;
+use std::ptr;
+
+use limits;
+
+use AsLua;
+use AsMutLua;
+use ExecutionError;
+use LuaContext;
+use LuaError;
+use LuaFunction;
+use LuaFunctionCallError;
+use LuaRead;
+use Push;
+use PushGuard;
+use rust_errors;
+
+/// Handle to a coroutine in the Lua context.
+///
+/// A coroutine (also called a *thread* by the Lua API) is a function whose execution can be
+/// suspended with `coroutine.yield` and resumed later. You can create a coroutine from a
+/// `LuaFunction` with `LuaCoroutine::new`, or read one that was created by a Lua script with
+/// `coroutine.create`.
+///
+/// Note that scripts need the coroutine library (see `Lua::open_coroutine`) in order to call
+/// `coroutine.yield`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LuaCoroutine, LuaCoroutineStatus, LuaFunction};
+///
+/// let mut lua = Lua::new();
+/// lua.open_coroutine();
+/// lua.execute::<()>(r#"
+///     function counter(a)
+///         local b = coroutine.yield(a + 1)
+///         return a + b
+///     end
+/// "#).unwrap();
+///
+/// let counter: LuaFunction<_> = lua.get("counter").unwrap();
+/// let mut co = LuaCoroutine::new(counter);
+///
+/// let first: i32 = co.resume(10).unwrap();
+/// assert_eq!(first, 11);
+/// assert_eq!(co.status(), LuaCoroutineStatus::Suspended);
+///
+/// let second: i32 = co.resume(5).unwrap();
+/// assert_eq!(second, 15);
+/// assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+/// ```
+#[derive(Debug)]
+pub struct LuaCoroutine<L> {
+    variable: L,
+    index: i32,
+}
+
+/// Status of a `LuaCoroutine`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LuaCoroutineStatus {
+    /// The coroutine hasn't started yet, or has yielded. It can be resumed.
+    Suspended,
+    /// The coroutine is currently running, or is waiting for another coroutine that it resumed.
+    Running,
+    /// The coroutine has returned or has stopped because of an error. It can't be resumed.
+    Dead,
+}
+
+unsafe impl<'lua, L> AsLua<'lua> for LuaCoroutine<L>
+    where L: AsLua<'lua>
+{
+    #[inline]
+    fn as_lua(&self) -> LuaContext {
+        self.variable.as_lua()
+    }
+}
+
+unsafe impl<'lua, L> AsMutLua<'lua> for LuaCoroutine<L>
+    where L: AsMutLua<'lua>
+{
+    #[inline]
+    fn as_mut_lua(&mut self) -> LuaContext {
+        self.variable.as_mut_lua()
+    }
+}
+
+impl<'lua, L> LuaCoroutine<L>
+    where L: AsMutLua<'lua>
+{
+    /// Creates a new coroutine that will execute the given function when it is first resumed.
+    ///
+    /// The arguments passed to the first call to `resume` are the parameters of the function.
+    #[inline]
+    pub fn new(mut function: LuaFunction<L>) -> LuaCoroutine<PushGuard<LuaFunction<L>>> {
+        unsafe {
+            let raw_lua = function.as_mut_lua().0;
+            let thread = ffi::lua_newthread(raw_lua);
+
+            // The new thread's stack is empty ; we put a copy of the function on it.
+            ffi::lua_pushvalue(raw_lua, -2);
+            ffi::lua_xmove(raw_lua, thread, 1);
+
+            LuaCoroutine {
+                variable: PushGuard::new(function, 1),
+                index: -1,
+            }
+        }
+    }
+
+    /// Returns the current status of the coroutine.
+    #[inline]
+    pub fn status(&self) -> LuaCoroutineStatus {
+        unsafe {
+            let thread = self.thread();
+            match ffi::lua_status(thread) {
+                ffi::LUA_YIELD => LuaCoroutineStatus::Suspended,
+                ffi::LUA_OK => {
+                    let mut ar = ffi::lua_Debug::default();
+                    if ffi::lua_getstack(thread, 0, &mut ar) != 0 {
+                        LuaCoroutineStatus::Running
+                    } else if ffi::lua_gettop(thread) == 0 {
+                        LuaCoroutineStatus::Dead
+                    } else {
+                        LuaCoroutineStatus::Suspended
+                    }
+                }
+                _ => LuaCoroutineStatus::Dead,
+            }
+        }
+    }
+
+    /// Resumes the coroutine with parameters.
+    ///
+    /// Just like with `LuaFunction::call_with_args`, you can pass a single value or multiple
+    /// values with a tuple. The first time the coroutine is resumed, they are the parameters of
+    /// the function. Afterwards, they are returned by the call to `coroutine.yield` that suspended
+    /// the coroutine.
+    ///
+    /// Returns the values passed to `coroutine.yield`, or the values returned by the function if
+    /// it has finished. Returns an error if the coroutine isn't suspended, if there is an error
+    /// while executing the Lua code, if the requested type doesn't match, or if we failed to push
+    /// an argument. The coroutine is dead after an error in the Lua code.
+    pub fn resume<'a, V, A, E>(&'a mut self, args: A) -> Result<V, LuaFunctionCallError<E>>
+        where A: for<'r> Push<&'r mut LuaCoroutine<L>, Err = E>,
+              V: LuaRead<PushGuard<&'a mut L>>
+    {
+        let (num_results, _) = self.resume_raw(args)?;
+        self.read_results(num_results)
+    }
+
+    /// Returns an iterator that resumes the coroutine again and again without any parameter,
+    /// and produces the values passed to `coroutine.yield`.
+    ///
+    /// The iteration ends when the coroutine returns. The values that it returns are ignored.
+    /// If the coroutine stops because of an error, the iterator produces the error then ends.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaCoroutine};
+    ///
+    /// let mut lua = Lua::new();
+    /// lua.open_coroutine();
+    ///
+    /// lua.execute::<()>(r#"
+    ///     squares = coroutine.create(function()
+    ///         for i = 1, 3 do coroutine.yield(i * i) end
+    ///     end)
+    /// "#).unwrap();
+    ///
+    /// let mut co: LuaCoroutine<_> = lua.get("squares").unwrap();
+    ///
+    /// let squares: Vec<i32> = co.iter().map(|v| v.unwrap()).collect();
+    /// assert_eq!(squares, vec![1, 4, 9]);
+    /// ```
+    #[inline]
+    pub fn iter<V>(&mut self) -> LuaCoroutineIterator<'_, L, V> {
+        LuaCoroutineIterator {
+            coroutine: self,
+            marker: PhantomData,
+        }
+    }
+
+    // Returns the raw coroutine.
+    #[inline]
+    fn thread(&self) -> *mut ffi::lua_State {
+        unsafe { ffi::lua_tothread(self.variable.as_lua().0, self.index) }
+    }
+
+    // Resumes the coroutine and moves the results on top of the stack, without a guard. Returns
+    // the number of results and whether the coroutine has finished.
+    fn resume_raw<A, E>(&mut self, args: A) -> Result<(i32, bool), LuaFunctionCallError<E>>
+        where A: for<'r> Push<&'r mut LuaCoroutine<L>, Err = E>
+    {
+        match self.status() {
+            LuaCoroutineStatus::Suspended => (),
+            LuaCoroutineStatus::Running => {
+                let msg = ExecutionError::from_message("cannot resume non-suspended coroutine".to_owned());
+                return Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg)));
+            }
+            LuaCoroutineStatus::Dead => {
+                let msg = ExecutionError::from_message("cannot resume dead coroutine".to_owned());
+                return Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg)));
+            }
+        }
+
+        let raw_lua = self.variable.as_mut_lua().0;
+        let thread = self.thread();
+
+        let num_args = match args.push_to_lua(self) {
+            Ok(g) => g.forget_internal(),
+            Err((err, _)) => return Err(LuaFunctionCallError::PushError(err)),
+        };
+
+        unsafe {
+            if ffi::lua_checkstack(thread, num_args) == 0 {
+                ffi::lua_pop(raw_lua, num_args);
+                let msg = ExecutionError::from_message("too many arguments to resume".to_owned());
+                return Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg)));
+            }
+            ffi::lua_xmove(raw_lua, thread, num_args);
+
+            limits::enter(thread);
+            let status = ffi::lua_resume(thread, raw_lua, num_args);
+            let exceeded = limits::leave(thread);
+
+            match status {
+                ffi::LUA_OK | ffi::LUA_YIELD => {
+                    let num_results = ffi::lua_gettop(thread);
+                    if ffi::lua_checkstack(raw_lua, num_results + 1) == 0 {
+                        ffi::lua_pop(thread, num_results);
+                        let msg = ExecutionError::from_message("too many results to resume".to_owned());
+                        return Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(msg)));
+                    }
+                    ffi::lua_xmove(thread, raw_lua, num_results);
+                    Ok((num_results, status == ffi::LUA_OK))
+                }
+                ffi::LUA_ERRMEM => Err(LuaFunctionCallError::LuaError(LuaError::OutOfMemory)),
+                ffi::LUA_ERRRUN if exceeded.is_some() => {
+                    Err(LuaFunctionCallError::LuaError(exceeded.unwrap()))
+                }
+                _ => {
+                    // the stack of a dead coroutine isn't unwound, so we can still build the
+                    // traceback
+                    ffi::luaL_traceback(raw_lua, thread, ptr::null(), 0);
+                    ffi::lua_xmove(thread, raw_lua, 1);
+                    let raw_lua = self.variable.as_lua();
+                    let mut guard = PushGuard {
+                        lua: &mut self.variable,
+                        size: 2,
+                        raw_lua,
+                    };
+                    if let Some(payload) = rust_errors::take_resumable_panic(raw_lua.0, -1) {
+                        drop(guard);
+                        panic::resume_unwind(payload);
+                    }
+                    ffi::lua_pushvalue(raw_lua.0, -2);
+                    let traceback: Option<String> = LuaRead::lua_read(&mut guard).ok();
+                    ffi::lua_pop(raw_lua.0, 1);
+                    let err = ExecutionError::from_lua(&mut guard, traceback);
+                    Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(err)))
+                }
+            }
+        }
+    }
+
+    // Reads the values left on the stack by `resume_raw`.
+    fn read_results<'a, V, E>(&'a mut self, num_results: i32) -> Result<V, LuaFunctionCallError<E>>
+        where V: LuaRead<PushGuard<&'a mut L>>
+    {
+        let size = if num_results == 0 {
+            // Reading a missing value is the same as reading `nil`.
+            unsafe { ffi::lua_pushnil(self.variable.as_mut_lua().0) };
+            1
+        } else {
+            num_results
+        };
+
+        let raw_lua = self.variable.as_lua();
+        let guard = PushGuard {
+            lua: &mut self.variable,
+            size,
+            raw_lua,
+        };
+
+        match LuaRead::lua_read_at_position(guard, -size) {
+            Err(_) => Err(LuaFunctionCallError::LuaError(LuaError::WrongType)),
+            Ok(x) => Ok(x),
+        }
+    }
+}
+
+impl<'lua, L> LuaRead<L> for LuaCoroutine<L>
+    where L: AsMutLua<'lua>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<LuaCoroutine<L>, L> {
+        if unsafe { ffi::lua_isthread(lua.as_mut_lua().0, index) } {
+            Ok(LuaCoroutine {
+                variable: lua,
+                index,
+            })
+        } else {
+            Err(lua)
+        }
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("thread")
+    }
+}
+
+/// Iterator that resumes a coroutine until it finishes.
+///
+/// See `LuaCoroutine::iter` for more info.
+#[derive(Debug)]
+pub struct LuaCoroutineIterator<'c, L: 'c, V> {
+    coroutine: &'c mut LuaCoroutine<L>,
+    marker: PhantomData<V>,
+}
+
+impl<'c, 'lua, L, V> Iterator for LuaCoroutineIterator<'c, L, V>
+    where L: AsMutLua<'lua> + 'c,
+          V: for<'a> LuaRead<PushGuard<&'a mut L>>
+{
+    type Item = Result<V, LuaError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Result<V, LuaError>> {
+        if self.coroutine.status() != LuaCoroutineStatus::Suspended {
+            return None;
+        }
+
+        match self.coroutine.resume_raw(()) {
+            Ok((num_results, true)) => {
+                unsafe { ffi::lua_pop(self.coroutine.variable.as_mut_lua().0, num_results) };
+                None
+            }
+            Ok((num_results, false)) => Some(self.coroutine.read_results(num_results).map_err(From::from)),
+            Err(err) => Some(Err(From::from(err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Lua;
+    use LuaCoroutine;
+    use LuaCoroutineStatus;
+    use LuaError;
+    use LuaFunction;
+    use LuaFunctionCallError;
+    use function1;
+
+    #[test]
+    fn resume_and_yield() {
+        let mut lua = Lua::new();
+        lua.open_coroutine();
+        lua.execute::<()>(r#"
+            function f(a, b)
+                local c = coroutine.yield(a + b)
+                local d, e = coroutine.yield(c * 2)
+                return d - e
+            end
+        "#).unwrap();
+
+        let f: LuaFunction<_> = lua.get("f").unwrap();
+        let mut co = LuaCoroutine::new(f);
+        assert_eq!(co.status(), LuaCoroutineStatus::Suspended);
+
+        let v: i32 = co.resume((1, 2)).unwrap();
+        assert_eq!(v, 3);
+        let v: i32 = co.resume(7).unwrap();
+        assert_eq!(v, 14);
+        assert_eq!(co.status(), LuaCoroutineStatus::Suspended);
+        let v: i32 = co.resume((10, 4)).unwrap();
+        assert_eq!(v, 6);
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn yield_multiple_values() {
+        let mut lua = Lua::new();
+        lua.open_coroutine();
+
+        lua.execute::<()>(r#"
+            co = coroutine.create(function() coroutine.yield(1, "two", true) end)
+        "#).unwrap();
+
+        let mut co: LuaCoroutine<_> = lua.get("co").unwrap();
+
+        let (a, b, c): (i32, String, bool) = co.resume(()).unwrap();
+        assert_eq!((a, &b[..], c), (1, "two", true));
+    }
+
+    #[test]
+    fn resume_dead_coroutine() {
+        let mut lua = Lua::new();
+        let f = LuaFunction::load(&mut lua, "return 5").unwrap();
+        let mut co = LuaCoroutine::new(f);
+
+        let v: i32 = co.resume(()).unwrap();
+        assert_eq!(v, 5);
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+
+        match co.resume::<(), _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(_))) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn error_kills_coroutine() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        let f = LuaFunction::load(&mut lua, "error('oops')").unwrap();
+        let mut co = LuaCoroutine::new(f);
+
+        match co.resume::<(), _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(err))) => {
+                assert!(err.message().contains("oops"));
+                assert!(err.traceback().unwrap().contains("in function 'error'"));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut lua = Lua::new();
+        let f = LuaFunction::load(&mut lua, "return 'hello'").unwrap();
+        let mut co = LuaCoroutine::new(f);
+
+        match co.resume::<i32, _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::WrongType)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn iterate_generator() {
+        let mut lua = Lua::new();
+        lua.open_coroutine();
+        lua.execute::<()>(r#"
+            function gen(n)
+                return coroutine.create(function()
+                    for i = 1, n do coroutine.yield(i) end
+                    return "done"
+                end)
+            end
+        "#).unwrap();
+
+        let mut gen: LuaFunction<_> = lua.get("gen").unwrap();
+        let mut co: LuaCoroutine<_> = gen.call_with_args(4).unwrap();
+
+        let values: Vec<i32> = co.iter().map(|v| v.unwrap()).collect();
+        assert_eq!(values, vec![1, 2, 3, 4]);
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn iterate_stops_after_error() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.open_coroutine();
+
+        lua.execute::<()>(r#"
+            co = coroutine.create(function()
+                coroutine.yield(1)
+                error("failure")
+            end)
+        "#).unwrap();
+
+        let mut co: LuaCoroutine<_> = lua.get("co").unwrap();
+
+        let mut iter = co.iter::<i32>();
+        assert_eq!(iter.next().unwrap().unwrap(), 1);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn status_running_from_callback() {
+        let mut lua = Lua::new();
+        lua.open_coroutine();
+        lua.set("check", function1(|status: String| assert_eq!(status, "running")));
+
+        lua.execute::<()>(r#"
+            co = coroutine.create(function()
+                check(coroutine.status(coroutine.running()))
+            end)
+        "#).unwrap();
+
+        let mut co: LuaCoroutine<_> = lua.get("co").unwrap();
+        co.resume::<(), _, _>(()).unwrap();
+        assert_eq!(co.status(), LuaCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn instruction_limit_applies() {
+        let mut lua = Lua::new();
+        lua.set_instruction_limit(Some(10000));
+        let f = LuaFunction::load(&mut lua, "while true do end").unwrap();
+        let mut co = LuaCoroutine::new(f);
+
+        match co.resume::<(), _, _>(()) {
+            Err(LuaFunctionCallError::LuaError(LuaError::InstructionLimit)) => (),
+            _ => panic!(),
+        }
+    }
+}
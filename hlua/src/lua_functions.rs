@@ -1,23 +1,31 @@
 use ffi;
 use libc;
 
+use std::any::Any;
+use std::borrow::Cow;
 use std::error::Error;
+use std::ffi::CStr;
 use std::fmt;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Error as IoError;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 
 use AsLua;
 use AsMutLua;
 
+use ExecutionError;
+use limits;
 use LuaContext;
 use LuaRead;
 use LuaError;
+use LuaRef;
 use Push;
 use PushGuard;
 use PushOne;
+use rust_errors;
 use Void;
 
 /// Wrapper around a `&str`. When pushed, the content will be parsed as Lua code and turned into a
@@ -92,12 +100,14 @@ impl<'lua, L, R> Push<L> for LuaCodeFromReader<R>
                 reader: R,
                 buffer: [u8; 128],
                 triggered_error: Option<IoError>,
+                panic: Option<Box<dyn Any + Send>>,
             }
 
             let mut read_data = ReadData {
                 reader: self.0,
                 buffer: mem::uninitialized(),
                 triggered_error: None,
+                panic: None,
             };
 
             extern "C" fn reader<R>(_: *mut ffi::lua_State,
@@ -110,17 +120,24 @@ impl<'lua, L, R> Push<L> for LuaCodeFromReader<R>
                     let data: *mut ReadData<R> = data as *mut _;
                     let data: &mut ReadData<R> = &mut *data;
 
-                    if data.triggered_error.is_some() {
+                    if data.triggered_error.is_some() || data.panic.is_some() {
                         (*size) = 0;
                         return data.buffer.as_ptr() as *const libc::c_char;
                     }
 
-                    match data.reader.read(&mut data.buffer) {
-                        Ok(len) => (*size) = len as libc::size_t,
-                        Err(e) => {
+                    // A panic is resumed once `lua_load` has returned.
+                    let reader = &mut data.reader;
+                    let buffer = &mut data.buffer;
+                    match panic::catch_unwind(AssertUnwindSafe(|| reader.read(buffer))) {
+                        Ok(Ok(len)) => (*size) = len as libc::size_t,
+                        Ok(Err(e)) => {
                             (*size) = 0;
                             data.triggered_error = Some(e);
                         }
+                        Err(payload) => {
+                            (*size) = 0;
+                            data.panic = Some(payload);
+                        }
                     };
 
                     data.buffer.as_ptr() as *const libc::c_char
@@ -142,6 +159,11 @@ impl<'lua, L, R> Push<L> for LuaCodeFromReader<R>
                  })
             };
 
+            if let Some(payload) = read_data.panic {
+                drop(pushed_value);
+                panic::resume_unwind(payload);
+            }
+
             if read_data.triggered_error.is_some() {
                 let error = read_data.triggered_error.unwrap();
                 return Err((LuaError::ReadError(error), pushed_value.into_inner()));
@@ -151,14 +173,14 @@ impl<'lua, L, R> Push<L> for LuaCodeFromReader<R>
                 return Ok(pushed_value);
             }
 
+            if load_return_value == ffi::LUA_ERRMEM {
+                return Err((LuaError::OutOfMemory, pushed_value.into_inner()));
+            }
+
             let error_msg: String = LuaRead::lua_read(&pushed_value)
                 .ok()
                 .expect("can't find error message at the top of the Lua stack");
 
-            if load_return_value == ffi::LUA_ERRMEM {
-                panic!("LUA_ERRMEM");
-            }
-
             if load_return_value == ffi::LUA_ERRSYNTAX {
                 return Err((LuaError::SyntaxError(error_msg), pushed_value.into_inner()));
             }
@@ -174,6 +196,44 @@ impl<'lua, L, R> PushOne<L> for LuaCodeFromReader<R>
 {
 }
 
+// Message handler passed to `lua_pcall`. Replaces the error object with a table containing the
+// original error object and the stack traceback, so that both can be read once the stack has
+// been unwound.
+extern "C" fn error_handler(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        ffi::lua_createtable(lua, 2, 0);
+        ffi::lua_pushvalue(lua, 1);
+        ffi::lua_rawseti(lua, -2, 1);
+        ffi::luaL_traceback(lua, lua, ptr::null(), 1);
+        ffi::lua_rawseti(lua, -2, 2);
+        1
+    }
+}
+
+// Turns the table built by `error_handler` at the top of the stack into an `ExecutionError`.
+// Leaves the stack untouched, except if the error is a panic that must be resumed.
+unsafe fn read_handler_result<'lua, L>(mut lua: L) -> ExecutionError
+    where L: AsMutLua<'lua>
+{
+    let raw_lua = lua.as_lua().0;
+    if !ffi::lua_istable(raw_lua, -1) {
+        return ExecutionError::from_lua(lua, None);
+    }
+
+    ffi::lua_rawgeti(raw_lua, -1, 2);
+    let traceback: Option<String> = LuaRead::lua_read(&mut lua).ok();
+    ffi::lua_pop(raw_lua, 1);
+
+    ffi::lua_rawgeti(raw_lua, -1, 1);
+    if let Some(payload) = rust_errors::take_resumable_panic(raw_lua, -1) {
+        ffi::lua_pop(raw_lua, 1);
+        panic::resume_unwind(payload);
+    }
+    let err = ExecutionError::from_lua(&mut lua, traceback);
+    ffi::lua_pop(raw_lua, 1);
+    err
+}
+
 /// Handle to a function in the Lua context.
 ///
 /// Just like you can read variables as integers and strings, you can also read Lua functions by
@@ -195,6 +255,8 @@ impl<'lua, L, R> PushOne<L> for LuaCodeFromReader<R>
 #[derive(Debug)]
 pub struct LuaFunction<L> {
     variable: L,
+    // Absolute index of the function on the stack.
+    index: i32,
 }
 
 unsafe impl<'lua, L> AsLua<'lua> for LuaFunction<L>
@@ -246,6 +308,10 @@ impl<'lua, L> LuaFunction<L>
     /// If you pass a tuple, the first element of the tuple will be the first argument, the second
     /// element of the tuple the second argument, and so on.
     ///
+    /// The values returned by the function are read starting from the first one. Request a tuple
+    /// in order to read several of them, or a `Variadic` or a `MultiValue` in order to read all of
+    /// them. If the function doesn't return anything, reading a single value reads `nil`.
+    ///
     /// Returns an error if there is an error while executing the Lua code (eg. a function call
     /// returns an error), if the requested return type doesn't match the actual return type, or
     /// if we failed to push an argument.
@@ -254,11 +320,14 @@ impl<'lua, L> LuaFunction<L>
     ///
     /// ```
     /// let mut lua = hlua::Lua::new();
-    /// lua.execute::<()>("function sub(a, b) return a - b end").unwrap();
+    /// lua.execute::<()>("function sub(a, b) return a - b, b - a end").unwrap();
     ///
     /// let mut foo: hlua::LuaFunction<_> = lua.get("sub").unwrap();
     /// let result: i32 = foo.call_with_args((18, 4)).unwrap();
     /// assert_eq!(result, 14);
+    ///
+    /// let (a, b): (i32, i32) = foo.call_with_args((18, 4)).unwrap();
+    /// assert_eq!((a, b), (14, -14));
     /// ```
     #[inline]
     pub fn call_with_args<'a, V, A, E>(&'a mut self, args: A) -> Result<V, LuaFunctionCallError<E>>
@@ -266,36 +335,58 @@ impl<'lua, L> LuaFunction<L>
               V: LuaRead<PushGuard<&'a mut L>>
     {
         // calling pcall pops the parameters and pushes output
-        let (pcall_return_value, pushed_value) = unsafe {
+        let (pcall_return_value, mut pushed_value, first_result, exceeded) = unsafe {
+            let raw_lua = self.variable.as_mut_lua().0;
+            let handler_index = ffi::lua_gettop(raw_lua) + 1;
+
+            // the message handler must be below the function and its parameters
+            ffi::lua_pushcfunction(raw_lua, error_handler);
             // lua_pcall pops the function, so we have to make a copy of it
-            ffi::lua_pushvalue(self.variable.as_mut_lua().0, -1);
+            ffi::lua_pushvalue(raw_lua, self.index);
             let num_pushed = match args.push_to_lua(self) {
                 Ok(g) => g.forget_internal(),
-                Err((err, _)) => return Err(LuaFunctionCallError::PushError(err)),
+                Err((err, _)) => {
+                    ffi::lua_pop(raw_lua, 2);
+                    return Err(LuaFunctionCallError::PushError(err));
+                }
             };
-            let pcall_return_value = ffi::lua_pcall(self.variable.as_mut_lua().0, num_pushed, 1, 0);     // TODO: num ret values
+            limits::enter(raw_lua);
+            let pcall_return_value = ffi::lua_pcall(raw_lua, num_pushed, ffi::MULTRET,
+                                                    handler_index);
+            let exceeded = limits::leave(raw_lua);
+            ffi::lua_remove(raw_lua, handler_index);
+
+            // If there is no result, reading starts above the top of the stack, where Lua sees
+            // an absent value.
+            let num_results = ffi::lua_gettop(raw_lua) - handler_index + 1;
+            let first_result = if num_results == 0 { handler_index } else { -num_results };
 
             let raw_lua = self.variable.as_lua();
             let guard = PushGuard {
                 lua: &mut self.variable,
-                size: 1,
+                size: num_results,
                 raw_lua: raw_lua,
             };
 
-            (pcall_return_value, guard)
+            (pcall_return_value, guard, first_result, exceeded)
         };
 
         match pcall_return_value {
-            0 => match LuaRead::lua_read(pushed_value) {
+            0 => match LuaRead::lua_read_at_position(pushed_value, first_result) {
                 Err(_) => Err(LuaFunctionCallError::LuaError(LuaError::WrongType)),
                 Ok(x) => Ok(x),
             },
-            ffi::LUA_ERRMEM => panic!("lua_pcall returned LUA_ERRMEM"),
+            ffi::LUA_ERRMEM => Err(LuaFunctionCallError::LuaError(LuaError::OutOfMemory)),
+            ffi::LUA_ERRRUN if exceeded.is_some() => {
+                Err(LuaFunctionCallError::LuaError(exceeded.unwrap()))
+            }
             ffi::LUA_ERRRUN => {
-                let error_msg: String = LuaRead::lua_read(pushed_value)
-                    .ok()
-                    .expect("can't find error message at the top of the Lua stack");
-                Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(error_msg)))
+                let err = unsafe { read_handler_result(&mut pushed_value) };
+                Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(err)))
+            }
+            ffi::LUA_ERRERR | ffi::LUA_ERRGCMM => {
+                let err = ExecutionError::from_lua(&mut pushed_value, None);
+                Err(LuaFunctionCallError::LuaError(LuaError::ExecutionError(err)))
             }
             _ => panic!("Unknown error code returned by lua_pcall: {}", pcall_return_value),
         }
@@ -322,7 +413,10 @@ impl<'lua, L> LuaFunction<L>
         where R: Read
     {
         match LuaCodeFromReader(code).push_to_lua(lua) {
-            Ok(pushed) => Ok(LuaFunction { variable: pushed }),
+            Ok(pushed) => {
+                let index = unsafe { ffi::lua_gettop(pushed.as_lua().0) };
+                Ok(LuaFunction { variable: pushed, index })
+            }
             Err((err, _)) => Err(err),
         }
     }
@@ -337,6 +431,80 @@ impl<'lua, L> LuaFunction<L>
         let reader = Cursor::new(code.as_bytes());
         LuaFunction::load_from_reader(lua, reader)
     }
+
+    /// Builds a new `LuaFunction` from the code of a reader, and uses `env` as its environment.
+    ///
+    /// Global variables read or written by the code are looked up in `env` instead of in the
+    /// table of global variables. This makes it possible to run several scripts in the same Lua
+    /// context without them clobbering each other's variables. `env` should be a table, for
+    /// example one created with `Lua::new_env`.
+    ///
+    /// Returns an error if reading from the `Read` object fails or if there is a syntax error in
+    /// the code. A precompiled function whose first upvalue isn't `_ENV` is refused with a
+    /// `SyntaxError`, as it would keep using the table of global variables.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `env` has been created from another Lua context.
+    #[inline]
+    pub fn load_from_reader_with_env<R>(lua: L, code: R, env: &LuaRef)
+                                        -> Result<LuaFunction<PushGuard<L>>, LuaError>
+        where R: Read
+    {
+        let mut f = LuaFunction::load_from_reader(lua, code)?;
+
+        unsafe {
+            let raw_lua = f.variable.as_mut_lua().0;
+
+            // The first upvalue of a main chunk is always `_ENV`. A precompiled function may
+            // have no upvalue, in which case it can't access any global variable, or have its
+            // `_ENV` elsewhere.
+            let name = ffi::lua_getupvalue(raw_lua, f.index, 1);
+            if name.is_null() {
+                return Ok(f);
+            }
+            let is_env = CStr::from_ptr(name).to_bytes() == b"_ENV";
+            ffi::lua_pop(raw_lua, 1);
+            if !is_env {
+                let msg = "the first upvalue of the chunk isn't _ENV".to_owned();
+                return Err(LuaError::SyntaxError(msg));
+            }
+
+            env.push_no_err(&mut f.variable).forget_internal();
+            ffi::lua_setupvalue(raw_lua, f.index, 1);
+        }
+
+        Ok(f)
+    }
+
+    /// Builds a new `LuaFunction` from a raw string, and uses `env` as its environment.
+    ///
+    /// See `load_from_reader_with_env` for more information.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use hlua::{Lua, LuaFunction};
+    ///
+    /// let mut lua = Lua::new();
+    /// let env = lua.new_env();
+    ///
+    /// {
+    ///     let mut f = LuaFunction::load_with_env(&mut lua, "a = 5", &env).unwrap();
+    ///     f.call::<()>().unwrap();
+    /// }
+    ///
+    /// assert_eq!(lua.get::<i32, _>("a"), None);
+    /// let a: i32 = lua.execute_in_env("return a", &env).unwrap();
+    /// assert_eq!(a, 5);
+    /// ```
+    #[inline]
+    pub fn load_with_env(lua: L, code: &str, env: &LuaRef)
+                         -> Result<LuaFunction<PushGuard<L>>, LuaError>
+    {
+        let reader = Cursor::new(code.as_bytes());
+        LuaFunction::load_from_reader_with_env(lua, reader, env)
+    }
 }
 
 /// Error that can happen when calling a `LuaFunction`.
@@ -425,26 +593,37 @@ impl<'lua, L> LuaRead<L> for LuaFunction<L>
 {
     #[inline]
     fn lua_read_at_position(mut lua: L, index: i32) -> Result<LuaFunction<L>, L> {
-        assert!(index == -1);   // FIXME:
-        if unsafe { ffi::lua_isfunction(lua.as_mut_lua().0, -1) } {
-            Ok(LuaFunction { variable: lua })
+        let raw_lua = lua.as_mut_lua().0;
+        if unsafe { ffi::lua_isfunction(raw_lua, index) } {
+            let index = unsafe { ffi::lua_absindex(raw_lua, index) };
+            Ok(LuaFunction { variable: lua, index })
         } else {
             Err(lua)
         }
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("function")
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use AnyLuaString;
+    use AsLua;
+    use ErrorValue;
     use Lua;
     use LuaError;
     use LuaFunction;
     use LuaFunctionCallError;
+    use LuaRef;
     use LuaTable;
     use Void;
 
     use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
     use std::error::Error;
+    use std::panic::{self, AssertUnwindSafe};
 
     #[test]
     fn basic() {
@@ -489,6 +668,102 @@ mod tests {
         };
     }
 
+    #[test]
+    fn execution_error_location() {
+        let mut lua = Lua::new();
+        let mut f = LuaFunction::load(&mut lua, "local a\nlocal b = a.field").unwrap();
+        match f.call::<()>() {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().contains("attempt to index local 'a'"));
+                assert_eq!(err.chunk_name(), Some("chunk"));
+                assert_eq!(err.line(), Some(2));
+                assert!(err.traceback().unwrap().contains("in main chunk"));
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn execution_error_value() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        let mut f = LuaFunction::load(&mut lua, "error({code = 42})").unwrap();
+        match f.call::<()>() {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message(), "(error object is a table value)");
+                assert_eq!(err.line(), None);
+                let expected = ErrorValue::LuaArray(vec![(ErrorValue::LuaString("code".to_owned()),
+                                                          ErrorValue::LuaNumber(42.0))]);
+                assert_eq!(err.into_value(), expected);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn execution_error_function_value() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        let mut f = LuaFunction::load(&mut lua, "error({print, 'x'})").unwrap();
+        match f.call::<()>() {
+            Err(LuaError::ExecutionError(err)) => {
+                let expected = ErrorValue::LuaArray(vec![
+                    (ErrorValue::LuaNumber(1.0), ErrorValue::LuaFunction),
+                    (ErrorValue::LuaNumber(2.0), ErrorValue::LuaString("x".to_owned())),
+                ]);
+                assert_eq!(err.into_value(), expected);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn execution_error_without_location() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        let mut f = LuaFunction::load(&mut lua, "error('no location: 12: here', 0)").unwrap();
+        match f.call::<()>() {
+            Err(LuaError::ExecutionError(err)) => {
+                assert_eq!(err.message(), "no location: 12: here");
+                assert_eq!(err.chunk_name(), None);
+                assert_eq!(err.line(), None);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn execution_error_keeps_stack_balanced() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.execute::<()>("function fail() error('oops') end").unwrap();
+        for _ in 0..100 {
+            let mut f: LuaFunction<_> = lua.get("fail").unwrap();
+            assert!(f.call::<()>().is_err());
+        }
+        let top = unsafe { ::ffi::lua_gettop(lua.as_lua().0) };
+        assert_eq!(top, 0);
+    }
+
+    #[test]
+    fn reader_panic_is_resumed() {
+        struct Panicking;
+        impl Read for Panicking {
+            fn read(&mut self, _: &mut [u8]) -> ::std::io::Result<usize> {
+                panic!("reader panicked")
+            }
+        }
+
+        let mut lua = Lua::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = LuaFunction::load_from_reader(&mut lua, Panicking);
+        }));
+        assert!(result.is_err());
+
+        let val: i32 = lua.execute("return 3").unwrap();
+        assert_eq!(val, 3);
+    }
+
     #[test]
     fn wrong_type() {
         let mut lua = Lua::new();
@@ -517,6 +792,15 @@ mod tests {
         assert_eq!(val, 5);
     }
 
+    #[test]
+    fn function_from_multiple_results() {
+        let mut lua = Lua::new();
+        let mut f = LuaFunction::load(&mut lua, "return function(a) return a * 2 end, 2").unwrap();
+        let mut double: LuaFunction<_> = f.call().unwrap();
+        let val: i32 = double.call_with_args(21).unwrap();
+        assert_eq!(val, 42);
+    }
+
     #[test]
     fn execute_from_reader_errors_if_cant_read() {
         struct Reader { };
@@ -533,11 +817,103 @@ mod tests {
         let res: Result<(), _> = lua.execute_from_reader(reader);
         match res {
             Ok(_) => panic!("Reading succeded"),
-            Err(LuaError::ReadError(e)) => { assert_eq!("oh no!", e.description()) },
+            Err(LuaError::ReadError(e)) => { assert_eq!("oh no!", e.to_string()) },
             Err(_) => panic!("Unexpected error happened"),
         }
     }
 
+    #[test]
+    fn envs_dont_clobber_each_other() {
+        let mut lua = Lua::new();
+        let first = lua.new_env();
+        let second = lua.new_env();
+
+        lua.execute_in_env::<()>("count = 1; function get() return count end", &first).unwrap();
+        lua.execute_in_env::<()>("count = 2; function get() return count end", &second).unwrap();
+
+        let val: i32 = lua.execute_in_env("return get()", &first).unwrap();
+        assert_eq!(val, 1);
+        let val: i32 = lua.execute_in_env("return get()", &second).unwrap();
+        assert_eq!(val, 2);
+
+        assert_eq!(lua.get::<i32, _>("count"), None);
+        assert!(lua.get::<LuaFunction<_>, _>("get").is_none());
+    }
+
+    #[test]
+    fn env_reads_globals() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set("a", 3);
+
+        let env = lua.new_env();
+        let val: String = lua.execute_in_env("return tostring(a)", &env).unwrap();
+        assert_eq!(val, "3");
+
+        lua.execute_in_env::<()>("a = 4; tostring = nil", &env).unwrap();
+        let val: i32 = lua.execute_in_env("return a", &env).unwrap();
+        assert_eq!(val, 4);
+        let val: i32 = lua.get("a").unwrap();
+        assert_eq!(val, 3);
+        let val: String = lua.execute("return tostring(a)").unwrap();
+        assert_eq!(val, "3");
+    }
+
+    #[test]
+    fn env_without_base() {
+        let mut lua = Lua::new();
+        lua.open_base();
+
+        let env = lua.new_env_with_base(None);
+        match lua.execute_in_env::<()>("print(1)", &env) {
+            Err(LuaError::ExecutionError(_)) => (),
+            _ => panic!(),
+        }
+
+        let val: Option<i32> = lua.execute_in_env("return print", &env).unwrap();
+        assert_eq!(val, None);
+    }
+
+    #[test]
+    fn load_with_env() {
+        let mut lua = Lua::new();
+        let env: LuaRef = lua.execute("return { x = 7 }").unwrap();
+
+        let val: i32 = {
+            let mut f = LuaFunction::load_with_env(&mut lua, "x = x + 1; return x", &env).unwrap();
+            f.call().unwrap()
+        };
+        assert_eq!(val, 8);
+
+        let mut table: LuaTable<_> = env.get(&mut lua).unwrap();
+        assert_eq!(table.get::<i32, _, _>("x"), Some(8));
+    }
+
+    #[test]
+    fn load_precompiled_with_env() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let env: LuaRef = lua.execute("return { x = 7 }").unwrap();
+
+        let main: AnyLuaString = lua.execute("return string.dump(load('return x'))").unwrap();
+        let val: i32 = {
+            let mut f = LuaFunction::load_from_reader_with_env(&mut lua, &main.0[..], &env)
+                .unwrap();
+            f.call().unwrap()
+        };
+        assert_eq!(val, 7);
+
+        // The first upvalue of this function is `y`, and `_ENV` is the second one.
+        let inner: AnyLuaString = lua.execute(r#"
+            local y = 1
+            return string.dump(function() return y, x end)
+        "#).unwrap();
+        match LuaFunction::load_from_reader_with_env(&mut lua, &inner.0[..], &env) {
+            Err(LuaError::SyntaxError(_)) => (),
+            _ => panic!(),
+        };
+    }
+
     fn _assert_error() {
         // Compile-time trait checks.
         fn _assert<T: Error>(_: T) {}
@@ -545,4 +921,12 @@ mod tests {
         _assert(LuaFunctionCallError::LuaError::<Void>(LuaError::WrongType));
         _assert(LuaFunctionCallError::PushError(IoError::new(IoErrorKind::Other, "Test")));
     }
+
+    fn _assert_send_sync() {
+        // Compile-time trait checks.
+        fn _assert<T: Send + Sync>() {}
+
+        _assert::<LuaError>();
+        _assert::<LuaFunctionCallError<IoError>>();
+    }
 }
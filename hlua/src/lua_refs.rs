@@ -0,0 +1,354 @@
+use ffi;
+use libc;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use raw;
+use AsMutLua;
+use LuaContext;
+use LuaRead;
+use Push;
+use PushGuard;
+use PushOne;
+use Void;
+
+/// Owned reference to a value stored in the registry of a Lua context.
+///
+/// Handles like `LuaFunction` or `LuaTable` borrow the Lua context and point to a slot of the
+/// stack, which means that they can't be stored for later use. A `LuaRef` on the other hand keeps
+/// the value alive in the registry and doesn't borrow anything. You can store it in a struct,
+/// clone it, and turn it back into a `LuaFunction`, a `LuaTable` or any other type with the `get`
+/// method whenever you need it.
+///
+/// You can obtain a `LuaRef` by loading any value, for example with `Lua::get` or as a parameter
+/// of a Rust callback. A `LuaRef` can also be pushed back to Lua.
+///
+/// Cloning a `LuaRef` is cheap, as all the clones point to the same registry slot. The slot is
+/// released once all the clones have been destroyed. It is safe to drop a `LuaRef` after the Lua
+/// context has been destroyed.
+///
+/// # Panic
+///
+/// Using a `LuaRef` with a different Lua context than the one it has been created from panics.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, LuaFunction, LuaRef};
+///
+/// struct EventHandlers {
+///     on_tick: Option<LuaRef>,
+/// }
+///
+/// let mut lua = Lua::new();
+/// lua.execute::<()>("function tick(n) return n * 2 end").unwrap();
+///
+/// let handlers = EventHandlers { on_tick: lua.get("tick") };
+///
+/// // The global variable can change without affecting the reference.
+/// lua.execute::<()>("tick = nil").unwrap();
+///
+/// let mut tick: LuaFunction<_> = handlers.on_tick.as_ref().unwrap().get(&mut lua).unwrap();
+/// let val: i32 = tick.call_with_args(21).unwrap();
+/// assert_eq!(val, 42);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LuaRef {
+    inner: Rc<RefInner>,
+}
+
+#[derive(Debug)]
+struct RefInner {
+    context: Rc<RefContext>,
+    id: libc::c_int,
+}
+
+/// Shared by all the references of a Lua context.
+#[derive(Debug)]
+struct RefContext {
+    // Set to false when the Lua context is closed.
+    alive: Cell<bool>,
+    // Thread that we use to create and release references, so that we never touch the stack of a
+    // thread that may be running.
+    thread: *mut ffi::lua_State,
+}
+
+// The `RefContext` of a state is stored as a userdata in the registry, at the address of this
+// static.
+static REGISTRY_KEY: u8 = 0;
+
+// Content of the userdata in the registry. Marks the references as dead when it is dropped, which
+// happens when the Lua context is closed.
+struct ContextOwner(Rc<RefContext>);
+
+impl Drop for ContextOwner {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.alive.set(false);
+    }
+}
+
+// Returns the `RefContext` of the state, creating it if necessary.
+unsafe fn context(lua: *mut ffi::lua_State) -> Rc<RefContext> {
+    let existing = raw::get::<ContextOwner>(lua, &REGISTRY_KEY);
+    if !existing.is_null() {
+        return (*existing).0.clone();
+    }
+
+    let thread = ffi::lua_newthread(lua);
+    ffi::luaL_ref(lua, ffi::LUA_REGISTRYINDEX);
+    let context = Rc::new(RefContext {
+        alive: Cell::new(true),
+        thread,
+    });
+
+    raw::insert(lua, &REGISTRY_KEY, ContextOwner(context.clone()));
+    context
+}
+
+impl Drop for RefInner {
+    #[inline]
+    fn drop(&mut self) {
+        if self.context.alive.get() {
+            unsafe { ffi::luaL_unref(self.context.thread, ffi::LUA_REGISTRYINDEX, self.id) }
+        }
+    }
+}
+
+impl LuaRef {
+    /// Pushes the referenced value on the stack and loads it.
+    ///
+    /// Returns `None` if the value can't be loaded as `V`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `lua` isn't the Lua context that the reference has been created from.
+    #[inline]
+    pub fn get<'lua, L, V>(&self, lua: L) -> Option<V>
+        where L: AsMutLua<'lua>,
+              V: LuaRead<PushGuard<L>>
+    {
+        let guard = self.push_no_err(lua);
+        LuaRead::lua_read(guard).ok()
+    }
+}
+
+/// Two references are equal if they point to the same Lua value, as with the `rawequal` Lua
+/// function.
+impl PartialEq for LuaRef {
+    fn eq(&self, other: &LuaRef) -> bool {
+        if Rc::ptr_eq(&self.inner, &other.inner) {
+            return true;
+        }
+
+        let context = &self.inner.context;
+        if !Rc::ptr_eq(context, &other.inner.context) || !context.alive.get() {
+            return false;
+        }
+
+        unsafe {
+            ffi::lua_rawgeti(context.thread, ffi::LUA_REGISTRYINDEX, self.inner.id);
+            ffi::lua_rawgeti(context.thread, ffi::LUA_REGISTRYINDEX, other.inner.id);
+            let equal = ffi::lua_rawequal(context.thread, -1, -2) != 0;
+            ffi::lua_pop(context.thread, 2);
+            equal
+        }
+    }
+}
+
+impl<'lua, L> LuaRead<L> for LuaRef
+    where L: AsMutLua<'lua>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<LuaRef, L> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua().0;
+            let context = context(raw_lua);
+
+            ffi::lua_pushvalue(raw_lua, index);
+            ffi::lua_xmove(raw_lua, context.thread, 1);
+            let id = ffi::luaL_ref(context.thread, ffi::LUA_REGISTRYINDEX);
+
+            Ok(LuaRef { inner: Rc::new(RefInner { context, id }) })
+        }
+    }
+}
+
+impl<'lua, L> Push<L> for &LuaRef
+    where L: AsMutLua<'lua>
+{
+    type Err = Void;      // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        unsafe {
+            let raw_lua = lua.as_mut_lua().0;
+            assert!(Rc::ptr_eq(&context(raw_lua), &self.inner.context),
+                    "LuaRef used with a different Lua context");
+
+            ffi::lua_rawgeti(raw_lua, ffi::LUA_REGISTRYINDEX, self.inner.id);
+            Ok(PushGuard {
+                lua,
+                size: 1,
+                raw_lua: LuaContext(raw_lua),
+            })
+        }
+    }
+}
+
+impl<'lua, L> PushOne<L> for &LuaRef
+    where L: AsMutLua<'lua>
+{
+}
+
+impl<'lua, L> Push<L> for LuaRef
+    where L: AsMutLua<'lua>
+{
+    type Err = Void;      // TODO: use `!` instead (https://github.com/rust-lang/rust/issues/35121)
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        (&self).push_to_lua(lua)
+    }
+}
+
+impl<'lua, L> PushOne<L> for LuaRef
+    where L: AsMutLua<'lua>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use Lua;
+    use LuaFunction;
+    use LuaRef;
+    use LuaTable;
+    use function0;
+    use function1;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn function_outlives_global() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("function add(a, b) return a + b end").unwrap();
+
+        let add: LuaRef = lua.get("add").unwrap();
+        lua.execute::<()>("add = nil").unwrap();
+
+        let mut f: LuaFunction<_> = add.get(&mut lua).unwrap();
+        let val: i32 = f.call_with_args((3, 4)).unwrap();
+        assert_eq!(val, 7);
+    }
+
+    #[test]
+    fn materialize_table() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("t = { a = 5 }").unwrap();
+
+        let t: LuaRef = lua.get("t").unwrap();
+        {
+            let mut table: LuaTable<_> = t.get(&mut lua).unwrap();
+            table.set("b", 10);
+        }
+
+        let b: i32 = lua.execute("return t.b").unwrap();
+        assert_eq!(b, 10);
+        assert!(t.get::<_, LuaFunction<_>>(&mut lua).is_none());
+    }
+
+    #[test]
+    fn push_back() {
+        let mut lua = Lua::new();
+        lua.set("a", "hello");
+
+        let a: LuaRef = lua.get("a").unwrap();
+        lua.set("b", &a);
+        lua.set("c", a.clone());
+
+        let val: String = lua.execute("return b .. c").unwrap();
+        assert_eq!(val, "hellohello");
+    }
+
+    #[test]
+    fn store_callback_from_lua() {
+        let stored: Rc<RefCell<Option<LuaRef>>> = Rc::new(RefCell::new(None));
+
+        let mut lua = Lua::new();
+        {
+            let stored = stored.clone();
+            lua.set("register", function1(move |f: LuaRef| *stored.borrow_mut() = Some(f)));
+        }
+        lua.execute::<()>("register(function(x) return x * 3 end)").unwrap();
+
+        let callback = stored.borrow_mut().take().unwrap();
+        let mut f: LuaFunction<_> = callback.get(&mut lua).unwrap();
+        let val: i32 = f.call_with_args(5).unwrap();
+        assert_eq!(val, 15);
+    }
+
+    #[test]
+    fn return_from_callback() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("x = 12").unwrap();
+        let x: LuaRef = lua.get("x").unwrap();
+        lua.set("get_x", function0(move || x.clone()));
+
+        let val: i32 = lua.execute("x = 0; return get_x()").unwrap();
+        assert_eq!(val, 12);
+    }
+
+    #[test]
+    fn references_are_released() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>("t = setmetatable({}, { __mode = 'v' }); t[1] = {}").unwrap();
+
+        let r: LuaRef = lua.execute::<LuaRef>("return t[1]").unwrap();
+        let clone = r.clone();
+        drop(r);
+        lua.execute::<()>("collectgarbage()").unwrap();
+        assert!(lua.execute::<bool>("return t[1] ~= nil").unwrap());
+
+        drop(clone);
+        lua.execute::<()>("collectgarbage()").unwrap();
+        assert!(lua.execute::<bool>("return t[1] == nil").unwrap());
+    }
+
+    #[test]
+    fn drop_after_lua() {
+        let r: LuaRef = {
+            let mut lua = Lua::new();
+            lua.execute::<()>("a = {}").unwrap();
+            lua.get("a").unwrap()
+        };
+        drop(r.clone());
+        drop(r);
+    }
+
+    #[test]
+    fn equality() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = {}; b = a; c = {}").unwrap();
+
+        let a: LuaRef = lua.get("a").unwrap();
+        let b: LuaRef = lua.get("b").unwrap();
+        let c: LuaRef = lua.get("c").unwrap();
+        assert_eq!(a, a.clone());
+        assert_eq!(a, b);
+        assert!(a != c);
+    }
+
+    #[test]
+    #[should_panic]
+    fn different_context() {
+        let mut lua1 = Lua::new();
+        let mut lua2 = Lua::new();
+        lua1.execute::<()>("a = 5").unwrap();
+
+        let a: LuaRef = lua1.get("a").unwrap();
+        lua2.set("a", &a);
+    }
+}
@@ -0,0 +1,1089 @@
+//! Conversions between Lua values and Rust types that implement `Serialize` or `Deserialize`.
+
+use ffi;
+use libc;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::ptr;
+use std::slice;
+
+use AsMutLua;
+use LuaTable;
+use PushGuard;
+
+/// Pushes a Rust value on the stack by turning it into Lua values.
+///
+/// Structs and maps become tables whose keys are the names of the fields or the keys of the map,
+/// sequences and tuples become tables whose keys are `1`, `2`, `3`, etc., `None` and `()` become
+/// `nil`, and unit enum variants become strings. Other enum variants become a table with a single
+/// element, whose key is the name of the variant. Integers that a Lua number can't represent
+/// exactly, which can only happen above 2^53 in absolute value, result in an error.
+///
+/// Returns a guard that you can use to load the value, for example as a `LuaTable`.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use] extern crate serde_derive;
+/// extern crate hlua;
+///
+/// use hlua::{Lua, LuaRead, LuaTable};
+///
+/// #[derive(Serialize)]
+/// struct Config {
+///     name: String,
+///     ports: Vec<u16>,
+/// }
+///
+/// # fn main() {
+/// let mut lua = Lua::new();
+/// let config = Config { name: "server".to_owned(), ports: vec![80, 443] };
+///
+/// let guard = hlua::to_lua(&mut lua, &config).unwrap();
+/// let mut table: LuaTable<_> = LuaRead::lua_read(guard).ok().unwrap();
+/// assert_eq!(table.get::<String, _, _>("name").unwrap(), "server");
+/// # }
+/// ```
+pub fn to_lua<'lua, L, T>(mut lua: L, value: &T) -> Result<PushGuard<L>, LuaSerdeError>
+    where L: AsMutLua<'lua>,
+          T: ?Sized + Serialize
+{
+    let raw_lua = lua.as_mut_lua();
+    let top = unsafe { ffi::lua_gettop(raw_lua.0) };
+
+    match value.serialize(Serializer { lua: raw_lua.0 }) {
+        Ok(()) => Ok(PushGuard {
+            lua,
+            size: 1,
+            raw_lua,
+        }),
+        Err(err) => {
+            unsafe { ffi::lua_settop(raw_lua.0, top) };
+            Err(err)
+        }
+    }
+}
+
+/// Builds a Rust value from the content of a Lua table.
+///
+/// This is the opposite of `to_lua`. A sequence can be loaded from a table whose keys are `1`, `2`,
+/// `3`, etc., and a struct or a map can be loaded from any table. Fields that are missing from the
+/// table are considered to be `nil`.
+///
+/// The error contains the path of the value that couldn't be loaded, for example
+/// `servers[2].port`. Note that the indices in the path start at 1, like in Lua.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use] extern crate serde_derive;
+/// extern crate hlua;
+///
+/// use hlua::Lua;
+///
+/// #[derive(Deserialize, Debug, PartialEq)]
+/// struct Config {
+///     name: String,
+///     ports: Vec<u16>,
+///     verbose: Option<bool>,
+/// }
+///
+/// # fn main() {
+/// let mut lua = Lua::new();
+/// lua.execute::<()>(r#"config = { name = "server", ports = { 80, 443 } }"#).unwrap();
+///
+/// let config: Config = hlua::from_lua(lua.get("config").unwrap()).unwrap();
+/// assert_eq!(config, Config { name: "server".to_owned(), ports: vec![80, 443], verbose: None });
+///
+/// lua.execute::<()>(r#"config.ports[2] = "https""#).unwrap();
+/// let err = hlua::from_lua::<Config, _>(lua.get("config").unwrap()).unwrap_err();
+/// assert_eq!(err.path(), "ports[2]");
+/// # }
+/// ```
+pub fn from_lua<'lua, T, L>(mut table: LuaTable<L>) -> Result<T, LuaSerdeError>
+    where L: AsMutLua<'lua>,
+          T: DeserializeOwned
+{
+    let raw_lua = table.as_mut_lua().0;
+    unsafe {
+        let top = ffi::lua_gettop(raw_lua);
+        let index = ffi::lua_absindex(raw_lua, table.stack_index());
+        let result = T::deserialize(Deserializer { lua: raw_lua, index });
+        ffi::lua_settop(raw_lua, top);
+        result
+    }
+}
+
+/// Error that can happen when converting between Lua values and Rust types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuaSerdeError {
+    // Path to the value that caused the error, in reverse order.
+    path: Vec<PathSegment>,
+    message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathSegment {
+    Field(String),
+    Index(i64),
+}
+
+impl LuaSerdeError {
+    /// Returns the path to the value that caused the error, for example `servers[2].port`. Empty
+    /// if the error concerns the value itself.
+    pub fn path(&self) -> String {
+        let mut path = String::new();
+        for segment in self.path.iter().rev() {
+            match *segment {
+                PathSegment::Field(ref name) => {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(name);
+                }
+                PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+            }
+        }
+        path
+    }
+
+    /// Returns the description of the error, without the path.
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    // Adds a segment at the start of the path.
+    #[inline]
+    fn prepend(mut self, segment: PathSegment) -> LuaSerdeError {
+        self.path.push(segment);
+        self
+    }
+}
+
+impl fmt::Display for LuaSerdeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(fmt, "{}", self.message)
+        } else {
+            write!(fmt, "{}: {}", self.path(), self.message)
+        }
+    }
+}
+
+impl Error for LuaSerdeError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl ser::Error for LuaSerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> LuaSerdeError {
+        LuaSerdeError {
+            path: Vec::new(),
+            message: msg.to_string(),
+        }
+    }
+}
+
+impl de::Error for LuaSerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> LuaSerdeError {
+        LuaSerdeError {
+            path: Vec::new(),
+            message: msg.to_string(),
+        }
+    }
+}
+
+// Makes sure that we can push a few more values on the stack.
+fn check_stack(lua: *mut ffi::lua_State) -> Result<(), LuaSerdeError> {
+    if unsafe { ffi::lua_checkstack(lua, 4) } == 0 {
+        Err(ser::Error::custom("value is too deeply nested"))
+    } else {
+        Ok(())
+    }
+}
+
+// Pushes a string on the stack.
+unsafe fn push_bytes(lua: *mut ffi::lua_State, bytes: &[u8]) {
+    ffi::lua_pushlstring(lua, bytes.as_ptr() as *const _, bytes.len() as libc::size_t);
+}
+
+// Returns the content of the string at the given index. The value must be a string and not a
+// number, as `lua_tolstring` would modify the value.
+unsafe fn read_bytes<'a>(lua: *mut ffi::lua_State, index: i32) -> &'a [u8] {
+    let mut len = 0;
+    let data = ffi::lua_tolstring(lua, index, &mut len);
+    slice::from_raw_parts(data as *const u8, len)
+}
+
+// Builds the segment of path corresponding to the key at the given index.
+unsafe fn key_segment(lua: *mut ffi::lua_State, index: i32) -> PathSegment {
+    match ffi::lua_type(lua, index) {
+        ffi::LUA_TSTRING => {
+            PathSegment::Field(String::from_utf8_lossy(read_bytes(lua, index)).into_owned())
+        }
+        ffi::LUA_TNUMBER => PathSegment::Index(ffi::lua_tonumberx(lua, index, ptr::null_mut()) as i64),
+        ty => {
+            let name = CStr::from_ptr(ffi::lua_typename(lua, ty)).to_string_lossy().into_owned();
+            PathSegment::Field(format!("<{}>", name))
+        }
+    }
+}
+
+/// Serializer that pushes values on the stack.
+#[derive(Copy, Clone)]
+struct Serializer {
+    lua: *mut ffi::lua_State,
+}
+
+impl Serializer {
+    #[inline]
+    fn push_number(self, value: f64) -> Result<(), LuaSerdeError> {
+        check_stack(self.lua)?;
+        unsafe { ffi::lua_pushnumber(self.lua, value) };
+        Ok(())
+    }
+
+    // Pushes an integer, or returns an error if a Lua number can't represent it exactly.
+    fn push_integer(self, value: i128) -> Result<(), LuaSerdeError> {
+        // `value` comes from a 64-bit integer, so converting `number` back can't saturate
+        let number = value as f64;
+        if number as i128 != value {
+            return Err(ser::Error::custom(format!("{} can't be represented exactly by a Lua \
+                                                   number", value)));
+        }
+        self.push_number(number)
+    }
+
+    // Pushes a table and returns a serializer that fills it.
+    #[inline]
+    fn push_table(self) -> Result<SerializeTable, LuaSerdeError> {
+        check_stack(self.lua)?;
+        unsafe {
+            ffi::lua_newtable(self.lua);
+            Ok(SerializeTable {
+                lua: self.lua,
+                table: ffi::lua_gettop(self.lua),
+                next_index: 1,
+                in_variant: false,
+            })
+        }
+    }
+
+    // Pushes a table with a single element whose key is the variant, and returns a serializer
+    // that fills the value of this element.
+    #[inline]
+    fn push_variant(self, variant: &str) -> Result<SerializeTable, LuaSerdeError> {
+        check_stack(self.lua)?;
+        unsafe {
+            ffi::lua_newtable(self.lua);
+            push_bytes(self.lua, variant.as_bytes());
+        }
+        let mut table = self.push_table()?;
+        table.in_variant = true;
+        Ok(table)
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = ();
+    type Error = LuaSerdeError;
+    type SerializeSeq = SerializeTable;
+    type SerializeTuple = SerializeTable;
+    type SerializeTupleStruct = SerializeTable;
+    type SerializeTupleVariant = SerializeTable;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeTable;
+
+    fn serialize_bool(self, v: bool) -> Result<(), LuaSerdeError> {
+        check_stack(self.lua)?;
+        unsafe { ffi::lua_pushboolean(self.lua, v as libc::c_int) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), LuaSerdeError> {
+        self.push_integer(v as i128)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), LuaSerdeError> {
+        self.push_integer(v as i128)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), LuaSerdeError> {
+        self.push_number(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), LuaSerdeError> {
+        self.push_number(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), LuaSerdeError> {
+        let mut buf = [0; 4];
+        self.serialize_str(v.encode_utf8(&mut buf))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), LuaSerdeError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), LuaSerdeError> {
+        check_stack(self.lua)?;
+        unsafe { push_bytes(self.lua, v) };
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), LuaSerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), LuaSerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), LuaSerdeError> {
+        check_stack(self.lua)?;
+        unsafe { ffi::lua_pushnil(self.lua) };
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), LuaSerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str)
+                              -> Result<(), LuaSerdeError>
+    {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _: &'static str, value: &T)
+                                                       -> Result<(), LuaSerdeError>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _: &'static str, _: u32,
+                                                        variant: &'static str, value: &T)
+                                                        -> Result<(), LuaSerdeError>
+    {
+        check_stack(self.lua)?;
+        unsafe {
+            ffi::lua_newtable(self.lua);
+            push_bytes(self.lua, variant.as_bytes());
+        }
+        value.serialize(self).map_err(|e| e.prepend(PathSegment::Field(variant.to_owned())))?;
+        unsafe { ffi::lua_rawset(self.lua, -3) };
+        Ok(())
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<SerializeTable, LuaSerdeError> {
+        self.push_table()
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<SerializeTable, LuaSerdeError> {
+        self.push_table()
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize)
+                              -> Result<SerializeTable, LuaSerdeError>
+    {
+        self.push_table()
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize)
+                               -> Result<SerializeTable, LuaSerdeError>
+    {
+        self.push_variant(variant)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<SerializeTable, LuaSerdeError> {
+        self.push_table()
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<SerializeTable, LuaSerdeError> {
+        self.push_table()
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize)
+                                -> Result<SerializeTable, LuaSerdeError>
+    {
+        self.push_variant(variant)
+    }
+}
+
+/// Fills a table that has been pushed on the stack.
+struct SerializeTable {
+    lua: *mut ffi::lua_State,
+    // Absolute index of the table.
+    table: i32,
+    // Key of the next element of a sequence.
+    next_index: i64,
+    // If true, the table is the value of a table created by `push_variant`.
+    in_variant: bool,
+}
+
+impl SerializeTable {
+    fn push_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), LuaSerdeError> {
+        let index = self.next_index;
+        value.serialize(Serializer { lua: self.lua })
+             .map_err(|e| e.prepend(PathSegment::Index(index)))?;
+        unsafe { ffi::lua_rawseti(self.lua, self.table, index as libc::c_int) };
+        self.next_index += 1;
+        Ok(())
+    }
+
+    fn set_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+                                        -> Result<(), LuaSerdeError>
+    {
+        check_stack(self.lua)?;
+        unsafe { push_bytes(self.lua, key.as_bytes()) };
+        value.serialize(Serializer { lua: self.lua })
+             .map_err(|e| e.prepend(PathSegment::Field(key.to_owned())))?;
+        unsafe { ffi::lua_rawset(self.lua, self.table) };
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), LuaSerdeError> {
+        if self.in_variant {
+            // Stores the table in the table created by `push_variant`.
+            unsafe { ffi::lua_rawset(self.lua, -3) };
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), LuaSerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), LuaSerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), LuaSerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), LuaSerdeError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), LuaSerdeError> {
+        key.serialize(Serializer { lua: self.lua })?;
+
+        // Using `nil` or NaN as a key would trigger a Lua error.
+        unsafe {
+            let invalid = match ffi::lua_type(self.lua, -1) {
+                ffi::LUA_TNIL => Some("nil"),
+                ffi::LUA_TNUMBER if ffi::lua_tonumberx(self.lua, -1, ptr::null_mut()).is_nan() => {
+                    Some("NaN")
+                }
+                _ => None,
+            };
+            if let Some(invalid) = invalid {
+                ffi::lua_pop(self.lua, 1);
+                return Err(ser::Error::custom(format!("{} can't be used as a table key", invalid)));
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), LuaSerdeError> {
+        let key = unsafe { key_segment(self.lua, -1) };
+        value.serialize(Serializer { lua: self.lua }).map_err(|e| e.prepend(key))?;
+        unsafe { ffi::lua_rawset(self.lua, self.table) };
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+                                              -> Result<(), LuaSerdeError>
+    {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable {
+    type Ok = ();
+    type Error = LuaSerdeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+                                              -> Result<(), LuaSerdeError>
+    {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<(), LuaSerdeError> {
+        self.finish()
+    }
+}
+
+/// Deserializer that reads a value on the stack.
+#[derive(Copy, Clone)]
+struct Deserializer {
+    lua: *mut ffi::lua_State,
+    // Absolute index of the value.
+    index: i32,
+}
+
+impl Deserializer {
+    // Returns an error that indicates that the value doesn't have the expected type.
+    fn invalid_type(self, expected: &dyn de::Expected) -> LuaSerdeError {
+        unsafe {
+            let ty = ffi::lua_type(self.lua, self.index);
+            let name = CStr::from_ptr(ffi::lua_typename(self.lua, ty)).to_string_lossy();
+            de::Error::invalid_type(de::Unexpected::Other(&name), expected)
+        }
+    }
+
+    // Returns the length of the sequence if the value is a table whose keys are `1` to `n`.
+    fn sequence_len(self) -> Option<i32> {
+        unsafe {
+            let len = ffi::lua_rawlen(self.lua, self.index) as i32;
+            let mut count = 0;
+            ffi::lua_pushnil(self.lua);
+            while ffi::lua_next(self.lua, self.index) != 0 {
+                ffi::lua_pop(self.lua, 1);
+                count += 1;
+                let key = ffi::lua_tonumberx(self.lua, -1, ptr::null_mut());
+                let is_index = ffi::lua_type(self.lua, -1) == ffi::LUA_TNUMBER &&
+                               key.fract() == 0.0 && key >= 1.0 && key <= len as f64;
+                if !is_index {
+                    ffi::lua_pop(self.lua, 1);
+                    return None;
+                }
+            }
+            if count == len { Some(len) } else { None }
+        }
+    }
+
+    fn visit_seq<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        if unsafe { !ffi::lua_istable(self.lua, self.index) } {
+            return Err(self.invalid_type(&visitor));
+        }
+
+        let len = unsafe { ffi::lua_rawlen(self.lua, self.index) } as i32;
+        let mut access = SeqAccess {
+            lua: self.lua,
+            table: self.index,
+            len,
+            next: 1,
+        };
+        let value = visitor.visit_seq(&mut access)?;
+        if access.next <= len {
+            return Err(de::Error::invalid_length(len as usize, &"fewer elements in table"));
+        }
+        Ok(value)
+    }
+
+    fn visit_map<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        if unsafe { !ffi::lua_istable(self.lua, self.index) } {
+            return Err(self.invalid_type(&visitor));
+        }
+
+        check_stack(self.lua)?;
+        unsafe { ffi::lua_pushnil(self.lua) };
+        visitor.visit_map(MapAccess {
+            lua: self.lua,
+            table: self.index,
+            key: None,
+        })
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = LuaSerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        unsafe {
+            match ffi::lua_type(self.lua, self.index) {
+                ffi::LUA_TNIL | ffi::LUA_TNONE => visitor.visit_unit(),
+                ffi::LUA_TBOOLEAN => visitor.visit_bool(ffi::lua_toboolean(self.lua, self.index) != 0),
+                ffi::LUA_TNUMBER => {
+                    let n = ffi::lua_tonumberx(self.lua, self.index, ptr::null_mut());
+                    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                        visitor.visit_i64(n as i64)
+                    } else {
+                        visitor.visit_f64(n)
+                    }
+                }
+                ffi::LUA_TSTRING => {
+                    let bytes = read_bytes(self.lua, self.index);
+                    match String::from_utf8(bytes.to_owned()) {
+                        Ok(s) => visitor.visit_string(s),
+                        Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+                    }
+                }
+                ffi::LUA_TTABLE => {
+                    check_stack(self.lua)?;
+                    match self.sequence_len() {
+                        Some(len) if len >= 1 => self.visit_seq(visitor),
+                        _ => self.visit_map(visitor),
+                    }
+                }
+                _ => Err(self.invalid_type(&visitor)),
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        if unsafe { ffi::lua_isnil(self.lua, self.index) } {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V)
+                                                   -> Result<V::Value, LuaSerdeError>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        self.visit_seq(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V)
+                                          -> Result<V::Value, LuaSerdeError>
+    {
+        self.visit_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, _: usize, visitor: V)
+                                                 -> Result<V::Value, LuaSerdeError>
+    {
+        self.visit_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        self.visit_map(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value, LuaSerdeError>
+    {
+        self.visit_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value, LuaSerdeError>
+    {
+        unsafe {
+            match ffi::lua_type(self.lua, self.index) {
+                ffi::LUA_TSTRING => {
+                    let variant = String::from_utf8_lossy(read_bytes(self.lua, self.index)).into_owned();
+                    visitor.visit_enum(variant.into_deserializer())
+                }
+                ffi::LUA_TTABLE => {
+                    // The table must contain exactly one element.
+                    check_stack(self.lua)?;
+                    ffi::lua_pushnil(self.lua);
+                    if ffi::lua_next(self.lua, self.index) == 0 {
+                        return Err(de::Error::invalid_length(0, &"a table with a single element"));
+                    }
+                    let top = ffi::lua_gettop(self.lua);
+                    ffi::lua_pushvalue(self.lua, top - 1);
+                    if ffi::lua_next(self.lua, self.index) != 0 {
+                        return Err(de::Error::invalid_length(2, &"a table with a single element"));
+                    }
+                    visitor.visit_enum(EnumAccess {
+                        lua: self.lua,
+                        key: top - 1,
+                        value: top,
+                    })
+                }
+                _ => Err(self.invalid_type(&visitor)),
+            }
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+        unit_struct identifier
+    }
+}
+
+/// Reads the elements of a sequence.
+struct SeqAccess {
+    lua: *mut ffi::lua_State,
+    table: i32,
+    len: i32,
+    next: i32,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = LuaSerdeError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, LuaSerdeError>
+        where T: DeserializeSeed<'de>
+    {
+        if self.next > self.len {
+            return Ok(None);
+        }
+
+        check_stack(self.lua)?;
+        let index = self.next;
+        self.next += 1;
+
+        unsafe {
+            ffi::lua_rawgeti(self.lua, self.table, index);
+            let value_index = ffi::lua_gettop(self.lua);
+            let value = seed.deserialize(Deserializer { lua: self.lua, index: value_index });
+            ffi::lua_settop(self.lua, value_index - 1);
+            value.map(Some).map_err(|e| e.prepend(PathSegment::Index(index as i64)))
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.next + 1) as usize)
+    }
+}
+
+/// Reads the elements of a table with `lua_next`.
+///
+/// Between two calls to `lua_next`, the current key is on the top of the stack.
+struct MapAccess {
+    lua: *mut ffi::lua_State,
+    table: i32,
+    // Path segment of the current key.
+    key: Option<PathSegment>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = LuaSerdeError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, LuaSerdeError>
+        where K: DeserializeSeed<'de>
+    {
+        unsafe {
+            if ffi::lua_next(self.lua, self.table) == 0 {
+                return Ok(None);
+            }
+
+            let key_index = ffi::lua_gettop(self.lua) - 1;
+            let segment = key_segment(self.lua, key_index);
+            let key = seed.deserialize(Deserializer { lua: self.lua, index: key_index })
+                          .map_err(|e| e.prepend(segment.clone()))?;
+            self.key = Some(segment);
+            Ok(Some(key))
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, LuaSerdeError>
+        where V: DeserializeSeed<'de>
+    {
+        unsafe {
+            let value_index = ffi::lua_gettop(self.lua);
+            let value = seed.deserialize(Deserializer { lua: self.lua, index: value_index });
+            ffi::lua_settop(self.lua, value_index - 1);
+            match self.key.take() {
+                Some(segment) => value.map_err(|e| e.prepend(segment)),
+                None => value,
+            }
+        }
+    }
+}
+
+/// Reads an enum stored as a table with a single element.
+struct EnumAccess {
+    lua: *mut ffi::lua_State,
+    key: i32,
+    value: i32,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = LuaSerdeError;
+    type Variant = VariantAccess;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, VariantAccess), LuaSerdeError>
+        where V: DeserializeSeed<'de>
+    {
+        let segment = unsafe { key_segment(self.lua, self.key) };
+        let variant = seed.deserialize(Deserializer { lua: self.lua, index: self.key })?;
+        Ok((variant, VariantAccess {
+            value: Deserializer { lua: self.lua, index: self.value },
+            segment,
+        }))
+    }
+}
+
+struct VariantAccess {
+    value: Deserializer,
+    segment: PathSegment,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = LuaSerdeError;
+
+    fn unit_variant(self) -> Result<(), LuaSerdeError> {
+        de::Deserialize::deserialize(self.value).map_err(|e: LuaSerdeError| e.prepend(self.segment))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, LuaSerdeError>
+        where T: DeserializeSeed<'de>
+    {
+        seed.deserialize(self.value).map_err(|e| e.prepend(self.segment))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, LuaSerdeError> {
+        self.value.visit_seq(visitor).map_err(|e| e.prepend(self.segment))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V)
+                                       -> Result<V::Value, LuaSerdeError>
+    {
+        self.value.visit_map(visitor).map_err(|e| e.prepend(self.segment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffi;
+
+    use AsLua;
+    use Lua;
+    use LuaRead;
+    use LuaTable;
+    use from_lua;
+    use to_lua;
+
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Server {
+        host: String,
+        port: u16,
+        tags: Vec<String>,
+        weight: Option<f64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Mode {
+        Off,
+        Fixed(u32),
+        Range(u32, u32),
+        Custom { name: String },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Config {
+        servers: Vec<Server>,
+        modes: Vec<Mode>,
+        limits: HashMap<String, i32>,
+    }
+
+    fn sample() -> Config {
+        let mut limits = HashMap::new();
+        limits.insert("cpu".to_owned(), 4);
+        limits.insert("memory".to_owned(), 1024);
+
+        Config {
+            servers: vec![
+                Server { host: "a".to_owned(), port: 80, tags: vec![], weight: None },
+                Server { host: "b".to_owned(), port: 443, tags: vec!["tls".to_owned()], weight: Some(0.5) },
+            ],
+            modes: vec![Mode::Off, Mode::Fixed(3), Mode::Range(1, 2), Mode::Custom { name: "x".to_owned() }],
+            limits,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut lua = Lua::new();
+        let config = sample();
+
+        let table: LuaTable<_> = LuaRead::lua_read(to_lua(&mut lua, &config).unwrap()).ok().unwrap();
+        let back: Config = from_lua(table).unwrap();
+        assert_eq!(back, config);
+    }
+
+    #[test]
+    fn serialized_layout() {
+        let mut lua = Lua::new();
+        let mut table: LuaTable<_> = LuaRead::lua_read(to_lua(&mut lua, &sample()).unwrap()).ok().unwrap();
+        let mut servers: LuaTable<_> = table.get("servers").unwrap();
+        let mut second: LuaTable<_> = servers.get(2).unwrap();
+        assert_eq!(second.get::<String, _, _>("host").unwrap(), "b");
+        assert_eq!(second.get::<i32, _, _>("port").unwrap(), 443);
+        assert_eq!(second.get::<f64, _, _>("weight").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn enums_layout() {
+        let mut lua = Lua::new();
+        lua.execute::<()>(r#"
+            modes = { "Off", { Fixed = 7 }, { Range = { 2, 5 } }, { Custom = { name = "y" } } }
+        "#).unwrap();
+
+        let modes: Vec<Mode> = from_lua(lua.get("modes").unwrap()).unwrap();
+        assert_eq!(modes, vec![Mode::Off, Mode::Fixed(7), Mode::Range(2, 5),
+                               Mode::Custom { name: "y".to_owned() }]);
+    }
+
+    #[test]
+    fn from_script() {
+        let mut lua = Lua::new();
+        lua.execute::<()>(r#"
+            config = {
+                servers = { { host = "h", port = 22, tags = { "ssh" } } },
+                modes = {},
+                limits = { files = 10 },
+            }
+        "#).unwrap();
+
+        let config: Config = from_lua(lua.get("config").unwrap()).unwrap();
+        assert_eq!(config.servers[0].tags, vec!["ssh".to_owned()]);
+        assert_eq!(config.servers[0].weight, None);
+        assert_eq!(config.limits["files"], 10);
+    }
+
+    #[test]
+    fn error_path() {
+        let mut lua = Lua::new();
+        lua.execute::<()>(r#"
+            config = {
+                servers = { { host = "h", port = 22, tags = {} }, { host = "i", port = "x", tags = {} } },
+                modes = {},
+                limits = {},
+            }
+        "#).unwrap();
+
+        let err = from_lua::<Config, _>(lua.get("config").unwrap()).unwrap_err();
+        assert_eq!(err.path(), "servers[2].port");
+        assert!(err.to_string().starts_with("servers[2].port: invalid type: string"));
+    }
+
+    #[test]
+    fn missing_field() {
+        let mut lua = Lua::new();
+        lua.execute::<()>(r#"server = { host = "h", tags = {} }"#).unwrap();
+
+        let err = from_lua::<Server, _>(lua.get("server").unwrap()).unwrap_err();
+        assert_eq!(err.message(), "missing field `port`");
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut lua = Lua::new();
+        lua.execute::<()>(r#"server = { host = "h", port = 70000, tags = {} }"#).unwrap();
+
+        let err = from_lua::<Server, _>(lua.get("server").unwrap()).unwrap_err();
+        assert_eq!(err.path(), "port");
+    }
+
+    #[test]
+    fn inexact_integer() {
+        #[derive(Serialize)]
+        struct Item {
+            id: u64,
+        }
+
+        let mut lua = Lua::new();
+        let items = vec![Item { id: 1 << 60 }, Item { id: (1 << 53) + 1 }];
+
+        let err = to_lua(&mut lua, &items).unwrap_err();
+        assert_eq!(err.path(), "[2].id");
+        assert!(err.message().contains("9007199254740993"));
+        assert!(to_lua(&mut lua, &i64::MIN).is_ok());
+    }
+
+    #[test]
+    fn invalid_map_key() {
+        let mut lua = Lua::new();
+        let mut map = HashMap::new();
+        map.insert(Some(1), 2);
+        map.insert(None, 3);
+
+        assert!(to_lua(&mut lua, &map).is_err());
+        let top = unsafe { ffi::lua_gettop(lua.as_lua().state_ptr()) };
+        assert_eq!(top, 0);
+    }
+}
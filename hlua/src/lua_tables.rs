@@ -1,6 +1,8 @@
+use std::borrow::Cow;
 use std::marker::PhantomData;
 
 use ffi;
+use libc;
 use LuaContext;
 
 use AsLua;
@@ -52,6 +54,14 @@ impl<L> LuaTable<L> {
             self.index + offset
         }
     }
+
+    // Returns the index on the stack of this table, for the code of this crate that manipulates
+    // the table with the raw Lua API.
+    #[cfg(feature = "serde")]
+    #[inline]
+    pub(crate) fn stack_index(&self) -> i32 {
+        self.index
+    }
 }
 
 unsafe impl<'lua, L> AsLua<'lua> for LuaTable<L>
@@ -86,6 +96,11 @@ impl<'lua, L> LuaRead<L> for LuaTable<L>
             Err(lua)
         }
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
 }
 
 impl<'lua, L> LuaTable<L>
@@ -266,6 +281,259 @@ impl<'lua, L> LuaTable<L>
         }
     }
 
+    /// Returns the length of the table, like the `#` operator in Lua.
+    ///
+    /// This calls the `__len` metamethod of the table, if any. Returns `None` if the metamethod
+    /// doesn't return a non-negative integer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("a = { 9, 8, 7 }").unwrap();
+    ///
+    /// let mut table: hlua::LuaTable<_> = lua.get("a").unwrap();
+    /// assert_eq!(table.len(), Some(3));
+    /// ```
+    #[inline]
+    pub fn len(&mut self) -> Option<usize> {
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            ffi::lua_len(raw_lua, self.offset(0));
+            let raw_lua = self.as_lua();
+            let guard = PushGuard {
+                lua: &mut *self,
+                size: 1,
+                raw_lua,
+            };
+            LuaRead::lua_read(guard).ok()
+        }
+    }
+
+    /// Returns the length of the table, without calling the `__len` metamethod.
+    #[inline]
+    pub fn raw_len(&mut self) -> usize {
+        unsafe { ffi::lua_rawlen(self.as_mut_lua().0, self.offset(0)) }
+    }
+
+    /// Returns true if the table is empty, without calling any metamethod.
+    #[inline]
+    pub fn is_empty(&mut self) -> bool {
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            ffi::lua_pushnil(raw_lua);
+            if ffi::lua_next(raw_lua, self.offset(-1)) == 0 {
+                true
+            } else {
+                ffi::lua_pop(raw_lua, 2);
+                false
+            }
+        }
+    }
+
+    /// Loads a value in the table given its index, without calling the `__index` metamethod.
+    ///
+    /// Apart from this, this is the same as `get`.
+    #[inline]
+    pub fn raw_get<'a, R, I, E>(&'a mut self, index: I) -> Option<R>
+        where R: LuaRead<PushGuard<&'a mut LuaTable<L>>>,
+              I: for<'b> PushOne<&'b mut &'a mut LuaTable<L>, Err = E>,
+              E: Into<Void>,
+    {
+        unsafe {
+            let mut me = self;
+
+            index.push_no_err(&mut me).assert_one_and_forget();
+            ffi::lua_rawget(me.as_mut_lua().0, me.offset(-1));
+
+            let raw_lua = me.as_lua();
+            let guard = PushGuard {
+                lua: me,
+                size: 1,
+                raw_lua,
+            };
+
+            if ffi::lua_isnil(raw_lua.0, -1) {
+                None
+            } else {
+                LuaRead::lua_read(guard).ok()
+            }
+        }
+    }
+
+    /// Inserts or modifies an element of the table, without calling the `__newindex`
+    /// metamethod.
+    ///
+    /// Apart from this, this is the same as `set`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.open_base();
+    /// lua.execute::<()>("
+    ///     a = setmetatable({}, { __newindex = function() error('read-only') end })").unwrap();
+    ///
+    /// let mut table: hlua::LuaTable<_> = lua.get("a").unwrap();
+    /// table.raw_set("x", 5);
+    /// assert_eq!(table.get::<i32, _, _>("x"), Some(5));
+    /// ```
+    #[inline]
+    pub fn raw_set<I, V, Ei, Ev>(&mut self, index: I, value: V)
+        where I: for<'r> PushOne<&'r mut LuaTable<L>, Err = Ei>,
+              V: for<'r, 's> PushOne<&'r mut PushGuard<&'s mut LuaTable<L>>, Err = Ev>,
+              Ei: Into<Void>,
+              Ev: Into<Void>,
+    {
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            let my_offset = self.offset(-2);
+
+            let mut guard = index.push_no_err(self);
+            assert_eq!(guard.size, 1);
+            value.push_no_err(&mut guard).assert_one_and_forget();
+            guard.forget();
+
+            ffi::lua_rawset(raw_lua, my_offset);
+        }
+    }
+
+    /// Returns true if the table contains a non-nil value at the given index.
+    ///
+    /// Like `get`, this calls the `__index` metamethod of the table, if any.
+    #[inline]
+    pub fn contains_key<I, E>(&mut self, index: I) -> bool
+        where I: for<'r> PushOne<&'r mut LuaTable<L>, Err = E>,
+              E: Into<Void>,
+    {
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            let my_offset = self.offset(-1);
+
+            index.push_no_err(&mut *self).assert_one_and_forget();
+            ffi::lua_gettable(raw_lua, my_offset);
+            let contains = !ffi::lua_isnil(raw_lua, -1);
+            ffi::lua_pop(raw_lua, 1);
+            contains
+        }
+    }
+
+    /// Appends a value at the end of the table, like `table.insert(t, value)` in Lua.
+    ///
+    /// The end of the table is given by `raw_len`, as the `__len` metamethod isn't called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("a = { 9, 8 }").unwrap();
+    ///
+    /// {
+    ///     let mut table: hlua::LuaTable<_> = lua.get("a").unwrap();
+    ///     table.push(7);
+    /// }
+    ///
+    /// let a: Vec<i32> = lua.get("a").unwrap();
+    /// assert_eq!(a, vec![9, 8, 7]);
+    /// ```
+    #[inline]
+    pub fn push<V, E>(&mut self, value: V)
+        where V: for<'r> PushOne<&'r mut LuaTable<L>, Err = E>,
+              E: Into<Void>,
+    {
+        let len = self.raw_len();
+        self.insert(len + 1, value)
+    }
+
+    /// Inserts a value at the given position, shifting up the elements after it, like
+    /// `table.insert(t, position, value)` in Lua. Positions start at 1.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `position` isn't between 1 and the raw length of the table plus one.
+    #[inline]
+    pub fn insert<V, E>(&mut self, position: usize, value: V)
+        where V: for<'r> PushOne<&'r mut LuaTable<L>, Err = E>,
+              E: Into<Void>,
+    {
+        let len = self.raw_len();
+        assert!(position >= 1 && position <= len + 1, "position out of bounds");
+
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            for i in (position .. len + 1).rev() {
+                ffi::lua_rawgeti(raw_lua, self.offset(0), i as libc::c_int);
+                ffi::lua_rawseti(raw_lua, self.offset(-1), i as libc::c_int + 1);
+            }
+
+            value.push_no_err(&mut *self).assert_one_and_forget();
+            ffi::lua_rawseti(raw_lua, self.offset(-1), position as libc::c_int);
+        }
+    }
+
+    /// Removes the value at the given position, shifting down the elements after it, like
+    /// `table.remove(t, position)` in Lua. Positions start at 1.
+    ///
+    /// Returns `None` without doing anything if `position` isn't between 1 and the length of the
+    /// table, as returned by `raw_len`. Otherwise the value is removed, and returned if it can be
+    /// read as `R`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut lua = hlua::Lua::new();
+    /// lua.execute::<()>("a = { 9, 8, 7 }").unwrap();
+    ///
+    /// let mut table: hlua::LuaTable<_> = lua.get("a").unwrap();
+    /// assert_eq!(table.remove::<i32>(1), Some(9));
+    /// assert_eq!(table.get::<i32, _, _>(1), Some(8));
+    /// assert_eq!(table.raw_len(), 2);
+    /// ```
+    #[inline]
+    pub fn remove<'a, R>(&'a mut self, position: usize) -> Option<R>
+        where R: LuaRead<PushGuard<&'a mut LuaTable<L>>>
+    {
+        let len = self.raw_len();
+        if position < 1 || position > len {
+            return None;
+        }
+
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            ffi::lua_rawgeti(raw_lua, self.offset(0), position as libc::c_int);
+            for i in position .. len {
+                ffi::lua_rawgeti(raw_lua, self.offset(-1), i as libc::c_int + 1);
+                ffi::lua_rawseti(raw_lua, self.offset(-2), i as libc::c_int);
+            }
+            ffi::lua_pushnil(raw_lua);
+            ffi::lua_rawseti(raw_lua, self.offset(-2), len as libc::c_int);
+
+            let raw_lua = self.as_lua();
+            let guard = PushGuard {
+                lua: self,
+                size: 1,
+                raw_lua,
+            };
+            LuaRead::lua_read(guard).ok()
+        }
+    }
+
+    /// Removes all the elements of the table, without calling any metamethod.
+    #[inline]
+    pub fn clear(&mut self) {
+        unsafe {
+            let raw_lua = self.as_mut_lua().0;
+            ffi::lua_pushnil(raw_lua);
+            while ffi::lua_next(raw_lua, self.offset(-1)) != 0 {
+                // Assigning nil to an existing field is allowed while traversing the table.
+                ffi::lua_pop(raw_lua, 1);
+                ffi::lua_pushvalue(raw_lua, -1);
+                ffi::lua_pushnil(raw_lua);
+                ffi::lua_rawset(raw_lua, self.offset(-3));
+            }
+        }
+    }
+
     /// Inserts an empty array, then loads it.
     #[inline]
     pub fn empty_array<'s, I, E>(&'s mut self, index: I) -> LuaTable<PushGuard<&'s mut LuaTable<L>>>
@@ -483,6 +751,9 @@ impl<'t, L, K, V> Drop for LuaTableIterator<'t, L, K, V> {
 
 #[cfg(test)]
 mod tests {
+    use ffi;
+
+    use AsMutLua;
     use Lua;
     use LuaTable;
     use PushGuard;
@@ -640,4 +911,150 @@ mod tests {
         let mut metatable = registry.get_or_create_metatable();
         metatable.set(3, "hello");
     }
+
+    #[test]
+    fn len() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.execute::<()>("a = { 9, 8, 7 }; b = setmetatable({}, { __len = function() return 5 end })")
+           .unwrap();
+
+        {
+            let mut table: LuaTable<_> = lua.get("a").unwrap();
+            assert_eq!(table.len(), Some(3));
+            assert_eq!(table.raw_len(), 3);
+            assert!(!table.is_empty());
+        }
+
+        let mut table: LuaTable<_> = lua.get("b").unwrap();
+        assert_eq!(table.len(), Some(5));
+        assert_eq!(table.raw_len(), 0);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn len_not_an_integer() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.execute::<()>("a = setmetatable({ 1 }, { __len = function() return 'many' end })")
+           .unwrap();
+
+        {
+            let mut table: LuaTable<_> = lua.get("a").unwrap();
+            assert_eq!(table.len(), None);
+            table.push(2);
+            table.insert(1, 0);
+            assert_eq!(table.remove::<i32>(3), Some(2));
+            assert_eq!(table.raw_len(), 2);
+        }
+
+        let a: Vec<i32> = lua.get("a").unwrap();
+        assert_eq!(a, vec![0, 1]);
+    }
+
+    #[test]
+    fn raw_get_set() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.execute::<()>("
+            log = {}
+            a = setmetatable({}, {
+                __index = function(t, k) return 'default' end,
+                __newindex = function(t, k, v) log[#log + 1] = k end,
+            })").unwrap();
+
+        {
+            let mut table: LuaTable<_> = lua.get("a").unwrap();
+            assert_eq!(table.get::<String, _, _>("x"), Some("default".to_owned()));
+            assert_eq!(table.raw_get::<String, _, _>("x"), None);
+            assert!(table.contains_key("x"));
+
+            table.raw_set("x", "value");
+            assert_eq!(table.raw_get::<String, _, _>("x"), Some("value".to_owned()));
+            table.set("y", 12);
+        }
+
+        let log: Vec<String> = lua.get("log").unwrap();
+        assert_eq!(log, vec!["y".to_owned()]);
+    }
+
+    #[test]
+    fn contains_key() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { x = 1, y = false }").unwrap();
+
+        let mut table: LuaTable<_> = lua.get("a").unwrap();
+        assert!(table.contains_key("x"));
+        assert!(table.contains_key("y"));
+        assert!(!table.contains_key("z"));
+        assert!(!table.contains_key(1));
+    }
+
+    #[test]
+    fn push_insert_remove() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { 1, 2, 3 }").unwrap();
+
+        {
+            let mut table: LuaTable<_> = lua.get("a").unwrap();
+            table.push(4);
+            table.insert(1, 0);
+            table.insert(3, 10);
+            assert_eq!(table.remove::<i32>(4), Some(2));
+            assert_eq!(table.remove::<i32>(6), None);
+            assert_eq!(table.remove::<i32>(0), None);
+        }
+
+        let a: Vec<i32> = lua.get("a").unwrap();
+        assert_eq!(a, vec![0, 1, 10, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn insert_out_of_bounds() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { 1, 2, 3 }").unwrap();
+
+        let mut table: LuaTable<_> = lua.get("a").unwrap();
+        table.insert(5, 0);
+    }
+
+    #[test]
+    fn clear() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.execute::<()>("a = { 1, 2, 3, x = 4, [{}] = 5 }").unwrap();
+
+        {
+            let mut table: LuaTable<_> = lua.get("a").unwrap();
+            table.clear();
+            assert!(table.is_empty());
+        }
+
+        assert!(lua.execute::<bool>("return next(a) == nil").unwrap());
+    }
+
+    #[test]
+    fn helpers_keep_stack_balanced() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("a = { 1, 2, 3 }").unwrap();
+
+        {
+            let mut table: LuaTable<_> = lua.get("a").unwrap();
+            table.len();
+            table.raw_len();
+            table.is_empty();
+            table.contains_key(1);
+            table.push(4);
+            table.insert(1, 0);
+            table.remove::<i32>(1);
+            table.remove::<String>(2);
+            table.raw_set(1, 5);
+            table.clear();
+            table.push(1);
+        }
+
+        let top: i32 = unsafe { ffi::lua_gettop(lua.as_mut_lua().0) };
+        assert_eq!(top, 0);
+    }
 }
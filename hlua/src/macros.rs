@@ -17,26 +17,36 @@ macro_rules! implement_lua_push {
 #[macro_export]
 macro_rules! implement_lua_read {
     ($ty:ty) => {
-        impl<'s, 'c> hlua::LuaRead<&'c mut hlua::InsideCallback> for &'s mut $ty {
+        impl<'s, 'c> $crate::LuaRead<&'c mut $crate::InsideCallback> for &'s mut $ty {
             #[inline]
-            fn lua_read_at_position(lua: &'c mut hlua::InsideCallback, index: i32) -> Result<&'s mut $ty, &'c mut hlua::InsideCallback> {
+            fn lua_read_at_position(lua: &'c mut $crate::InsideCallback, index: i32) -> Result<&'s mut $ty, &'c mut $crate::InsideCallback> {
                 // FIXME:
                 unsafe { ::std::mem::transmute($crate::read_userdata::<$ty>(lua, index)) }
             }
+
+            #[inline]
+            fn lua_type_name() -> ::std::borrow::Cow<'static, str> {
+                ::std::borrow::Cow::Borrowed(stringify!($ty))
+            }
         }
 
-        impl<'s, 'c> hlua::LuaRead<&'c mut hlua::InsideCallback> for &'s $ty {
+        impl<'s, 'c> $crate::LuaRead<&'c mut $crate::InsideCallback> for &'s $ty {
             #[inline]
-            fn lua_read_at_position(lua: &'c mut hlua::InsideCallback, index: i32) -> Result<&'s $ty, &'c mut hlua::InsideCallback> {
+            fn lua_read_at_position(lua: &'c mut $crate::InsideCallback, index: i32) -> Result<&'s $ty, &'c mut $crate::InsideCallback> {
                 // FIXME:
                 unsafe { ::std::mem::transmute($crate::read_userdata::<$ty>(lua, index)) }
             }
+
+            #[inline]
+            fn lua_type_name() -> ::std::borrow::Cow<'static, str> {
+                ::std::borrow::Cow::Borrowed(stringify!($ty))
+            }
         }
 
-        impl<'s, 'b, 'c> hlua::LuaRead<&'b mut &'c mut hlua::InsideCallback> for &'s mut $ty {
+        impl<'s, 'b, 'c> $crate::LuaRead<&'b mut &'c mut $crate::InsideCallback> for &'s mut $ty {
             #[inline]
-            fn lua_read_at_position(lua: &'b mut &'c mut hlua::InsideCallback, index: i32) -> Result<&'s mut $ty, &'b mut &'c mut hlua::InsideCallback> {
-                let ptr_lua = lua as *mut &mut hlua::InsideCallback;
+            fn lua_read_at_position(lua: &'b mut &'c mut $crate::InsideCallback, index: i32) -> Result<&'s mut $ty, &'b mut &'c mut $crate::InsideCallback> {
+                let ptr_lua = lua as *mut &mut $crate::InsideCallback;
                 let deref_lua = unsafe { ::std::ptr::read(ptr_lua) };
                 let res = Self::lua_read_at_position(deref_lua, index);
                 match res {
@@ -44,12 +54,17 @@ macro_rules! implement_lua_read {
                     _ => Err(lua)
                 }
             }
+
+            #[inline]
+            fn lua_type_name() -> ::std::borrow::Cow<'static, str> {
+                ::std::borrow::Cow::Borrowed(stringify!($ty))
+            }
         }
 
-        impl<'s, 'b, 'c> hlua::LuaRead<&'b mut &'c mut hlua::InsideCallback> for &'s $ty {
+        impl<'s, 'b, 'c> $crate::LuaRead<&'b mut &'c mut $crate::InsideCallback> for &'s $ty {
             #[inline]
-            fn lua_read_at_position(lua: &'b mut &'c mut hlua::InsideCallback, index: i32) -> Result<&'s $ty, &'b mut &'c mut hlua::InsideCallback> {
-                let ptr_lua = lua as *mut &mut hlua::InsideCallback;
+            fn lua_read_at_position(lua: &'b mut &'c mut $crate::InsideCallback, index: i32) -> Result<&'s $ty, &'b mut &'c mut $crate::InsideCallback> {
+                let ptr_lua = lua as *mut &mut $crate::InsideCallback;
                 let deref_lua = unsafe { ::std::ptr::read(ptr_lua) };
                 let res = Self::lua_read_at_position(deref_lua, index);
                 match res {
@@ -57,6 +72,11 @@ macro_rules! implement_lua_read {
                     _ => Err(lua)
                 }
             }
+
+            #[inline]
+            fn lua_type_name() -> ::std::borrow::Cow<'static, str> {
+                ::std::borrow::Cow::Borrowed(stringify!($ty))
+            }
         }
     };
 }
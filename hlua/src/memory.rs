@@ -0,0 +1,185 @@
+use std::cmp;
+use std::ptr;
+
+use ffi;
+use libc;
+
+/// Memory accounting of a Lua context created by hlua.
+///
+/// A pointer to this struct is passed as the user data of the allocation function.
+struct Allocator {
+    used: usize,
+    peak: usize,
+    limit: Option<usize>,
+}
+
+// Allocation function of all the Lua contexts created by hlua.
+extern "C" fn alloc(ud: *mut libc::c_void,
+                    ptr: *mut libc::c_void,
+                    osize: libc::size_t,
+                    nsize: libc::size_t)
+                    -> *mut libc::c_void {
+    unsafe {
+        let allocator = &mut *(ud as *mut Allocator);
+
+        // When `ptr` is null, `osize` contains the type of the object being created instead of a
+        // size.
+        let osize = if ptr.is_null() { 0 } else { osize };
+
+        if nsize == 0 {
+            libc::free(ptr);
+            allocator.used -= osize;
+            return ptr::null_mut();
+        }
+
+        // Lua assumes that shrinking a block never fails, so we only check the limit when growing.
+        if nsize > osize {
+            if let Some(limit) = allocator.limit {
+                if allocator.used - osize + nsize > limit {
+                    return ptr::null_mut();
+                }
+            }
+        }
+
+        let new_ptr = libc::realloc(ptr, nsize);
+        if new_ptr.is_null() {
+            return ptr::null_mut();
+        }
+
+        allocator.used = allocator.used - osize + nsize;
+        allocator.peak = cmp::max(allocator.peak, allocator.used);
+        new_ptr
+    }
+}
+
+// Returns the allocator of the state, or `None` if the state wasn't created by `new_state`.
+unsafe fn get<'a>(lua: *mut ffi::lua_State) -> Option<&'a mut Allocator> {
+    let mut ud = ptr::null_mut();
+    let f = ffi::lua_getallocf(lua, &mut ud);
+    if f as usize == alloc as ffi::lua_Alloc as usize && !ud.is_null() {
+        Some(&mut *(ud as *mut Allocator))
+    } else {
+        None
+    }
+}
+
+/// Creates a new Lua state whose memory usage is tracked and optionally limited. Returns null if
+/// the creation failed.
+///
+/// The state must be destroyed with `close_state`.
+pub fn new_state(limit: Option<usize>) -> *mut ffi::lua_State {
+    let allocator = Box::new(Allocator {
+        used: 0,
+        peak: 0,
+        limit,
+    });
+    let ud = Box::into_raw(allocator);
+
+    unsafe {
+        let lua = ffi::lua_newstate(alloc, ud as *mut libc::c_void);
+        if lua.is_null() {
+            drop(Box::from_raw(ud));
+        }
+        lua
+    }
+}
+
+/// Closes a Lua state and destroys its allocator if it was created by `new_state`.
+pub unsafe fn close_state(lua: *mut ffi::lua_State) {
+    let allocator = get(lua).map(|a| a as *mut Allocator);
+    ffi::lua_close(lua);
+    if let Some(allocator) = allocator {
+        drop(Box::from_raw(allocator));
+    }
+}
+
+/// Changes the memory limit of a state. Returns false if the state wasn't created by `new_state`.
+pub fn set_limit(lua: *mut ffi::lua_State, limit: Option<usize>) -> bool {
+    match unsafe { get(lua) } {
+        Some(allocator) => {
+            allocator.limit = limit;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the number of bytes currently allocated by the state.
+pub fn used(lua: *mut ffi::lua_State) -> usize {
+    unsafe {
+        match get(lua) {
+            Some(allocator) => allocator.used,
+            // Fall back to the accounting of the garbage collector.
+            None => {
+                let kbytes = ffi::lua_gc(lua, ffi::LUA_GCCOUNT, 0) as usize;
+                let bytes = ffi::lua_gc(lua, ffi::LUA_GCCOUNTB, 0) as usize;
+                kbytes * 1024 + bytes
+            }
+        }
+    }
+}
+
+/// Returns the highest number of bytes that have been allocated at once by the state.
+pub fn peak(lua: *mut ffi::lua_State) -> usize {
+    match unsafe { get(lua) } {
+        Some(allocator) => allocator.peak,
+        None => used(lua),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Lua;
+    use LuaError;
+
+    #[test]
+    fn memory_limit_returns_error() {
+        let mut lua = Lua::with_memory_limit(256 * 1024);
+
+        let r = lua.execute::<()>(r#"
+            local t = {}
+            for i = 1, 10000000 do t[i] = i end
+        "#);
+        match r {
+            Err(LuaError::OutOfMemory) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn state_usable_after_out_of_memory() {
+        let mut lua = Lua::with_memory_limit(256 * 1024);
+        lua.execute::<()>("a = 12").unwrap();
+
+        assert!(lua.execute::<()>("local s = 'x'; while true do s = s .. s end").is_err());
+
+        let a: i32 = lua.execute("return a * 2").unwrap();
+        assert_eq!(a, 24);
+    }
+
+    #[test]
+    fn usage_is_tracked() {
+        let mut lua = Lua::new();
+        let before = lua.memory_used();
+        assert!(before > 0);
+
+        lua.execute::<()>("t = {} for i = 1, 10000 do t[i] = i end").unwrap();
+        assert!(lua.memory_used() > before);
+        assert!(lua.peak_memory_used() >= lua.memory_used());
+    }
+
+    #[test]
+    fn changing_the_limit() {
+        let mut lua = Lua::new();
+        assert!(lua.set_memory_limit(Some(lua.memory_used() + 16 * 1024)));
+
+        let code = "local t = {} for i = 1, 100000 do t[i] = i end";
+        match lua.execute::<()>(code) {
+            Err(LuaError::OutOfMemory) => (),
+            other => panic!("{:?}", other),
+        }
+
+        assert!(lua.set_memory_limit(None));
+        lua.execute::<()>(code).unwrap();
+    }
+}
@@ -0,0 +1,553 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+use ffi;
+use libc;
+
+use functions_write;
+use raw::{self, push_bytes, push_error, raise, string_at};
+use rust_errors;
+use AsLua;
+use Lua;
+use LuaFunction;
+use LuaRead;
+use LuaRef;
+use PushGuard;
+
+/// Writer that keeps in memory everything written to it.
+///
+/// All the clones of an `OutputCapture` share the same buffer. This makes it possible to give a
+/// clone to `Lua::set_output` and to read what the Lua code has printed with the original.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, OutputCapture};
+///
+/// let mut lua = Lua::new();
+/// lua.open_base();
+///
+/// let capture = OutputCapture::new();
+/// lua.set_output(Box::new(capture.clone()));
+///
+/// lua.execute::<()>("print('hello', 5)").unwrap();
+/// assert_eq!(capture.contents(), "hello\t5\n");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OutputCapture {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl OutputCapture {
+    /// Builds a new empty `OutputCapture`.
+    #[inline]
+    pub fn new() -> OutputCapture {
+        OutputCapture::default()
+    }
+
+    /// Returns a copy of what has been written so far.
+    #[inline]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.buffer.borrow().clone()
+    }
+
+    /// Returns what has been written so far as a string. Invalid UTF-8 sequences are replaced
+    /// with `U+FFFD REPLACEMENT CHARACTER`.
+    #[inline]
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).into_owned()
+    }
+
+    /// Erases what has been written so far.
+    #[inline]
+    pub fn clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+}
+
+impl Write for OutputCapture {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Standard stream of the Lua code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Writers that replace the standard streams of a state. `None` means that the stream isn't
+/// redirected.
+struct Outputs {
+    stdout: Option<Box<dyn Write>>,
+    stderr: Option<Box<dyn Write>>,
+}
+
+// The `Outputs` of a state are stored as a userdata in the registry, at the address of this
+// static.
+static REGISTRY_KEY: u8 = 0;
+// The metatable of the redirected `io.stdout` and `io.stderr` handles is stored in the registry,
+// at the address of this static.
+static HANDLE_METATABLE_KEY: u8 = 0;
+// The `io` table whose functions have been replaced is stored in the registry, at the address of
+// this static.
+static REDIRECTED_IO_KEY: u8 = 0;
+
+// Replaces the functions of the `io` library that use the standard output. Called with the
+// redirected `io.stdout` and `io.stderr` handles.
+const REDIRECT_IO: &str = r#"
+local stdout, stderr = ...
+local raw_output, raw_write, raw_flush, raw_close, raw_type =
+    io.output, io.write, io.flush, io.close, io.type
+
+-- Redirected handle used as the default output, or nil if `io.output` has been given a file.
+local current = stdout
+
+io.stdout, io.stderr = stdout, stderr
+
+if raw_output then
+    function io.output(file)
+        if file == nil then
+            return current or raw_output()
+        end
+        if file == stdout or file == stderr then
+            current = file
+            return file
+        end
+        current = nil
+        return raw_output(file)
+    end
+end
+
+function io.write(...)
+    if current then return current:write(...) end
+    return raw_write(...)
+end
+
+function io.flush()
+    if current then return current:flush() end
+    return raw_flush()
+end
+
+function io.close(file)
+    if file == nil then
+        if current then return current:close() end
+        return raw_close()
+    end
+    if file == stdout or file == stderr then return file:close() end
+    return raw_close(file)
+end
+
+function io.type(obj)
+    if obj == stdout or obj == stderr then return "file" end
+    return raw_type(obj)
+end
+"#;
+
+/// Redirects `stream` to `output`.
+pub fn set(lua: &mut Lua, stream: Stream, output: Box<dyn Write>) {
+    unsafe {
+        let outputs = &mut *get_or_create(lua.as_lua().0);
+        match stream {
+            Stream::Stdout => outputs.stdout = Some(output),
+            Stream::Stderr => outputs.stderr = Some(output),
+        }
+    }
+
+    install(lua)
+}
+
+/// If a stream has been redirected, replaces `print` and the `io` library, if they have been
+/// opened, with versions that use the redirected streams. Does nothing for the functions that
+/// have already been replaced.
+pub fn install(lua: &mut Lua) {
+    let raw_lua = lua.as_lua().0;
+
+    unsafe {
+        if get(raw_lua).is_null() {
+            return;
+        }
+
+        ffi::lua_getglobal(raw_lua, b"print\0".as_ptr() as *const _);
+        if !ffi::lua_isnil(raw_lua, -1) {
+            ffi::lua_pushcfunction(raw_lua, print);
+            ffi::lua_setglobal(raw_lua, b"print\0".as_ptr() as *const _);
+        }
+        ffi::lua_pop(raw_lua, 1);
+
+        ffi::lua_getglobal(raw_lua, b"io\0".as_ptr() as *const _);
+        ffi::lua_rawgetp(raw_lua, ffi::LUA_REGISTRYINDEX,
+                         &REDIRECTED_IO_KEY as *const u8 as *const _);
+        let redirected = !ffi::lua_istable(raw_lua, -2) || ffi::lua_rawequal(raw_lua, -1, -2) != 0;
+        ffi::lua_pop(raw_lua, 1);
+        if redirected {
+            ffi::lua_pop(raw_lua, 1);
+            return;
+        }
+        ffi::lua_rawsetp(raw_lua, ffi::LUA_REGISTRYINDEX,
+                         &REDIRECTED_IO_KEY as *const u8 as *const _);
+    }
+
+    let stdout = handle(lua, Stream::Stdout);
+    let stderr = handle(lua, Stream::Stderr);
+    let mut redirect = LuaFunction::load(lua, REDIRECT_IO).unwrap();
+    redirect.call_with_args::<(), _, _>((&stdout, &stderr))
+            .expect("failed to redirect the io library");
+}
+
+// Returns the `Outputs` of the state, or null if no stream has ever been redirected.
+unsafe fn get(lua: *mut ffi::lua_State) -> *mut Outputs {
+    raw::get(lua, &REGISTRY_KEY)
+}
+
+unsafe fn get_or_create(lua: *mut ffi::lua_State) -> *mut Outputs {
+    raw::get_or_insert_with(lua, &REGISTRY_KEY, || Outputs {
+        stdout: None,
+        stderr: None,
+    })
+}
+
+// Writes `data` to `stream`, or to the standard stream of the process if it isn't redirected.
+unsafe fn write(lua: *mut ffi::lua_State, stream: Stream, data: &[u8]) -> io::Result<()> {
+    let outputs = get(lua);
+    let output = if outputs.is_null() {
+        None
+    } else {
+        match stream {
+            Stream::Stdout => (*outputs).stdout.as_mut(),
+            Stream::Stderr => (*outputs).stderr.as_mut(),
+        }
+    };
+
+    match (output, stream) {
+        (Some(output), _) => output.write_all(data),
+        (None, Stream::Stdout) => io::stdout().write_all(data),
+        (None, Stream::Stderr) => io::stderr().write_all(data),
+    }
+}
+
+unsafe fn flush(lua: *mut ffi::lua_State, stream: Stream) -> io::Result<()> {
+    let outputs = get(lua);
+    let output = if outputs.is_null() {
+        None
+    } else {
+        match stream {
+            Stream::Stdout => (*outputs).stdout.as_mut(),
+            Stream::Stderr => (*outputs).stderr.as_mut(),
+        }
+    };
+
+    match (output, stream) {
+        (Some(output), _) => output.flush(),
+        (None, Stream::Stdout) => io::stdout().flush(),
+        (None, Stream::Stderr) => io::stderr().flush(),
+    }
+}
+
+// Runs `f`, which writes to a stream. Raises a Lua error if it panics.
+unsafe fn catch_write<F>(lua: *mut ffi::lua_State, f: F) -> io::Result<()>
+    where F: FnOnce() -> io::Result<()>
+{
+    match rust_errors::catch(lua, f) {
+        Some(result) => result,
+        None => {
+            ffi::lua_error(lua);
+            unreachable!()
+        }
+    }
+}
+
+// Replacement for `print`. Like the original, it ignores writing errors.
+extern "C" fn print(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let num_args = ffi::lua_gettop(lua);
+        ffi::lua_getglobal(lua, b"tostring\0".as_ptr() as *const _);
+
+        for i in 1..=num_args {
+            ffi::lua_pushvalue(lua, -1);
+            ffi::lua_pushvalue(lua, i);
+            ffi::lua_call(lua, 1, 1);
+            if ffi::lua_tolstring(lua, -1, ptr::null_mut()).is_null() {
+                raise(lua, "'tostring' must return a string to 'print'".to_owned());
+            }
+
+            let value = string_at(lua, -1);
+            let _ = catch_write(lua, || {
+                if i > 1 {
+                    write(lua, Stream::Stdout, b"\t")?;
+                }
+                write(lua, Stream::Stdout, value)
+            });
+            ffi::lua_pop(lua, 1);
+        }
+
+        let _ = catch_write(lua, || write(lua, Stream::Stdout, b"\n"));
+        0
+    }
+}
+
+// Pushes a new handle that writes to `stream` and returns a reference to it.
+fn handle(lua: &mut Lua, stream: Stream) -> LuaRef {
+    let raw_lua = lua.as_lua();
+
+    unsafe {
+        let data = ffi::lua_newuserdata(raw_lua.0, mem::size_of::<Stream>() as libc::size_t);
+        ptr::write(data as *mut Stream, stream);
+
+        ffi::lua_rawgetp(raw_lua.0, ffi::LUA_REGISTRYINDEX,
+                         &HANDLE_METATABLE_KEY as *const u8 as *const _);
+        if ffi::lua_isnil(raw_lua.0, -1) {
+            ffi::lua_pop(raw_lua.0, 1);
+            push_handle_metatable(raw_lua.0);
+        }
+        ffi::lua_setmetatable(raw_lua.0, -2);
+    }
+
+    let guard = PushGuard {
+        lua,
+        size: 1,
+        raw_lua,
+    };
+    LuaRead::lua_read(guard).ok().unwrap()
+}
+
+unsafe fn push_handle_metatable(lua: *mut ffi::lua_State) {
+    ffi::lua_createtable(lua, 0, 2);
+
+    ffi::lua_createtable(lua, 0, 4);
+    ffi::lua_pushcfunction(lua, handle_write);
+    ffi::lua_setfield(lua, -2, b"write\0".as_ptr() as *const _);
+    ffi::lua_pushcfunction(lua, handle_flush);
+    ffi::lua_setfield(lua, -2, b"flush\0".as_ptr() as *const _);
+    ffi::lua_pushcfunction(lua, handle_close);
+    ffi::lua_setfield(lua, -2, b"close\0".as_ptr() as *const _);
+    ffi::lua_pushcfunction(lua, handle_setvbuf);
+    ffi::lua_setfield(lua, -2, b"setvbuf\0".as_ptr() as *const _);
+    ffi::lua_setfield(lua, -2, b"__index\0".as_ptr() as *const _);
+
+    ffi::lua_pushcfunction(lua, handle_tostring);
+    ffi::lua_setfield(lua, -2, b"__tostring\0".as_ptr() as *const _);
+
+    ffi::lua_pushvalue(lua, -1);
+    ffi::lua_rawsetp(lua, ffi::LUA_REGISTRYINDEX, &HANDLE_METATABLE_KEY as *const u8 as *const _);
+}
+
+// Returns the stream of the handle passed as first parameter, or raises an error if it isn't a
+// redirected handle.
+unsafe fn check_handle(lua: *mut ffi::lua_State) -> Stream {
+    if ffi::lua_type(lua, 1) == ffi::LUA_TUSERDATA && ffi::lua_getmetatable(lua, 1) != 0 {
+        ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX,
+                         &HANDLE_METATABLE_KEY as *const u8 as *const _);
+        let is_handle = ffi::lua_rawequal(lua, -1, -2) != 0;
+        ffi::lua_pop(lua, 2);
+        if is_handle {
+            return *(ffi::lua_touserdata(lua, 1) as *const Stream);
+        }
+    }
+
+    raise(lua, functions_write::argument_error(lua, 1, "FILE*"))
+}
+
+extern "C" fn handle_write(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let stream = check_handle(lua);
+        let num_args = ffi::lua_gettop(lua);
+
+        for i in 2..=num_args {
+            let ty = ffi::lua_type(lua, i);
+            if ty != ffi::LUA_TSTRING && ty != ffi::LUA_TNUMBER {
+                raise(lua, functions_write::argument_error(lua, i, "string"));
+            }
+        }
+
+        let result = catch_write(lua, || {
+            for i in 2..=num_args {
+                write(lua, stream, string_at(lua, i))?;
+            }
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => {
+                ffi::lua_pushvalue(lua, 1);
+                1
+            }
+            Err(err) => push_error(lua, &err.to_string()),
+        }
+    }
+}
+
+extern "C" fn handle_flush(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let stream = check_handle(lua);
+        match catch_write(lua, || flush(lua, stream)) {
+            Ok(()) => {
+                ffi::lua_pushboolean(lua, 1);
+                1
+            }
+            Err(err) => push_error(lua, &err.to_string()),
+        }
+    }
+}
+
+extern "C" fn handle_close(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        check_handle(lua);
+        push_error(lua, "cannot close standard file")
+    }
+}
+
+extern "C" fn handle_setvbuf(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        check_handle(lua);
+        ffi::lua_pushboolean(lua, 1);
+        1
+    }
+}
+
+extern "C" fn handle_tostring(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let msg: &[u8] = match check_handle(lua) {
+            Stream::Stdout => b"file (stdout)",
+            Stream::Stderr => b"file (stderr)",
+        };
+        push_bytes(lua, msg);
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+
+    use Lua;
+    use OutputCapture;
+
+    #[test]
+    fn print_is_redirected() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        let capture = lua.capture_output();
+
+        lua.execute::<()>("print('a', 1, nil, true) print()").unwrap();
+        assert_eq!(capture.contents(), "a\t1\tnil\ttrue\n\n");
+    }
+
+    #[test]
+    fn print_uses_tostring() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        let capture = lua.capture_output();
+
+        lua.execute::<()>("print(setmetatable({}, { __tostring = function() return 'obj' end }))")
+           .unwrap();
+        assert_eq!(capture.contents(), "obj\n");
+    }
+
+    #[test]
+    fn io_write_is_redirected() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let capture = lua.capture_output();
+
+        lua.execute::<()>("io.write('a', 1, '\\n') io.stdout:write('b'):write('c') io.flush()")
+           .unwrap();
+        assert_eq!(capture.contents(), "a1\nbc");
+
+        let ty: String = lua.execute("return io.type(io.stdout)").unwrap();
+        assert_eq!(ty, "file");
+        let output: bool = lua.execute("return io.output() == io.stdout").unwrap();
+        assert!(output);
+    }
+
+    #[test]
+    fn stderr_is_redirected() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let out = lua.capture_output();
+        let err = OutputCapture::new();
+        lua.set_error_output(Box::new(err.clone()));
+
+        lua.execute::<()>("io.stderr:write('oops') io.output(io.stderr) io.write('!')").unwrap();
+        assert_eq!(out.contents(), "");
+        assert_eq!(err.contents(), "oops!");
+    }
+
+    #[test]
+    fn libraries_opened_afterwards_are_redirected() {
+        let mut lua = Lua::new();
+        let capture = lua.capture_output();
+        lua.openlibs();
+
+        lua.execute::<()>("print('a') io.write('b')").unwrap();
+        assert_eq!(capture.contents(), "a\nb");
+    }
+
+    #[test]
+    fn write_wrong_argument() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.capture_output();
+
+        match lua.execute::<()>("io.write({})") {
+            Err(err) => assert!(err.to_string().contains("bad argument #1 to 'write'")),
+            Ok(_) => panic!(),
+        }
+        let closed: (Option<bool>, String) = lua.execute("return io.stdout:close()").unwrap();
+        assert_eq!(closed, (None, "cannot close standard file".to_owned()));
+        let closed: (Option<bool>, String) = lua.execute("return io.close()").unwrap();
+        assert_eq!(closed, (None, "cannot close standard file".to_owned()));
+    }
+
+    #[test]
+    fn close_default_output_file() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.capture_output();
+
+        let path = env::temp_dir().join("hlua-close-default-output.txt");
+        lua.set("path", path.to_str().unwrap());
+        let closed: bool = lua.execute("io.output(path) io.write('a') return io.close()").unwrap();
+        assert!(closed);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_error() {
+        struct Failing;
+        impl Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_output(Box::new(Failing));
+
+        let (ok, msg): (Option<bool>, String) = lua.execute("return io.write('a')").unwrap();
+        assert_eq!(ok, None);
+        assert_eq!(msg, "disk full");
+
+        // Like the original, `print` ignores the errors.
+        lua.execute::<()>("print('a')").unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Helpers for the modules that use the Lua C API directly.
+
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use ffi;
+use libc;
+
+/// Returns the Rust value stored in the registry at the address of `key`, or null if there is
+/// none.
+pub unsafe fn get<T>(lua: *mut ffi::lua_State, key: &'static u8) -> *mut T {
+    ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, key as *const u8 as *const _);
+    let data = ffi::lua_touserdata(lua, -1) as *mut T;
+    ffi::lua_pop(lua, 1);
+    data
+}
+
+/// Stores a Rust value in the registry at the address of `key`, and returns a pointer to it.
+///
+/// The value is dropped when the Lua context is closed, or after it has been replaced by another
+/// call to this function.
+pub unsafe fn insert<T>(lua: *mut ffi::lua_State, key: &'static u8, value: T) -> *mut T {
+    let data = ffi::lua_newuserdata(lua, mem::size_of::<T>() as libc::size_t) as *mut T;
+    ptr::write(data, value);
+
+    ffi::lua_createtable(lua, 0, 1);
+    ffi::lua_pushcfunction(lua, destructor::<T>);
+    ffi::lua_setfield(lua, -2, b"__gc\0".as_ptr() as *const _);
+    ffi::lua_setmetatable(lua, -2);
+
+    ffi::lua_rawsetp(lua, ffi::LUA_REGISTRYINDEX, key as *const u8 as *const _);
+    data
+}
+
+/// Returns the Rust value stored in the registry at the address of `key`, storing the value
+/// returned by `init` first if there is none.
+pub unsafe fn get_or_insert_with<T, F>(lua: *mut ffi::lua_State, key: &'static u8, init: F)
+                                       -> *mut T
+    where F: FnOnce() -> T
+{
+    let data = get::<T>(lua, key);
+    if data.is_null() {
+        insert(lua, key, init())
+    } else {
+        data
+    }
+}
+
+/// `__gc` metamethod that drops the Rust value of type `T` at the start of a userdata.
+pub extern "C" fn destructor<T>(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        drop_ignoring_panics(ffi::lua_touserdata(lua, 1) as *mut T);
+        0
+    }
+}
+
+/// Drops a value owned by Lua, from a `__gc` metamethod.
+///
+/// A panic can't go through Lua, and raising an error from a `__gc` metamethod would make the
+/// allocation that triggered the garbage collection fail, which may happen outside of any
+/// protected call. There is no one to report it to, so it is ignored.
+pub unsafe fn drop_ignoring_panics<T>(data: *mut T) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| ptr::drop_in_place(data)));
+}
+
+/// Returns the string at `index`. Numbers are converted in place.
+pub unsafe fn string_at<'a>(lua: *mut ffi::lua_State, index: libc::c_int) -> &'a [u8] {
+    let mut len = 0;
+    let data = ffi::lua_tolstring(lua, index, &mut len);
+    slice::from_raw_parts(data as *const u8, len)
+}
+
+/// Pushes a string that may contain any byte.
+pub unsafe fn push_bytes(lua: *mut ffi::lua_State, bytes: &[u8]) {
+    ffi::lua_pushlstring(lua, bytes.as_ptr() as *const _, bytes.len() as libc::size_t);
+}
+
+/// Pushes `nil` and the error message, like the functions of the `io` library do. Returns the
+/// number of pushed values.
+pub unsafe fn push_error(lua: *mut ffi::lua_State, message: &str) -> libc::c_int {
+    ffi::lua_pushnil(lua);
+    push_bytes(lua, message.as_bytes());
+    2
+}
+
+/// Raises a Lua error with `message`.
+pub unsafe fn raise(lua: *mut ffi::lua_State, message: String) -> ! {
+    push_bytes(lua, message.as_bytes());
+    drop(message);
+    ffi::lua_error(lua);
+    unreachable!()
+}
@@ -0,0 +1,162 @@
+use std::any::Any;
+use std::error::Error;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use ffi;
+use libc;
+
+use raw;
+
+// The metatable of the error objects is stored in the registry, at the address of this static. It
+// also contains the `resume` setting of the state.
+static REGISTRY_KEY: u8 = 0;
+
+/// Error object that carries a Rust value through a Lua error.
+struct RustError {
+    // `None` once the content has been taken back by Rust.
+    content: Option<Content>,
+    message: String,
+}
+
+enum Content {
+    Panic(Box<dyn Any + Send>),
+    Error(Box<dyn Error + Send + Sync>),
+}
+
+/// Runs `f` and catches any panic that happens inside.
+///
+/// If `f` panics, an error object containing the panic is pushed on the stack and `None` is
+/// returned. The caller must then call `lua_error`, once there is nothing left to destroy in its
+/// frame.
+pub fn catch<F, R>(lua: *mut ffi::lua_State, f: F) -> Option<R>
+    where F: FnOnce() -> R
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+            let message = if let Some(msg) = payload.downcast_ref::<&str>() {
+                format!("panic in Rust callback: {}", msg)
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                format!("panic in Rust callback: {}", msg)
+            } else {
+                "panic in Rust callback".to_owned()
+            };
+
+            unsafe { push(lua, Content::Panic(payload), message) };
+            None
+        }
+    }
+}
+
+/// Pushes an error object containing a Rust error. Its string representation is the message of
+/// the error.
+pub unsafe fn push_error(lua: *mut ffi::lua_State, error: Box<dyn Error + Send + Sync>) {
+    let message = error.to_string();
+    push(lua, Content::Error(error), message);
+}
+
+/// Sets whether panics should be resumed once they reach the Rust code that started running the
+/// Lua code.
+pub fn set_resume(lua: *mut ffi::lua_State, resume: bool) {
+    unsafe {
+        push_metatable(lua);
+        ffi::lua_pushboolean(lua, resume as libc::c_int);
+        ffi::lua_setfield(lua, -2, b"resume\0".as_ptr() as *const _);
+        ffi::lua_pop(lua, 1);
+    }
+}
+
+/// If the value at `index` is a panic and the state is configured to resume panics, returns the
+/// payload of the panic. The caller is responsible for calling `resume_unwind`.
+pub unsafe fn take_resumable_panic(lua: *mut ffi::lua_State, index: libc::c_int)
+                                   -> Option<Box<dyn Any + Send>>
+{
+    let data = get(lua, index)?;
+
+    push_metatable(lua);
+    ffi::lua_getfield(lua, -1, b"resume\0".as_ptr() as *const _);
+    let resume = ffi::lua_toboolean(lua, -1) != 0;
+    ffi::lua_pop(lua, 2);
+    if !resume {
+        return None;
+    }
+
+    match (*data).content.take() {
+        Some(Content::Panic(payload)) => Some(payload),
+        other => {
+            (*data).content = other;
+            None
+        }
+    }
+}
+
+/// If the value at `index` contains a Rust error, takes it back.
+pub unsafe fn take_error(lua: *mut ffi::lua_State, index: libc::c_int)
+                         -> Option<Box<dyn Error + Send + Sync>>
+{
+    let data = get(lua, index)?;
+
+    match (*data).content.take() {
+        Some(Content::Error(error)) => Some(error),
+        other => {
+            (*data).content = other;
+            None
+        }
+    }
+}
+
+unsafe fn push(lua: *mut ffi::lua_State, content: Content, message: String) {
+    let data = ffi::lua_newuserdata(lua, mem::size_of::<RustError>() as libc::size_t);
+    ptr::write(data as *mut RustError, RustError {
+        content: Some(content),
+        message,
+    });
+    push_metatable(lua);
+    ffi::lua_setmetatable(lua, -2);
+}
+
+// Returns the error object at `index`, or `None` if the value isn't one.
+unsafe fn get(lua: *mut ffi::lua_State, index: libc::c_int) -> Option<*mut RustError> {
+    let index = ffi::lua_absindex(lua, index);
+    if ffi::lua_type(lua, index) != ffi::LUA_TUSERDATA || ffi::lua_getmetatable(lua, index) == 0 {
+        return None;
+    }
+
+    ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, &REGISTRY_KEY as *const u8 as *const _);
+    let is_rust_error = ffi::lua_rawequal(lua, -1, -2) != 0;
+    ffi::lua_pop(lua, 2);
+
+    if is_rust_error {
+        Some(ffi::lua_touserdata(lua, index) as *mut RustError)
+    } else {
+        None
+    }
+}
+
+// Pushes the metatable of the error objects, creating it if necessary.
+unsafe fn push_metatable(lua: *mut ffi::lua_State) {
+    ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, &REGISTRY_KEY as *const u8 as *const _);
+    if !ffi::lua_isnil(lua, -1) {
+        return;
+    }
+    ffi::lua_pop(lua, 1);
+
+    ffi::lua_createtable(lua, 0, 3);
+    ffi::lua_pushcfunction(lua, raw::destructor::<RustError>);
+    ffi::lua_setfield(lua, -2, b"__gc\0".as_ptr() as *const _);
+    ffi::lua_pushcfunction(lua, tostring);
+    ffi::lua_setfield(lua, -2, b"__tostring\0".as_ptr() as *const _);
+    ffi::lua_pushvalue(lua, -1);
+    ffi::lua_rawsetp(lua, ffi::LUA_REGISTRYINDEX, &REGISTRY_KEY as *const u8 as *const _);
+}
+
+extern "C" fn tostring(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let data = ffi::lua_touserdata(lua, 1) as *const RustError;
+        let message = &(*data).message;
+        ffi::lua_pushlstring(lua, message.as_ptr() as *const _, message.len() as libc::size_t);
+        1
+    }
+}
@@ -1,5 +1,4 @@
 use ffi;
-use any::{AnyLuaValue, AnyHashableLuaValue};
 
 use Push;
 use PushGuard;
@@ -7,10 +6,14 @@ use PushOne;
 use AsMutLua;
 use TuplePushError;
 use LuaRead;
+use Strict;
 
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::hash::Hash;
 use std::iter;
+use std::marker::PhantomData;
 
 #[inline]
 fn push_iter<'lua, L, V, I, E>(mut lua: L, iterator: I) -> Result<PushGuard<L>, (E, L)>
@@ -101,78 +104,6 @@ impl<'lua, L, T, E> PushOne<L> for Vec<T>
 {
 }
 
-impl<'lua, L> LuaRead<L> for Vec<AnyLuaValue>
-    where L: AsMutLua<'lua>
-{
-    fn lua_read_at_position(lua: L, index: i32) -> Result<Self, L> {
-        // We need this as iteration order isn't guaranteed to match order of
-        // keys, even if they're numeric
-        // https://www.lua.org/manual/5.2/manual.html#pdf-next
-        let mut dict: BTreeMap<i32, AnyLuaValue> = BTreeMap::new();
-
-        let mut me = lua;
-        unsafe { ffi::lua_pushnil(me.as_mut_lua().0) };
-        let index = index - 1;
-
-        loop {
-            if unsafe { ffi::lua_next(me.as_mut_lua().0, index) } == 0 {
-                break;
-            }
-
-            let key = {
-                let maybe_key: Option<i32> =
-                    LuaRead::lua_read_at_position(&mut me, -2).ok();
-                match maybe_key {
-                    None => {
-                        // Cleaning up after ourselves
-                        unsafe { ffi::lua_pop(me.as_mut_lua().0, 2) };
-                        return Err(me)
-                    }
-                    Some(k) => k,
-                }
-            };
-
-            let value: AnyLuaValue =
-                LuaRead::lua_read_at_position(&mut me, -1).ok().unwrap();
-
-            unsafe { ffi::lua_pop(me.as_mut_lua().0, 1) };
-
-            dict.insert(key, value);
-        }
-
-        let (maximum_key, minimum_key) =
-            (*dict.keys().max().unwrap_or(&1), *dict.keys().min().unwrap_or(&1));
-
-        if minimum_key != 1 {
-            // Rust doesn't support sparse arrays or arrays with negative
-            // indices
-            return Err(me);
-        }
-
-        let mut result =
-            Vec::with_capacity(maximum_key as usize);
-
-        // We expect to start with first element of table and have this
-        // be smaller that first key by one
-        let mut previous_key = 0;
-
-        // By this point, we actually iterate the map to move values to Vec
-        // and check that table represented non-sparse 1-indexed array
-        for (k, v) in dict {
-            if previous_key + 1 != k {
-                return Err(me)
-            } else {
-                // We just push, thus converting Lua 1-based indexing
-                // to Rust 0-based indexing
-                result.push(v);
-                previous_key = k;
-            }
-        }
-
-        Ok(result)
-    }
-}
-
 impl<'a, 'lua, L, T, E> Push<L> for &'a [T]
     where L: AsMutLua<'lua>,
           T: Clone + for<'b> Push<&'b mut L, Err = E>
@@ -191,46 +122,6 @@ impl<'a, 'lua, L, T, E> PushOne<L> for &'a [T]
 {
 }
 
-impl<'lua, L> LuaRead<L> for HashMap<AnyHashableLuaValue, AnyLuaValue>
-    where L: AsMutLua<'lua>
-{
-    // TODO: this should be implemented using the LuaTable API instead of raw Lua calls.
-    fn lua_read_at_position(lua: L, index: i32) -> Result<Self, L> {
-        let mut me = lua;
-        unsafe { ffi::lua_pushnil(me.as_mut_lua().0) };
-        let index = index - 1;
-        let mut result = HashMap::new();
-
-        loop {
-            if unsafe { ffi::lua_next(me.as_mut_lua().0, index) } == 0 {
-                break;
-            }
-
-            let key = {
-                let maybe_key: Option<AnyHashableLuaValue> =
-                    LuaRead::lua_read_at_position(&mut me, -2).ok();
-                match maybe_key {
-                    None => {
-                        // Cleaning up after ourselves
-                        unsafe { ffi::lua_pop(me.as_mut_lua().0, 2) };
-                        return Err(me)
-                    }
-                    Some(k) => k,
-                }
-            };
-
-            let value: AnyLuaValue =
-                LuaRead::lua_read_at_position(&mut me, -1).ok().unwrap();
-
-            unsafe { ffi::lua_pop(me.as_mut_lua().0, 1) };
-
-            result.insert(key, value);
-        }
-
-        Ok(result)
-    }
-}
-
 // TODO: use an enum for the error to allow different error types for K and V
 impl<'lua, L, K, V, E> Push<L> for HashMap<K, V>
     where L: AsMutLua<'lua>,
@@ -278,13 +169,321 @@ impl<'lua, L, K, E> PushOne<L> for HashSet<K>
 {
 }
 
+// Reason why a table couldn't be read as a collection.
+enum ReadFailure {
+    NotATable,
+    // A key couldn't be read.
+    Key,
+    // A value couldn't be read.
+    Value,
+    // The entry was refused by the collection, for example because of a duplicate key.
+    Refused,
+    // The keys of a sequence don't go from 1 to its length.
+    Sparse,
+}
+
+// Reads all the entries of the table at `index` and passes them to `insert`, which returns false
+// to refuse an entry. Stops at the first failure.
+fn read_entries<'lua, L, K, V, F>(lua: &mut L, index: i32, mut insert: F) -> Result<(), ReadFailure>
+    where L: AsMutLua<'lua>,
+          K: for<'a> LuaRead<&'a mut L>,
+          V: for<'a> LuaRead<&'a mut L>,
+          F: FnMut(K, V) -> bool
+{
+    let raw_lua = lua.as_mut_lua().0;
+    if unsafe { ffi::lua_type(raw_lua, index) } != ffi::LUA_TTABLE {
+        return Err(ReadFailure::NotATable);
+    }
+
+    let index = unsafe { ffi::lua_absindex(raw_lua, index) };
+    unsafe { ffi::lua_pushnil(raw_lua) };
+    while unsafe { ffi::lua_next(raw_lua, index) } != 0 {
+        // The key is read from a copy, as reading a number as a string converts it in place,
+        // which would confuse `lua_next`.
+        unsafe { ffi::lua_pushvalue(raw_lua, -2) };
+        let key = K::lua_read_at_position(&mut *lua, -1).ok();
+        let value = V::lua_read_at_position(&mut *lua, -2).ok();
+        unsafe { ffi::lua_pop(raw_lua, 2) };
+
+        let failure = match (key, value) {
+            (None, _) => ReadFailure::Key,
+            (_, None) => ReadFailure::Value,
+            (Some(key), Some(value)) => if insert(key, value) { continue } else { ReadFailure::Refused },
+        };
+
+        // Cleaning up after ourselves
+        unsafe { ffi::lua_pop(raw_lua, 1) };
+        return Err(failure);
+    }
+
+    Ok(())
+}
+
+// Reads the table at `index` as a sequence, whose keys go from 1 to its length.
+fn read_sequence<'lua, L, T>(lua: &mut L, index: i32) -> Result<Vec<T>, ReadFailure>
+    where L: AsMutLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L>
+{
+    // We need this as iteration order isn't guaranteed to match order of
+    // keys, even if they're numeric
+    // https://www.lua.org/manual/5.2/manual.html#pdf-next
+    let mut dict = BTreeMap::new();
+    read_entries(lua, index, |Strict(key): Strict<i64>, value: T| {
+        key >= 1 && dict.insert(key, value).is_none()
+    })?;
+
+    // The keys are unique and positive, so they go from 1 to the length of the sequence if the
+    // last one is the length.
+    match dict.keys().next_back() {
+        Some(&last) if last as usize != dict.len() => Err(ReadFailure::Sparse),
+        _ => Ok(dict.into_values().collect()),
+    }
+}
+
+// Describes what was expected when reading a sequence failed.
+fn sequence_expected<T>(failure: ReadFailure) -> Cow<'static, str>
+    where T: LuaReadName
+{
+    match failure {
+        ReadFailure::NotATable => Cow::Borrowed("table"),
+        ReadFailure::Value => Cow::Owned(format!("sequence of {}", T::name())),
+        ReadFailure::Key | ReadFailure::Refused | ReadFailure::Sparse => Cow::Borrowed("sequence"),
+    }
+}
+
+// Describes what was expected when reading a map failed.
+fn map_expected<K, V>(failure: ReadFailure) -> Cow<'static, str>
+    where K: LuaReadName,
+          V: LuaReadName
+{
+    match failure {
+        ReadFailure::NotATable => Cow::Borrowed("table"),
+        ReadFailure::Key => Cow::Owned(format!("table with {} keys", K::name())),
+        ReadFailure::Value => Cow::Owned(format!("table with {} values", V::name())),
+        ReadFailure::Refused | ReadFailure::Sparse => Cow::Borrowed("table with unique keys"),
+    }
+}
+
+// Describes what was expected when reading a set failed.
+fn set_expected<T>(failure: ReadFailure) -> Cow<'static, str>
+    where T: LuaReadName
+{
+    match failure {
+        ReadFailure::NotATable => Cow::Borrowed("table"),
+        _ => Cow::Owned(format!("set of {}", T::name())),
+    }
+}
+
+// `LuaRead::lua_type_name` of a type, without having to name the `L` parameter.
+trait LuaReadName {
+    fn name() -> Cow<'static, str>;
+}
+
+struct NameOf<T, L>(PhantomData<(T, L)>);
+
+impl<T, L> LuaReadName for NameOf<T, L>
+    where T: LuaRead<L>
+{
+    #[inline]
+    fn name() -> Cow<'static, str> {
+        T::lua_type_name()
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for Vec<T>
+    where L: AsMutLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<Vec<T>, L> {
+        read_sequence(&mut lua, index).map_err(|_| lua)
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        match read_sequence::<_, T>(&mut lua, index) {
+            Ok(_) => (0, Self::lua_type_name()),
+            Err(failure) => (0, sequence_expected::<NameOf<T, &mut L>>(failure)),
+        }
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for VecDeque<T>
+    where L: AsMutLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L>
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<VecDeque<T>, L> {
+        Vec::lua_read_at_position(lua, index).map(VecDeque::from)
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        <Vec<T> as LuaRead<L>>::lua_read_error(lua, index)
+    }
+}
+
+impl<'lua, L, T, const N: usize> LuaRead<L> for [T; N]
+    where L: AsMutLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<[T; N], L> {
+        match read_sequence(&mut lua, index).map(<[T; N]>::try_from) {
+            Ok(Ok(array)) => Ok(array),
+            _ => Err(lua),
+        }
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        match read_sequence::<_, T>(&mut lua, index) {
+            Ok(_) => (0, Cow::Owned(format!("sequence of {} elements", N))),
+            Err(failure) => (0, sequence_expected::<NameOf<T, &mut L>>(failure)),
+        }
+    }
+}
+
+impl<'lua, L, K, V> LuaRead<L> for HashMap<K, V>
+    where L: AsMutLua<'lua>,
+          K: for<'a> LuaRead<&'a mut L> + Eq + Hash,
+          V: for<'a> LuaRead<&'a mut L>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<HashMap<K, V>, L> {
+        let mut result = HashMap::new();
+        match read_entries(&mut lua, index, |k, v| result.insert(k, v).is_none()) {
+            Ok(()) => Ok(result),
+            Err(_) => Err(lua),
+        }
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        let mut result = HashMap::new();
+        match read_entries(&mut lua, index, |k: K, v: V| result.insert(k, v).is_none()) {
+            Ok(()) => (0, Self::lua_type_name()),
+            Err(failure) => (0, map_expected::<NameOf<K, &mut L>, NameOf<V, &mut L>>(failure)),
+        }
+    }
+}
+
+impl<'lua, L, K, V> LuaRead<L> for BTreeMap<K, V>
+    where L: AsMutLua<'lua>,
+          K: for<'a> LuaRead<&'a mut L> + Ord,
+          V: for<'a> LuaRead<&'a mut L>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<BTreeMap<K, V>, L> {
+        let mut result = BTreeMap::new();
+        match read_entries(&mut lua, index, |k, v| result.insert(k, v).is_none()) {
+            Ok(()) => Ok(result),
+            Err(_) => Err(lua),
+        }
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        let mut result = BTreeMap::new();
+        match read_entries(&mut lua, index, |k: K, v: V| result.insert(k, v).is_none()) {
+            Ok(()) => (0, Self::lua_type_name()),
+            Err(failure) => (0, map_expected::<NameOf<K, &mut L>, NameOf<V, &mut L>>(failure)),
+        }
+    }
+}
+
+// Sets are tables whose keys are the elements and whose values are `true`, like the ones pushed
+// from a `HashSet`.
+impl<'lua, L, T> LuaRead<L> for HashSet<T>
+    where L: AsMutLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L> + Eq + Hash
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<HashSet<T>, L> {
+        let mut result = HashSet::new();
+        match read_entries(&mut lua, index, |k, Strict(v): Strict<bool>| v && result.insert(k)) {
+            Ok(()) => Ok(result),
+            Err(_) => Err(lua),
+        }
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        let mut result = HashSet::new();
+        match read_entries(&mut lua, index, |k: T, Strict(v): Strict<bool>| v && result.insert(k)) {
+            Ok(()) => (0, Self::lua_type_name()),
+            Err(failure) => (0, set_expected::<NameOf<T, &mut L>>(failure)),
+        }
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for BTreeSet<T>
+    where L: AsMutLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L> + Ord
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<BTreeSet<T>, L> {
+        let mut result = BTreeSet::new();
+        match read_entries(&mut lua, index, |k, Strict(v): Strict<bool>| v && result.insert(k)) {
+            Ok(()) => Ok(result),
+            Err(_) => Err(lua),
+        }
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("table")
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        let mut result = BTreeSet::new();
+        match read_entries(&mut lua, index, |k: T, Strict(v): Strict<bool>| v && result.insert(k)) {
+            Ok(()) => (0, Self::lua_type_name()),
+            Err(failure) => (0, set_expected::<NameOf<T, &mut L>>(failure)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::{HashMap, HashSet, BTreeMap};
+    use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet, VecDeque};
     use Lua;
+    use LuaError;
     use LuaTable;
     use AnyLuaValue;
     use AnyHashableLuaValue;
+    use function1;
 
     #[test]
     fn write() {
@@ -360,7 +559,7 @@ mod tests {
 
         lua.set("v", &orig[..]);
 
-        let read: Vec<_> = lua.get("v").unwrap();
+        let read: Vec<AnyLuaValue> = lua.get("v").unwrap();
         for (o, r) in orig.iter().zip(read.iter()) {
             if let AnyLuaValue::LuaNumber(ref n) = *r {
                 assert_eq!(o, n);
@@ -376,7 +575,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { [-1] = -1, [2] = 2, [42] = 42 }"#).unwrap();
 
-        let read: Option<Vec<_>> = lua.get("v");
+        let read: Option<Vec<AnyLuaValue>> = lua.get("v");
         if read.is_some() {
             panic!("Unexpected success");
         }
@@ -388,7 +587,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { }"#).unwrap();
 
-        let read: Vec<_> = lua.get("v").unwrap();
+        let read: Vec<AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(read.len(), 0);
     }
 
@@ -398,7 +597,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { [-1] = -1, ["foo"] = 2, [{}] = 42 }"#).unwrap();
 
-        let read: Option<Vec<_>> = lua.get("v");
+        let read: Option<Vec<AnyLuaValue>> = lua.get("v");
         if read.is_some() {
             panic!("Unexpected success");
         }
@@ -418,7 +617,7 @@ mod tests {
 
         lua.set("v", &orig[..]);
 
-        let read: Vec<_> = lua.get("v").unwrap();
+        let read: Vec<AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(read, orig);
     }
 
@@ -428,7 +627,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { 1, 2, 3 }"#).unwrap();
 
-        let read: Vec<_> = lua.get("v").unwrap();
+        let read: Vec<AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(
             read,
             [1., 2., 3.].iter()
@@ -452,7 +651,7 @@ mod tests {
         for (o, r) in orig_btree.iter().zip(read_btree.iter()) {
             if let (&AnyHashableLuaValue::LuaNumber(i), &AnyLuaValue::LuaNumber(n)) = r {
                 let (&o_i, &o_n) = o;
-                assert_eq!(o_i, i);
+                assert_eq!(o_i as i64, i);
                 assert_eq!(o_n, n);
             } else {
                 panic!("Unexpected variant");
@@ -466,7 +665,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { [-1] = -1, [2] = 2, [42] = 42 }"#).unwrap();
 
-        let read: HashMap<_, _> = lua.get("v").unwrap();
+        let read: HashMap<AnyHashableLuaValue, AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(-1)], AnyLuaValue::LuaNumber(-1.));
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(2)], AnyLuaValue::LuaNumber(2.));
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(42)], AnyLuaValue::LuaNumber(42.));
@@ -479,7 +678,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { }"#).unwrap();
 
-        let read: HashMap<_, _> = lua.get("v").unwrap();
+        let read: HashMap<AnyHashableLuaValue, AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(read.len(), 0);
     }
 
@@ -489,7 +688,7 @@ mod tests {
 
         lua.execute::<()>(r#"v = { [-1] = -1, ["foo"] = 2, [2.] = 42 }"#).unwrap();
 
-        let read: HashMap<_, _> = lua.get("v").unwrap();
+        let read: HashMap<AnyHashableLuaValue, AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(-1)], AnyLuaValue::LuaNumber(-1.));
         assert_eq!(read[&AnyHashableLuaValue::LuaString("foo".to_owned())], AnyLuaValue::LuaNumber(2.));
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(2)], AnyLuaValue::LuaNumber(42.));
@@ -502,9 +701,8 @@ mod tests {
 
         lua.execute::<()>(r#"v = { [-1.25] = -1, [2.5] = 42 }"#).unwrap();
 
-        let read: HashMap<_, _> = lua.get("v").unwrap();
-        // It works by truncating integers in some unspecified way
-        // https://www.lua.org/manual/5.2/manual.html#lua_tointegerx
+        let read: HashMap<AnyHashableLuaValue, AnyLuaValue> = lua.get("v").unwrap();
+        // It works by truncating the keys towards zero
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(-1)], AnyLuaValue::LuaNumber(-1.));
         assert_eq!(read[&AnyHashableLuaValue::LuaNumber(2)], AnyLuaValue::LuaNumber(42.));
         assert_eq!(read.len(), 2);
@@ -522,7 +720,7 @@ mod tests {
         let orig_clone = orig.clone();
         lua.set("v", orig);
 
-        let read: HashMap<_, _> = lua.get("v").unwrap();
+        let read: HashMap<AnyHashableLuaValue, AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(read, orig_clone);
     }
 
@@ -532,10 +730,132 @@ mod tests {
 
         lua.execute::<()>(r#"v = { [1] = 2, [2] = 3, [3] = 4 }"#).unwrap();
 
-        let read: HashMap<_, _> = lua.get("v").unwrap();
+        let read: HashMap<AnyHashableLuaValue, AnyLuaValue> = lua.get("v").unwrap();
         assert_eq!(
             read,
             [2., 3., 4.].iter().enumerate()
-                .map(|(k, v)| (AnyHashableLuaValue::LuaNumber((k + 1) as i32), AnyLuaValue::LuaNumber(*v))).collect::<HashMap<_, _>>());
+                .map(|(k, v)| (AnyHashableLuaValue::LuaNumber((k + 1) as i64), AnyLuaValue::LuaNumber(*v))).collect::<HashMap<_, _>>());
+    }
+
+    #[test]
+    fn reading_typed_vec_works() {
+        let mut lua = Lua::new();
+
+        lua.execute::<()>(r#"v = { 1, 2, 3 }; w = { "a", "b" }"#).unwrap();
+
+        let v: Vec<i32> = lua.get("v").unwrap();
+        assert_eq!(v, vec![1, 2, 3]);
+        let w: Vec<String> = lua.get("w").unwrap();
+        assert_eq!(w, vec!["a".to_owned(), "b".to_owned()]);
+        let v: VecDeque<u8> = lua.get("v").unwrap();
+        assert_eq!(v, VecDeque::from(vec![1, 2, 3]));
+
+        assert!(lua.get::<Vec<bool>, _>("v").is_none());
+    }
+
+    #[test]
+    fn reading_nested_vec_works() {
+        let mut lua = Lua::new();
+
+        lua.execute::<()>(r#"v = { { 1, 2 }, {}, { 3 } }"#).unwrap();
+
+        let v: Vec<Vec<i32>> = lua.get("v").unwrap();
+        assert_eq!(v, vec![vec![1, 2], vec![], vec![3]]);
+    }
+
+    #[test]
+    fn reading_typed_vec_with_string_keys_fails() {
+        let mut lua = Lua::new();
+
+        lua.execute::<()>(r#"v = { 1, 2, ["3"] = 3 }; w = { 1, 2, x = 3 }"#).unwrap();
+
+        assert!(lua.get::<Vec<i32>, _>("v").is_none());
+        assert!(lua.get::<Vec<i32>, _>("w").is_none());
+    }
+
+    #[test]
+    fn reading_array_works() {
+        let mut lua = Lua::new();
+
+        lua.execute::<()>(r#"v = { 1, 2, 3 }; w = { 1, nil, 3 }"#).unwrap();
+
+        let v: [i32; 3] = lua.get("v").unwrap();
+        assert_eq!(v, [1, 2, 3]);
+        assert!(lua.get::<[i32; 2], _>("v").is_none());
+        assert!(lua.get::<[i32; 4], _>("v").is_none());
+        assert!(lua.get::<[i32; 3], _>("w").is_none());
+    }
+
+    #[test]
+    fn reading_typed_maps_works() {
+        let mut lua = Lua::new();
+
+        lua.execute::<()>(r#"v = { a = 1, b = 2, [3] = 3 }"#).unwrap();
+
+        let v: HashMap<String, i32> = lua.get("v").unwrap();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v["a"], 1);
+        assert_eq!(v["3"], 3);
+
+        let v: BTreeMap<String, u8> = lua.get("v").unwrap();
+        assert_eq!(v.keys().collect::<Vec<_>>(), vec!["3", "a", "b"]);
+
+        assert!(lua.get::<HashMap<i32, i32>, _>("v").is_none());
+        assert!(lua.get::<BTreeMap<String, String>, _>("v").is_some());
+        assert!(lua.get::<BTreeMap<String, bool>, _>("v").is_none());
+    }
+
+    #[test]
+    fn reading_map_with_duplicate_keys_fails() {
+        let mut lua = Lua::new();
+
+        lua.execute::<()>(r#"v = { [1] = "a", ["1"] = "b" }"#).unwrap();
+
+        assert!(lua.get::<HashMap<String, String>, _>("v").is_none());
+        assert!(lua.get::<BTreeMap<String, String>, _>("v").is_none());
+    }
+
+    #[test]
+    fn reading_sets_works() {
+        let mut lua = Lua::new();
+
+        let mut orig = HashSet::new();
+        orig.insert(4);
+        orig.insert(8);
+        lua.set("v", orig.clone());
+        lua.execute::<()>(r#"w = { a = true, b = false }"#).unwrap();
+
+        let v: HashSet<i32> = lua.get("v").unwrap();
+        assert_eq!(v, orig);
+        let v: BTreeSet<i32> = lua.get("v").unwrap();
+        assert_eq!(v.into_iter().collect::<Vec<_>>(), vec![4, 8]);
+
+        assert!(lua.get::<HashSet<String>, _>("w").is_none());
+    }
+
+    #[test]
+    fn argument_error_messages() {
+        let mut lua = Lua::new();
+        lua.set("sum", function1(|v: Vec<i32>| v.iter().sum::<i32>()));
+        lua.set("count", function1(|m: HashMap<String, i32>| m.len() as i32));
+        lua.set("first", function1(|a: [i32; 2]| a[0]));
+
+        let check = |lua: &mut Lua, code: &str, expected: &str| {
+            match lua.execute::<()>(code) {
+                Err(LuaError::ExecutionError(err)) => {
+                    assert!(err.message().ends_with(expected), "{}", err.message());
+                }
+                _ => panic!(),
+            }
+        };
+
+        check(&mut lua, "sum(5)", "bad argument #1 to 'sum' (table expected, got number)");
+        check(&mut lua, "sum({1, 'x'})",
+              "bad argument #1 to 'sum' (sequence of number expected, got table)");
+        check(&mut lua, "sum({1, nil, 3})", "bad argument #1 to 'sum' (sequence expected, got table)");
+        check(&mut lua, "count({a = true})",
+              "bad argument #1 to 'count' (table with number values expected, got table)");
+        check(&mut lua, "first({1, 2, 3})",
+              "bad argument #1 to 'first' (sequence of 2 elements expected, got table)");
     }
 }
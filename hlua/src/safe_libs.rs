@@ -0,0 +1,252 @@
+use vfs;
+use Lua;
+use LuaFunction;
+
+/// Selects which parts of the standard library `Lua::open_safe_libs` makes available.
+///
+/// Each profile gives access to everything the previous one does, so the profiles can be compared
+/// with `<` and `>`. None of them gives access to the `debug` library, to the functions that run
+/// other programs or that modify the filesystem, or to native modules.
+///
+/// In all the profiles, `load` only accepts source code, as loading malformed precompiled chunks
+/// can crash the interpreter. `print` is always available.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SafetyProfile {
+    /// Pure computation: the base library without `dofile` and `loadfile`, and the `bit32`,
+    /// `coroutine`, `math`, `string` and `table` libraries.
+    Computation,
+
+    /// Same as `Computation`, plus `os.clock`, `os.date`, `os.difftime` and `os.time`.
+    Clock,
+
+    /// Same as `Clock`, plus reading files. This gives access to `dofile`, `loadfile`, `require`
+    /// for modules written in Lua, and the `io` library except that `io.open` refuses to open
+    /// files for writing and that `io.output`, `io.popen` and `io.tmpfile` are removed. The files of
+    /// the filesystem set with `Lua::set_vfs` can't be modified either, even if it is set after
+    /// the libraries have been opened.
+    ReadOnlyFilesystem,
+}
+
+// Removes or replaces the dangerous functions of the libraries opened by `Lua::open_safe_libs`.
+// Called with two booleans: whether the clock functions and the filesystem are allowed.
+const RESTRICT_LIBS: &str = r#"
+local clock, filesystem = ...
+local error, pairs, type = error, pairs, type
+local find, format = string.find, string.format
+local raw_load, raw_loadfile = load, loadfile
+
+-- Loading a malformed precompiled chunk can crash the interpreter.
+function load(chunk, chunkname, mode, ...)
+    return raw_load(chunk, chunkname, "t", ...)
+end
+if loadstring then loadstring = load end
+
+local loaded = package and package.loaded
+local function remove_library(name)
+    _G[name] = nil
+    if loaded then loaded[name] = nil end
+end
+
+remove_library("debug")
+dofile, loadfile = nil, nil
+
+if clock then
+    local allowed = { clock = true, date = true, difftime = true, time = true }
+    for name in pairs(os) do
+        if not allowed[name] then os[name] = nil end
+    end
+else
+    remove_library("os")
+end
+
+if filesystem then
+    function loadfile(filename, mode, ...)
+        return raw_loadfile(filename, "t", ...)
+    end
+
+    function dofile(filename)
+        local f, err = loadfile(filename)
+        if not f then error(err, 0) end
+        return f()
+    end
+
+    local raw_open = io.open
+    function io.open(filename, mode)
+        if type(mode) == "string" and find(mode, "[wa+]") then
+            return nil, format("%s: opening files for writing is not allowed", filename)
+        end
+        return raw_open(filename, mode)
+    end
+    io.output, io.popen, io.tmpfile = nil, nil, nil
+
+    -- Only keep the searchers for preloaded modules and for modules written in Lua.
+    local searchpath = package.searchpath
+    package.loadlib, package.cpath = nil, ""
+    package.searchers[2] = function(name)
+        local filename, err = searchpath(name, package.path)
+        if not filename then return err end
+        local f, err = loadfile(filename)
+        if not f then
+            error(format("error loading module '%s' from file '%s':\n\t%s", name, filename, err), 0)
+        end
+        return f, filename
+    end
+    package.searchers[3], package.searchers[4] = nil, nil
+else
+    remove_library("io")
+    remove_library("package")
+    require = nil
+end
+"#;
+
+/// Opens the libraries of `profile` and removes what they shouldn't give access to.
+pub fn open<'lua>(lua: &mut Lua<'lua>, profile: SafetyProfile) {
+    lua.open_base();
+    if profile >= SafetyProfile::ReadOnlyFilesystem {
+        lua.open_package();
+    }
+    lua.open_coroutine();
+    lua.open_table();
+    if profile >= SafetyProfile::ReadOnlyFilesystem {
+        lua.open_io();
+    }
+    if profile >= SafetyProfile::Clock {
+        lua.open_os();
+    }
+    lua.open_string();
+    lua.open_bit32();
+    lua.open_math();
+
+    let clock = profile >= SafetyProfile::Clock;
+    let filesystem = profile >= SafetyProfile::ReadOnlyFilesystem;
+    if filesystem {
+        vfs::forbid_writes(lua);
+    }
+
+    let mut restrict = LuaFunction::load(lua, RESTRICT_LIBS).unwrap();
+    restrict.call_with_args::<(), _, _>((clock, filesystem))
+            .expect("failed to restrict the standard library");
+}
+
+#[cfg(test)]
+mod tests {
+    use Lua;
+    use MemoryVfs;
+    use SafetyProfile;
+
+    #[test]
+    fn computation_profile() {
+        let mut lua = Lua::new();
+        lua.open_safe_libs(SafetyProfile::Computation);
+
+        let removed: bool = lua.execute("return os == nil and io == nil and debug == nil and \
+                                         package == nil and require == nil and \
+                                         dofile == nil and loadfile == nil").unwrap();
+        assert!(removed);
+
+        let val: String = lua.execute("return string.format('%d', math.max(1, 2))").unwrap();
+        assert_eq!(val, "2");
+        let val: i32 = lua.execute("return load('return 5')()").unwrap();
+        assert_eq!(val, 5);
+    }
+
+    #[test]
+    fn binary_chunks_refused() {
+        let mut lua = Lua::new();
+        lua.open_safe_libs(SafetyProfile::Computation);
+
+        let (f, err): (Option<bool>, String) =
+            lua.execute("return load(string.dump(function() end))").unwrap();
+        assert_eq!(f, None);
+        assert!(err.contains("binary"));
+
+        let f: Option<bool> = lua.execute("return loadstring(string.dump(function() end))")
+                                 .unwrap();
+        assert_eq!(f, None);
+    }
+
+    #[test]
+    fn previously_opened_libraries_removed() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.open_safe_libs(SafetyProfile::Clock);
+
+        let removed: bool = lua.execute("return debug == nil and io == nil and \
+                                         package == nil").unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn clock_profile() {
+        let mut lua = Lua::new();
+        lua.open_safe_libs(SafetyProfile::Clock);
+
+        let val: bool = lua.execute("return os.time() > 0 and os.clock() >= 0").unwrap();
+        assert!(val);
+        let removed: bool = lua.execute("return os.execute == nil and os.exit == nil and \
+                                         os.remove == nil and os.getenv == nil and \
+                                         io == nil").unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn read_only_filesystem_profile() {
+        let mut lua = Lua::new();
+        lua.open_safe_libs(SafetyProfile::ReadOnlyFilesystem);
+
+        let val: String = lua.execute("local f = assert(io.open('Cargo.toml')) \
+                                       local line = f:read('*l') f:close() return line").unwrap();
+        assert_eq!(val, "[package]");
+
+        let (f, err): (Option<bool>, String) =
+            lua.execute("return io.open('hlua-safe-libs-test.txt', 'w')").unwrap();
+        assert_eq!(f, None);
+        assert!(err.contains("not allowed"));
+
+        let removed: bool = lua.execute("return io.popen == nil and io.output == nil and \
+                                         os.remove == nil and package.loadlib == nil and \
+                                         #package.searchers == 2").unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn vfs_read_only() {
+        for &vfs_first in &[true, false] {
+            let mut lua = Lua::new();
+            let vfs = MemoryVfs::new();
+            vfs.insert("data.txt", "hello");
+            if vfs_first {
+                lua.set_vfs(Box::new(vfs.clone()));
+                lua.open_safe_libs(SafetyProfile::ReadOnlyFilesystem);
+            } else {
+                lua.open_safe_libs(SafetyProfile::ReadOnlyFilesystem);
+                lua.set_vfs(Box::new(vfs.clone()));
+            }
+
+            let val: String = lua.execute("return io.open('data.txt'):read('*a')").unwrap();
+            assert_eq!(val, "hello");
+
+            let (f, _): (Option<bool>, String) =
+                lua.execute("return io.open('data.txt', 'w')").unwrap();
+            assert_eq!(f, None);
+            let (f, _): (Option<bool>, String) =
+                lua.execute("return io.open('new.txt', 'a+')").unwrap();
+            assert_eq!(f, None);
+            assert!(lua.execute::<()>("io.input('new.txt')").is_err());
+
+            assert_eq!(vfs.get("data.txt"), Some(b"hello".to_vec()));
+            assert_eq!(vfs.get("new.txt"), None);
+        }
+    }
+
+    #[test]
+    fn require_lua_modules() {
+        let mut lua = Lua::new();
+        lua.open_safe_libs(SafetyProfile::ReadOnlyFilesystem);
+        lua.execute::<()>("package.preload.answer = function() return 42 end").unwrap();
+
+        let val: i32 = lua.execute("return require('answer')").unwrap();
+        assert_eq!(val, 42);
+        assert!(lua.execute::<()>("require('hlua_missing_module')").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
+
+use ffi;
+
+use AnyLuaString;
+use AsLua;
+use LuaRead;
+use Push;
+use PushGuard;
+use PushOne;
+use StringInLua;
+
+/// Reads a value without any implicit conversion.
+///
+/// By default, Lua converts strings to numbers and numbers to strings when needed, and hlua does
+/// the same when reading values. This means that reading an `i32` from the string `"12"` or a
+/// `String` from the number `12` succeeds. Reading a `Strict<T>` instead only succeeds if the Lua
+/// value already has the right type.
+///
+/// Pushing a `Strict<T>` is the same as pushing the `T`.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, Strict};
+///
+/// let mut lua = Lua::new();
+/// lua.set("a", "12");
+/// lua.set("b", 12);
+///
+/// assert_eq!(lua.get::<i32, _>("a"), Some(12));
+/// assert_eq!(lua.get::<Strict<i32>, _>("a"), None);
+/// assert_eq!(lua.get::<Strict<i32>, _>("b"), Some(Strict(12)));
+///
+/// assert_eq!(lua.get::<String, _>("b"), Some("12".to_owned()));
+/// assert_eq!(lua.get::<Strict<String>, _>("b"), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Strict<T>(pub T);
+
+impl<T> Strict<T> {
+    /// Returns the inner value.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Strict<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Strict<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<L, T> Push<L> for Strict<T>
+    where T: Push<L>
+{
+    type Err = T::Err;
+
+    #[inline]
+    fn push_to_lua(self, lua: L) -> Result<PushGuard<L>, (T::Err, L)> {
+        self.0.push_to_lua(lua)
+    }
+}
+
+impl<L, T> PushOne<L> for Strict<T> where T: PushOne<L> {}
+
+macro_rules! strict_impl(
+    ($t:ty, $lua_type:expr) => (
+        impl<'lua, L> LuaRead<L> for Strict<$t> where L: AsLua<'lua> {
+            #[inline]
+            fn lua_read_at_position(lua: L, index: i32) -> Result<Strict<$t>, L> {
+                if unsafe { ffi::lua_type(lua.as_lua().0, index) } != $lua_type {
+                    return Err(lua);
+                }
+
+                LuaRead::lua_read_at_position(lua, index).map(Strict)
+            }
+
+            #[inline]
+            fn lua_type_name() -> Cow<'static, str> {
+                <$t as LuaRead<L>>::lua_type_name()
+            }
+        }
+    );
+);
+
+strict_impl!(i8, ffi::LUA_TNUMBER);
+strict_impl!(i16, ffi::LUA_TNUMBER);
+strict_impl!(i32, ffi::LUA_TNUMBER);
+strict_impl!(i64, ffi::LUA_TNUMBER);
+strict_impl!(i128, ffi::LUA_TNUMBER);
+strict_impl!(isize, ffi::LUA_TNUMBER);
+strict_impl!(u8, ffi::LUA_TNUMBER);
+strict_impl!(u16, ffi::LUA_TNUMBER);
+strict_impl!(u32, ffi::LUA_TNUMBER);
+strict_impl!(u64, ffi::LUA_TNUMBER);
+strict_impl!(u128, ffi::LUA_TNUMBER);
+strict_impl!(usize, ffi::LUA_TNUMBER);
+strict_impl!(f32, ffi::LUA_TNUMBER);
+strict_impl!(f64, ffi::LUA_TNUMBER);
+strict_impl!(bool, ffi::LUA_TBOOLEAN);
+strict_impl!(String, ffi::LUA_TSTRING);
+strict_impl!(AnyLuaString, ffi::LUA_TSTRING);
+
+impl<'lua, L> LuaRead<L> for Strict<StringInLua<L>>
+    where L: AsLua<'lua>
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<Strict<StringInLua<L>>, L> {
+        if unsafe { ffi::lua_type(lua.as_lua().0, index) } != ffi::LUA_TSTRING {
+            return Err(lua);
+        }
+
+        LuaRead::lua_read_at_position(lua, index).map(Strict)
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Lua;
+    use LuaError;
+    use Strict;
+    use StringInLua;
+    use function1;
+
+    #[test]
+    fn strict_numbers_reject_strings() {
+        let mut lua = Lua::new();
+        lua.set("a", "12");
+        lua.set("b", "2.5");
+
+        assert_eq!(lua.get::<Strict<i32>, _>("a"), None);
+        assert_eq!(lua.get::<Strict<u64>, _>("a"), None);
+        assert_eq!(lua.get::<Strict<f64>, _>("b"), None);
+    }
+
+    #[test]
+    fn strict_integers_reject_non_integral_numbers() {
+        let mut lua = Lua::new();
+        lua.set("a", 3.7);
+
+        assert_eq!(lua.get::<Strict<i32>, _>("a"), None);
+        assert_eq!(lua.get::<Strict<f64>, _>("a"), Some(Strict(3.7)));
+    }
+
+    #[test]
+    fn strict_strings_reject_numbers() {
+        let mut lua = Lua::new();
+        lua.set("a", 12);
+        lua.set("b", "hello");
+
+        assert_eq!(lua.get::<Strict<String>, _>("a"), None);
+        assert_eq!(lua.get::<Strict<String>, _>("b"), Some(Strict("hello".to_owned())));
+
+        let s: Strict<StringInLua<_>> = lua.get("b").unwrap();
+        assert_eq!(&**s, "hello");
+    }
+
+    #[test]
+    fn strict_optional() {
+        let mut lua = Lua::new();
+
+        let val: Option<Strict<i32>> = lua.execute("return nil").unwrap();
+        assert_eq!(val, None);
+        let val: Option<Strict<i32>> = lua.execute("return 12").unwrap();
+        assert_eq!(val, Some(Strict(12)));
+        assert!(lua.execute::<Option<Strict<i32>>>("return '12'").is_err());
+    }
+
+    #[test]
+    fn strict_argument_error() {
+        let mut lua = Lua::new();
+        lua.set("double", function1(|n: Strict<i32>| *n * 2));
+
+        let val: i32 = lua.execute("return double(21)").unwrap();
+        assert_eq!(val, 42);
+
+        match lua.execute::<()>("double('21')") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().ends_with("bad argument #1 to 'double' (number expected, got string)"));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn push_strict() {
+        let mut lua = Lua::new();
+        lua.set("a", Strict(5));
+
+        let val: i32 = lua.get("a").unwrap();
+        assert_eq!(val, 5);
+    }
+}
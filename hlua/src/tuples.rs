@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use AsMutLua;
 use AsLua;
 
@@ -26,6 +28,11 @@ macro_rules! tuple_impl {
             fn lua_read_at_position(lua: LU, index: i32) -> Result<($ty,), LU> {
                 LuaRead::lua_read_at_position(lua, index).map(|v| (v,))
             }
+
+            #[inline]
+            fn lua_read_error(lua: LU, index: i32) -> (i32, Cow<'static, str>) {
+                <$ty as LuaRead<LU>>::lua_read_error(lua, index)
+            }
         }
     );
 
@@ -98,6 +105,29 @@ macro_rules! tuple_impl {
                 Ok(($first, $($other),+))
 
             }
+
+            #[inline]
+            fn lua_read_error(mut lua: LU, index: i32) -> (i32, Cow<'static, str>) {
+                let mut i = index;
+
+                if <$first as LuaRead<&mut LU>>::lua_read_at_position(&mut lua, i).is_err() {
+                    let (offset, expected) = <$first as LuaRead<&mut LU>>::lua_read_error(&mut lua, i);
+                    return (i - index + offset, expected);
+                }
+
+                i += 1;
+
+                $(
+                    if <$other as LuaRead<&mut LU>>::lua_read_at_position(&mut lua, i).is_err() {
+                        let (offset, expected) = <$other as LuaRead<&mut LU>>::lua_read_error(&mut lua, i);
+                        return (i - index + offset, expected);
+                    }
+                    i += 1;
+                )+
+
+                // all the values can be read
+                (0, Self::lua_type_name())
+            }
         }
 
         tuple_impl!($($other),+);
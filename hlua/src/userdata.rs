@@ -1,4 +1,5 @@
-use std::any::{Any, TypeId};
+use std::any::{type_name, Any, TypeId};
+use std::borrow::Cow;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::mem;
@@ -13,6 +14,7 @@ use Push;
 use PushGuard;
 use LuaContext;
 use LuaRead;
+use raw;
 
 use InsideCallback;
 use LuaTable;
@@ -23,7 +25,8 @@ extern "C" fn destructor_wrapper<T>(lua: *mut ffi::lua_State) -> libc::c_int {
     unsafe {
         let obj = ffi::lua_touserdata(lua, -1);
         ptr::drop_in_place(obj as *mut TypeId);
-        ptr::drop_in_place((obj as *mut u8).offset(mem::size_of::<TypeId>() as isize) as *mut T);
+        let data = (obj as *mut u8).offset(mem::size_of::<TypeId>() as isize) as *mut T;
+        raw::drop_ignoring_panics(data);
         0
     }
 }
@@ -174,6 +177,11 @@ impl<'lua, T, L> LuaRead<L> for UserdataOnStack<T, L>
             })
         }
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed(type_name::<T>())
+    }
 }
 
 unsafe impl<'lua, T, L> AsLua<'lua> for UserdataOnStack<T, L>
@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
 use std::mem;
 use std::slice;
 use std::str;
@@ -16,6 +19,24 @@ use PushGuard;
 use PushOne;
 use Void;
 
+// Reads the number at `index` and converts it to an integer type, if it is integral and in the
+// range of the type. `lua_tointegerx` and `lua_tounsignedx` would silently truncate the number.
+macro_rules! read_integer(
+    ($t:ident, $lua:expr, $index:expr) => ({
+        let mut success = 0;
+        let val = unsafe { ffi::lua_tonumberx($lua.as_lua().0, $index, &mut success) };
+        // comparisons with NaN are false, and `$t::MAX as f64 + 1.0` is exactly the first value
+        // out of range even when `$t::MAX` itself can't be represented
+        if success != 0 && val.fract() == 0.0 && val >= $t::MIN as f64 &&
+           val < $t::MAX as f64 + 1.0
+        {
+            Some(val as $t)
+        } else {
+            None
+        }
+    });
+);
+
 macro_rules! integer_impl(
     ($t:ident) => (
         impl<'lua, L> Push<L> for $t where L: AsMutLua<'lua> {
@@ -35,13 +56,16 @@ macro_rules! integer_impl(
         impl<'lua, L> LuaRead<L> for $t where L: AsLua<'lua> {
             #[inline]
             fn lua_read_at_position(lua: L, index: i32) -> Result<$t, L> {
-                let mut success = unsafe { mem::uninitialized() };
-                let val = unsafe { ffi::lua_tointegerx(lua.as_lua().0, index, &mut success) };
-                match success {
-                    0 => Err(lua),
-                    _ => Ok(val as $t)
+                match read_integer!($t, lua, index) {
+                    Some(val) => Ok(val),
+                    None => Err(lua),
                 }
             }
+
+            #[inline]
+            fn lua_type_name() -> Cow<'static, str> {
+                Cow::Borrowed("number")
+            }
         }
     );
 );
@@ -49,7 +73,6 @@ macro_rules! integer_impl(
 integer_impl!(i8);
 integer_impl!(i16);
 integer_impl!(i32);
-// integer_impl!(i64)   // data loss
 
 macro_rules! unsigned_impl(
     ($t:ident) => (
@@ -70,13 +93,16 @@ macro_rules! unsigned_impl(
         impl<'lua, L> LuaRead<L> for $t where L: AsLua<'lua> {
             #[inline]
             fn lua_read_at_position(lua: L, index: i32) -> Result<$t, L> {
-                let mut success = unsafe { mem::uninitialized() };
-                let val = unsafe { ffi::lua_tounsignedx(lua.as_lua().0, index, &mut success) };
-                match success {
-                    0 => Err(lua),
-                    _ => Ok(val as $t)
+                match read_integer!($t, lua, index) {
+                    Some(val) => Ok(val),
+                    None => Err(lua),
                 }
             }
+
+            #[inline]
+            fn lua_type_name() -> Cow<'static, str> {
+                Cow::Borrowed("number")
+            }
         }
     );
 );
@@ -84,7 +110,83 @@ macro_rules! unsigned_impl(
 unsigned_impl!(u8);
 unsigned_impl!(u16);
 unsigned_impl!(u32);
-// unsigned_impl!(u64);   // data loss
+
+/// Error that can happen when pushing an integer that a Lua number can't represent exactly.
+///
+/// Lua 5.2 stores all numbers as `f64`, which represents exactly all the integers up to 2^53 in
+/// absolute value, but only some of the larger ones.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IntegerPushError;
+
+impl fmt::Display for IntegerPushError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "integer can't be represented exactly by a Lua number")
+    }
+}
+
+impl Error for IntegerPushError {
+    fn description(&self) -> &str {
+        "integer can't be represented exactly by a Lua number"
+    }
+}
+
+// Converts an integer to a Lua number, if this can be done without rounding it.
+macro_rules! exact_number(
+    ($t:ident, $val:expr) => ({
+        let val: $t = $val;
+        let number = val as ffi::lua_Number;
+        // `number as $t` saturates, so the range must be checked first
+        if number >= $t::MIN as f64 && number < $t::MAX as f64 + 1.0 && number as $t == val {
+            Some(number)
+        } else {
+            None
+        }
+    });
+);
+
+macro_rules! large_integer_impl(
+    ($t:ident) => (
+        impl<'lua, L> Push<L> for $t where L: AsMutLua<'lua> {
+            type Err = IntegerPushError;
+
+            #[inline]
+            fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (IntegerPushError, L)> {
+                let number = match exact_number!($t, self) {
+                    Some(number) => number,
+                    None => return Err((IntegerPushError, lua)),
+                };
+                unsafe { ffi::lua_pushnumber(lua.as_mut_lua().0, number) };
+                let raw_lua = lua.as_lua();
+                Ok(PushGuard { lua, size: 1, raw_lua })
+            }
+        }
+
+        impl<'lua, L> PushOne<L> for $t where L: AsMutLua<'lua> {
+        }
+
+        impl<'lua, L> LuaRead<L> for $t where L: AsLua<'lua> {
+            #[inline]
+            fn lua_read_at_position(lua: L, index: i32) -> Result<$t, L> {
+                match read_integer!($t, lua, index) {
+                    Some(val) => Ok(val),
+                    None => Err(lua),
+                }
+            }
+
+            #[inline]
+            fn lua_type_name() -> Cow<'static, str> {
+                Cow::Borrowed("number")
+            }
+        }
+    );
+);
+
+large_integer_impl!(i64);
+large_integer_impl!(u64);
+large_integer_impl!(isize);
+large_integer_impl!(usize);
+large_integer_impl!(i128);
+large_integer_impl!(u128);
 
 macro_rules! numeric_impl(
     ($t:ident) => (
@@ -112,6 +214,11 @@ macro_rules! numeric_impl(
                     _ => Ok(val as $t)
                 }
             }
+
+            #[inline]
+            fn lua_type_name() -> Cow<'static, str> {
+                Cow::Borrowed("number")
+            }
         }
     );
 );
@@ -161,6 +268,11 @@ impl<'lua, L> LuaRead<L> for String
             Err(_) => Err(lua),
         }
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("string")
+    }
 }
 
 impl<'lua, L> Push<L> for AnyLuaString
@@ -200,6 +312,11 @@ impl<'lua, L> LuaRead<L> for AnyLuaString
         let c_slice = unsafe { slice::from_raw_parts(c_str_raw as *const u8, size) };
         Ok(AnyLuaString(c_slice.to_vec()))
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("string")
+    }
 }
 
 impl<'lua, 's, L> Push<L> for &'s str
@@ -272,6 +389,11 @@ impl<'lua, L> LuaRead<L> for StringInLua<L>
             size: size,
         })
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("string")
+    }
 }
 
 impl<L> Deref for StringInLua<L> {
@@ -317,6 +439,11 @@ impl<'lua, L> LuaRead<L> for bool
 
         Ok(unsafe { ffi::lua_toboolean(lua.as_lua().0, index) != 0 })
     }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        Cow::Borrowed("boolean")
+    }
 }
 
 impl<'lua, L> Push<L> for ()
@@ -366,11 +493,38 @@ where T: PushOne<L, Err = E>,
 {
 }
 
+impl<'lua, L, T> LuaRead<L> for Option<T>
+where T: LuaRead<L>,
+      L: AsLua<'lua>
+{
+    #[inline]
+    fn lua_read_at_position(lua: L, index: i32) -> Result<Option<T>, L> {
+        if unsafe { ffi::lua_type(lua.as_lua().0, index) } <= ffi::LUA_TNIL {
+            return Ok(None);
+        }
+
+        T::lua_read_at_position(lua, index).map(Some)
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        T::lua_type_name()
+    }
+
+    #[inline]
+    fn lua_read_error(lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        T::lua_read_error(lua, index)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use AnyLuaValue;
     use AnyLuaString;
+    use function0;
+    use IntegerPushError;
     use Lua;
+    use LuaError;
     use StringInLua;
 
     #[test]
@@ -415,6 +569,102 @@ mod tests {
         assert_eq!(x, 2);
     }
 
+    #[test]
+    fn readwrite_64bits_integers() {
+        let mut lua = Lua::new();
+
+        lua.checked_set("a", 1_500_000_000_123i64).unwrap();
+        lua.checked_set("b", -(1i64 << 53)).unwrap();
+        lua.checked_set("c", 9_007_199_254_740_992u64).unwrap();
+        lua.checked_set("d", 42usize).unwrap();
+        lua.checked_set("e", -42isize).unwrap();
+        lua.checked_set("f", -(1i128 << 100)).unwrap();
+
+        let a: i64 = lua.get("a").unwrap();
+        assert_eq!(a, 1_500_000_000_123);
+        let b: i64 = lua.get("b").unwrap();
+        assert_eq!(b, -(1 << 53));
+        let c: u64 = lua.get("c").unwrap();
+        assert_eq!(c, 9_007_199_254_740_992);
+        let d: usize = lua.get("d").unwrap();
+        assert_eq!(d, 42);
+        let e: isize = lua.get("e").unwrap();
+        assert_eq!(e, -42);
+        let f: i128 = lua.get("f").unwrap();
+        assert_eq!(f, -(1 << 100));
+    }
+
+    #[test]
+    fn read_non_integral_number_as_integer() {
+        let mut lua = Lua::new();
+
+        lua.set("a", 2.5f64);
+
+        assert!(lua.get::<i32, _>("a").is_none());
+        assert!(lua.get::<u8, _>("a").is_none());
+        assert!(lua.get::<i64, _>("a").is_none());
+        assert!(lua.get::<usize, _>("a").is_none());
+    }
+
+    #[test]
+    fn read_out_of_range_integers() {
+        let mut lua = Lua::new();
+
+        lua.set("a", 300);
+        lua.set("b", -1);
+        lua.set("c", 1e19f64);
+
+        assert!(lua.get::<i8, _>("a").is_none());
+        assert!(lua.get::<u8, _>("a").is_none());
+        assert_eq!(lua.get::<i16, _>("a"), Some(300));
+        assert!(lua.get::<u32, _>("b").is_none());
+        assert!(lua.get::<u64, _>("b").is_none());
+        assert!(lua.get::<i64, _>("c").is_none());
+        assert_eq!(lua.get::<u64, _>("c"), Some(10_000_000_000_000_000_000));
+    }
+
+    #[test]
+    fn push_inexact_integers() {
+        let mut lua = Lua::new();
+
+        assert_eq!(lua.checked_set("a", u64::MAX), Err(IntegerPushError));
+        assert_eq!(lua.checked_set("b", (1i64 << 53) + 1), Err(IntegerPushError));
+        assert_eq!(lua.checked_set("c", i128::MAX), Err(IntegerPushError));
+        assert_eq!(lua.checked_set("d", u128::MAX), Err(IntegerPushError));
+        assert!(lua.get::<u64, _>("a").is_none());
+
+        // large integers that are exactly representable can be pushed
+        lua.checked_set("e", i64::MIN).unwrap();
+        lua.checked_set("f", 1u64 << 63).unwrap();
+        lua.checked_set("g", (1u64 << 53) + 2).unwrap();
+        assert_eq!(lua.get::<i64, _>("e"), Some(i64::MIN));
+        assert_eq!(lua.get::<u64, _>("f"), Some(1 << 63));
+        assert_eq!(lua.get::<u64, _>("g"), Some((1 << 53) + 2));
+
+        // this is a Lua error, not a panic that would be resumed
+        lua.set("h", function0(|| u64::MAX));
+        lua.set_resume_panics(true);
+        match lua.execute::<()>("h()") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().contains("can't be pushed"));
+                assert!(err.rust_error().is_none());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_infinite_number_as_integer() {
+        let mut lua = Lua::new();
+
+        lua.set("a", f64::INFINITY);
+        lua.set("b", f64::NAN);
+
+        assert!(lua.get::<i64, _>("a").is_none());
+        assert!(lua.get::<i128, _>("a").is_none());
+        assert!(lua.get::<i64, _>("b").is_none());
+    }
+
     #[test]
     fn readwrite_floats() {
         let mut lua = Lua::new();
@@ -535,4 +785,17 @@ mod tests {
         assert_eq!(lua.get("no_value"), None::<String>);
         assert_eq!(lua.get("some_value"), Some("Hello!".to_string()));
     }
+
+    #[test]
+    fn read_opt() {
+        let mut lua = Lua::new();
+
+        assert_eq!(lua.execute::<Option<i32>>("return nil").unwrap(), None);
+        assert_eq!(lua.execute::<Option<i32>>("return 5").unwrap(), Some(5));
+        assert!(lua.execute::<Option<i32>>("return {}").is_err());
+
+        lua.set("opt", ::function1(|a: Option<i32>| a.unwrap_or(-1)));
+        assert_eq!(lua.execute::<i32>("return opt(3)").unwrap(), 3);
+        assert_eq!(lua.execute::<i32>("return opt(nil)").unwrap(), -1);
+    }
 }
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
+use std::vec;
+
+use ffi;
+
+use AnyLuaValue;
+use AsLua;
+use AsMutLua;
+use LuaRead;
+use Push;
+use PushGuard;
+
+/// Any number of Lua values of the same type.
+///
+/// When read, a `Variadic` collects all the values from its position up to the top of the stack.
+/// This means that it can be used as the last parameter of a Rust function in order to accept any
+/// number of trailing arguments, or to read all the values returned by a Lua function.
+///
+/// When pushed, each element is pushed as a separate value. This can be used to return any number
+/// of values from a Rust function.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, Variadic};
+///
+/// let mut lua = Lua::new();
+/// lua.set("sum", hlua::function2(|first: i32, others: Variadic<i32>| {
+///     first + others.iter().sum::<i32>()
+/// }));
+/// lua.set("range", hlua::function1(|n: i32| Variadic((0 .. n).collect())));
+///
+/// let total: i32 = lua.execute("return sum(1, 2, 3, 4)").unwrap();
+/// assert_eq!(total, 10);
+///
+/// let values: Variadic<i32> = lua.execute("return range(4)").unwrap();
+/// assert_eq!(values.0, vec![0, 1, 2, 3]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Variadic<T>(pub Vec<T>);
+
+/// Any number of Lua values of any type.
+///
+/// This is the type to use in order to read all the values returned by a Lua function, or to
+/// return a varying number of values of different types from a Rust function.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{AnyLuaValue, Lua, LuaFunction, MultiValue};
+///
+/// let mut lua = Lua::new();
+/// lua.execute::<()>("function info() return 'hlua', 5.2, true end").unwrap();
+///
+/// let mut info: LuaFunction<_> = lua.get("info").unwrap();
+/// let values: Vec<AnyLuaValue> = info.call::<MultiValue>().unwrap().into();
+/// assert_eq!(values, vec![AnyLuaValue::LuaString("hlua".to_owned()),
+///                         AnyLuaValue::LuaNumber(5.2),
+///                         AnyLuaValue::LuaBoolean(true)]);
+/// ```
+pub type MultiValue = Variadic<AnyLuaValue>;
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    #[inline]
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+impl<T> From<Vec<T>> for Variadic<T> {
+    #[inline]
+    fn from(values: Vec<T>) -> Variadic<T> {
+        Variadic(values)
+    }
+}
+
+impl<T> From<Variadic<T>> for Vec<T> {
+    #[inline]
+    fn from(values: Variadic<T>) -> Vec<T> {
+        values.0
+    }
+}
+
+impl<T> IntoIterator for Variadic<T> {
+    type Item = T;
+    type IntoIter = vec::IntoIter<T>;
+
+    #[inline]
+    fn into_iter(self) -> vec::IntoIter<T> {
+        self.0.into_iter()
+    }
+}
+
+impl<'lua, L, T, E> Push<L> for Variadic<T>
+    where L: AsMutLua<'lua>,
+          T: for<'a> Push<&'a mut L, Err = E>
+{
+    type Err = E;
+
+    #[inline]
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (E, L)> {
+        if unsafe { ffi::lua_checkstack(lua.as_mut_lua().0, self.0.len() as i32) } == 0 {
+            panic!("not enough space on the Lua stack to push {} values", self.0.len());
+        }
+
+        let mut total = 0;
+        for value in self.0 {
+            let pushed = value.push_to_lua(&mut lua).map(|guard| guard.forget_internal());
+            match pushed {
+                Ok(size) => total += size,
+                Err((err, _)) => {
+                    unsafe { ffi::lua_pop(lua.as_mut_lua().0, total) };
+                    return Err((err, lua));
+                }
+            }
+        }
+
+        let raw_lua = lua.as_lua();
+        Ok(PushGuard {
+            lua,
+            size: total,
+            raw_lua,
+        })
+    }
+}
+
+impl<'lua, L, T> LuaRead<L> for Variadic<T>
+    where L: AsLua<'lua>,
+          T: for<'a> LuaRead<&'a mut L>
+{
+    #[inline]
+    fn lua_read_at_position(mut lua: L, index: i32) -> Result<Variadic<T>, L> {
+        let (first, top) = bounds(&lua, index);
+
+        let mut values = Vec::with_capacity((top + 1 - first).max(0) as usize);
+        for i in first..=top {
+            match T::lua_read_at_position(&mut lua, i) {
+                Ok(value) => values.push(value),
+                Err(_) => return Err(lua),
+            }
+        }
+
+        Ok(Variadic(values))
+    }
+
+    #[inline]
+    fn lua_type_name() -> Cow<'static, str> {
+        <T as LuaRead<&mut L>>::lua_type_name()
+    }
+
+    #[inline]
+    fn lua_read_error(mut lua: L, index: i32) -> (i32, Cow<'static, str>) {
+        let (first, top) = bounds(&lua, index);
+
+        for i in first..=top {
+            if T::lua_read_at_position(&mut lua, i).is_err() {
+                let (offset, expected) = T::lua_read_error(&mut lua, i);
+                return (i - first + offset, expected);
+            }
+        }
+
+        (0, Self::lua_type_name())
+    }
+}
+
+// Returns the absolute indices of the first and the last values read by a `Variadic` at `index`.
+#[inline]
+fn bounds<'lua, L>(lua: &L, index: i32) -> (i32, i32)
+    where L: AsLua<'lua>
+{
+    let raw_lua = lua.as_lua().0;
+    unsafe { (ffi::lua_absindex(raw_lua, index), ffi::lua_gettop(raw_lua)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use AnyLuaValue;
+    use Lua;
+    use LuaError;
+    use LuaFunction;
+    use MultiValue;
+    use Variadic;
+    use function1;
+    use function2;
+
+    #[test]
+    fn variadic_arguments() {
+        let mut lua = Lua::new();
+        lua.set("count", function1(|values: Variadic<i32>| values.len() as i32));
+
+        let val: i32 = lua.execute("return count()").unwrap();
+        assert_eq!(val, 0);
+        let val: i32 = lua.execute("return count(5, 6, 7)").unwrap();
+        assert_eq!(val, 3);
+    }
+
+    #[test]
+    fn variadic_after_fixed_arguments() {
+        let mut lua = Lua::new();
+        lua.set("join", function2(|sep: String, parts: Variadic<String>| parts.join(&sep)));
+
+        let val: String = lua.execute("return join('-', 'a', 'b', 'c')").unwrap();
+        assert_eq!(val, "a-b-c");
+        let val: String = lua.execute("return join('-')").unwrap();
+        assert_eq!(val, "");
+    }
+
+    #[test]
+    fn variadic_wrong_type() {
+        let mut lua = Lua::new();
+        lua.set("sum", function2(|a: i32, others: Variadic<i32>| a + others.iter().sum::<i32>()));
+
+        match lua.execute::<()>("sum(1, 2, 'three')") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().ends_with("bad argument #3 to 'sum' (number expected, got string)"));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn return_multiple_values() {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set("values", function1(|n: i32| {
+            let values: MultiValue = Variadic((0 .. n).map(|i| AnyLuaValue::LuaNumber(i as f64)).collect());
+            values
+        }));
+
+        let (a, b, c): (i32, i32, i32) = lua.execute("return values(3)").unwrap();
+        assert_eq!((a, b, c), (0, 1, 2));
+        let count: i32 = lua.execute("return select('#', values(5))").unwrap();
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn call_reads_all_results() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("function f() return 1, 'two', nil end").unwrap();
+
+        let mut f: LuaFunction<_> = lua.get("f").unwrap();
+        let values: MultiValue = f.call().unwrap();
+        assert_eq!(values.0, vec![AnyLuaValue::LuaNumber(1.0),
+                                  AnyLuaValue::LuaString("two".to_owned()),
+                                  AnyLuaValue::LuaNil]);
+    }
+
+    #[test]
+    fn call_reads_tuple() {
+        let mut lua = Lua::new();
+        lua.execute::<()>("function divmod(a, b) return (a - a % b) / b, a % b end").unwrap();
+
+        let mut f: LuaFunction<_> = lua.get("divmod").unwrap();
+        let (q, r): (i32, i32) = f.call_with_args((17, 5)).unwrap();
+        assert_eq!((q, r), (3, 2));
+    }
+
+    #[test]
+    fn call_first_result() {
+        let mut lua = Lua::new();
+        let val: i32 = lua.execute("return 1, 2, 3").unwrap();
+        assert_eq!(val, 1);
+    }
+
+    #[test]
+    fn call_no_result() {
+        let mut lua = Lua::new();
+        let val: Option<i32> = lua.execute("return").unwrap();
+        assert_eq!(val, None);
+        let values: MultiValue = lua.execute("return").unwrap();
+        assert!(values.is_empty());
+        let val: AnyLuaValue = lua.execute("return").unwrap();
+        assert_eq!(val, AnyLuaValue::LuaNil);
+    }
+}
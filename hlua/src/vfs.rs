@@ -0,0 +1,1686 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::ptr;
+use std::rc::Rc;
+
+use ffi;
+use libc;
+
+use functions_write;
+use raw::{self, push_bytes, push_error, raise, string_at};
+use rust_errors;
+use AsLua;
+use Lua;
+use LuaFunction;
+use LuaRead;
+use LuaRef;
+use PushGuard;
+
+/// Filesystem seen by the Lua code.
+///
+/// Once installed with `Lua::set_vfs`, the `io` library, `loadfile`, `dofile`, `require`,
+/// `os.remove` and `os.rename` access this filesystem instead of the real one.
+///
+/// The paths passed to the methods are relative and normalized: their components are separated
+/// with `/`, and they don't contain any `.` or `..` component. A path that would go above the
+/// root of the filesystem, such as `../secret.txt`, is refused before reaching the `Vfs`. So is a
+/// path containing a `\` or a `:`, which Windows could interpret as a separator or a drive.
+///
+/// Files are read and written as a whole. A file opened by the Lua code is read when it is
+/// opened, and written when it is flushed or closed.
+pub trait Vfs {
+    /// Returns the content of the file at `path`.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /// Replaces the content of the file at `path`, creating the file if necessary.
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()>;
+
+    /// Removes the file at `path`.
+    fn remove(&self, path: &str) -> io::Result<()>;
+
+    /// Returns true if there is a file at `path`.
+    ///
+    /// The default implementation tries to read the file.
+    #[inline]
+    fn exists(&self, path: &str) -> bool {
+        self.read(path).is_ok()
+    }
+}
+
+/// Filesystem whose files are stored in memory.
+///
+/// All the clones of a `MemoryVfs` share the same files. This makes it possible to give a clone
+/// to `Lua::set_vfs` and to access the files written by the Lua code with the original.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, MemoryVfs};
+///
+/// let mut lua = Lua::new();
+/// lua.openlibs();
+///
+/// let vfs = MemoryVfs::new();
+/// vfs.insert("utils.lua", "return { answer = 42 }");
+/// lua.set_vfs(Box::new(vfs.clone()));
+///
+/// let answer: i32 = lua.execute("return require('utils').answer").unwrap();
+/// assert_eq!(answer, 42);
+///
+/// lua.execute::<()>("local f = io.open('out.txt', 'w') f:write('hello') f:close()").unwrap();
+/// assert_eq!(vfs.get("out.txt"), Some(b"hello".to_vec()));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryVfs {
+    files: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryVfs {
+    /// Builds a new empty `MemoryVfs`.
+    #[inline]
+    pub fn new() -> MemoryVfs {
+        MemoryVfs::default()
+    }
+
+    /// Adds a file, or replaces its content if it already exists.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `path` goes above the root of the filesystem or contains a `\` or a `:`.
+    pub fn insert<C>(&self, path: &str, contents: C)
+        where C: Into<Vec<u8>>
+    {
+        let path = normalize(path).expect("invalid path");
+        self.files.borrow_mut().insert(path, contents.into());
+    }
+
+    /// Returns the content of a file, or `None` if it doesn't exist.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        let path = normalize(path)?;
+        self.files.borrow().get(&path).cloned()
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.files.borrow().get(path).cloned().ok_or_else(not_found)
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        self.files.borrow_mut().insert(path.to_owned(), contents.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.files.borrow_mut().remove(path).map(|_| ()).ok_or_else(not_found)
+    }
+
+    #[inline]
+    fn exists(&self, path: &str) -> bool {
+        self.files.borrow().contains_key(path)
+    }
+}
+
+/// Filesystem made of the content of a directory of the real filesystem.
+///
+/// The Lua code can't access the files outside of the directory, except by following symbolic
+/// links that point outside of it.
+///
+/// # Example
+///
+/// ```no_run
+/// use hlua::{DirVfs, Lua};
+///
+/// let mut lua = Lua::new();
+/// lua.openlibs();
+/// lua.set_vfs(Box::new(DirVfs::new("plugins/my_plugin")));
+///
+/// // Runs `plugins/my_plugin/main.lua`.
+/// lua.execute::<()>("dofile('main.lua')").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct DirVfs {
+    root: PathBuf,
+}
+
+impl DirVfs {
+    /// Builds a new `DirVfs` whose root is the directory `root`.
+    #[inline]
+    pub fn new<P>(root: P) -> DirVfs
+        where P: Into<PathBuf>
+    {
+        DirVfs { root: root.into() }
+    }
+}
+
+impl Vfs for DirVfs {
+    #[inline]
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(path))
+    }
+
+    #[inline]
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        fs::write(self.root.join(path), contents)
+    }
+
+    #[inline]
+    fn remove(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.root.join(path))
+    }
+
+    #[inline]
+    fn exists(&self, path: &str) -> bool {
+        self.root.join(path).is_file()
+    }
+}
+
+/// Wraps around another filesystem and refuses to modify it.
+///
+/// # Example
+///
+/// ```
+/// use hlua::{Lua, MemoryVfs, ReadOnlyVfs};
+///
+/// let mut lua = Lua::new();
+/// lua.openlibs();
+///
+/// let vfs = MemoryVfs::new();
+/// vfs.insert("config.lua", "return 5");
+/// lua.set_vfs(Box::new(ReadOnlyVfs(vfs)));
+///
+/// let config: i32 = lua.execute("return dofile('config.lua')").unwrap();
+/// assert_eq!(config, 5);
+/// let (file, _): (Option<bool>, String) = lua.execute("return io.open('config.lua', 'w')")
+///                                             .unwrap();
+/// assert_eq!(file, None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ReadOnlyVfs<V>(pub V);
+
+impl<V> Vfs for ReadOnlyVfs<V>
+    where V: Vfs
+{
+    #[inline]
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.0.read(path)
+    }
+
+    #[inline]
+    fn write(&self, _: &str, _: &[u8]) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    #[inline]
+    fn remove(&self, _: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    #[inline]
+    fn exists(&self, path: &str) -> bool {
+        self.0.exists(path)
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Read-only file system")
+}
+
+// Turns a path used by the Lua code into a path that can be passed to a `Vfs`. Returns `None` if
+// the path goes above the root or doesn't designate a file. Backslashes and colons are refused
+// because `DirVfs` could be escaped on Windows with paths like `..\secret` or `C:\secret`.
+fn normalize(path: &str) -> Option<String> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop()?;
+            }
+            component if component.contains(&['\\', ':'][..]) => return None,
+            component => components.push(component),
+        }
+    }
+
+    if components.is_empty() {
+        None
+    } else {
+        Some(components.join("/"))
+    }
+}
+
+fn resolve(path: &str) -> io::Result<String> {
+    normalize(path).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::PermissionDenied, "Path outside of the virtual filesystem")
+    })
+}
+
+// The `Vfs` of a state is stored as a userdata in the registry, at the address of this static.
+static REGISTRY_KEY: u8 = 0;
+// A userdata is stored in the registry at the address of this static once the Lua code isn't
+// allowed to modify the files anymore.
+static READ_ONLY_KEY: u8 = 0;
+// The metatable of the files is stored in the registry, at the address of this static.
+static FILE_METATABLE_KEY: u8 = 0;
+// The `io` and `package` tables whose functions have been replaced are stored in the registry, at
+// the address of these statics.
+static REPLACED_IO_KEY: u8 = 0;
+static REPLACED_PACKAGE_KEY: u8 = 0;
+
+// Replaces the functions of the `io` library. Called with a table containing the functions that
+// handle the files of the virtual filesystem.
+const REPLACE_IO: &str = r#"
+local vfs = ...
+local open, select_file, open_lines, file_type =
+    vfs.open, vfs.select_file, vfs.open_lines, vfs.file_type
+local raw_input, raw_output, raw_read, raw_write, raw_lines, raw_flush, raw_close, raw_type =
+    io.input, io.output, io.read, io.write, io.lines, io.flush, io.close, io.type
+
+-- Files of the virtual filesystem used as default input and output, or nil if the default files
+-- are handled by the original functions.
+local input, output
+
+io.open = open
+io.popen, io.tmpfile = nil, nil
+
+function io.type(obj)
+    return file_type(obj) or raw_type(obj)
+end
+
+function io.input(file)
+    if file == nil then return input or raw_input() end
+    input = select_file(file, "r")
+    return input or raw_input(file)
+end
+
+function io.read(...)
+    if input then return input:read(...) end
+    return raw_read(...)
+end
+
+function io.lines(filename, ...)
+    if filename ~= nil then return open_lines(filename, ...) end
+    if input then return input:lines(...) end
+    return raw_lines(nil, ...)
+end
+
+if raw_output then
+    function io.output(file)
+        if file == nil then return output or raw_output() end
+        output = select_file(file, "w")
+        return output or raw_output(file)
+    end
+end
+
+function io.write(...)
+    if output then return output:write(...) end
+    return raw_write(...)
+end
+
+function io.flush()
+    if output then return output:flush() end
+    return raw_flush()
+end
+
+function io.close(file)
+    if file == nil then
+        if output then return output:close() end
+        return raw_close()
+    end
+    if file_type(file) then return file:close() end
+    return raw_close(file)
+end
+"#;
+
+/// Sets the filesystem seen by the Lua code.
+pub fn set(lua: &mut Lua, vfs: Box<dyn Vfs>) {
+    let raw_lua = lua.as_lua().0;
+
+    unsafe {
+        let existing = raw::get::<Rc<dyn Vfs>>(raw_lua, &REGISTRY_KEY);
+        if existing.is_null() {
+            raw::insert::<Rc<dyn Vfs>>(raw_lua, &REGISTRY_KEY, Rc::from(vfs));
+        } else {
+            *existing = Rc::from(vfs);
+        }
+    }
+
+    install(lua)
+}
+
+/// Prevents the Lua code from modifying the files, whether the filesystem has already been set or
+/// is set later. Used by `Lua::open_safe_libs`, whose wrapper of `io.open` is replaced when a
+/// filesystem is set.
+pub fn forbid_writes(lua: &mut Lua) {
+    unsafe {
+        raw::get_or_insert_with(lua.as_lua().0, &READ_ONLY_KEY, || ());
+    }
+}
+
+/// If a filesystem has been set, replaces the functions of the libraries that have been opened
+/// so that they use it. Does nothing for the functions that have already been replaced.
+pub fn install(lua: &mut Lua) {
+    let raw_lua = lua.as_lua().0;
+
+    unsafe {
+        ffi::lua_rawgetp(raw_lua, ffi::LUA_REGISTRYINDEX, &REGISTRY_KEY as *const u8 as *const _);
+        let installed = !ffi::lua_isnil(raw_lua, -1);
+        ffi::lua_pop(raw_lua, 1);
+        if !installed {
+            return;
+        }
+
+        replace_global(raw_lua, b"loadfile\0", loadfile);
+        replace_global(raw_lua, b"dofile\0", dofile);
+
+        ffi::lua_getglobal(raw_lua, b"os\0".as_ptr() as *const _);
+        if ffi::lua_istable(raw_lua, -1) {
+            replace_field(raw_lua, b"remove\0", os_remove);
+            replace_field(raw_lua, b"rename\0", os_rename);
+            ffi::lua_pushnil(raw_lua);
+            ffi::lua_setfield(raw_lua, -2, b"tmpname\0".as_ptr() as *const _);
+        }
+        ffi::lua_pop(raw_lua, 1);
+
+        ffi::lua_getglobal(raw_lua, b"package\0".as_ptr() as *const _);
+        if take_unreplaced(raw_lua, &REPLACED_PACKAGE_KEY) {
+            replace_package(raw_lua);
+        }
+        ffi::lua_pop(raw_lua, 1);
+
+        ffi::lua_getglobal(raw_lua, b"io\0".as_ptr() as *const _);
+        let replace_io = take_unreplaced(raw_lua, &REPLACED_IO_KEY);
+        ffi::lua_pop(raw_lua, 1);
+        if !replace_io {
+            return;
+        }
+    }
+
+    let functions = io_functions(lua);
+    let mut replace = LuaFunction::load(lua, REPLACE_IO).unwrap();
+    replace.call_with_args::<(), _, _>(&functions)
+           .expect("failed to replace the io library");
+}
+
+// Replaces the global variable `name` with `function`, if it exists.
+unsafe fn replace_global(lua: *mut ffi::lua_State, name: &[u8], function: ffi::lua_CFunction) {
+    ffi::lua_getglobal(lua, name.as_ptr() as *const _);
+    if !ffi::lua_isnil(lua, -1) {
+        ffi::lua_pushcfunction(lua, function);
+        ffi::lua_setglobal(lua, name.as_ptr() as *const _);
+    }
+    ffi::lua_pop(lua, 1);
+}
+
+// Replaces the field `name` of the table at the top of the stack with `function`, if it exists.
+unsafe fn replace_field(lua: *mut ffi::lua_State, name: &[u8], function: ffi::lua_CFunction) {
+    ffi::lua_getfield(lua, -1, name.as_ptr() as *const _);
+    let exists = !ffi::lua_isnil(lua, -1);
+    ffi::lua_pop(lua, 1);
+    if exists {
+        ffi::lua_pushcfunction(lua, function);
+        ffi::lua_setfield(lua, -2, name.as_ptr() as *const _);
+    }
+}
+
+// Returns true if the value at the top of the stack is a table whose functions haven't been
+// replaced yet, and remembers that they are going to be.
+unsafe fn take_unreplaced(lua: *mut ffi::lua_State, key: &'static u8) -> bool {
+    if !ffi::lua_istable(lua, -1) {
+        return false;
+    }
+
+    ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, key as *const u8 as *const _);
+    let replaced = ffi::lua_rawequal(lua, -1, -2) != 0;
+    ffi::lua_pop(lua, 1);
+    if replaced {
+        return false;
+    }
+
+    ffi::lua_pushvalue(lua, -1);
+    ffi::lua_rawsetp(lua, ffi::LUA_REGISTRYINDEX, key as *const u8 as *const _);
+    true
+}
+
+// Makes the `package` table at the top of the stack load modules from the virtual filesystem.
+unsafe fn replace_package(lua: *mut ffi::lua_State) {
+    replace_field(lua, b"searchpath\0", searchpath);
+    ffi::lua_pushnil(lua);
+    ffi::lua_setfield(lua, -2, b"loadlib\0".as_ptr() as *const _);
+    ffi::lua_pushstring(lua, b"\0".as_ptr() as *const _);
+    ffi::lua_setfield(lua, -2, b"cpath\0".as_ptr() as *const _);
+
+    ffi::lua_getfield(lua, -1, b"searchers\0".as_ptr() as *const _);
+    if ffi::lua_istable(lua, -1) {
+        // The second searcher loads Lua modules, and the third and fourth ones load native
+        // modules. The searchers added after them are kept.
+        ffi::lua_pushvalue(lua, -2);
+        ffi::lua_pushcclosure(lua, searcher, 1);
+        ffi::lua_rawseti(lua, -2, 2);
+
+        let len = ffi::lua_rawlen(lua, -1) as libc::c_int;
+        for i in 5..=len {
+            ffi::lua_rawgeti(lua, -1, i);
+            ffi::lua_rawseti(lua, -2, i - 2);
+        }
+        for i in (len - 2).max(2) + 1..=len {
+            ffi::lua_pushnil(lua);
+            ffi::lua_rawseti(lua, -2, i);
+        }
+    }
+    ffi::lua_pop(lua, 1);
+}
+
+// Builds the table of functions passed to `REPLACE_IO`.
+fn io_functions(lua: &mut Lua) -> LuaRef {
+    let raw_lua = lua.as_lua();
+
+    unsafe {
+        ffi::lua_createtable(raw_lua.0, 0, 4);
+        ffi::lua_pushcfunction(raw_lua.0, open);
+        ffi::lua_setfield(raw_lua.0, -2, b"open\0".as_ptr() as *const _);
+        ffi::lua_pushcfunction(raw_lua.0, select_file);
+        ffi::lua_setfield(raw_lua.0, -2, b"select_file\0".as_ptr() as *const _);
+        ffi::lua_pushcfunction(raw_lua.0, open_lines);
+        ffi::lua_setfield(raw_lua.0, -2, b"open_lines\0".as_ptr() as *const _);
+        ffi::lua_pushcfunction(raw_lua.0, file_type);
+        ffi::lua_setfield(raw_lua.0, -2, b"file_type\0".as_ptr() as *const _);
+    }
+
+    let guard = PushGuard {
+        lua,
+        size: 1,
+        raw_lua,
+    };
+    LuaRead::lua_read(guard).ok().unwrap()
+}
+
+// Returns the filesystem of the state.
+unsafe fn vfs(lua: *mut ffi::lua_State) -> Rc<dyn Vfs> {
+    let vfs = raw::get::<Rc<dyn Vfs>>(lua, &REGISTRY_KEY);
+    assert!(!vfs.is_null());
+    if raw::get::<()>(lua, &READ_ONLY_KEY).is_null() {
+        (*vfs).clone()
+    } else {
+        Rc::new(ReadOnlyVfs(Shared((*vfs).clone())))
+    }
+}
+
+// Filesystem shared with the files that are open, so that it can be wrapped in a `ReadOnlyVfs`.
+struct Shared(Rc<dyn Vfs>);
+
+impl Vfs for Shared {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.0.read(path)
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        self.0.write(path, contents)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.0.remove(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.0.exists(path)
+    }
+}
+
+// Calls a method of the filesystem.
+//
+// If it panics, an error object is pushed and `None` is returned. The caller must then pass the
+// values that it owns to `rethrow`, as raising an error skips their destructors.
+unsafe fn catch<F, R>(lua: *mut ffi::lua_State, f: F) -> Option<R>
+    where F: FnOnce(&Rc<dyn Vfs>) -> R
+{
+    let vfs = vfs(lua);
+    let result = rust_errors::catch(lua, || f(&vfs));
+    drop(vfs);
+    result
+}
+
+// Drops `owned`, then raises the error object pushed by `catch`.
+unsafe fn rethrow<T>(lua: *mut ffi::lua_State, owned: T) -> ! {
+    drop(owned);
+    ffi::lua_error(lua);
+    unreachable!()
+}
+
+// Returns the string passed as parameter `arg`, or raises an error if it isn't a string.
+unsafe fn check_string<'a>(lua: *mut ffi::lua_State, arg: libc::c_int) -> &'a [u8] {
+    let ty = ffi::lua_type(lua, arg);
+    if ty != ffi::LUA_TSTRING && ty != ffi::LUA_TNUMBER {
+        raise(lua, functions_write::argument_error(lua, arg, "string"));
+    }
+    string_at(lua, arg)
+}
+
+// Returns the path passed as parameter `arg`, or raises an error if it isn't a string.
+unsafe fn check_path(lua: *mut ffi::lua_State, arg: libc::c_int) -> String {
+    String::from_utf8_lossy(check_string(lua, arg)).into_owned()
+}
+
+// Returns the string passed as parameter `arg`, or `default` if there is none.
+unsafe fn opt_string(lua: *mut ffi::lua_State, arg: libc::c_int, default: &[u8]) -> &[u8]
+{
+    if ffi::lua_isnoneornil(lua, arg) {
+        return default;
+    }
+    let ty = ffi::lua_type(lua, arg);
+    if ty != ffi::LUA_TSTRING && ty != ffi::LUA_TNUMBER {
+        raise(lua, functions_write::argument_error(lua, arg, "string"));
+    }
+    string_at(lua, arg)
+}
+
+/// File of the virtual filesystem opened by the Lua code.
+struct File {
+    vfs: Rc<dyn Vfs>,
+    path: String,
+    data: Vec<u8>,
+    position: usize,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    // True if `data` has been modified since the last time it has been written.
+    dirty: bool,
+    closed: bool,
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File({:?})", self.path)
+    }
+}
+
+// Returns true if `mode` is a valid mode for `io.open`.
+fn valid_mode(mode: &[u8]) -> bool {
+    let rest = match mode.split_first() {
+        Some((b'r', rest)) | Some((b'w', rest)) | Some((b'a', rest)) => rest,
+        _ => return false,
+    };
+    let rest = rest.strip_prefix(b"+").unwrap_or(rest);
+    rest.iter().all(|&c| c == b'b')
+}
+
+impl File {
+    // Opens a file. `mode` must be valid.
+    fn open(vfs: Rc<dyn Vfs>, path: &str, mode: &[u8]) -> io::Result<File> {
+        let path = resolve(path)?;
+        let update = mode.get(1) == Some(&b'+');
+
+        let data = match mode[0] {
+            b'w' => Vec::new(),
+            b'a' => match vfs.read(&path) {
+                Ok(data) => data,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err),
+            },
+            _ => vfs.read(&path)?,
+        };
+
+        // Like the C library, report as soon as possible that the file can't be written.
+        let writable = mode[0] != b'r' || update;
+        if writable {
+            vfs.write(&path, &data)?;
+        }
+
+        Ok(File {
+            vfs,
+            path,
+            data,
+            position: 0,
+            readable: mode[0] == b'r' || update,
+            writable,
+            append: mode[0] == b'a',
+            dirty: false,
+            closed: false,
+        })
+    }
+
+    fn remaining(&self) -> &[u8] {
+        &self.data[self.position.min(self.data.len())..]
+    }
+
+    fn read_line(&mut self, keep_newline: bool) -> Option<Vec<u8>> {
+        let rest = self.remaining();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (len, consumed) = match rest.iter().position(|&c| c == b'\n') {
+            Some(pos) if keep_newline => (pos + 1, pos + 1),
+            Some(pos) => (pos, pos + 1),
+            None => (rest.len(), rest.len()),
+        };
+        let line = rest[..len].to_vec();
+        self.position += consumed;
+        Some(line)
+    }
+
+    fn read_all(&mut self) -> Vec<u8> {
+        let rest = self.remaining().to_vec();
+        self.position += rest.len();
+        rest
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Option<Vec<u8>> {
+        let rest = self.remaining();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let bytes = rest[..count.min(rest.len())].to_vec();
+        self.position += bytes.len();
+        Some(bytes)
+    }
+
+    fn read_number(&mut self) -> Option<f64> {
+        let rest = self.remaining();
+        let spaces = rest.iter().take_while(|c| c.is_ascii_whitespace()).count();
+        let len = rest[spaces..].iter()
+                                .take_while(|&&c| c.is_ascii_digit() || b"+-.eE".contains(&c))
+                                .count();
+        let number = String::from_utf8_lossy(&rest[spaces..spaces + len]).parse().ok();
+        self.position += spaces + len;
+        number
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.append {
+            self.position = self.data.len();
+        }
+
+        let end = self.position + bytes.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[self.position..end].copy_from_slice(bytes);
+        self.position = end;
+        self.dirty = true;
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.vfs.write(&self.path, &self.data)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // The files that the Lua code forgets to close are written when they are collected. The
+        // errors can't be reported to anyone.
+        if !self.closed {
+            let _ = self.flush();
+        }
+    }
+}
+
+unsafe fn push_file(lua: *mut ffi::lua_State, file: File) {
+    let data = ffi::lua_newuserdata(lua, mem::size_of::<File>() as libc::size_t);
+    ptr::write(data as *mut File, file);
+
+    ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, &FILE_METATABLE_KEY as *const u8 as *const _);
+    if ffi::lua_isnil(lua, -1) {
+        ffi::lua_pop(lua, 1);
+        push_file_metatable(lua);
+    }
+    ffi::lua_setmetatable(lua, -2);
+}
+
+unsafe fn push_file_metatable(lua: *mut ffi::lua_State) {
+    ffi::lua_createtable(lua, 0, 3);
+
+    ffi::lua_createtable(lua, 0, 8);
+    let methods: [(&[u8], ffi::lua_CFunction); 8] = [
+        (b"read\0", file_read),
+        (b"lines\0", file_lines),
+        (b"write\0", file_write),
+        (b"seek\0", file_seek),
+        (b"flush\0", file_flush),
+        (b"close\0", file_close),
+        (b"setvbuf\0", file_setvbuf),
+        (b"__tostring\0", file_tostring),
+    ];
+    for &(name, function) in methods.iter() {
+        ffi::lua_pushcfunction(lua, function);
+        ffi::lua_setfield(lua, -2, name.as_ptr() as *const _);
+    }
+    ffi::lua_setfield(lua, -2, b"__index\0".as_ptr() as *const _);
+
+    ffi::lua_pushcfunction(lua, file_tostring);
+    ffi::lua_setfield(lua, -2, b"__tostring\0".as_ptr() as *const _);
+    ffi::lua_pushcfunction(lua, raw::destructor::<File>);
+    ffi::lua_setfield(lua, -2, b"__gc\0".as_ptr() as *const _);
+
+    ffi::lua_pushvalue(lua, -1);
+    ffi::lua_rawsetp(lua, ffi::LUA_REGISTRYINDEX, &FILE_METATABLE_KEY as *const u8 as *const _);
+}
+
+// Returns the file at `index`, or null if the value isn't a file of the virtual filesystem.
+unsafe fn to_file(lua: *mut ffi::lua_State, index: libc::c_int) -> *mut File {
+    if ffi::lua_type(lua, index) != ffi::LUA_TUSERDATA || ffi::lua_getmetatable(lua, index) == 0 {
+        return ptr::null_mut();
+    }
+
+    ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, &FILE_METATABLE_KEY as *const u8 as *const _);
+    let is_file = ffi::lua_rawequal(lua, -1, -2) != 0;
+    ffi::lua_pop(lua, 2);
+    if is_file {
+        ffi::lua_touserdata(lua, index) as *mut File
+    } else {
+        ptr::null_mut()
+    }
+}
+
+// Returns the open file passed as parameter `arg`, or raises an error.
+unsafe fn check_file(lua: *mut ffi::lua_State, arg: libc::c_int) -> *mut File {
+    let file = to_file(lua, arg);
+    if file.is_null() {
+        raise(lua, functions_write::argument_error(lua, arg, "FILE*"));
+    }
+    if (*file).closed {
+        raise(lua, "attempt to use a closed file".to_owned());
+    }
+    file
+}
+
+// Reads from `file` with the formats between `first` and `last`, like `file:read` does. Returns
+// the number of values pushed.
+unsafe fn read_formats(lua: *mut ffi::lua_State, file: *mut File, first: libc::c_int,
+                       last: libc::c_int) -> libc::c_int
+{
+    let file = &mut *file;
+    if !file.readable {
+        return push_error(lua, "Bad file descriptor");
+    }
+
+    if first > last {
+        return match file.read_line(false) {
+            Some(line) => {
+                push_bytes(lua, &line);
+                1
+            }
+            None => {
+                ffi::lua_pushnil(lua);
+                1
+            }
+        };
+    }
+
+    for i in first..=last {
+        let value = if ffi::lua_type(lua, i) == ffi::LUA_TNUMBER {
+            let count = ffi::lua_tointegerx(lua, i, ptr::null_mut()).max(0) as usize;
+            file.read_bytes(count)
+        } else {
+            let format = if ffi::lua_type(lua, i) == ffi::LUA_TSTRING { string_at(lua, i) } else { b"" };
+            let format = format.strip_prefix(b"*").unwrap_or(format);
+            match format.first() {
+                Some(b'n') => {
+                    match file.read_number() {
+                        Some(number) => ffi::lua_pushnumber(lua, number),
+                        None => ffi::lua_pushnil(lua),
+                    }
+                    continue;
+                }
+                Some(b'l') => file.read_line(false),
+                Some(b'L') => file.read_line(true),
+                Some(b'a') => Some(file.read_all()),
+                _ => raise(lua, format!("bad argument #{} to 'read' (invalid format)",
+                                        i - first + 1)),
+            }
+        };
+
+        match value {
+            Some(value) => push_bytes(lua, &value),
+            None => {
+                ffi::lua_pushnil(lua);
+                return i - first + 1;
+            }
+        }
+    }
+
+    last - first + 1
+}
+
+// Pushes an iterator that reads `file` with the formats between `first` and `last`. If `close`
+// is true, the file is closed once the iterator reaches the end.
+unsafe fn push_lines(lua: *mut ffi::lua_State, file: libc::c_int, close: bool,
+                     first: libc::c_int, last: libc::c_int)
+{
+    let num_formats = (last - first + 1).max(0);
+    if num_formats > 250 || ffi::lua_checkstack(lua, num_formats + 3) == 0 {
+        raise(lua, "too many arguments".to_owned());
+    }
+
+    ffi::lua_pushvalue(lua, file);
+    ffi::lua_pushboolean(lua, close as libc::c_int);
+    ffi::lua_pushinteger(lua, num_formats as ffi::lua_Integer);
+    for i in first..=last {
+        ffi::lua_pushvalue(lua, i);
+    }
+    ffi::lua_pushcclosure(lua, lines_iterator, num_formats + 3);
+}
+
+extern "C" fn lines_iterator(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = to_file(lua, ffi::lua_upvalueindex(1));
+        if (*file).closed {
+            raise(lua, "file is already closed".to_owned());
+        }
+
+        let num_formats = ffi::lua_tointegerx(lua, ffi::lua_upvalueindex(3), ptr::null_mut());
+        let num_formats = num_formats as libc::c_int;
+        ffi::lua_settop(lua, 0);
+        for i in 0..num_formats {
+            ffi::lua_pushvalue(lua, ffi::lua_upvalueindex(4 + i));
+        }
+
+        let num_results = read_formats(lua, file, 1, num_formats);
+        if !ffi::lua_isnil(lua, -num_results) {
+            return num_results;
+        }
+
+        if ffi::lua_toboolean(lua, ffi::lua_upvalueindex(2)) != 0 {
+            let result = catch(lua, |_| (*file).flush());
+            (*file).closed = true;
+            if result.is_none() {
+                rethrow(lua, ());
+            }
+        }
+        0
+    }
+}
+
+extern "C" fn open(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let mode = opt_string(lua, 2, b"r");
+        if !valid_mode(mode) {
+            raise(lua, "bad argument #2 to 'open' (invalid mode)".to_owned());
+        }
+
+        let path = check_path(lua, 1);
+        match catch(lua, |vfs| File::open(vfs.clone(), &path, mode)) {
+            Some(Ok(file)) => {
+                push_file(lua, file);
+                1
+            }
+            Some(Err(err)) => push_error(lua, &format!("{}: {}", path, err)),
+            None => rethrow(lua, path),
+        }
+    }
+}
+
+// Opens the file at `path` for `io.input`, `io.output` or `io.lines`. Raises an error on failure.
+unsafe fn open_or_raise(lua: *mut ffi::lua_State, path: libc::c_int, mode: &[u8]) {
+    let path = check_path(lua, path);
+    match catch(lua, |vfs| File::open(vfs.clone(), &path, mode)) {
+        Some(Ok(file)) => push_file(lua, file),
+        Some(Err(err)) => {
+            let message = format!("cannot open file '{}' ({})", path, err);
+            drop(path);
+            raise(lua, message)
+        }
+        None => rethrow(lua, path),
+    }
+}
+
+// Used by `io.input` and `io.output`. Returns the file passed as parameter, opening it if it is
+// a path, or `nil` if it isn't a file of the virtual filesystem.
+extern "C" fn select_file(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let ty = ffi::lua_type(lua, 1);
+        if !to_file(lua, 1).is_null() {
+            check_file(lua, 1);
+            ffi::lua_pushvalue(lua, 1);
+        } else if ty == ffi::LUA_TSTRING || ty == ffi::LUA_TNUMBER {
+            let mode: &[u8] = if opt_string(lua, 2, b"r") == b"w" { b"w" } else { b"r" };
+            open_or_raise(lua, 1, mode);
+        } else {
+            ffi::lua_pushnil(lua);
+        }
+        1
+    }
+}
+
+extern "C" fn open_lines(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let last = ffi::lua_gettop(lua);
+        open_or_raise(lua, 1, b"r");
+        ffi::lua_replace(lua, 1);
+        push_lines(lua, 1, true, 2, last);
+        1
+    }
+}
+
+extern "C" fn file_type(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = to_file(lua, 1);
+        if file.is_null() {
+            ffi::lua_pushnil(lua);
+        } else if (*file).closed {
+            push_bytes(lua, b"closed file");
+        } else {
+            push_bytes(lua, b"file");
+        }
+        1
+    }
+}
+
+extern "C" fn file_read(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = check_file(lua, 1);
+        read_formats(lua, file, 2, ffi::lua_gettop(lua))
+    }
+}
+
+extern "C" fn file_lines(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        check_file(lua, 1);
+        push_lines(lua, 1, false, 2, ffi::lua_gettop(lua));
+        1
+    }
+}
+
+extern "C" fn file_write(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = &mut *check_file(lua, 1);
+        let num_args = ffi::lua_gettop(lua);
+
+        for i in 2..=num_args {
+            let ty = ffi::lua_type(lua, i);
+            if ty != ffi::LUA_TSTRING && ty != ffi::LUA_TNUMBER {
+                raise(lua, functions_write::argument_error(lua, i, "string"));
+            }
+        }
+
+        if !file.writable {
+            return push_error(lua, "Bad file descriptor");
+        }
+        for i in 2..=num_args {
+            file.write(string_at(lua, i));
+        }
+
+        ffi::lua_pushvalue(lua, 1);
+        1
+    }
+}
+
+extern "C" fn file_seek(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = &mut *check_file(lua, 1);
+
+        let whence = opt_string(lua, 2, b"cur");
+        let base = match whence {
+            b"set" => 0,
+            b"cur" => file.position,
+            b"end" => file.data.len(),
+            _ => raise(lua, "bad argument #1 to 'seek' (invalid option)".to_owned()),
+        };
+
+        let mut is_num = 1;
+        let offset = if ffi::lua_isnoneornil(lua, 3) {
+            0
+        } else {
+            ffi::lua_tointegerx(lua, 3, &mut is_num)
+        };
+        if is_num == 0 {
+            raise(lua, functions_write::argument_error(lua, 3, "number"));
+        }
+
+        let position = base as i64 + offset as i64;
+        if position < 0 {
+            return push_error(lua, "Invalid argument");
+        }
+        file.position = position as usize;
+        ffi::lua_pushnumber(lua, position as f64);
+        1
+    }
+}
+
+extern "C" fn file_flush(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = check_file(lua, 1);
+        match catch(lua, |_| (*file).flush()) {
+            Some(Ok(())) => {
+                ffi::lua_pushboolean(lua, 1);
+                1
+            }
+            Some(Err(err)) => push_error(lua, &err.to_string()),
+            None => rethrow(lua, ()),
+        }
+    }
+}
+
+extern "C" fn file_close(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = check_file(lua, 1);
+        let result = catch(lua, |_| (*file).flush());
+        (*file).closed = true;
+        (*file).data = Vec::new();
+
+        match result {
+            Some(Ok(())) => {
+                ffi::lua_pushboolean(lua, 1);
+                1
+            }
+            Some(Err(err)) => push_error(lua, &err.to_string()),
+            None => rethrow(lua, ()),
+        }
+    }
+}
+
+extern "C" fn file_setvbuf(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        check_file(lua, 1);
+        ffi::lua_pushboolean(lua, 1);
+        1
+    }
+}
+
+extern "C" fn file_tostring(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let file = to_file(lua, 1);
+        if file.is_null() {
+            raise(lua, functions_write::argument_error(lua, 1, "FILE*"));
+        }
+
+        let description = if (*file).closed {
+            "file (closed)".to_owned()
+        } else {
+            format!("file ({:p})", file)
+        };
+        push_bytes(lua, description.as_bytes());
+        1
+    }
+}
+
+// Reads a chunk for `lua_load`.
+struct ChunkReader {
+    data: *const u8,
+    len: usize,
+    done: bool,
+}
+
+extern "C" fn read_chunk(_: *mut ffi::lua_State, data: *mut libc::c_void,
+                         size: *mut libc::size_t) -> *const libc::c_char
+{
+    unsafe {
+        let reader = &mut *(data as *mut ChunkReader);
+        if reader.done {
+            *size = 0;
+            return ptr::null();
+        }
+
+        reader.done = true;
+        *size = reader.len as libc::size_t;
+        reader.data as *const _
+    }
+}
+
+// Error of `load_file`.
+enum LoadError {
+    // Nothing has been pushed.
+    Message(String),
+    // Reading the file has panicked. The error object has been pushed, and must be raised with
+    // `rethrow`.
+    Panic,
+}
+
+// Loads the file at `path` as a Lua function and pushes it.
+//
+// Precompiled chunks are refused, as loading a malformed one can crash the interpreter.
+unsafe fn load_file(lua: *mut ffi::lua_State, path: &str) -> Result<(), LoadError> {
+    let contents = catch(lua, |vfs| resolve(path).and_then(|p| vfs.read(&p)));
+    let contents = match contents {
+        Some(Ok(contents)) => contents,
+        Some(Err(err)) => return Err(LoadError::Message(format!("cannot open {}: {}", path, err))),
+        None => return Err(LoadError::Panic),
+    };
+
+    // The content is moved to a Lua string so that nothing leaks if `lua_load` raises an error.
+    push_bytes(lua, &contents);
+    drop(contents);
+    let chunk_name = CString::new(format!("@{}", path)).unwrap_or_default();
+
+    let mut len = 0;
+    let data = ffi::lua_tolstring(lua, -1, &mut len);
+    let mut reader = ChunkReader {
+        data: data as *const u8,
+        len,
+        done: false,
+    };
+    let status = ffi::lua_load(lua, read_chunk, &mut reader as *mut ChunkReader as *mut _,
+                               chunk_name.as_ptr(), b"t\0".as_ptr() as *const _);
+    ffi::lua_remove(lua, -2);
+
+    if status == 0 {
+        return Ok(());
+    }
+
+    let message = String::from_utf8_lossy(string_at(lua, -1)).into_owned();
+    ffi::lua_pop(lua, 1);
+    Err(LoadError::Message(message))
+}
+
+// Replacement for `loadfile`. The mode parameter is ignored, as only text chunks are allowed.
+extern "C" fn loadfile(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        if ffi::lua_isnoneornil(lua, 1) {
+            return push_error(lua, "cannot read chunks from the standard input");
+        }
+
+        let has_env = ffi::lua_gettop(lua) >= 3;
+        let path = check_path(lua, 1);
+        match load_file(lua, &path) {
+            Ok(()) => {
+                if has_env {
+                    ffi::lua_pushvalue(lua, 3);
+                    if ffi::lua_setupvalue(lua, -2, 1).is_null() {
+                        ffi::lua_pop(lua, 1);
+                    }
+                }
+                1
+            }
+            Err(LoadError::Message(message)) => push_error(lua, &message),
+            Err(LoadError::Panic) => rethrow(lua, path),
+        }
+    }
+}
+
+// Replacement for `dofile`.
+extern "C" fn dofile(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        if ffi::lua_isnoneornil(lua, 1) {
+            raise(lua, "cannot read chunks from the standard input".to_owned());
+        }
+
+        ffi::lua_settop(lua, 1);
+        let result = {
+            let path = check_path(lua, 1);
+            load_file(lua, &path)
+        };
+        match result {
+            Ok(()) => (),
+            Err(LoadError::Message(message)) => raise(lua, message),
+            Err(LoadError::Panic) => rethrow(lua, ()),
+        }
+
+        ffi::lua_call(lua, 0, ffi::MULTRET);
+        ffi::lua_gettop(lua) - 1
+    }
+}
+
+// Replacement for `os.remove`.
+extern "C" fn os_remove(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let path = check_path(lua, 1);
+        match catch(lua, |vfs| resolve(&path).and_then(|p| vfs.remove(&p))) {
+            Some(Ok(())) => {
+                ffi::lua_pushboolean(lua, 1);
+                1
+            }
+            Some(Err(err)) => push_error(lua, &format!("{}: {}", path, err)),
+            None => rethrow(lua, path),
+        }
+    }
+}
+
+// Replacement for `os.rename`.
+extern "C" fn os_rename(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        // Both parameters are checked before anything is allocated, as raising an error skips
+        // destructors.
+        check_string(lua, 2);
+        let from = check_path(lua, 1);
+        let to = check_path(lua, 2);
+        let result = catch(lua, |vfs| {
+            let from = resolve(&from)?;
+            let to = resolve(&to)?;
+            let contents = vfs.read(&from)?;
+            vfs.write(&to, &contents)?;
+            vfs.remove(&from)
+        });
+
+        match result {
+            Some(Ok(())) => {
+                ffi::lua_pushboolean(lua, 1);
+                1
+            }
+            Some(Err(err)) => push_error(lua, &format!("{}: {}", from, err)),
+            None => rethrow(lua, (from, to)),
+        }
+    }
+}
+
+// Looks for `name` in `path` like `package.searchpath` does. On failure, returns the list of the
+// files that have been tried.
+fn search_path(vfs: &dyn Vfs, name: &str, path: &str, sep: &str, rep: &str)
+               -> Result<String, String>
+{
+    let name = if sep.is_empty() { name.to_owned() } else { name.replace(sep, rep) };
+
+    let mut tried = String::new();
+    for template in path.split(';').filter(|t| !t.is_empty()) {
+        let filename = template.replace('?', &name);
+        if resolve(&filename).map(|p| vfs.exists(&p)).unwrap_or(false) {
+            return Ok(filename);
+        }
+        tried.push_str(&format!("\n\tno file '{}'", filename));
+    }
+
+    Err(tried)
+}
+
+// Replacement for `package.searchpath`.
+extern "C" fn searchpath(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        // The parameters are checked before anything is allocated, as raising an error skips
+        // destructors.
+        check_string(lua, 1);
+        check_string(lua, 2);
+        let sep = String::from_utf8_lossy(opt_string(lua, 3, b".")).into_owned();
+        let rep = String::from_utf8_lossy(opt_string(lua, 4, b"/")).into_owned();
+        let name = check_path(lua, 1);
+        let path = check_path(lua, 2);
+
+        match catch(lua, |vfs| search_path(&**vfs, &name, &path, &sep, &rep)) {
+            Some(Ok(filename)) => {
+                push_bytes(lua, filename.as_bytes());
+                1
+            }
+            Some(Err(tried)) => push_error(lua, &tried),
+            None => rethrow(lua, (name, path, sep, rep)),
+        }
+    }
+}
+
+// Searcher that loads Lua modules from the virtual filesystem. Its upvalue is the `package`
+// table.
+extern "C" fn searcher(lua: *mut ffi::lua_State) -> libc::c_int {
+    unsafe {
+        let name = check_path(lua, 1);
+
+        ffi::lua_getfield(lua, ffi::lua_upvalueindex(1), b"path\0".as_ptr() as *const _);
+        if ffi::lua_type(lua, -1) != ffi::LUA_TSTRING {
+            drop(name);
+            raise(lua, "'package.path' must be a string".to_owned());
+        }
+        let path = String::from_utf8_lossy(string_at(lua, -1)).into_owned();
+        ffi::lua_pop(lua, 1);
+
+        let filename = match catch(lua, |vfs| search_path(&**vfs, &name, &path, ".", "/")) {
+            Some(Ok(filename)) => filename,
+            Some(Err(tried)) => {
+                push_bytes(lua, tried.as_bytes());
+                return 1;
+            }
+            None => rethrow(lua, (name, path)),
+        };
+
+        match load_file(lua, &filename) {
+            Ok(()) => (),
+            Err(LoadError::Message(message)) => {
+                let message = format!("error loading module '{}' from file '{}':\n\t{}",
+                                      name, filename, message);
+                drop((name, path, filename));
+                raise(lua, message);
+            }
+            Err(LoadError::Panic) => rethrow(lua, (name, path, filename)),
+        }
+        push_bytes(lua, filename.as_bytes());
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    use AnyLuaString;
+    use DirVfs;
+    use Lua;
+    use LuaError;
+    use MemoryVfs;
+    use ReadOnlyVfs;
+    use Vfs;
+
+    use super::normalize;
+
+    fn lua_with_files(files: &[(&str, &str)]) -> (Lua<'static>, MemoryVfs) {
+        let mut lua = Lua::new();
+        lua.openlibs();
+
+        let vfs = MemoryVfs::new();
+        for &(path, contents) in files {
+            vfs.insert(path, contents);
+        }
+        lua.set_vfs(Box::new(vfs.clone()));
+        (lua, vfs)
+    }
+
+    #[test]
+    fn read_files() {
+        let (mut lua, _) = lua_with_files(&[("data.txt", "12 3.5\nsecond line\nend")]);
+
+        let (a, b, line, rest): (i32, f64, String, String) = lua.execute(r#"
+            local f = assert(io.open("data.txt"))
+            local a, b = f:read("*n", "*n")
+            f:read("*l")
+            return a, b, f:read("*L"), f:read("*a")
+        "#).unwrap();
+        assert_eq!((a, b), (12, 3.5));
+        assert_eq!(line, "second line\n");
+        assert_eq!(rest, "end");
+    }
+
+    #[test]
+    fn read_past_end() {
+        let (mut lua, _) = lua_with_files(&[("data.txt", "ab")]);
+
+        let values: (String, Option<String>, String) = lua.execute(r#"
+            local f = assert(io.open("data.txt"))
+            return f:read(5), f:read("*l"), f:read("*a")
+        "#).unwrap();
+        assert_eq!(values, ("ab".to_owned(), None, "".to_owned()));
+    }
+
+    #[test]
+    fn lines() {
+        let (mut lua, _) = lua_with_files(&[("list.txt", "a\nb\nc\n")]);
+
+        let joined: String = lua.execute(r#"
+            local parts = {}
+            for line in io.lines("list.txt") do parts[#parts + 1] = line end
+            local f = io.open("list.txt")
+            for char in f:lines(1) do parts[#parts + 1] = char end
+            return table.concat(parts, ",")
+        "#).unwrap();
+        assert_eq!(joined, "a,b,c,a,\n,b,\n,c,\n");
+    }
+
+    #[test]
+    fn write_files() {
+        let (mut lua, vfs) = lua_with_files(&[("log.txt", "old\n")]);
+
+        lua.execute::<()>(r#"
+            local f = assert(io.open("out.txt", "w"))
+            f:write("a", 1, "\n"):write("b")
+            f:close()
+
+            f = assert(io.open("log.txt", "a"))
+            f:write("new\n")
+            f:close()
+        "#).unwrap();
+        assert_eq!(vfs.get("out.txt"), Some(b"a1\nb".to_vec()));
+        assert_eq!(vfs.get("log.txt"), Some(b"old\nnew\n".to_vec()));
+    }
+
+    #[test]
+    fn unclosed_files_written_when_collected() {
+        let (mut lua, vfs) = lua_with_files(&[]);
+
+        lua.execute::<()>(r#"
+            local f = assert(io.open("out.txt", "w"))
+            f:write("forgotten")
+        "#).unwrap();
+        assert_eq!(vfs.get("out.txt"), Some(Vec::new()));
+
+        lua.execute::<()>("collectgarbage()").unwrap();
+        assert_eq!(vfs.get("out.txt"), Some(b"forgotten".to_vec()));
+    }
+
+    #[test]
+    fn seek_and_update() {
+        let (mut lua, vfs) = lua_with_files(&[("data.txt", "hello world")]);
+
+        let values: (i32, String, i32) = lua.execute(r#"
+            local f = assert(io.open("data.txt", "r+"))
+            local size = f:seek("end")
+            f:seek("set", 6)
+            f:write("there")
+            f:seek("set")
+            local content = f:read("*a")
+            local position = f:seek("cur", -5)
+            f:close()
+            return size, content, position
+        "#).unwrap();
+        assert_eq!(values, (11, "hello there".to_owned(), 6));
+        assert_eq!(vfs.get("data.txt"), Some(b"hello there".to_vec()));
+    }
+
+    #[test]
+    fn missing_files_and_real_filesystem() {
+        let (mut lua, _) = lua_with_files(&[]);
+
+        let (f, err): (Option<bool>, String) = lua.execute("return io.open('Cargo.toml')").unwrap();
+        assert_eq!(f, None);
+        assert!(err.starts_with("Cargo.toml: "));
+
+        let f: Option<bool> = lua.execute("return io.open('../hlua/Cargo.toml')").unwrap();
+        assert_eq!(f, None);
+        let removed: bool = lua.execute("return io.popen == nil and os.tmpname == nil and \
+                                         package.loadlib == nil").unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn default_input_and_output() {
+        let (mut lua, vfs) = lua_with_files(&[("in.txt", "first\nsecond")]);
+
+        let line: String = lua.execute(r#"
+            io.input("in.txt")
+            io.output("out.txt")
+            io.write(io.read(), "!")
+            io.close()
+            io.output(io.stdout)
+            local lines = {}
+            for line in io.lines() do lines[#lines + 1] = line end
+            return table.concat(lines) .. tostring(io.type(io.input()))
+        "#).unwrap();
+        assert_eq!(line, "secondfile");
+        assert_eq!(vfs.get("out.txt"), Some(b"first!".to_vec()));
+    }
+
+    #[test]
+    fn closed_files() {
+        let (mut lua, _) = lua_with_files(&[("a.txt", "a")]);
+
+        let ty: String = lua.execute("local f = io.open('a.txt') f:close() return io.type(f)")
+                            .unwrap();
+        assert_eq!(ty, "closed file");
+
+        match lua.execute::<()>("local f = io.open('a.txt') f:close() f:read()") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().ends_with("attempt to use a closed file"));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn require_dofile_and_loadfile() {
+        let (mut lua, _) = lua_with_files(&[
+            ("lib/greet.lua", "return function(name) return 'hello ' .. name end"),
+            ("config.lua", "return 1 + 2"),
+            ("broken.lua", "return +"),
+        ]);
+        lua.execute::<()>("package.path = 'lib/?.lua'").unwrap();
+
+        let val: String = lua.execute("return require('greet')('lua')").unwrap();
+        assert_eq!(val, "hello lua");
+        let val: i32 = lua.execute("return dofile('config.lua')").unwrap();
+        assert_eq!(val, 3);
+
+        let (f, err): (Option<bool>, String) = lua.execute("return loadfile('broken.lua')")
+                                                  .unwrap();
+        assert_eq!(f, None);
+        assert!(err.starts_with("broken.lua:1:"));
+
+        match lua.execute::<()>("require('missing')") {
+            Err(LuaError::ExecutionError(err)) => {
+                assert!(err.message().contains("no file 'lib/missing.lua'"));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn loadfile_with_env() {
+        let (mut lua, _) = lua_with_files(&[("script.lua", "x = 5 return y")]);
+
+        let (y, x): (i32, i32) = lua.execute(r#"
+            local env = { y = 3 }
+            return loadfile("script.lua", "t", env)(), env.x
+        "#).unwrap();
+        assert_eq!((y, x), (3, 5));
+    }
+
+    #[test]
+    fn binary_chunks_refused() {
+        let (mut lua, vfs) = lua_with_files(&[]);
+        let dump: AnyLuaString = lua.execute("return string.dump(function() end)").unwrap();
+        vfs.insert("binary.luac", dump.0);
+
+        let f: Option<bool> = lua.execute("return loadfile('binary.luac')").unwrap();
+        assert_eq!(f, None);
+    }
+
+    #[test]
+    fn remove_and_rename() {
+        let (mut lua, vfs) = lua_with_files(&[("a.txt", "a"), ("b.txt", "b")]);
+
+        let ok: bool = lua.execute("return os.remove('a.txt') and os.rename('b.txt', 'c.txt')")
+                          .unwrap();
+        assert!(ok);
+        assert_eq!(vfs.get("a.txt"), None);
+        assert_eq!(vfs.get("b.txt"), None);
+        assert_eq!(vfs.get("c.txt"), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn panicking_vfs() {
+        struct Panicking;
+        impl Vfs for Panicking {
+            fn read(&self, _: &str) -> io::Result<Vec<u8>> { panic!("boom") }
+            fn write(&self, _: &str, _: &[u8]) -> io::Result<()> { panic!("boom") }
+            fn remove(&self, _: &str) -> io::Result<()> { panic!("boom") }
+            fn exists(&self, _: &str) -> bool { panic!("boom") }
+        }
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_vfs(Box::new(Panicking));
+
+        for code in &["io.open('a.txt')", "io.lines('a.txt')", "os.remove('a.txt')",
+                      "os.rename('a.txt', 'b.txt')", "package.searchpath('a', './?.lua')",
+                      "require('a')", "dofile('a.lua')", "loadfile('a.lua')"]
+        {
+            let code = format!("local ok, err = pcall(function() {} end) return ok, tostring(err)",
+                               code);
+            let (ok, err): (bool, String) = lua.execute(&code).unwrap();
+            assert!(!ok, "{}", code);
+            assert!(err.contains("boom"), "{}: {}", code, err);
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        let vfs = MemoryVfs::new();
+        vfs.insert("a.txt", "a");
+        lua.set_vfs(Box::new(ReadOnlyVfs(vfs.clone())));
+
+        let (f, err): (Option<bool>, String) = lua.execute("return io.open('a.txt', 'a')")
+                                                  .unwrap();
+        assert_eq!(f, None);
+        assert_eq!(err, "a.txt: Read-only file system");
+        let (removed, _): (Option<bool>, String) = lua.execute("return os.remove('a.txt')")
+                                                      .unwrap();
+        assert_eq!(removed, None);
+
+        let val: String = lua.execute("return io.open('a.txt'):read('*a')").unwrap();
+        assert_eq!(val, "a");
+        assert_eq!(vfs.get("a.txt"), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("a.txt"), Some("a.txt".to_owned()));
+        assert_eq!(normalize("/a//b/./c.txt"), Some("a/b/c.txt".to_owned()));
+        assert_eq!(normalize("a/../b/c/../d.txt"), Some("b/d.txt".to_owned()));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("a/.."), None);
+        assert_eq!(normalize("../secret"), None);
+        assert_eq!(normalize("a/../../secret"), None);
+        assert_eq!(normalize("..\\..\\secret"), None);
+        assert_eq!(normalize("a\\b"), None);
+        assert_eq!(normalize("C:\\secret"), None);
+        assert_eq!(normalize("C:/secret"), None);
+        assert_eq!(normalize("a/b:c"), None);
+    }
+
+    #[test]
+    fn directory() {
+        let root = env::temp_dir().join("hlua-dir-vfs-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/in.txt"), "from disk").unwrap();
+
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.set_vfs(Box::new(DirVfs::new(&root)));
+
+        let val: String = lua.execute(r#"
+            local f = io.open("out.txt", "w") f:write("from lua") f:close()
+            return io.open("./sub/../sub/in.txt"):read("*a")
+        "#).unwrap();
+        assert_eq!(val, "from disk");
+        assert_eq!(fs::read_to_string(root.join("out.txt")).unwrap(), "from lua");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn libraries_opened_afterwards() {
+        let mut lua = Lua::new();
+        let vfs = MemoryVfs::new();
+        vfs.insert("a.lua", "return 'a'");
+        lua.set_vfs(Box::new(vfs));
+        lua.openlibs();
+
+        let val: String = lua.execute("return dofile('a.lua') .. require('a')").unwrap();
+        assert_eq!(val, "aa");
+    }
+
+    #[test]
+    fn with_redirected_output() {
+        let (mut lua, vfs) = lua_with_files(&[]);
+        let output = lua.capture_output();
+
+        lua.execute::<()>(r#"
+            io.write("a")
+            io.output("out.txt") io.write("b") io.close()
+            io.output(io.stdout) io.write("c")
+        "#).unwrap();
+        assert_eq!(output.contents(), "ac");
+        assert_eq!(vfs.get("out.txt"), Some(b"b".to_vec()));
+    }
+}
@@ -112,7 +112,8 @@ pub struct lua_Debug {
     pub isvararg: libc::c_char,
     pub istailcall: libc::c_char,
     pub short_src: [libc::c_char ; 60],
-    //i_ci: *CallInfo
+    // private part, written by `lua_getstack`
+    i_ci: *mut libc::c_void,
 }
 
 extern "C" {
@@ -192,7 +193,7 @@ extern "C" {
     pub fn lua_setfenv(L: *mut lua_State, idx: c_int) -> c_int;
 
     pub fn lua_callk(L: *mut lua_State, nargs: c_int, nresults: c_int, ctx: c_int, k: Option<lua_CFunction>);
-    pub fn lua_getctx(L: *mut lua_State, ctx: c_int) -> c_int;
+    pub fn lua_getctx(L: *mut lua_State, ctx: *mut c_int) -> c_int;
     pub fn lua_pcallk(L: *mut lua_State, nargs: c_int, nresults: c_int, errfunc: c_int, ctx: c_int, k: Option<lua_CFunction>) -> c_int;
     pub fn lua_load(L: *mut lua_State, reader: lua_Reader, dt: *mut libc::c_void, chunkname: *const libc::c_char, mode: *const libc::c_char) -> c_int;
     pub fn lua_dump(L: *mut lua_State, writer: lua_Writer, data: *mut libc::c_void) -> c_int;
@@ -229,17 +230,20 @@ extern "C" {
     pub fn luaL_openlibs(L: *mut lua_State);
     pub fn luaL_ref(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn luaL_unref(L: *mut lua_State, idx: c_int, ref_id: c_int);
-
-    pub fn luaopen_base(L: *mut lua_State);
-    pub fn luaopen_bit32(L: *mut lua_State);
-    pub fn luaopen_coroutine(L: *mut lua_State);
-    pub fn luaopen_debug(L: *mut lua_State);
-    pub fn luaopen_io(L: *mut lua_State);
-    pub fn luaopen_math(L: *mut lua_State);
-    pub fn luaopen_os(L: *mut lua_State);
-    pub fn luaopen_package(L: *mut lua_State);
-    pub fn luaopen_string(L: *mut lua_State);
-    pub fn luaopen_table(L: *mut lua_State);
+    pub fn luaL_getmetafield(L: *mut lua_State, obj: c_int, e: *const libc::c_char) -> c_int;
+    pub fn luaL_traceback(L: *mut lua_State, L1: *mut lua_State, msg: *const libc::c_char,
+                          level: c_int);
+
+    pub fn luaopen_base(L: *mut lua_State) -> c_int;
+    pub fn luaopen_bit32(L: *mut lua_State) -> c_int;
+    pub fn luaopen_coroutine(L: *mut lua_State) -> c_int;
+    pub fn luaopen_debug(L: *mut lua_State) -> c_int;
+    pub fn luaopen_io(L: *mut lua_State) -> c_int;
+    pub fn luaopen_math(L: *mut lua_State) -> c_int;
+    pub fn luaopen_os(L: *mut lua_State) -> c_int;
+    pub fn luaopen_package(L: *mut lua_State) -> c_int;
+    pub fn luaopen_string(L: *mut lua_State) -> c_int;
+    pub fn luaopen_table(L: *mut lua_State) -> c_int;
 }
 
 #[inline(always)]
@@ -351,7 +355,8 @@ impl default::Default for lua_Debug {
             nparams: 0,
             isvararg: 0,
             istailcall: 0,
-            short_src: [0 ; 60]
+            short_src: [0 ; 60],
+            i_ci: ptr::null_mut(),
         }
     }
 }